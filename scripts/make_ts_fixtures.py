@@ -0,0 +1,249 @@
+#!/usr/bin/env python3
+
+#  Me TV — It's TV for me computer.
+#
+#  A GTK+/GStreamer client for watching and recording DVB.
+#
+#  Copyright © 2020  Russel Winder
+#
+#  This program is free software: you can redistribute it and/or modify
+#  it under the terms of the GNU General Public License as published by
+#  the Free Software Foundation, either version 3 of the License, or
+#  (at your option) any later version.
+#
+#  This program is distributed in the hope that it will be useful,
+#  but WITHOUT ANY WARRANTY; without even the implied warranty of
+#  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+#  GNU General Public License for more details.
+#
+#  You should have received a copy of the GNU General Public License
+#  along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+"""
+Create the small MPEG transport streams in tests/fixtures used to exercise the GStreamer
+side of Me TV without a DVB adapter.
+
+audio_service.ts is two seconds of a single radio service, 4164 "Me TV Radio", with silent
+MPEG-1 layer II audio and a PAT, PMT, SDT, NIT (with logical channel number 7), and present
+and following EIT every half second. The present event is event 1 starting at 2020-05-17
+20:00:00 UTC for an hour, the following event is event 2 starting at 21:00:00 for half an
+hour.
+
+truncated.ts is the first half second of audio_service.ts with an EIT section with a bad
+CRC inserted and the last packet cut short, as might be the case with a bad recording.
+
+Run from the root of the project:
+
+    python3 scripts/make_ts_fixtures.py
+"""
+
+import datetime
+import os
+import struct
+
+PACKET_SIZE = 188
+
+PAT_PID = 0x0000
+NIT_PID = 0x0010
+SDT_PID = 0x0011
+EIT_PID = 0x0012
+PMT_PID = 0x0100
+AUDIO_PID = 0x0101
+
+TRANSPORT_STREAM_ID = 0x1000
+ORIGINAL_NETWORK_ID = 0x233a
+NETWORK_ID = 0x3005
+SERVICE_ID = 4164
+LOGICAL_CHANNEL_NUMBER = 7
+
+DURATION = 2.0  # seconds
+TABLE_INTERVAL = 0.5  # seconds
+
+# MPEG-1 layer II, 32 kb/s, 48 kHz, mono: 1152 samples, so 24 ms, in 96 bytes. With all the
+# bit allocations zero, the frame is silence.
+AUDIO_FRAME = bytes([0xff, 0xfd, 0x14, 0xc0]) + bytes(92)
+AUDIO_FRAME_TICKS = 2160  # 24 ms at 90 kHz
+FIRST_PTS = 90000
+
+
+def crc32(data):
+    crc = 0xffffffff
+    for byte in data:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x04c11db7 if crc & 0x80000000 else crc << 1) & 0xffffffff
+    return crc
+
+
+def section(table_id, table_id_extension, body, section_number=0, last_section_number=0, version=0):
+    """A long form section, with the CRC."""
+    length = 5 + len(body) + 4
+    data = struct.pack(
+        '>BHHBBB',
+        table_id,
+        0xb000 | length,
+        table_id_extension,
+        0xc1 | (version << 1),
+        section_number,
+        last_section_number,
+    ) + body
+    return data + struct.pack('>I', crc32(data))
+
+
+def descriptor(tag, body):
+    return bytes([tag, len(body)]) + body
+
+
+def descriptors_loop(descriptors):
+    body = b''.join(descriptors)
+    return struct.pack('>H', 0xf000 | len(body)) + body
+
+
+def dvb_string(text):
+    return text.encode('latin-1')
+
+
+def bcd(value):
+    return ((value // 10) << 4) | (value % 10)
+
+
+def mjd_utc(when):
+    mjd = (when.date() - datetime.date(1858, 11, 17)).days
+    return struct.pack('>HBBB', mjd, bcd(when.hour), bcd(when.minute), bcd(when.second))
+
+
+def bcd_duration(seconds):
+    return bytes([bcd(seconds // 3600), bcd(seconds // 60 % 60), bcd(seconds % 60)])
+
+
+def pat():
+    body = struct.pack('>HH', 0, 0xe000 | NIT_PID) + struct.pack('>HH', SERVICE_ID, 0xe000 | PMT_PID)
+    return section(0x00, TRANSPORT_STREAM_ID, body)
+
+
+def pmt():
+    audio = struct.pack('>BH', 0x03, 0xe000 | AUDIO_PID) + descriptors_loop([descriptor(0x0a, b'eng\x00')])
+    body = struct.pack('>H', 0xe000 | AUDIO_PID) + descriptors_loop([]) + audio
+    return section(0x02, SERVICE_ID, body)
+
+
+def sdt():
+    service_descriptor = descriptor(0x48, bytes([0x02]) + bytes([5]) + dvb_string('Me TV') + bytes([11]) + dvb_string('Me TV Radio'))
+    service = struct.pack('>HB', SERVICE_ID, 0xfc) + struct.pack('>H', 0x8000 | len(service_descriptor)) + service_descriptor
+    body = struct.pack('>HB', ORIGINAL_NETWORK_ID, 0xff) + service
+    return section(0x42, TRANSPORT_STREAM_ID, body)
+
+
+def nit():
+    network_descriptors = descriptors_loop([descriptor(0x40, dvb_string('Me TV Test'))])
+    transport_descriptors = descriptors_loop([
+        descriptor(0x41, struct.pack('>HB', SERVICE_ID, 0x02)),
+        descriptor(0x5f, struct.pack('>I', 0x0000233a)),
+        descriptor(0x83, struct.pack('>HH', SERVICE_ID, 0xfc00 | LOGICAL_CHANNEL_NUMBER)),
+    ])
+    transport = struct.pack('>HH', TRANSPORT_STREAM_ID, ORIGINAL_NETWORK_ID) + transport_descriptors
+    body = network_descriptors + struct.pack('>H', 0xf000 | len(transport)) + transport
+    return section(0x40, NETWORK_ID, body)
+
+
+def eit(section_number, event_id, start, duration, name, text):
+    short_event = descriptor(0x4d, b'eng' + bytes([len(name)]) + dvb_string(name) + bytes([len(text)]) + dvb_string(text))
+    running_status = 4 if section_number == 0 else 1
+    event = struct.pack('>H', event_id) + mjd_utc(start) + bcd_duration(duration) \
+        + struct.pack('>H', (running_status << 13) | len(short_event)) + short_event
+    body = struct.pack('>HHBB', TRANSPORT_STREAM_ID, ORIGINAL_NETWORK_ID, 1, 0x4e) + event
+    return section(0x4e, SERVICE_ID, body, section_number, 1)
+
+
+PRESENT_EVENT = eit(0, 1, datetime.datetime(2020, 5, 17, 20, 0, 0), 3600, 'Test Card Music', 'Music to test receivers by.')
+FOLLOWING_EVENT = eit(1, 2, datetime.datetime(2020, 5, 17, 21, 0, 0), 1800, 'The News', 'The latest news.')
+
+
+class Packetiser:
+
+    def __init__(self):
+        self.continuity_counters = {}
+
+    def packet(self, pid, payload, payload_unit_start, adaptation=b''):
+        counter = self.continuity_counters.get(pid, 0)
+        self.continuity_counters[pid] = (counter + 1) % 16
+        space = PACKET_SIZE - 4 - len(payload)
+        if adaptation:
+            # The adaptation field fills the packet: length byte, flags and PCR, then stuffing.
+            stuffing = space - 1 - len(adaptation)
+            assert stuffing >= 0
+            field = bytes([space - 1]) + adaptation + b'\xff' * stuffing
+            control = 0x30
+        else:
+            assert space == 0
+            field = b''
+            control = 0x10
+        header = struct.pack('>BHB', 0x47, (0x4000 if payload_unit_start else 0) | pid, control | counter)
+        data = header + field + payload
+        assert len(data) == PACKET_SIZE
+        return data
+
+    def section_packets(self, pid, section):
+        data = b'\x00' + section
+        packets = []
+        while data:
+            payload, data = data[:PACKET_SIZE - 4], data[PACKET_SIZE - 4:]
+            # Sections are padded with 0xff rather than an adaptation field.
+            packets.append(self.packet(pid, payload.ljust(PACKET_SIZE - 4, b'\xff'), not packets))
+        return packets
+
+    def audio_packet(self, pts):
+        pts_bytes = bytes([
+            0x21 | ((pts >> 29) & 0x0e),
+            (pts >> 22) & 0xff,
+            0x01 | ((pts >> 14) & 0xfe),
+            (pts >> 7) & 0xff,
+            0x01 | ((pts << 1) & 0xfe),
+        ])
+        pes = b'\x00\x00\x01\xc0' + struct.pack('>H', 3 + len(pts_bytes) + len(AUDIO_FRAME)) \
+            + bytes([0x80, 0x80, len(pts_bytes)]) + pts_bytes + AUDIO_FRAME
+        pcr_base = pts - 18000
+        pcr = struct.pack('>IH', (pcr_base >> 1) & 0xffffffff, ((pcr_base & 1) << 15) | 0x7e00)
+        return self.packet(AUDIO_PID, pes, True, bytes([0x10]) + pcr)
+
+
+def tables(packetiser):
+    packets = []
+    for pid, table in [(PAT_PID, pat()), (PMT_PID, pmt()), (SDT_PID, sdt()), (NIT_PID, nit()), (EIT_PID, PRESENT_EVENT), (EIT_PID, FOLLOWING_EVENT)]:
+        packets += packetiser.section_packets(pid, table)
+    return packets
+
+
+def audio_service(duration):
+    packetiser = Packetiser()
+    packets = []
+    frames = int(duration * 90000 / AUDIO_FRAME_TICKS)
+    frames_per_tables = int(TABLE_INTERVAL * 90000 / AUDIO_FRAME_TICKS)
+    for frame in range(frames):
+        if frame % frames_per_tables == 0:
+            packets += tables(packetiser)
+        packets.append(packetiser.audio_packet(FIRST_PTS + frame * AUDIO_FRAME_TICKS))
+    return packetiser, packets
+
+
+def truncated():
+    packetiser, packets = audio_service(TABLE_INTERVAL)
+    bad_section = bytearray(PRESENT_EVENT)
+    bad_section[-1] ^= 0xff
+    packets[len(packets) // 2:len(packets) // 2] = packetiser.section_packets(EIT_PID, bytes(bad_section))
+    packets[-1] = packets[-1][:100]
+    return packets
+
+
+def write(path, packets):
+    with open(path, 'wb') as file:
+        for packet in packets:
+            file.write(packet)
+    print('Wrote {} bytes to {}.'.format(os.path.getsize(path), path))
+
+
+if __name__ == '__main__':
+    directory = os.path.join('tests', 'fixtures')
+    os.makedirs(directory, exist_ok=True)
+    write(os.path.join(directory, 'audio_service.ts'), audio_service(DURATION)[1])
+    write(os.path.join(directory, 'truncated.ts'), truncated())
@@ -19,15 +19,52 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::{thread, time};
+use std::{process, thread, time};
 use std::error::Error;
+use std::path::Path;
 
 use clap::{Arg, App};
 
 use gst::{gst_element_error, gst_element_warning};
 use gst::prelude::*;
 
+use me_tv::{channel_names, dvb, frontend_info, tuner_pool};
+use me_tv::frontend_info::FrontendId;
+use tuner_pool::{Claim, Priority, Request, TunerCapabilities, TunerPool};
+
+/// How long to wait for the GUI to give up a tuner: time for the user to answer and a bit.
+const TUNER_WAIT: time::Duration = time::Duration::from_secs(tuner_pool::PREEMPTION_TIMEOUT.as_secs() + 15);
+
+/// The tuners present and what they can receive.
+fn present_tuners() -> Vec<TunerCapabilities> {
+    let regex = regex::Regex::new(r"^/dev/dvb/adapter([0-9]+)/frontend([0-9]+)$").unwrap();
+    glob::glob("/dev/dvb/adapter*/frontend*").map(|paths| paths.filter_map(|p| p.ok()).filter_map(|path| {
+        let captures = regex.captures(path.to_str()?)?;
+        let tuner = FrontendId { adapter: captures[1].parse().ok()?, frontend: captures[2].parse().ok()? };
+        let delivery_systems = frontend_info::query(&frontend_info::DeviceIoctls, &path).map(|info| info.delivery_systems).unwrap_or_default();
+        Some(TunerCapabilities { tuner, delivery_systems })
+    }).collect()).unwrap_or_default()
+}
+
+/// Claim a tuner for recording the channel: the one asked for, or the best one available.
+/// If the GUI is using it, the user of the GUI is asked to give it up.
+fn claim_tuner(pool: &TunerPool, channels_file: &Path, channel: &str, tuner: Option<FrontendId>, scheduled: bool) -> Result<Claim, String> {
+    let request = Request {
+        delivery_system: channel_names::get_delivery_system(channels_file, channel),
+        multiplex: channel_names::get_frequency(channels_file, channel),
+        priority: if scheduled { Priority::ScheduledRecording } else { Priority::Recording },
+        description: format!("{}recording {}", if scheduled { "scheduled " } else { "" }, channel),
+    };
+    let tuners = match tuner {
+        Some(tuner) => vec![TunerCapabilities { tuner, delivery_systems: vec![] }],
+        None => present_tuners(),
+    };
+    pool.claim_waiting(&tuners, &request, TUNER_WAIT)
+}
+
 fn main() {
+    // Set before any thread is started, the environment is not safe to change afterwards.
+    channel_names::init_channels_conf();
     let matches = App::new("me-tv-record")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Russel Winder <russel@winder.org.uk>")
@@ -39,16 +76,16 @@ A channel name and a duration must be provided.
             .short("a")
             .long("adapter")
             .value_name("NUMBER")
-            .help("Sets the adapter number to use.")
+            .help("Sets the adapter number to use, by default a tuner is chosen from those available.")
             .takes_value(true)
-            .default_value("0"))
+            .requires("frontend"))
         .arg(Arg::with_name("frontend")
             .short("f")
             .long("frontend")
             .value_name("NUMBER")
-            .help("Sets the frontend number to use.")
+            .help("Sets the frontend number to use, by default a tuner is chosen from those available.")
             .takes_value(true)
-            .default_value("0"))
+            .requires("adapter"))
         .arg(Arg::with_name("channel")
             .short("c")
             .long("channel")
@@ -70,17 +107,68 @@ A channel name and a duration must be provided.
             .help("Path to output file, must be specified, no default.")
             .takes_value(true)
             .required(true))
+        .arg(Arg::with_name("ts_file")
+            .long("ts-file")
+            .value_name("PATH")
+            .help("Record from a transport stream file, in real time, rather than the channel, for testing.")
+            .takes_value(true))
+        .arg(Arg::with_name("scheduled")
+            .long("scheduled")
+            .help("The recording was scheduled, so may take a tuner from someone watching, after asking them."))
         .arg(Arg::with_name("verbose")
             .short("v")
             .long("verbose")
             .help("sets verbose mode"))
         .get_matches();
     let be_verbose = matches.is_present("verbose");
-    let adapter = matches.value_of("adapter").unwrap().parse::<u8>().expect("Couldn't parse adapter value as a positive integer.");
-    let frontend = matches.value_of("frontend").unwrap().parse::<u8>().expect("Couldn't parse frontend value as a positive integer.");
+    let requested_tuner = match (matches.value_of("adapter"), matches.value_of("frontend")) {
+        (Some(adapter), Some(frontend)) => Some(FrontendId {
+            adapter: adapter.parse::<u8>().expect("Couldn't parse adapter value as a positive integer."),
+            frontend: frontend.parse::<u8>().expect("Couldn't parse frontend value as a positive integer."),
+        }),
+        _ => None,
+    };
     let channel = matches.value_of("channel").unwrap();
     let duration = matches.value_of("duration").unwrap().parse::<u32>().expect("Couldn't parse the provided duration as a positive integer.");
     let output_path = matches.value_of("output").unwrap();
+    let ts_file_uri = matches.value_of("ts_file").map(|path| {
+        let path = std::fs::canonicalize(path).expect("Couldn't find the transport stream file.");
+        glib::filename_to_uri(&path, None).expect("Couldn't create a URI for the transport stream file.").to_string()
+    });
+    let pool = TunerPool::get_default();
+    // A transport stream file needs no tuner.
+    let claim = if ts_file_uri.is_some() {
+        None
+    } else {
+        // Each delivery system has its own channels file, use one the tuner can receive.
+        let delivery_systems = requested_tuner.as_ref()
+            .and_then(|FrontendId { adapter, frontend }| {
+                let path = format!("/dev/dvb/adapter{}/frontend{}", adapter, frontend);
+                frontend_info::query(&frontend_info::DeviceIoctls, Path::new(&path)).ok()
+            })
+            .map(|info| info.delivery_systems)
+            .filter(|delivery_systems| ! delivery_systems.is_empty())
+            .unwrap_or_else(|| dvb::DeliverySystem::iterator().cloned().collect());
+        let channels_file = match channel_names::find_channels_file(channel, &delivery_systems) {
+            Some(path) => path,
+            None => {
+                println!("Cannot record channel '{}': it is not in a channels file.", channel);
+                process::exit(exitcode::DATAERR);
+            },
+        };
+        if let Err(message) = channel_names::use_channels_file(&channels_file) {
+            println!("Cannot record channel '{}': {}", channel, message);
+            process::exit(exitcode::CANTCREAT);
+        }
+        match claim_tuner(&pool, &channels_file, channel, requested_tuner, matches.is_present("scheduled")) {
+            Ok(claim) => Some(claim),
+            Err(message) => {
+                println!("Cannot record channel '{}': {}", channel, message);
+                process::exit(exitcode::UNAVAILABLE);
+            },
+        }
+    };
+    let FrontendId { adapter, frontend } = claim.as_ref().map(|claim| claim.tuner.clone()).unwrap_or(FrontendId { adapter: 0, frontend: 0 });
     if be_verbose {
         println!("Recording channel '{}' for {} minutes on adapter {} frontend {}.", channel, duration, adapter, frontend);
     }
@@ -93,7 +181,11 @@ A channel name and a duration must be provided.
     let pipeline = gst::Pipeline::new(None);
     let uridecodebin = {
         let element = gst::ElementFactory::make("uridecodebin", None).expect("cannot make uridecodebin");
-        element.set_property("uri", &format!("dvb://{}", channel)).expect("cannot set uri property on uridecodebin");
+        let uri = match ts_file_uri {
+            Some(ref uri) => uri.clone(),
+            None => format!("dvb://{}", channel),
+        };
+        element.set_property("uri", &uri).expect("cannot set uri property on uridecodebin");
         element.connect("source-setup",  false, {
             let adapter_number = adapter;
             let frontend_number = frontend;
@@ -133,6 +225,11 @@ A channel name and a duration must be provided.
     let filesink = {
         let element = gst::ElementFactory::make("filesink", None).expect("cannot make filesrc");
         element.set_property("location", &output_path).expect("cannot set location for filesrc");
+        // A file can be read much faster than it was broadcast, so synchronise with the clock
+        // to record for the duration.
+        if ts_file_uri.is_some() {
+            element.set_property("sync", &true).expect("cannot set sync for filesink");
+        }
         element
     };
     pipeline.add_many(&[&uridecodebin, &mp4mux, &filesink]).expect("could not add elements to pipeline");
@@ -239,4 +336,7 @@ A channel name and a duration must be provided.
         }
     }
     pipeline.set_state(gst::State::Null).unwrap();
+    if let Some(claim) = claim {
+        pool.release(&claim);
+    }
 }
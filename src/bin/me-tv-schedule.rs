@@ -71,16 +71,16 @@ for example 0559 or 05:59, basically hhmm[ss] or hh:mm:[:ss].
             .short("a")
             .long("adapter")
             .value_name("NUMBER")
-            .help("Sets the adapter number to use.")
+            .help("Sets the adapter number to use, by default a tuner is chosen from those available when recording starts.")
             .takes_value(true)
-            .default_value("0"))
+            .requires("frontend"))
         .arg(Arg::with_name("frontend")
             .short("f")
             .long("frontend")
             .value_name("NUMBER")
-            .help("Sets the frontend number to use.")
+            .help("Sets the frontend number to use, by default a tuner is chosen from those available when recording starts.")
             .takes_value(true)
-            .default_value("0"))
+            .requires("adapter"))
         .arg(Arg::with_name("channel")
             .short("c")
             .long("channel")
@@ -122,8 +122,13 @@ for example 0559 or 05:59, basically hhmm[ss] or hh:mm:[:ss].
             .help("sets verbose mode"))
         .get_matches();
     let be_verbose = matches.is_present("verbose");
-    let adapter = matches.value_of("adapter").unwrap().parse::<u8>().expect("Couldn't parse adapter value as a positive integer.");
-    let frontend = matches.value_of("frontend").unwrap().parse::<u8>().expect("Couldn't parse frontend value as a positive integer.");
+    let tuner = match (matches.value_of("adapter"), matches.value_of("frontend")) {
+        (Some(adapter), Some(frontend)) => Some((
+            adapter.parse::<u8>().expect("Couldn't parse adapter value as a positive integer."),
+            frontend.parse::<u8>().expect("Couldn't parse frontend value as a positive integer."),
+        )),
+        _ => None,
+    };
     let channel = matches.value_of("channel").unwrap();
     let start_time = parse_to_datetime(matches.value_of("start_time").unwrap()).expect("Could not parse start time.");
     let now = {
@@ -162,26 +167,32 @@ for example 0559 or 05:59, basically hhmm[ss] or hh:mm:[:ss].
         process::exit(exitcode::USAGE);
     }
     let output_file = matches.value_of("output").unwrap();
+    let tuner_description = match tuner {
+        Some((adapter, frontend)) => format!("adapter {}, frontend {}", adapter, frontend),
+        None => "a tuner chosen when recording starts".to_string(),
+    };
     if be_verbose {
         println!(
-            "Scheduling recording of channel '{}' at {:?} for {} minutes to file {} using adapter {}, frontend {}.",
+            "Scheduling recording of channel '{}' at {:?} for {} minutes to file {} using {}.",
             channel,
             start_time,
             duration.num_minutes(),
             output_file,
-            adapter,
-            frontend,
+            tuner_description,
         );
     }
+    // The recording is scheduled so it can take a tuner from someone watching.
+    let mut record_command = format!(
+        "me-tv-record --scheduled --channel={} --duration={} --output={}",
+        channel,
+        duration.num_minutes(),
+        output_file,
+    );
+    if let Some((adapter, frontend)) = tuner {
+        record_command += &format!(" --adapter={} --frontend={}", adapter, frontend);
+    }
     let echo_process = process::Command::new("echo")
-        .arg(format!(
-            "me-tv-record --channel={} --duration={} --output={} --adapter={} --frontend={}",
-            channel,
-            duration.num_minutes(),
-            output_file,
-            adapter,
-            frontend,
-        ))
+        .arg(record_command)
         .stdout(process::Stdio::piped())
         .spawn()
         .expect("Failed to start echo process.");
@@ -19,13 +19,58 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log::warn;
+
+use serde_derive::{Deserialize, Serialize};
 
 use xdg;
 
+use crate::dvb::DeliverySystem;
+
+/// Which of the channels go in the channel lists, set in the preferences.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ChannelFilter {
+    All,
+    Television,
+    Radio,
+}
+
+impl Default for ChannelFilter {
+    fn default() -> Self { ChannelFilter::All }
+}
+
+impl ChannelFilter {
+    /// The name used for the state of the channel filter action of the control window.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelFilter::All => "all",
+            ChannelFilter::Television => "television",
+            ChannelFilter::Radio => "radio",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChannelFilter> {
+        [ChannelFilter::All, ChannelFilter::Television, ChannelFilter::Radio].iter().find(|f| f.name() == name).cloned()
+    }
+
+    pub fn accepts(&self, is_radio: bool) -> bool {
+        match self {
+            ChannelFilter::All => true,
+            ChannelFilter::Television => ! is_radio,
+            ChannelFilter::Radio => is_radio,
+        }
+    }
+}
+
 /// An internal function that can be tested.
 fn get_names_from_file(file: &File) -> Vec<String> {
     let buf_reader = BufReader::new(file);
@@ -38,7 +83,66 @@ fn get_names_from_file(file: &File) -> Vec<String> {
         .collect()
 }
 
+/// An internal function that can be tested.
+///
+/// Returns the name and the key/value bindings of each channel block in the file,
+/// in the order they appear.
+fn get_channels_from_file(file: &File) -> Vec<(String, HashMap<String, String>)> {
+    let buf_reader = BufReader::new(file);
+    let mut channels: Vec<(String, HashMap<String, String>)> = vec![];
+    for line in buf_reader.lines().filter_map(|i| i.ok()) {
+        let line = line.trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            channels.push((String::from(line[1..(line.len() - 1)].trim()), HashMap::new()));
+        } else if let Some((_, properties)) = channels.last_mut() {
+            let parts = line.splitn(2, '=').map(|x| x.trim()).collect::<Vec<&str>>();
+            if parts.len() == 2 {
+                properties.insert(parts[0].to_string(), parts[1].to_string());
+            }
+        }
+    }
+    channels
+}
+
+/// Whether a channel can be received using one of the delivery systems. A channel with no
+/// delivery system given is assumed receivable, one with a delivery system that Me TV does
+/// not support is not.
+fn is_receivable(properties: &HashMap<String, String>, delivery_systems: &[DeliverySystem]) -> bool {
+    match properties.get("DELIVERY_SYSTEM") {
+        Some(name) => match DeliverySystem::from_name(name) {
+            Some(delivery_system) => delivery_systems.contains(&delivery_system),
+            None => false,
+        },
+        None => true,
+    }
+}
+
+/// Whether a channel is a radio channel: it has audio but no video.
+fn is_radio(properties: &HashMap<String, String>) -> bool {
+    let has_pids = |key: &str| properties.get(key).map_or(false, |pids| pids.split_whitespace().any(|pid| pid != "0"));
+    has_pids("AUDIO_PID") && ! has_pids("VIDEO_PID")
+}
+
+/// An internal function that can be tested.
+fn get_radio_names_from_file(file: &File) -> HashSet<String> {
+    get_channels_from_file(file).into_iter()
+        .filter(|(_, properties)| is_radio(properties))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// An internal function that can be tested.
+fn get_receivable_names_from_file(file: &File, delivery_systems: &[DeliverySystem]) -> Vec<String> {
+    get_channels_from_file(file).into_iter()
+        .filter(|(_, properties)| is_receivable(properties, delivery_systems))
+        .map(|(name, _)| name)
+        .collect()
+}
+
 /// Return a `PathBuf` to the GStreamer dvbsrc plugin channels file using the XDG directory structure.
+///
+/// Earlier versions of Me TV used only this file, now it is only used if there is no
+/// channels file for the delivery system.
 pub fn channels_file_path() -> PathBuf {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("gstreamer-1.0").expect("Cannot set XDG prefix.");
     let mut path_buf = xdg_dirs.get_config_home();
@@ -46,20 +150,126 @@ pub fn channels_file_path() -> PathBuf {
     path_buf
 }
 
-/// Read the file that the GStreamer dvbsrc plugin uses and extract a list of the channels.
+/// Return a `PathBuf` to the channels file for a delivery system, it is in the same
+/// directory as the GStreamer dvbsrc plugin channels file.
+pub fn channels_file_path_for(delivery_system: &DeliverySystem) -> PathBuf {
+    let mut path_buf = channels_file_path();
+    path_buf.set_file_name(format!("dvb-channels-{}.conf", delivery_system));
+    path_buf
+}
+
+/// Copy the channels file of earlier versions of Me TV to be the channels file of the
+/// delivery system it was created for, unless there already is one. The original is left
+/// in place for anything else using the GStreamer dvbsrc plugin.
+pub fn migrate_channels_file(delivery_system: &DeliverySystem) {
+    let old_path = channels_file_path();
+    let new_path = channels_file_path_for(delivery_system);
+    if old_path.is_file() && ! new_path.exists() {
+        if let Err(error) = fs::copy(&old_path, &new_path) {
+            warn!("Could not copy {:?} to {:?}: {}", old_path, new_path, error);
+        }
+    }
+}
+
+/// The file the GStreamer dvbsrc plugin looks up channels in, a link to the channels file
+/// being used. Each process has its own.
+fn channels_conf_link_path() -> PathBuf {
+    let directory = match xdg::BaseDirectories::with_prefix("me-tv").ok().and_then(|dirs| dirs.get_runtime_directory().ok().map(|d| d.join("me-tv"))) {
+        Some(directory) => directory,
+        None => env::temp_dir().join(format!("me-tv-{}", nix::unistd::getuid())),
+    };
+    directory.join(format!("dvb-channels-{}.conf", process::id()))
+}
+
+/// Point the GStreamer dvbsrc plugin at the link to the channels file being used. The
+/// plugin has no property for the channels file, it reads the GST_DVB_CHANNELS_CONF
+/// environment variable, and changing the environment while other threads run is unsafe,
+/// so this must be called once at the start, before any threads are started.
+pub fn init_channels_conf() {
+    env::set_var("GST_DVB_CHANNELS_CONF", channels_conf_link_path());
+}
+
+/// Make the GStreamer dvbsrc plugin look up channels in a channels file. The plugin reads
+/// the file each time a dvb:// URI is used, so this must be called before each change of
+/// the pipeline to the playing state.
+pub fn use_channels_file(path: &Path) -> Result<(), String> {
+    link_channels_file(&channels_conf_link_path(), path)
+}
+
+/// Replace a link with one to a channels file, in one step so that the link is never missing.
+fn link_channels_file(link: &Path, path: &Path) -> Result<(), String> {
+    if let Some(directory) = link.parent() {
+        fs::create_dir_all(directory).map_err(|e| format!("Cannot create {:?}: {}", directory, e))?;
+    }
+    let new_link = link.with_extension("conf.new");
+    let _ = fs::remove_file(&new_link);
+    std::os::unix::fs::symlink(path, &new_link).map_err(|e| format!("Cannot link {:?} to {:?}: {}", new_link, path, e))?;
+    fs::rename(&new_link, link).map_err(|e| format!("Cannot rename {:?} to {:?}: {}", new_link, link, e))
+}
+
+/// Read a channels file and extract a list of the channels that can be received
+/// using one of the delivery systems.
 ///
-/// GStreamer uses the XDG directory structure with, currently, gstreamer-1.0 as its
-/// name. The dvbsrc plugin assumes the name dvb-channels.conf. The DVBv5 file format
-/// is INI/TOML style: a sequence of blocks, one for each channel, starting with a channel
-/// name surrounded by brackets and then a sequence of binding of keys to values each
-/// one indented.
-pub fn get_names() -> Option<Vec<String>> {
-    match File::open(channels_file_path()) {
-        Ok(file) => Some(get_names_from_file(&file)),
+/// The DVBv5 file format is INI/TOML style: a sequence of blocks, one for each channel,
+/// starting with a channel name surrounded by brackets and then a sequence of binding of
+/// keys to values each one indented.
+pub fn get_names(channels_file: &Path, delivery_systems: &[DeliverySystem]) -> Option<Vec<String>> {
+    match File::open(channels_file) {
+        Ok(file) => Some(get_receivable_names_from_file(&file, delivery_systems)),
         Err(_) => None,
     }
 }
 
+/// Return the channels file for one of the delivery systems that has a channel, if there is
+/// one. The channels file of earlier versions of Me TV is tried last.
+pub fn find_channels_file(channel_name: &str, delivery_systems: &[DeliverySystem]) -> Option<PathBuf> {
+    delivery_systems.iter()
+        .map(channels_file_path_for)
+        .chain(std::iter::once(channels_file_path()))
+        .find(|path| match File::open(path) {
+            Ok(file) => get_names_from_file(&file).iter().any(|name| name == channel_name),
+            Err(_) => false,
+        })
+}
+
+/// Return the value bound to a key in the block for a given channel, if there is one.
+pub fn get_channel_property(channels_file: &Path, channel_name: &str, key: &str) -> Option<String> {
+    match File::open(channels_file) {
+        Ok(file) => get_channels_from_file(&file).into_iter()
+            .find(|(name, _)| name == channel_name)
+            .and_then(|(_, properties)| properties.get(key).cloned()),
+        Err(_) => None,
+    }
+}
+
+/// Return the names of the radio channels of a channels file.
+pub fn get_radio_names(channels_file: &Path) -> HashSet<String> {
+    match File::open(channels_file) {
+        Ok(file) => get_radio_names_from_file(&file),
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// Return the service ID of each channel of a channels file, by channel name.
+pub fn get_service_ids(channels_file: &Path) -> HashMap<String, u16> {
+    match File::open(channels_file) {
+        Ok(file) => get_channels_from_file(&file).into_iter()
+            .filter_map(|(name, properties)| properties.get("SERVICE_ID").and_then(|s| s.parse::<u16>().ok()).map(|service_id| (name, service_id)))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Return the frequency of the multiplex a channel is on.
+pub fn get_frequency(channels_file: &Path, channel_name: &str) -> Option<u64> {
+    get_channel_property(channels_file, channel_name, "FREQUENCY").and_then(|s| s.parse::<u64>().ok())
+}
+
+/// Return the delivery system of a channel, if it is one Me TV supports.
+pub fn get_delivery_system(channels_file: &Path, channel_name: &str) -> Option<DeliverySystem> {
+    get_channel_property(channels_file, channel_name, "DELIVERY_SYSTEM").and_then(|s| DeliverySystem::from_name(&s))
+}
+
 /// Encode a string as used for display to one suitable to be an MRL.
 pub fn encode_to_mrl(channel_name: &String) -> String {
     "dvb://".to_owned() + &channel_name.replace(" ", "%20")
@@ -71,7 +281,9 @@ mod test {
 
     use tempfile;
 
-    use super::{get_names_from_file,encode_to_mrl};
+    use crate::dvb::DeliverySystem;
+
+    use super::{ChannelFilter, get_names_from_file, get_channels_from_file, get_radio_names_from_file, get_receivable_names_from_file, encode_to_mrl, link_channels_file};
 
     #[test]
     fn empty_file() {
@@ -107,6 +319,91 @@ mod test {
         assert_eq!(get_names_from_file(&tmpfile), result);
     }
 
+    #[test]
+    fn channel_properties() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        tmpfile.write_all("\
+[BBC ONE Lon]
+	SERVICE_ID = 4164
+	VIDEO_PID = 101
+	AUDIO_PID = 102 106
+
+[BBC Radio 4]
+	SERVICE_ID = 4160
+	AUDIO_PID = 406
+".as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        let channels = get_channels_from_file(&tmpfile);
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].0, "BBC ONE Lon");
+        assert_eq!(channels[0].1.get("SERVICE_ID").unwrap(), "4164");
+        assert_eq!(channels[0].1.get("AUDIO_PID").unwrap(), "102 106");
+        assert_eq!(channels[1].0, "BBC Radio 4");
+        assert_eq!(channels[1].1.get("VIDEO_PID"), None);
+    }
+
+    #[test]
+    fn radio_channels_have_no_video() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        tmpfile.write_all("\
+[BBC ONE Lon]
+	SERVICE_ID = 4164
+	VIDEO_PID = 101
+	AUDIO_PID = 102 106
+
+[BBC Radio 4]
+	SERVICE_ID = 4160
+	AUDIO_PID = 406
+
+[Classic FM]
+	SERVICE_ID = 8261
+	VIDEO_PID = 0
+	AUDIO_PID = 1601
+
+[BBC RB 1]
+	SERVICE_ID = 4608
+".as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        let mut names = get_radio_names_from_file(&tmpfile).into_iter().collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["BBC Radio 4", "Classic FM"]);
+    }
+
+    #[test]
+    fn channel_filters() {
+        assert!(ChannelFilter::All.accepts(true) && ChannelFilter::All.accepts(false));
+        assert!(ChannelFilter::Radio.accepts(true) && ! ChannelFilter::Radio.accepts(false));
+        assert!(! ChannelFilter::Television.accepts(true) && ChannelFilter::Television.accepts(false));
+        assert_eq!(ChannelFilter::from_name(ChannelFilter::Radio.name()), Some(ChannelFilter::Radio));
+        assert_eq!(ChannelFilter::from_name("films"), None);
+    }
+
+    #[test]
+    fn only_receivable_channels_are_listed() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        tmpfile.write_all("\
+[BBC ONE Lon]
+	FREQUENCY = 490000000
+	DELIVERY_SYSTEM = DVBT
+
+[BBC ONE HD]
+	FREQUENCY = 514000000
+	DELIVERY_SYSTEM = DVBT2
+
+[Satellite Channel]
+	DELIVERY_SYSTEM = DVBS2
+
+[Somewhere]
+	FREQUENCY = 490000000
+".as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(get_receivable_names_from_file(&tmpfile, &[DeliverySystem::DVBT]), vec!["BBC ONE Lon", "Somewhere"]);
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(get_receivable_names_from_file(&tmpfile, &[DeliverySystem::DVBT, DeliverySystem::DVBT2]), vec!["BBC ONE Lon", "BBC ONE HD", "Somewhere"]);
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(get_receivable_names_from_file(&tmpfile, &[DeliverySystem::DVBC_ANNEX_A]), vec!["Somewhere"]);
+    }
+
     #[test]
     fn encode_to_mrl_with_no_spaces() {
         assert_eq!(encode_to_mrl(&"ITV".to_owned()), "dvb://ITV");
@@ -122,4 +419,15 @@ mod test {
         assert_eq!(encode_to_mrl(&"BBC One Lon".to_owned()), "dvb://BBC%20One%20Lon");
     }
 
+    #[test]
+    fn the_link_is_replaced_by_one_to_the_channels_file_used() {
+        let directory = tempfile::tempdir().unwrap();
+        let link = directory.path().join("me-tv").join("dvb-channels-1.conf");
+        let dvbt = directory.path().join("dvb-channels-DVBT.conf");
+        let dvbc = directory.path().join("dvb-channels-DVBC_ANNEX_A.conf");
+        link_channels_file(&link, &dvbt).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), dvbt);
+        link_channels_file(&link, &dvbc).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), dvbc);
+    }
 }
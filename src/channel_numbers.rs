@@ -0,0 +1,257 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+
+//! Channel numbers: the logical channel numbers broadcast in the NIT, or numbers the user
+//! has given channels, and entering a number a digit at a time.
+//!
+//! Service IDs are only unique within a network, so the broadcast numbers are kept for
+//! each channels file, that is for each delivery system.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::warn;
+
+use crate::preferences;
+
+/// The most digits a channel number can have, the number is used as soon as this many
+/// digits have been entered.
+pub const MAX_DIGITS: usize = 4;
+
+/// How long to wait, in milliseconds, for another digit before using the digits entered.
+pub const DIGIT_ENTRY_TIMEOUT: u32 = 2000;
+
+/// The digits of a channel number as they are entered.
+#[derive(Debug, Default)]
+pub struct DigitEntry {
+    digits: String,
+}
+
+impl DigitEntry {
+    pub fn new() -> DigitEntry {
+        Default::default()
+    }
+
+    /// Add a digit, returning the number if it cannot have any more digits.
+    pub fn push(&mut self, digit: u8) -> Option<u16> {
+        assert!(digit < 10);
+        self.digits.push((b'0' + digit) as char);
+        if self.digits.len() >= MAX_DIGITS { self.take() } else { None }
+    }
+
+    /// The number entered, if there is one, ready for the next number.
+    pub fn take(&mut self) -> Option<u16> {
+        let digits = std::mem::take(&mut self.digits);
+        digits.parse::<u16>().ok()
+    }
+
+    /// The digits entered so far, for display.
+    pub fn get_text(&self) -> &str {
+        &self.digits
+    }
+}
+
+/// Sort channel names into number order, the channels with no number coming after the
+/// numbered ones in name order.
+pub fn sort_by_number(names: &mut [String], number_of: impl Fn(&str) -> Option<u16>) {
+    names.sort_by_cached_key(|name| (number_of(name).unwrap_or(u16::MAX), name.clone()));
+}
+
+/// The position of the channel with a number. If no channel has a number, the number is
+/// the position in the list, counting from 1.
+pub fn find_channel(numbers: &[Option<u16>], number: u16) -> Option<usize> {
+    match numbers.iter().position(|n| *n == Some(number)) {
+        Some(index) => Some(index),
+        None if numbers.iter().all(|n| n.is_none()) && number > 0 && (number as usize) <= numbers.len() => Some(number as usize - 1),
+        None => None,
+    }
+}
+
+/// The numbers of the channels of each channels file, by channels file name.
+type Numbers = BTreeMap<String, BTreeMap<u16, u16>>;
+
+/// Read the numbers file: a `[channels file name]` line followed by lines of
+/// `service ID = number` for each channels file. Lines that are not are ignored, the file
+/// is only a cache of what has been broadcast.
+fn parse_numbers(text: &str) -> Numbers {
+    let mut numbers = Numbers::new();
+    let mut current = None;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            current = Some(line[1..line.len() - 1].to_string());
+            continue;
+        }
+        let mut parts = line.splitn(2, '=').map(|part| part.trim().parse::<u16>());
+        if let (Some(ref channels_file), Some(Ok(service_id)), Some(Ok(number))) = (&current, parts.next(), parts.next()) {
+            numbers.entry(channels_file.clone()).or_default().insert(service_id, number);
+        }
+    }
+    numbers
+}
+
+fn numbers_to_text(numbers: &Numbers) -> String {
+    numbers.iter()
+        .map(|(channels_file, numbers)| {
+            let mut text = format!("[{}]\n", channels_file);
+            text.extend(numbers.iter().map(|(service_id, number)| format!("{} = {}\n", service_id, number)));
+            text
+        })
+        .collect()
+}
+
+/// The key of the numbers of a channels file.
+fn channels_file_key(channels_file: &Path) -> String {
+    channels_file.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+lazy_static! {
+    static ref BROADCAST_NUMBERS: Mutex<Numbers> = Mutex::new(Numbers::new());
+}
+
+/// The logical channel numbers are kept between runs since the NIT is only seen when
+/// watching a channel.
+fn numbers_file_path() -> PathBuf {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("me-tv").expect("Cannot set XDG prefix.");
+    let mut path_buf = xdg_dirs.get_cache_home();
+    path_buf.push("logical-channel-numbers");
+    path_buf
+}
+
+/// Read the logical channel numbers seen in earlier runs.
+pub fn init() {
+    if let Ok(text) = fs::read_to_string(numbers_file_path()) {
+        if let Ok(mut numbers) = BROADCAST_NUMBERS.lock() {
+            *numbers = parse_numbers(&text);
+        }
+    }
+}
+
+/// Add logical channel numbers found in a NIT while watching a channel of a channels file,
+/// returning whether any numbers changed.
+pub fn add_broadcast_numbers(channels_file: &Path, new_numbers: &BTreeMap<u16, u16>) -> bool {
+    let text = match BROADCAST_NUMBERS.lock() {
+        Ok(mut all_numbers) => {
+            let numbers = all_numbers.entry(channels_file_key(channels_file)).or_default();
+            let mut changed = false;
+            for (service_id, number) in new_numbers {
+                if numbers.insert(*service_id, *number) != Some(*number) {
+                    changed = true;
+                }
+            }
+            if ! changed { return false; }
+            numbers_to_text(&all_numbers)
+        },
+        Err(_) => return false,
+    };
+    let path = numbers_file_path();
+    if let Err(error) = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, text)) {
+        warn!("Could not write {:?}: {}", path, error);
+    }
+    true
+}
+
+pub fn get_broadcast_number(channels_file: &Path, service_id: u16) -> Option<u16> {
+    match BROADCAST_NUMBERS.lock() {
+        Ok(numbers) => numbers.get(&channels_file_key(channels_file)).and_then(|numbers| numbers.get(&service_id).cloned()),
+        Err(_) => None,
+    }
+}
+
+/// The number of a channel: the one the user has given it, otherwise the one broadcast.
+pub fn get_channel_number(channels_file: &Path, channel_name: &str, service_id: Option<u16>) -> Option<u16> {
+    preferences::get_channel_number(channel_name).or_else(|| service_id.and_then(|service_id| get_broadcast_number(channels_file, service_id)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digits_make_a_number() {
+        let mut entry = DigitEntry::new();
+        assert_eq!(entry.take(), None);
+        assert_eq!(entry.push(1), None);
+        assert_eq!(entry.push(0), None);
+        assert_eq!(entry.push(1), None);
+        assert_eq!(entry.get_text(), "101");
+        assert_eq!(entry.take(), Some(101));
+        assert_eq!(entry.get_text(), "");
+    }
+
+    #[test]
+    fn the_number_is_used_at_the_last_digit() {
+        let mut entry = DigitEntry::new();
+        for digit in &[0, 8, 0] {
+            assert_eq!(entry.push(*digit), None);
+        }
+        assert_eq!(entry.push(1), Some(801));
+        assert_eq!(entry.take(), None);
+    }
+
+    #[test]
+    fn numbered_channels_come_first() {
+        let mut names = vec!["Radio 4", "BBC TWO", "Al Jazeera", "BBC ONE", "ITV"].into_iter().map(String::from).collect::<Vec<String>>();
+        let numbers = |name: &str| match name {
+            "BBC ONE" => Some(1),
+            "BBC TWO" => Some(2),
+            "ITV" => Some(3),
+            "Radio 4" => Some(704),
+            _ => None,
+        };
+        sort_by_number(&mut names, numbers);
+        assert_eq!(names, vec!["BBC ONE", "BBC TWO", "ITV", "Radio 4", "Al Jazeera"]);
+    }
+
+    #[test]
+    fn find_a_channel_by_number() {
+        let numbers = [Some(1), Some(2), None, Some(101)];
+        assert_eq!(find_channel(&numbers, 101), Some(3));
+        assert_eq!(find_channel(&numbers, 3), None);
+        // With no numbers at all a number is a position in the list.
+        assert_eq!(find_channel(&[None, None, None], 3), Some(2));
+        assert_eq!(find_channel(&[None, None, None], 4), None);
+        assert_eq!(find_channel(&[None, None, None], 0), None);
+    }
+
+    #[test]
+    fn numbers_file_round_trip() {
+        let mut numbers = Numbers::new();
+        numbers.insert("dvb-channels-DVBT.conf".to_string(), vec![(4164, 1), (4287, 101), (8384, 704)].into_iter().collect());
+        numbers.insert("dvb-channels-DVBC_ANNEX_A.conf".to_string(), vec![(4164, 101)].into_iter().collect());
+        assert_eq!(parse_numbers(&numbers_to_text(&numbers)), numbers);
+    }
+
+    #[test]
+    fn numbers_file_lines_that_are_not_numbers_are_ignored() {
+        let numbers = parse_numbers("4163 = 7\n[dvb-channels-DVBT.conf]\n4164 = 1\nrubbish\n4165 = x\n= 3\n");
+        assert_eq!(numbers.len(), 1);
+        assert_eq!(numbers["dvb-channels-DVBT.conf"].len(), 1);
+    }
+
+    #[test]
+    fn numbers_are_kept_by_channels_file_name() {
+        assert_eq!(channels_file_key(Path::new("/home/me/.config/gstreamer-1.0/dvb-channels-DVBT.conf")), "dvb-channels-DVBT.conf");
+    }
+}
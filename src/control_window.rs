@@ -19,6 +19,7 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
 use std::process;
@@ -35,15 +36,20 @@ use gtk::prelude::*;
 use tempfile;
 
 use crate::about;
-use crate::channel_names::{channels_file_path, get_names};
+use crate::channel_names::{ChannelFilter, channels_file_path_for};
 use crate::control_window_button::ControlWindowButton;
+use crate::diagnostics_dialog;
 use crate::dialogs::display_an_error_dialog;
+use crate::dvb::DeliverySystem;
 use crate::epg_manager::EPGEventMessage;
 use crate::frontend_manager::FrontendId;
 use crate::preferences;
 use crate::preferences_dialog;
-use crate::remote_control::TargettedKeystroke;
+use crate::remote_control::{self, TargettedKeystroke};
+use crate::shortcuts_window;
 use crate::transmitter_dialog;
+use crate::ts_file;
+use crate::tuner_pool::{Claim, PREEMPTION_POLL_INTERVAL, PREEMPTION_TIMEOUT, TunerPool};
 
 /// A `ControlWindow` is an `gtk::ApplicationWindow` but there is no inheritance
 /// so use a bit of composition.
@@ -53,9 +59,9 @@ pub struct ControlWindow {
     main_box: gtk::Box,
     frontends_box: gtk::Box,
     label: gtk::Label,
-    pub channel_names_store: gtk::ListStore, // Used by ControlWindowButton and FrontendWindow.
-    channel_names_loaded: Cell<bool>,
     control_window_buttons: RefCell<Vec<Rc<ControlWindowButton>>>,
+    preemptions_asked: RefCell<Vec<String>>,
+    focused_frontend: RefCell<Option<FrontendId>>, // The frontend whose window last had the focus.
     pub to_epg_manager: std::sync::mpsc::Sender<EPGEventMessage>, // Used by ControlWindowButton.
 }
 
@@ -65,6 +71,9 @@ pub enum Message {
     FrontendAppeared{fei: FrontendId},
     FrontendDisappeared{fei: FrontendId},
     TargettedKeystrokeReceived{tk: TargettedKeystroke},
+    PreemptionRequestsChanged{requests: Vec<(Claim, String)>},
+    PreferencesFileChanged,
+    ChannelsFileChanged,
 }
 
 impl ControlWindow {
@@ -95,6 +104,16 @@ impl ControlWindow {
         window.add_action(&channels_file_action);
         let preferences_action = gio::SimpleAction::new("preferences", None);
         window.add_action(&preferences_action);
+        let diagnostics_action = gio::SimpleAction::new("diagnostics", None);
+        window.add_action(&diagnostics_action);
+        let channel_filter_action = gio::SimpleAction::new_stateful(
+            "channel_filter",
+            Some(glib::VariantTy::new("s").unwrap()),
+            &glib::Variant::from(preferences::get_channel_filter().name()),
+        );
+        window.add_action(&channel_filter_action);
+        let shortcuts_action = gio::SimpleAction::new("shortcuts", None);
+        window.add_action(&shortcuts_action);
         let about_action = gio::SimpleAction::new("about", None);
         window.add_action(&about_action);
         menu_button.set_menu_model(Some(&window_menu));
@@ -111,12 +130,11 @@ impl ControlWindow {
             main_box,
             frontends_box,
             label,
-            channel_names_store: gtk::ListStore::new(&[String::static_type()]),
-            channel_names_loaded: Cell::new(false),
             control_window_buttons: RefCell::new(Vec::new()),
+            preemptions_asked: RefCell::new(Vec::new()),
+            focused_frontend: RefCell::new(None),
             to_epg_manager,
         });
-        control_window.update_channels_store();
         epg_action.connect_activate({
             let c_w = control_window.clone();
             move |_, _| {
@@ -141,17 +159,45 @@ impl ControlWindow {
             let c_w = control_window.clone();
             move |_, _| preferences_dialog::present(&c_w)
         });
+        diagnostics_action.connect_activate({
+            let c_w = control_window.clone();
+            move |_, _| diagnostics_dialog::present(&c_w)
+        });
+        channel_filter_action.connect_activate({
+            let c_w = control_window.clone();
+            move |action, parameter| {
+                if let Some(channel_filter) = parameter.and_then(|p| p.get_str()).and_then(ChannelFilter::from_name) {
+                    action.set_state(&glib::Variant::from(channel_filter.name()));
+                    preferences::set_channel_filter(channel_filter, true);
+                    c_w.update_channels_store();
+                }
+            }
+        });
+        shortcuts_action.connect_activate({
+            let c_w = control_window.clone();
+            // Channels can only be paused when played from a transport stream file.
+            move |_, _| shortcuts_window::present(c_w.window.upcast_ref::<gtk::Window>(), ts_file::get_ts_file().is_some())
+        });
         about_action.connect_activate({
             let c_w = control_window.clone();
             move |_, _| about::present(Some(&c_w.window))
         });
         {
             let c_w = control_window.clone();
+            let c_f_a = channel_filter_action.clone();
             message_channel.attach(None, move |message| {
                 match message {
                     Message::FrontendAppeared{fei} => add_frontend(&c_w, &fei),
                     Message::FrontendDisappeared{fei} => remove_frontend(&c_w, &fei),
                     Message::TargettedKeystrokeReceived{tk} => process_targetted_keystroke(&c_w, &tk),
+                    Message::PreemptionRequestsChanged{requests} => process_preemption_requests(&c_w, requests),
+                    // The delivery system of a frontend, and so its channels file, may have changed.
+                    Message::PreferencesFileChanged => if preferences::reload() {
+                        remote_control::update_input_remote_controls();
+                        c_f_a.set_state(&glib::Variant::from(preferences::get_channel_filter().name()));
+                        c_w.update_channels_store();
+                    },
+                    Message::ChannelsFileChanged => c_w.update_channels_store(),
                 }
                 Continue(true)
             });
@@ -159,46 +205,70 @@ impl ControlWindow {
         control_window
     }
 
-    /// Transfer the list of channel names held by the control window into the selector box and set the default.
+    /// Reread the channels files into the channel lists of all the frontends.
     pub fn update_channels_store(&self) {
-        self.channel_names_store.clear();
-        match get_names() {
-            Some(mut channel_names) => {
-                channel_names.sort();
-                for name in channel_names {
-                    self.channel_names_store.insert_with_values(None, &[0], &[&name]);
-                };
-                self.channel_names_loaded.set(true);
-            },
-            None => {
-                self.channel_names_store.insert_with_values(None, &[0], &[&"No channels file."]);
-                self.channel_names_loaded.set(false);
-            }
-        }
         for button in self.control_window_buttons.borrow().iter() {
-            button.reset_active_channel();
+            ControlWindowButton::update_channels_store(button);
+        }
+    }
+
+    /// The list of channels of the first frontend, the default channel is for that frontend.
+    pub fn get_default_channel_names_store(&self) -> Option<gtk::ListStore> { // Used in preferences_dialog.rs
+        self.control_window_buttons.borrow().iter()
+            .find(|button| button.frontend_id == FrontendId{adapter: 0, frontend: 0})
+            .map(|button| button.channel_names_store.clone())
+    }
+
+    /// The frontends there are buttons for.
+    pub fn get_frontend_ids(&self) -> Vec<FrontendId> { // Used in preferences_dialog.rs
+        self.control_window_buttons.borrow().iter().map(|cwb| cwb.frontend_id.clone()).collect()
+    }
+
+    /// Record the frontend whose window has the focus, remote control keys may go to it.
+    pub fn set_focused_frontend(&self, fei: &FrontendId) { // Used in frontend_window.rs
+        self.focused_frontend.replace(Some(fei.clone()));
+    }
+
+    /// The channels of all the frontends, in name order, with the number broadcast for
+    /// each, if there is one.
+    pub fn get_broadcast_channel_numbers(&self) -> Vec<(String, Option<u16>)> { // Used in preferences_dialog.rs
+        let mut channels = BTreeMap::new();
+        for button in self.control_window_buttons.borrow().iter().filter(|button| button.is_channels_store_loaded()) {
+            for (name, number) in button.get_broadcast_channel_numbers() {
+                let entry = channels.entry(name).or_insert(None);
+                if entry.is_none() {
+                    *entry = number;
+                }
+            }
         }
+        channels.into_iter().collect()
     }
 
-    pub fn is_channels_store_loaded(&self) -> bool { self.channel_names_loaded.get() }
+    /// The frontends and the delivery system each is used with.
+    pub fn get_frontend_delivery_systems(&self) -> Vec<(FrontendId, DeliverySystem)> { // Used in preferences_dialog.rs
+        self.control_window_buttons.borrow().iter()
+            .map(|button| (button.frontend_id.clone(), button.get_delivery_system()))
+            .collect()
+    }
 
 }
 
-/// Ensure that the GStreamer dvbsrc channels file is present.
+/// Ensure that the channels file for the delivery system of a frontend is present.
 ///
 /// If the transmitter files are not present this function will do nothing.
 ///
 /// Currently try to use dvbv5-scan to create the file, or if it isn't present, try dvbscan or w_scan.
 fn ensure_channel_file_present(control_window: &Rc<ControlWindow>) {
-    match  transmitter_dialog::present(Some(&control_window.window)) {
-        Some(path_to_transmitter_file) => {
+    match  transmitter_dialog::present(Some(&control_window.window), &control_window.get_frontend_delivery_systems()) {
+        Some((fei, delivery_system, path_to_transmitter_file)) => {
+            let scan_command = format!("dvbv5-scan -a {} -f {} {}", fei.adapter, fei.frontend, path_to_transmitter_file.to_str().unwrap());
             //  TODO Turn this into a dialog that follows the GNOME HIG. Probably best to create a custom dialog.
             let start_dialog = gtk::MessageDialog::new(
                 Some(&control_window.window),
                 gtk::DialogFlags::MODAL,
                 gtk::MessageType::Info,
                 gtk::ButtonsType::OkCancel,   // TODO This button type is discourage by the GNOME HIG, incorrect button placements.
-                &format!("Run:\n\n    dvbv5-scan {}\n\n?\n\nYou need to have already closed all open channel viewers for this to work.", path_to_transmitter_file.to_str().unwrap()),
+                &format!("Run:\n\n    {}\n\n?\n\nYou need to have already closed all open channel viewers for this to work.", scan_command),
             );
             let response = gtk::ResponseType::from(start_dialog.run());
             start_dialog.destroy();
@@ -208,7 +278,7 @@ fn ensure_channel_file_present(control_window: &Rc<ControlWindow>) {
                     gtk::DialogFlags::MODAL,
                     gtk::MessageType::Info,
                     gtk::ButtonsType::None,
-                    &format!("Running:\n\n    dvbv5-scan {}\n\nThis may take a while.", path_to_transmitter_file.to_str().unwrap())
+                    &format!("Running:\n\n    {}\n\nThis may take a while.", scan_command)
                 );
                 wait_dialog.show_all();
                 let (sender, receiver) = glib::MainContext::channel::<bool>(glib::PRIORITY_DEFAULT);
@@ -230,6 +300,10 @@ fn ensure_channel_file_present(control_window: &Rc<ControlWindow>) {
                     move || {
                         let mut temporary_file = tempfile::NamedTempFile::new().expect("Could not create a temporary file.");
                         match process::Command::new("dvbv5-scan")
+                            .arg("-a")
+                            .arg(fei.adapter.to_string())
+                            .arg("-f")
+                            .arg(fei.frontend.to_string())
                             .arg("-o")
                             .arg(&temporary_file.path())
                             .arg(&p_t_t_f)
@@ -239,7 +313,7 @@ fn ensure_channel_file_present(control_window: &Rc<ControlWindow>) {
                                     .write(true)
                                     .truncate(true)
                                     .create(true)
-                                    .open(channels_file_path())
+                                    .open(channels_file_path_for(&delivery_system))
                                     .expect("Could not open channels file.");
                                 let mut buffer = String::new();
                                 temporary_file.read_to_string(&mut buffer).expect("Could not read temporary channels file.");
@@ -278,11 +352,11 @@ fn add_frontend(control_window: &Rc<ControlWindow>, fei: &FrontendId) {
                             display_an_error_dialog(Some(&c_w_b.control_window.window), "The channel is the empty string and cannot be tuned to.");
                         } else {
                             // TODO What to do if None is returned?
-                            if let Some(iterator) = control_window.channel_names_store.get_iter_first() {
+                            if let Some(iterator) = c_w_b.channel_names_store.get_iter_first() {
                                 loop {
-                                    if let Some(channel_name) = control_window.channel_names_store.get_value(&iterator, 0).get::<String>().unwrap() {
+                                    if let Some(channel_name) = c_w_b.channel_names_store.get_value(&iterator, 0).get::<String>().unwrap() {
                                         if target_channel_name == channel_name {
-                                            match control_window.channel_names_store.get_path(&iterator) {
+                                            match c_w_b.channel_names_store.get_path(&iterator) {
                                                 Some(mut tree_path) => {
                                                     let index = tree_path.get_indices_with_depth()[0];
                                                     if index < 0 { panic!("index cannot be a negative integer"); }
@@ -294,7 +368,7 @@ fn add_frontend(control_window: &Rc<ControlWindow>, fei: &FrontendId) {
                                             break;
                                         }
                                     }
-                                    if !control_window.channel_names_store.iter_next(&iterator) {
+                                    if !c_w_b.channel_names_store.iter_next(&iterator) {
                                         display_an_error_dialog(Some(&c_w_b.control_window.window), &format!("The channel {} could not be found for immediate TV display.", target_channel_name));
                                         break;
                                     }
@@ -315,6 +389,7 @@ fn remove_frontend(control_window: &Rc<ControlWindow>, fei: &FrontendId) {
     let mut remove_index = 0;
     for (index, control_window_button) in control_window.control_window_buttons.borrow().iter().enumerate() {
         if control_window_button.frontend_id == *fei {
+            control_window_button.release_tuner();
             control_window.frontends_box.remove(&control_window_button.widget);
             remove_index = index;
             break;
@@ -328,10 +403,102 @@ fn remove_frontend(control_window: &Rc<ControlWindow>, fei: &FrontendId) {
     control_window.window.show_all();
 }
 
-/// Process a keystroke bound for a given frontend.
+/// Ask the user whether to give up a tuner that another Me TV process, a scheduled recording
+/// say, wants. No answer in time is taken as yes, the user may not be there.
+fn ask_to_give_up_tuner(control_window: &Rc<ControlWindow>, control_window_button: &Rc<ControlWindowButton>, claims: Vec<Claim>, wanted_for: &str) {
+    let uses = claims.iter().map(|claim| claim.description.clone()).collect::<Vec<String>>();
+    let dialog = gtk::MessageDialog::new(
+        Some(&control_window.window),
+        gtk::DialogFlags::DESTROY_WITH_PARENT,
+        gtk::MessageType::Question,
+        gtk::ButtonsType::YesNo,
+        &format!(
+            "{} is being used for {}, but is wanted for {}.\n\nGive it up? If there is no answer in {} seconds, it will be given up.",
+            control_window_button.frontend_id,
+            uses.join(", "),
+            wanted_for,
+            PREEMPTION_TIMEOUT.as_secs(),
+        ),
+    );
+    let answered = Rc::new(Cell::new(false));
+    dialog.connect_response({
+        let answered = answered.clone();
+        let c_w_b = control_window_button.clone();
+        move |d, response| {
+            if answered.replace(true) { return; }
+            if response == gtk::ResponseType::Yes {
+                // Stopping the frontend window releases all the claims on the tuner.
+                c_w_b.frontend_button.set_active(false);
+            } else {
+                let pool = TunerPool::get_default();
+                for claim in &claims {
+                    pool.refuse_preemption(claim);
+                }
+            }
+            d.destroy();
+        }
+    });
+    gtk::timeout_add_seconds(PREEMPTION_TIMEOUT.as_secs() as u32, {
+        let dialog = dialog.clone();
+        move || {
+            if ! answered.get() {
+                dialog.response(gtk::ResponseType::Yes);
+            }
+            Continue(false)
+        }
+    });
+    dialog.show();
+}
+
+/// Ask the user about any requests from other Me TV processes for the tuners being used,
+/// once for each tuner. The requests are looked for by a thread of their own, see `run`.
+fn process_preemption_requests(control_window: &Rc<ControlWindow>, requests: Vec<(Claim, String)>) {
+    control_window.preemptions_asked.borrow_mut().retain(|id| requests.iter().any(|(claim, _)| claim.id == *id));
+    let new_requests = requests.into_iter()
+        .filter(|(claim, _)| ! control_window.preemptions_asked.borrow().contains(&claim.id))
+        .collect::<Vec<(Claim, String)>>();
+    for control_window_button in control_window.control_window_buttons.borrow().iter() {
+        let tuner = &control_window_button.frontend_id;
+        let claims = new_requests.iter().filter(|(claim, _)| claim.tuner == *tuner).map(|(claim, _)| claim.clone()).collect::<Vec<Claim>>();
+        if let Some((_, wanted_for)) = new_requests.iter().find(|(claim, _)| claim.tuner == *tuner) {
+            control_window.preemptions_asked.borrow_mut().extend(claims.iter().map(|claim| claim.id.clone()));
+            ask_to_give_up_tuner(control_window, control_window_button, claims, wanted_for);
+        }
+    }
+}
+
+/// Pass on the requests from other Me TV processes for the tuners of this one whenever they
+/// change, looking for them in a thread of their own so as not to hold up the GTK event loop.
+pub fn run(to_cw: glib::Sender<Message>) {
+    TunerPool::get_default().watch_preemption_requests(PREEMPTION_POLL_INTERVAL, |requests| {
+        to_cw.send(Message::PreemptionRequestsChanged{requests}).is_ok()
+    });
+}
+
+/// The frontend whose window has, or last had, the focus. If that window has been closed,
+/// the frontend of any open window, and failing that the first frontend.
+fn get_focused_frontend(control_window: &ControlWindow) -> Option<FrontendId> {
+    let buttons = control_window.control_window_buttons.borrow();
+    if let Some(ref fei) = *control_window.focused_frontend.borrow() {
+        if buttons.iter().any(|cwb| cwb.frontend_id == *fei && cwb.has_frontend_window()) {
+            return Some(fei.clone());
+        }
+    }
+    buttons.iter().find(|cwb| cwb.has_frontend_window()).or_else(|| buttons.first()).map(|cwb| cwb.frontend_id.clone())
+}
+
+/// Process a keystroke bound for a given frontend, or for the focused frontend window if
+/// no frontend is given.
 fn process_targetted_keystroke(control_window: &Rc<ControlWindow>, tk: &TargettedKeystroke) {
+    let frontend_id = match tk.frontend_id {
+        Some(ref fei) => fei.clone(),
+        None => match get_focused_frontend(control_window) {
+            Some(fei) => fei,
+            None => return,
+        },
+    };
     for c_w_b in control_window.control_window_buttons.borrow().iter()
-        .filter(|cwb| cwb.frontend_id == tk.frontend_id) {
-        c_w_b.process_targetted_keystroke(&tk);
+        .filter(|cwb| cwb.frontend_id == frontend_id) {
+        ControlWindowButton::process_targetted_keystroke(c_w_b, &tk);
     }
 }
@@ -19,21 +19,29 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+use glib;
 use gtk;
 use gtk::prelude::*;
 
-use crate::channel_names::encode_to_mrl;
+use log::{info, warn};
+
+use crate::channel_names::{ChannelFilter, channels_file_path_for, encode_to_mrl, get_delivery_system, get_frequency, get_names, get_radio_names, get_service_ids};
+use crate::channel_numbers::{DIGIT_ENTRY_TIMEOUT, DigitEntry, find_channel, get_broadcast_number, get_channel_number, sort_by_number};
 use crate::control_window::ControlWindow;
 use crate::dialogs::display_an_error_dialog;
+use crate::dvb::DeliverySystem;
 use crate::frontend_manager::FrontendId;
 use crate::frontend_window::FrontendWindow;
-use crate::input_event_codes;
+use crate::keymap::{self, Action};
 use crate::metvcomboboxtext::{MeTVComboBoxText, MeTVComboBoxTextExt};
 use crate::preferences;
 use crate::remote_control::TargettedKeystroke;
+use crate::remote_input::{KEY_PRESS, RemoteInput};
+use crate::tuner_pool::{Allocation, Claim, Priority, Request, TunerPool};
 
 /// A `ControlWindowButton` is a `gtk::Box` but there is no inheritance so use
 /// a bit of composition.
@@ -44,7 +52,25 @@ pub struct ControlWindowButton {
     pub widget: gtk::Box, // ControlWindow instance needs access to this for packing.
     pub frontend_button: gtk::ToggleButton, // FrontendWindow needs access to this.
     pub channel_selector: MeTVComboBoxText, // FrontendWindow needs read access to this.
+    pub channel_names_store: gtk::ListStore, // Used by ControlWindow, FrontendWindow, and the preferences dialog.
+    channel_names_loaded: Cell<bool>,
+    updating_channels_store: Cell<bool>,
+    channels: RefCell<Vec<(String, Option<u16>, bool)>>, // The channels in the store, in order, with their service IDs and whether they are radio channels.
+    channel_number_entry: RefCell<DigitEntry>,
+    channel_number_timeout: RefCell<Option<glib::SourceId>>,
+    remote_input: RefCell<RemoteInput>, // The keys held down on the remote controls sending keys here.
     frontend_window: RefCell<Option<Rc<FrontendWindow>>>,
+    tuner_claim: RefCell<Option<Claim>>,
+}
+
+/// What is wanted of a tuner to watch or record a channel.
+pub fn channel_request(channels_file: &Path, channel_name: &str, priority: Priority) -> Request { // Used in frontend_window.rs
+    Request {
+        delivery_system: get_delivery_system(channels_file, channel_name),
+        multiplex: get_frequency(channels_file, channel_name),
+        priority,
+        description: format!("{} {}", if priority == Priority::Viewing { "watching" } else { "recording" }, channel_name),
+    }
 }
 
 impl ControlWindowButton {
@@ -60,7 +86,12 @@ impl ControlWindowButton {
         let frontend_button = gtk::ToggleButton::new_with_label(
             format!("adaptor{}\nfrontend{}", frontend_id.adapter, frontend_id.frontend).as_ref()
         );
-        let channel_selector = MeTVComboBoxText::new_and_set_model(&control_window.channel_names_store);
+        if let Some(info) = frontend_id.get_info() {
+            frontend_button.set_tooltip_text(Some(&info.to_string()));
+        }
+        preferences::add_frontend_preferences(&frontend_id, &frontend_id.get_info().map(|info| info.delivery_systems).unwrap_or_default());
+        let channel_names_store = gtk::ListStore::new(&[String::static_type()]);
+        let channel_selector = MeTVComboBoxText::new_and_set_model(&channel_names_store);
         let widget = gtk::Box::new(gtk::Orientation::Vertical, 0);
         widget.pack_start(&frontend_button, true, true, 0);
         widget.pack_start(&channel_selector, true, true, 0);
@@ -70,32 +101,198 @@ impl ControlWindowButton {
             widget,
             frontend_button,
             channel_selector,
+            channel_names_store,
+            channel_names_loaded: Cell::new(false),
+            updating_channels_store: Cell::new(false),
+            channels: RefCell::new(vec![]),
+            channel_number_entry: RefCell::new(DigitEntry::new()),
+            channel_number_timeout: RefCell::new(None),
+            remote_input: RefCell::new(RemoteInput::new()),
             frontend_window: RefCell::new(None),
+            tuner_claim: RefCell::new(None),
         });
-        control_window_button.reset_active_channel();
+        Self::update_channels_store(&control_window_button);
         control_window_button.channel_selector.connect_changed({
             let c_w_b = control_window_button.clone();
-            move |_| Self::on_channel_changed(&c_w_b, c_w_b.channel_selector.get_active().unwrap())
+            move |_| if let Some(index) = c_w_b.channel_selector.get_active() {
+                Self::on_channel_changed(&c_w_b, index)
+            }
         });
         control_window_button.frontend_button.connect_toggled({
             let c_w_b = control_window_button.clone();
             move |_| {
-                if c_w_b.control_window.is_channels_store_loaded() {
+                if c_w_b.is_channels_store_loaded() {
                     Self::toggle_button(&c_w_b);
                 } else {
-                    display_an_error_dialog(Some(&c_w_b.control_window.window), "No channel file, so no channel list, so cannot play a channel.");
+                    let message = if c_w_b.get_channels_file_path().is_file() {
+                        "The channel filter leaves no channels in the channel list, so cannot play a channel."
+                    } else {
+                        "No channel file, so no channel list, so cannot play a channel."
+                    };
+                    display_an_error_dialog(Some(&c_w_b.control_window.window), message);
                 }
             }
         });
         control_window_button
     }
 
-    /// Set the active channel to 0.
-    pub fn reset_active_channel(&self) {  // Used in control_window.rs
-        self.channel_selector.set_active(Some(0));
+    /// The delivery systems the tuner can receive, or if that is not known, the delivery
+    /// system the frontend is used with.
+    fn get_receivable_delivery_systems(&self) -> Vec<DeliverySystem> {
+        match self.frontend_id.get_info() {
+            Some(info) if ! info.delivery_systems.is_empty() => info.delivery_systems,
+            _ => vec![self.get_delivery_system()],
+        }
+    }
+
+    /// The delivery system the frontend is used with, it determines the channels file.
+    pub fn get_delivery_system(&self) -> DeliverySystem { // Used in control_window.rs and preferences_dialog.rs
+        let capabilities = self.frontend_id.get_info().map(|info| info.delivery_systems).unwrap_or_default();
+        preferences::get_frontend_delivery_system(&self.frontend_id, &capabilities)
+    }
+
+    /// The channels file for the frontend.
+    pub fn get_channels_file_path(&self) -> PathBuf { // Used in control_window.rs and frontend_window.rs
+        channels_file_path_for(&self.get_delivery_system())
+    }
+
+    /// Fill the list of channels from the channels file for the frontend, leaving out those
+    /// the tuner cannot receive and those the channel filter does not want. The list is in
+    /// name order, or number order if the user has asked for that.
+    ///
+    /// The channel selected stays selected if it is still in the list, otherwise the first
+    /// channel is selected, and tuned to if the frontend window is showing.
+    pub fn update_channels_store(control_window_button: &Rc<ControlWindowButton>) { // Used in control_window.rs
+        let current_channel = control_window_button.channel_selector.get_active_text();
+        // Emptying the list changes the selection, which must not be taken as the user changing channel.
+        control_window_button.updating_channels_store.set(true);
+        control_window_button.channel_names_store.clear();
+        let channels_file = control_window_button.get_channels_file_path();
+        let service_ids = get_service_ids(&channels_file);
+        let radio_names = get_radio_names(&channels_file);
+        let channel_filter = preferences::get_channel_filter();
+        let channel_names = match get_names(&channels_file, &control_window_button.get_receivable_delivery_systems()) {
+            Some(mut channel_names) => {
+                channel_names.retain(|name| channel_filter.accepts(radio_names.contains(name)));
+                if preferences::get_sort_channels_by_number() {
+                    sort_by_number(&mut channel_names, |name| get_channel_number(&channels_file, name, service_ids.get(name).cloned()));
+                } else {
+                    channel_names.sort();
+                }
+                if channel_names.is_empty() && channel_filter != ChannelFilter::All {
+                    control_window_button.channel_names_loaded.set(false);
+                    let kind = if channel_filter == ChannelFilter::Radio { "radio" } else { "TV" };
+                    vec![format!("No {} channels.", kind)]
+                } else {
+                    control_window_button.channel_names_loaded.set(true);
+                    channel_names
+                }
+            },
+            None => {
+                control_window_button.channel_names_loaded.set(false);
+                vec!["No channels file.".to_string()]
+            }
+        };
+        control_window_button.channels.replace(
+            channel_names.iter().map(|name| (name.clone(), service_ids.get(name).cloned(), radio_names.contains(name))).collect()
+        );
+        for name in &channel_names {
+            control_window_button.channel_names_store.insert_with_values(None, &[0], &[name]);
+        }
+        let index = current_channel.as_ref().and_then(|current| channel_names.iter().position(|name| name == current));
+        control_window_button.set_active_channel(index.unwrap_or(0) as u32);
+        control_window_button.updating_channels_store.set(false);
+        if index.is_none() && control_window_button.is_channels_store_loaded() {
+            Self::on_channel_changed(control_window_button, 0);
+        }
+    }
+
+    pub fn is_channels_store_loaded(&self) -> bool { self.channel_names_loaded.get() }
+
+    pub fn has_frontend_window(&self) -> bool { self.frontend_window.borrow().is_some() } // Used in control_window.rs
+
+    /// The service ID of a channel in the list, the key used to index the EPG data.
+    pub fn get_service_id(&self, channel_name: &str) -> Option<u16> { // Used in frontend_window.rs
+        self.channels.borrow().iter().find(|(name, _, _)| name == channel_name).and_then(|(_, service_id, _)| *service_id)
+    }
+
+    /// Whether a channel in the list is a radio channel, one with no video.
+    pub fn is_radio_channel(&self, channel_name: &str) -> bool { // Used in frontend_window.rs
+        self.channels.borrow().iter().any(|(name, _, radio)| name == channel_name && *radio)
+    }
+
+    /// The channels in the list with the number broadcast for each, if there is one.
+    pub fn get_broadcast_channel_numbers(&self) -> Vec<(String, Option<u16>)> { // Used in control_window.rs
+        let channels_file = self.get_channels_file_path();
+        self.channels.borrow().iter()
+            .map(|(name, service_id, _)| (name.clone(), service_id.and_then(|service_id| get_broadcast_number(&channels_file, service_id))))
+            .collect()
+    }
+
+    /// Change to the channel with a number.
+    fn set_channel_number(&self, number: u16) {
+        let channels_file = self.get_channels_file_path();
+        let numbers = self.channels.borrow().iter()
+            .map(|(name, service_id, _)| get_channel_number(&channels_file, name, *service_id))
+            .collect::<Vec<Option<u16>>>();
+        match find_channel(&numbers, number) {
+            Some(index) => self.channel_selector.set_active(Some(index as u32)),
+            None => info!("There is no channel {}.", number),
+        }
+    }
+
+    /// Add a digit to the channel number being entered, changing channel when the number
+    /// is complete or no digit has come for a while.
+    fn enter_channel_number_digit(control_window_button: &Rc<ControlWindowButton>, digit: u8) {
+        if let Some(source_id) = control_window_button.channel_number_timeout.replace(None) {
+            glib::source_remove(source_id);
+        }
+        let number = control_window_button.channel_number_entry.borrow_mut().push(digit);
+        match number {
+            Some(number) => control_window_button.finish_channel_number_entry(Some(number)),
+            None => {
+                if let Some(ref f_w) = *control_window_button.frontend_window.borrow() {
+                    f_w.show_channel_number_entry(Some(control_window_button.channel_number_entry.borrow().get_text()));
+                }
+                let source_id = gtk::timeout_add(DIGIT_ENTRY_TIMEOUT, {
+                    let c_w_b = control_window_button.clone();
+                    move || {
+                        c_w_b.channel_number_timeout.replace(None);
+                        let number = c_w_b.channel_number_entry.borrow_mut().take();
+                        c_w_b.finish_channel_number_entry(number);
+                        glib::Continue(false)
+                    }
+                });
+                control_window_button.channel_number_timeout.replace(Some(source_id));
+            },
+        }
+    }
+
+    /// Show the number and name of the channel, and what is on, in the OSD, unless a channel
+    /// number is being entered.
+    fn show_channel_info(control_window_button: &Rc<ControlWindowButton>, frontend_window: &Rc<FrontendWindow>) {
+        if ! control_window_button.channel_number_entry.borrow().get_text().is_empty() { return; }
+        let number = control_window_button.channel_selector.get_active()
+            .and_then(|index| control_window_button.channels.borrow().get(index as usize).cloned())
+            .and_then(|(name, service_id, _)| get_channel_number(&control_window_button.get_channels_file_path(), &name, service_id));
+        FrontendWindow::show_channel_info(frontend_window, number);
+    }
+
+    fn finish_channel_number_entry(&self, number: Option<u16>) {
+        if let Some(ref f_w) = *self.frontend_window.borrow() {
+            f_w.show_channel_number_entry(None);
+        }
+        if let Some(number) = number {
+            self.set_channel_number(number);
+        }
+    }
+
+    /// Set the active channel of all the channel selectors.
+    fn set_active_channel(&self, channel_index: u32) {
+        self.channel_selector.set_active(Some(channel_index));
         if let Some(ref frontend_window) = *self.frontend_window.borrow() {
-            frontend_window.channel_selector.set_active(Some(0));
-            frontend_window.fullscreen_channel_selector.set_active(Some(0));
+            frontend_window.channel_selector.set_active(Some(channel_index));
+            frontend_window.fullscreen_channel_selector.set_active(Some(channel_index));
         }
     }
 
@@ -117,16 +314,56 @@ impl ControlWindowButton {
         }
     }
 
+    /// Claim the tuner in the tuner pool for watching the selected channel.
+    ///
+    /// If the pool cannot be used at all, carry on without a claim rather than stop the
+    /// user watching.
+    fn claim_tuner(&self) -> Result<(), String> {
+        let channel_name = self.channel_selector.get_active_text().unwrap();
+        let tuner = &self.frontend_id;
+        let pool = TunerPool::get_default();
+        match pool.claim_tuner(tuner.clone(), &channel_request(&self.get_channels_file_path(), &channel_name, Priority::Viewing)) {
+            Ok(Allocation::Claimed(claim)) => {
+                self.tuner_claim.replace(Some(claim));
+                Ok(())
+            },
+            Ok(_) => {
+                let uses = pool.get_claims().unwrap_or_default().into_iter()
+                    .filter(|claim| claim.tuner == *tuner)
+                    .map(|claim| claim.description)
+                    .collect::<Vec<String>>();
+                Err(format!("{} is being used for {}.", tuner, uses.join(", ")))
+            },
+            Err(message) => {
+                warn!("Could not use the tuner pool: {}", message);
+                Ok(())
+            },
+        }
+    }
+
+    /// Release the claim on the tuner, if there is one.
+    pub fn release_tuner(&self) { // Used in control_window.rs
+        if let Some(claim) = self.tuner_claim.replace(None) {
+            TunerPool::get_default().release(&claim);
+        }
+    }
+
     /// Toggle the button.
     ///
     /// This function is called after the change of state of the frontend_button.
     fn toggle_button(control_window_button: &Rc<ControlWindowButton>) { // Used in control_window.rs
         if control_window_button.frontend_button.get_active() {
-            if control_window_button.control_window.is_channels_store_loaded() {
+            if control_window_button.is_channels_store_loaded() {
+                if let Err(message) = control_window_button.claim_tuner() {
+                    display_an_error_dialog(Some(&control_window_button.control_window.window), &message);
+                    control_window_button.frontend_button.set_active(false);
+                    return;
+                }
                 let frontend_window = match FrontendWindow::new(control_window_button.clone()) {
                     Ok(frontend_window) => frontend_window,
                     Err(_) => {
                         display_an_error_dialog(Some(&control_window_button.control_window.window), "Could not create a frontend window, most likely because\na GStreamer engine could not be created.");
+                        control_window_button.release_tuner();
                         return;
                     },
                 };
@@ -137,23 +374,33 @@ impl ControlWindowButton {
             }
             // TODO Should there be an else activity here?
         } else {
-            match control_window_button.frontend_window.replace(None) {
-                Some(ref frontend_window) => frontend_window.stop(),
-                None => panic!("Inconsistent state of frontend,"),
+            // There is no frontend window if the tuner could not be claimed.
+            if let Some(ref frontend_window) = control_window_button.frontend_window.replace(None) {
+                frontend_window.stop();
             }
+            control_window_button.release_tuner();
         }
     }
 
     /// Callback for an observed channel change.
     pub fn on_channel_changed(control_window_button: &Rc<ControlWindowButton>, channel_index: u32) { // Used in frontend_window.rs
+        if control_window_button.updating_channels_store.get() { return; }
         let status = control_window_button.frontend_button.get_active();
         if let Some(ref frontend_window) = *control_window_button.frontend_window.borrow() {
             if status {
+                FrontendWindow::set_recording(frontend_window, false);
                 frontend_window.engine.stop();
             }
             control_window_button.set_channel_index(channel_index);
             let channel_name = control_window_button.channel_selector.get_active_text().unwrap();
-            frontend_window.engine.set_mrl(&encode_to_mrl(&channel_name));
+            let channels_file = control_window_button.get_channels_file_path();
+            if let Some(ref mut claim) = *control_window_button.tuner_claim.borrow_mut() {
+                if let Err(message) = TunerPool::get_default().retune(claim, get_frequency(&channels_file, &channel_name)) {
+                    warn!("Could not update the tuner pool: {}", message);
+                }
+            }
+            frontend_window.engine.set_mrl(&encode_to_mrl(&channel_name), &channels_file);
+            FrontendWindow::update_radio_mode(frontend_window);
             preferences::set_last_channel(channel_name, true);
             if status {
                 // TODO Must handle not being able to tune to a channel better than panicking.
@@ -162,62 +409,65 @@ impl ControlWindowButton {
         }
     }
 
-    /// Process a targetted keystroke.
-    pub fn process_targetted_keystroke(&self, tk: &TargettedKeystroke) {
-        assert_eq!(self.frontend_id, tk.frontend_id);
-        match tk.keystroke {
-            input_event_codes::KEY_CHANNELUP => {
-                if tk.value > 0 {
-                    let selector = &self.channel_selector;
-                    let index = selector.get_active().unwrap();
-                    // TODO Need to stop going beyond the number of channels there are.
-                    selector.set_active(Some(index + 1));
-                }
+    /// Process a targetted keystroke: the teletext viewer has first use of a key press,
+    /// then the presses, repeats, and releases of keys become the actions of the keymap.
+    pub fn process_targetted_keystroke(control_window_button: &Rc<ControlWindowButton>, tk: &TargettedKeystroke) {
+        if tk.value == KEY_PRESS {
+            if let Some(ref f_w) = *control_window_button.frontend_window.borrow() {
+                if f_w.process_teletext_keystroke(tk.keystroke) { return; }
             }
-            input_event_codes::KEY_CHANNELDOWN => {
-                if tk.value > 0 {
-                    let selector = &self.channel_selector;
-                    let index = selector.get_active().unwrap();
-                    if index > 0 {
-                        selector.set_active(Some(index - 1));
+        }
+        let actions = control_window_button.remote_input.borrow_mut()
+            .process(tk.keystroke, tk.value, tk.time, &keymap::get_keymap(), &preferences::get_remote_control_repeat());
+        for action in actions {
+            Self::perform_action(control_window_button, action);
+        }
+    }
+
+    /// Do what a key of the keymap or a keyboard shortcut asks. Actions on the channel
+    /// being watched do nothing if there is no frontend window.
+    pub fn perform_action(control_window_button: &Rc<ControlWindowButton>, action: Action) {
+        match action {
+            Action::ChannelUp => {
+                let selector = &control_window_button.channel_selector;
+                if let Some(index) = selector.get_active() {
+                    if (index as i32) + 1 < control_window_button.channel_names_store.iter_n_children(None) {
+                        selector.set_active(Some(index + 1));
                     }
                 }
-            }
-            input_event_codes::KEY_VOLUMEUP => {
-                if tk.value > 0 {
-                    if let Some(ref f_w) = *self.frontend_window.borrow() {
-                        let button = &f_w.volume_button;
-                        let volume = button.get_value();
-                        let adjustment = button.get_adjustment();
-                        let increment = adjustment.get_step_increment();
-                        let maximum = adjustment.get_upper();
-                        let new_volume = volume + increment;
-                        if new_volume < maximum {
-                            button.set_value(new_volume);
-                        } else {
-                            button.set_value(maximum);
-                        }
+            },
+            Action::ChannelDown => {
+                let selector = &control_window_button.channel_selector;
+                if let Some(index) = selector.get_active() {
+                    if index > 0 {
+                        selector.set_active(Some(index - 1));
                     }
                 }
             },
-            input_event_codes::KEY_VOLUMEDOWN => {
-                if tk.value > 0 {
-                    if let Some(ref f_w) = *self.frontend_window.borrow() {
+            Action::Channel(number) => control_window_button.set_channel_number(number),
+            Action::Digit(digit) => Self::enter_channel_number_digit(control_window_button, digit),
+            Action::Epg => {
+                control_window_button.control_window.window.activate_action("epg", None);
+            },
+            _ => if let Some(ref f_w) = *control_window_button.frontend_window.borrow() {
+                match action {
+                    Action::VolumeUp | Action::VolumeDown => {
                         let button = &f_w.volume_button;
-                        let volume = button.get_value();
                         let adjustment = button.get_adjustment();
                         let increment = adjustment.get_step_increment();
-                        let minimum = adjustment.get_lower();
-                        let new_volume = volume - increment;
-                        if new_volume > minimum {
-                            button.set_value(new_volume);
-                        } else {
-                            button.set_value(minimum);
-                        }
-                    }
+                        let new_volume = if action == Action::VolumeUp { button.get_value() + increment } else { button.get_value() - increment };
+                        button.set_value(new_volume.max(adjustment.get_lower()).min(adjustment.get_upper()));
+                    },
+                    Action::Mute => FrontendWindow::toggle_mute(f_w),
+                    Action::Fullscreen => f_w.toggle_fullscreen(),
+                    Action::Record => FrontendWindow::toggle_recording(f_w),
+                    Action::Subtitles => FrontendWindow::cycle_subtitle_track(f_w),
+                    Action::AudioTrack => FrontendWindow::cycle_audio_track(f_w),
+                    Action::Pause => f_w.toggle_pause(),
+                    Action::Info => Self::show_channel_info(control_window_button, f_w),
+                    _ => {},
                 }
             },
-            _ => {},
         }
     }
 
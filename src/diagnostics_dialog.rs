@@ -0,0 +1,139 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::cell::Cell;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use gtk;
+use gtk::prelude::*;
+
+use crate::control_window::ControlWindow;
+use crate::errors::{self, ErrorKind};
+
+lazy_static! {
+    static ref DIAGNOSTICS: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+}
+
+/// Put the current error counts and recent errors into the labels and store.
+fn update(count_labels: &[(ErrorKind, gtk::Label)], store: &gtk::ListStore) {
+    for (kind, label) in count_labels {
+        label.set_text(&errors::get_count(*kind).to_string());
+    }
+    store.clear();
+    // Most recent first.
+    for (time, error) in errors::get_recent().iter().rev() {
+        store.insert_with_values(
+            None,
+            &[0, 1, 2],
+            &[&time.format("%Y-%m-%d %H:%M:%S").to_string(), &error.kind().to_string(), &error.to_string()]
+        );
+    }
+}
+
+fn create(control_window: &ControlWindow) -> gtk::Window {
+    let counts_grid = gtk::Grid::new();
+    counts_grid.set_column_spacing(12);
+    counts_grid.set_row_spacing(6);
+    let count_labels = ErrorKind::all().iter().enumerate().map(|(row, kind)| {
+        let name_label = gtk::Label::new(Some(&format!("{} errors", kind)));
+        name_label.set_halign(gtk::Align::Start);
+        counts_grid.attach(&name_label, 0, row as i32, 1, 1);
+        let count_label = gtk::Label::new(Some("0"));
+        count_label.set_halign(gtk::Align::End);
+        counts_grid.attach(&count_label, 1, row as i32, 1, 1);
+        (*kind, count_label)
+    }).collect::<Vec<(ErrorKind, gtk::Label)>>();
+    let store = gtk::ListStore::new(&[String::static_type(), String::static_type(), String::static_type()]);
+    let errors_view = {
+        let e_v = gtk::TreeView::new_with_model(&store);
+        for (column_number, title) in ["Time", "Kind", "Error"].iter().enumerate() {
+            let renderer = gtk::CellRendererText::new();
+            let column = gtk::TreeViewColumn::new();
+            column.set_title(title);
+            column.pack_start(&renderer, true);
+            column.add_attribute(&renderer, "text", column_number as i32);
+            e_v.append_column(&column);
+        }
+        e_v
+    };
+    let scrolled_window = {
+        let s_w = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+        s_w.set_vexpand(true);
+        s_w.add(&errors_view);
+        s_w
+    };
+    update(&count_labels, &store);
+    let refresh_button = gtk::Button::new_with_label("Refresh");
+    refresh_button.connect_clicked({
+        let count_labels = count_labels.clone();
+        let store = store.clone();
+        move |_| update(&count_labels, &store)
+    });
+    let clear_button = gtk::Button::new_with_label("Clear");
+    clear_button.connect_clicked({
+        let store = store.clone();
+        move |_| {
+            errors::clear();
+            update(&count_labels, &store);
+        }
+    });
+    let buttons_box = {
+        let b_b = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        b_b.set_halign(gtk::Align::End);
+        b_b.pack_start(&clear_button, false, false, 0);
+        b_b.pack_start(&refresh_button, false, false, 0);
+        b_b
+    };
+    let main_box = {
+        let m_b = gtk::Box::new(gtk::Orientation::Vertical, 12);
+        m_b.set_border_width(12);
+        m_b.pack_start(&counts_grid, false, false, 0);
+        m_b.pack_start(&scrolled_window, true, true, 0);
+        m_b.pack_start(&buttons_box, false, false, 0);
+        m_b
+    };
+    let window = gtk::Window::new(gtk::WindowType::Toplevel);
+    window.set_title("Me TV Diagnostics");
+    window.set_default_size(640, 400);
+    window.set_transient_for(Some(&control_window.window));
+    window.add(&main_box);
+    window.show_all();
+    window
+}
+
+/// Display the errors that have happened in a non-modal way, but only if the diagnostics
+/// are not already being displayed.
+pub fn present(control_window: &ControlWindow) {
+    if let Ok(active) = DIAGNOSTICS.lock() {
+        if ! active.get() {
+            let dialog = create(control_window);
+            dialog.connect_destroy(move |_| {
+                if let Ok(active) = DIAGNOSTICS.lock() {
+                    active.set(false);
+                }
+            });
+            dialog.show();
+            active.set(true);
+        }
+    }
+}
@@ -65,6 +65,13 @@ impl DeliverySystem {
         };
         panic!("Failure of preferences::DeliverySystem.");
     }
+
+    /// The `DeliverySystem` with a name, as used in the preferences or, with / rather
+    /// than _, in a DVBv5 channels file, if Me TV supports it.
+    pub fn from_name(name: &str) -> Option<DeliverySystem> {
+        let name = name.replace("/", "_");
+        DELIVERY_SYSTEMS.iter().find(|d_s| d_s.to_string() == name).cloned()
+    }
 }
 
 impl fmt::Display for DeliverySystem {
@@ -98,6 +105,13 @@ mod test {
         assert_eq!(DeliverySystem::from("DVBT2"), DeliverySystem::DVBT2);
     }
 
+    #[test]
+    fn construct_from_a_channels_file_name() {
+        assert_eq!(DeliverySystem::from_name("DVBC/ANNEX_A"), Some(DeliverySystem::DVBC_ANNEX_A));
+        assert_eq!(DeliverySystem::from_name("DVBT"), Some(DeliverySystem::DVBT));
+        assert_eq!(DeliverySystem::from_name("DVBS2"), None);
+    }
+
     #[test]
     fn render_to_string() {
         assert_eq!(DeliverySystem::DVBT2.to_string(), String::from("DVBT2"));
@@ -3,7 +3,7 @@
  *
  *  A GTK+/GStreamer client for watching and recording DVB.
  *
- *  Copyright © 2019  Russel Winder
+ *  Copyright © 2019, 2020  Russel Winder
  *
  *  This program is free software: you can redistribute it and/or modify
  *  it under the terms of the GNU General Public License as published by
@@ -19,6 +19,13 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use lazy_static::lazy_static;
+use log::warn;
+
 use glib;
 
 use gst;
@@ -52,15 +59,213 @@ impl EPGEventMessage {
 unsafe impl Send for EPGEventMessage {}
 unsafe impl Sync for EPGEventMessage {}
 
+/// The data about an event held in the EPG store, the name and text are those of the
+/// short event descriptor, empty if there is none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EPGEvent {
+    pub service_id: u16,
+    pub event_id: u16,
+    pub start_time: DateTime<Utc>,
+    pub duration: u32,
+    pub name: String,
+    pub text: String,
+}
+
+impl EPGEvent {
+    pub fn end_time(&self) -> DateTime<Utc> {
+        self.start_time + Duration::seconds(self.duration as i64)
+    }
+}
+
+/// The events on now and next of a service.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NowNext {
+    pub now: Option<EPGEvent>,
+    pub next: Option<EPGEvent>,
+}
+
+lazy_static! {
+    static ref EPG_EVENTS: Mutex<HashMap<u16, Vec<EPGEvent>>> = Mutex::new(HashMap::new());
+}
+
+/// EIT start times are UTC, turn the GStreamer representation into a chrono one.
+fn utc_from_gst_date_time(date_time: &gst::DateTime) -> Option<DateTime<Utc>> {
+    Utc.ymd_opt(date_time.get_year(), date_time.get_month() as u32, date_time.get_day() as u32)
+        .single()
+        .and_then(|date| date.and_hms_opt(date_time.get_hour() as u32, date_time.get_minute() as u32, date_time.get_second() as u32))
+}
+
+/// Add an event to the events for a service, replacing any previous version of
+/// the event and throwing away any events that have finished.
+fn add_event_to(events: &mut Vec<EPGEvent>, event: EPGEvent, now: DateTime<Utc>) {
+    events.retain(|e| e.event_id != event.event_id && e.end_time() > now);
+    events.push(event);
+    events.sort_by_key(|e| e.start_time);
+}
+
+/// Find the event that is being broadcast at a given time.
+fn find_event_at(events: &[EPGEvent], time: DateTime<Utc>) -> Option<&EPGEvent> {
+    events.iter().find(|e| e.start_time <= time && time < e.end_time())
+}
+
+/// Find the event being broadcast at a given time and the one after it. If nothing is
+/// being broadcast the next event is the first to start after the time.
+fn find_now_next(events: &[EPGEvent], time: DateTime<Utc>) -> NowNext {
+    let now = find_event_at(events, time).cloned();
+    let after = now.as_ref().map_or(time, |now| now.end_time());
+    let next = events.iter().find(|e| e.start_time >= after).cloned();
+    NowNext { now, next }
+}
+
+/// Find an event of a service by its event ID.
+fn find_event(events: &[EPGEvent], event_id: u16) -> Option<&EPGEvent> {
+    events.iter().find(|e| e.event_id == event_id)
+}
+
+/// Return the latest version of an event of a service, if the EPG still has it. The EIT
+/// may change the start time or duration of an event as it is being broadcast.
+pub fn get_event(service_id: u16, event_id: u16) -> Option<EPGEvent> {
+    match EPG_EVENTS.lock() {
+        Ok(epg_events) => epg_events.get(&service_id).and_then(|events| find_event(events, event_id).cloned()),
+        Err(_) => None,
+    }
+}
+
+/// Return the event currently being broadcast on a service, if the EPG knows about it.
+pub fn get_current_event(service_id: u16) -> Option<EPGEvent> {
+    match EPG_EVENTS.lock() {
+        Ok(epg_events) => epg_events.get(&service_id).and_then(|events| find_event_at(events, Utc::now()).cloned()),
+        Err(_) => None,
+    }
+}
+
+/// Return the events on now and next of a service, as far as the EPG knows them.
+pub fn get_now_next(service_id: u16) -> NowNext {
+    match EPG_EVENTS.lock() {
+        Ok(epg_events) => epg_events.get(&service_id).map(|events| find_now_next(events, Utc::now())).unwrap_or_default(),
+        Err(_) => NowNext::default(),
+    }
+}
+
+/// Turn an event from an EIT into an event for the EPG store, `None` if the start time
+/// makes no sense.
+pub fn epg_event_from_message(message: &EPGEventMessage) -> Option<EPGEvent> {
+    let (name, text) = message.descriptors.iter()
+        .filter_map(|descriptor| match descriptor {
+            EITDescriptor::ShortEvent { event_name, text, .. } => Some((event_name.clone(), text.clone())),
+            _ => None,
+        })
+        .next()
+        .unwrap_or_default();
+    utc_from_gst_date_time(&message.start_time).map(|start_time| EPGEvent {
+        service_id: message.service_id,
+        event_id: message.event_id,
+        start_time,
+        duration: message.duration,
+        name,
+        text,
+    })
+}
+
 pub fn run(mut to_cw: glib::Sender<Message>, from_gstreamer: std::sync::mpsc::Receiver<EPGEventMessage>) {
-    loop {
-        let event = from_gstreamer.recv();
-        //println!("EPG Manager received: {:?}", &event);
-        //
-        // What is the best data structure for the EPG? The rendering will
-        // be by channel number and date/time, so these seem to be the
-        // indexes needed. The question is how to structure the indexes.
-        // The issue is whether date/time first then channel number.
-        //
+    //
+    // What is the best data structure for the EPG? The rendering will
+    // be by channel number and date/time, so these seem to be the
+    // indexes needed. The question is how to structure the indexes.
+    // The issue is whether date/time first then channel number.
+    //
+    // For now events are indexed by service ID and kept in start time order.
+    //
+    while let Ok(message) = from_gstreamer.recv() {
+        match epg_event_from_message(&message) {
+            Some(event) => {
+                if let Ok(mut epg_events) = EPG_EVENTS.lock() {
+                    add_event_to(epg_events.entry(message.service_id).or_insert_with(Vec::new), event, Utc::now());
+                }
+            },
+            None => warn!("Could not process start time of event: {:?}", &message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_event(event_id: u16, hour: u32, duration: u32) -> EPGEvent {
+        EPGEvent {
+            service_id: 4164,
+            event_id,
+            start_time: Utc.ymd(2020, 5, 10).and_hms(hour, 0, 0),
+            duration,
+            name: format!("Programme {}", event_id),
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn events_are_kept_in_start_time_order() {
+        let mut events = vec![];
+        let now = Utc.ymd(2020, 5, 10).and_hms(9, 0, 0);
+        add_event_to(&mut events, create_event(2, 11, 3600), now);
+        add_event_to(&mut events, create_event(1, 10, 3600), now);
+        assert_eq!(events.iter().map(|e| e.event_id).collect::<Vec<u16>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn an_updated_event_replaces_the_old_one() {
+        let mut events = vec![];
+        let now = Utc.ymd(2020, 5, 10).and_hms(9, 0, 0);
+        add_event_to(&mut events, create_event(1, 10, 3600), now);
+        add_event_to(&mut events, create_event(1, 10, 1800), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].duration, 1800);
+    }
+
+    #[test]
+    fn finished_events_are_dropped() {
+        let mut events = vec![];
+        add_event_to(&mut events, create_event(1, 10, 3600), Utc.ymd(2020, 5, 10).and_hms(9, 0, 0));
+        add_event_to(&mut events, create_event(2, 11, 3600), Utc.ymd(2020, 5, 10).and_hms(11, 30, 0));
+        assert_eq!(events.iter().map(|e| e.event_id).collect::<Vec<u16>>(), vec![2]);
+    }
+
+    #[test]
+    fn find_an_event_by_id_after_it_changes() {
+        let mut events = vec![];
+        let now = Utc.ymd(2020, 5, 10).and_hms(9, 0, 0);
+        add_event_to(&mut events, create_event(1, 10, 3600), now);
+        add_event_to(&mut events, create_event(2, 11, 3600), now);
+        add_event_to(&mut events, create_event(1, 10, 5400), now);
+        assert_eq!(find_event(&events, 1).unwrap().end_time(), Utc.ymd(2020, 5, 10).and_hms(11, 30, 0));
+        assert_eq!(find_event(&events, 3), None);
+    }
+
+    #[test]
+    fn find_the_current_event() {
+        let events = vec![create_event(1, 10, 3600), create_event(2, 11, 3600)];
+        assert_eq!(find_event_at(&events, Utc.ymd(2020, 5, 10).and_hms(10, 59, 59)).unwrap().event_id, 1);
+        assert_eq!(find_event_at(&events, Utc.ymd(2020, 5, 10).and_hms(11, 0, 0)).unwrap().event_id, 2);
+        assert_eq!(find_event_at(&events, Utc.ymd(2020, 5, 10).and_hms(12, 0, 0)), None);
+    }
+
+    #[test]
+    fn find_now_and_next() {
+        let events = vec![create_event(1, 10, 3600), create_event(2, 11, 3600), create_event(3, 12, 3600)];
+        let now_next = find_now_next(&events, Utc.ymd(2020, 5, 10).and_hms(10, 30, 0));
+        assert_eq!(now_next.now.unwrap().name, "Programme 1");
+        assert_eq!(now_next.next.unwrap().name, "Programme 2");
+        let now_next = find_now_next(&events, Utc.ymd(2020, 5, 10).and_hms(12, 30, 0));
+        assert_eq!(now_next.now.unwrap().event_id, 3);
+        assert_eq!(now_next.next, None);
+    }
+
+    #[test]
+    fn with_nothing_on_now_next_is_the_next_to_start() {
+        let events = vec![create_event(1, 10, 1800), create_event(2, 11, 3600)];
+        let now_next = find_now_next(&events, Utc.ymd(2020, 5, 10).and_hms(10, 45, 0));
+        assert_eq!(now_next.now, None);
+        assert_eq!(now_next.next.unwrap().event_id, 2);
+        assert_eq!(find_now_next(&[], Utc.ymd(2020, 5, 10).and_hms(10, 45, 0)), NowNext::default());
     }
 }
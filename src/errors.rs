@@ -0,0 +1,210 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+
+use lazy_static::lazy_static;
+use log::warn;
+
+/// The number of errors kept for the diagnostics view.
+pub const RECENT_ERRORS_LENGTH: usize = 100;
+
+/// The things that can go wrong with the data from the broadcast or with the GStreamer
+/// system that should not stop Me TV.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeTvError {
+    /// A section of a table, EIT, NIT, etc., could not be made sense of.
+    SectionParse { table: String, message: String },
+    /// A GStreamer element could not be created or set up.
+    ElementCreation { element: String, message: String },
+    /// The GStreamer pipeline could not be put into a state.
+    StateChange { state: String, message: String },
+    /// An element message on the GStreamer bus had no structure to say what it is.
+    UnstructuredMessage { message: String },
+}
+
+/// The kinds of error, for counting.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorKind {
+    SectionParse,
+    ElementCreation,
+    StateChange,
+    UnstructuredMessage,
+}
+
+impl ErrorKind {
+    pub fn all() -> [ErrorKind; 4] {
+        [ErrorKind::SectionParse, ErrorKind::ElementCreation, ErrorKind::StateChange, ErrorKind::UnstructuredMessage]
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            ErrorKind::SectionParse => "Section parse",
+            ErrorKind::ElementCreation => "Element creation",
+            ErrorKind::StateChange => "State change",
+            ErrorKind::UnstructuredMessage => "Unstructured message",
+        })
+    }
+}
+
+impl MeTvError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MeTvError::SectionParse { .. } => ErrorKind::SectionParse,
+            MeTvError::ElementCreation { .. } => ErrorKind::ElementCreation,
+            MeTvError::StateChange { .. } => ErrorKind::StateChange,
+            MeTvError::UnstructuredMessage { .. } => ErrorKind::UnstructuredMessage,
+        }
+    }
+}
+
+impl fmt::Display for MeTvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeTvError::SectionParse { table, message } => write!(f, "Could not parse a section of the {} table: {}", table, message),
+            MeTvError::ElementCreation { element, message } => write!(f, "Could not create a {} element: {}", element, message),
+            MeTvError::StateChange { state, message } => write!(f, "Could not change to the {} state: {}", state, message),
+            MeTvError::UnstructuredMessage { message } => write!(f, "An element message has no structure: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MeTvError {}
+
+/// The counts of each kind of error and the most recent errors, oldest first.
+#[derive(Debug)]
+pub struct ErrorLog {
+    counts: [u64; 4],
+    recent: VecDeque<(DateTime<Local>, MeTvError)>,
+    capacity: usize,
+}
+
+impl Default for ErrorLog {
+    fn default() -> ErrorLog {
+        ErrorLog::new(RECENT_ERRORS_LENGTH)
+    }
+}
+
+impl ErrorLog {
+    pub fn new(capacity: usize) -> ErrorLog {
+        ErrorLog { counts: [0; 4], recent: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn record(&mut self, time: DateTime<Local>, error: MeTvError) {
+        self.counts[error.kind() as usize] += 1;
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((time, error));
+    }
+
+    pub fn get_count(&self, kind: ErrorKind) -> u64 {
+        self.counts[kind as usize]
+    }
+
+    pub fn get_recent(&self) -> Vec<(DateTime<Local>, MeTvError)> {
+        self.recent.iter().cloned().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.counts = [0; 4];
+        self.recent.clear();
+    }
+}
+
+lazy_static! {
+    static ref ERROR_LOG: Mutex<ErrorLog> = Mutex::new(ErrorLog::default());
+}
+
+/// Log an error and keep it for the diagnostics view.
+pub fn report(error: MeTvError) {
+    warn!("{}", error);
+    if let Ok(mut log) = ERROR_LOG.lock() {
+        log.record(Local::now(), error);
+    }
+}
+
+pub fn get_count(kind: ErrorKind) -> u64 {
+    ERROR_LOG.lock().map(|log| log.get_count(kind)).unwrap_or(0)
+}
+
+pub fn get_recent() -> Vec<(DateTime<Local>, MeTvError)> {
+    ERROR_LOG.lock().map(|log| log.get_recent()).unwrap_or_default()
+}
+
+pub fn clear() {
+    if let Ok(mut log) = ERROR_LOG.lock() {
+        log.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn section_error(n: u32) -> MeTvError {
+        MeTvError::SectionParse { table: "EIT".to_string(), message: n.to_string() }
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let mut log = ErrorLog::new(10);
+        log.record(Local::now(), section_error(1));
+        log.record(Local::now(), section_error(2));
+        log.record(Local::now(), MeTvError::StateChange { state: "Playing".to_string(), message: "failed".to_string() });
+        assert_eq!(log.get_count(ErrorKind::SectionParse), 2);
+        assert_eq!(log.get_count(ErrorKind::ElementCreation), 0);
+        assert_eq!(log.get_count(ErrorKind::StateChange), 1);
+        assert_eq!(log.get_count(ErrorKind::UnstructuredMessage), 0);
+        log.clear();
+        assert_eq!(log.get_count(ErrorKind::SectionParse), 0);
+        assert!(log.get_recent().is_empty());
+    }
+
+    #[test]
+    fn only_the_most_recent_errors_are_kept_but_all_are_counted() {
+        let mut log = ErrorLog::new(3);
+        for n in 1..=5 {
+            log.record(Local::now(), section_error(n));
+        }
+        assert_eq!(log.get_count(ErrorKind::SectionParse), 5);
+        assert_eq!(log.get_recent().into_iter().map(|(_, e)| e).collect::<Vec<MeTvError>>(), vec![section_error(3), section_error(4), section_error(5)]);
+    }
+
+    #[test]
+    fn errors_display_what_went_wrong() {
+        assert_eq!(section_error(7).to_string(), "Could not parse a section of the EIT table: 7");
+        assert_eq!(
+            MeTvError::ElementCreation { element: "gtksink".to_string(), message: "no such element".to_string() }.to_string(),
+            "Could not create a gtksink element: no such element"
+        );
+        assert_eq!(
+            MeTvError::UnstructuredMessage { message: "Element { src: tsdemux0 }".to_string() }.to_string(),
+            "An element message has no structure: Element { src: tsdemux0 }"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Watching the preferences file and the channels files so that changes made outside
+//! Me TV, a hand edit or a run of dvbv5-scan say, are picked up without a restart.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use glib;
+
+use log::{info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher, watcher};
+
+use crate::channel_names;
+use crate::control_window::Message;
+use crate::preferences;
+
+/// How long to wait for a file to stop changing before reporting the change, editors and
+/// dvbv5-scan may write a file in several goes.
+const SETTLING_TIME: Duration = Duration::from_millis(500);
+
+/// The kinds of file that can change.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    Preferences,
+    Channels,
+}
+
+/// The files watched: the preferences file and the channels files in a directory.
+#[derive(Clone, Debug)]
+pub struct WatchedFiles {
+    pub preferences_file: PathBuf,
+    pub channels_directory: PathBuf,
+}
+
+impl WatchedFiles {
+    /// The kind of file a path is, if it is one of the watched files.
+    fn change_for(&self, path: &Path) -> Option<Change> {
+        if path == self.preferences_file {
+            return Some(Change::Preferences);
+        }
+        let name = path.file_name()?.to_str()?;
+        if path.parent() == Some(&self.channels_directory) && name.starts_with("dvb-channels") && name.ends_with(".conf") {
+            Some(Change::Channels)
+        } else {
+            None
+        }
+    }
+
+    /// The kinds of file changed by an event. A rename, as when a file is written to a
+    /// temporary file and then renamed, is a change to both paths.
+    fn changes_for(&self, event: &DebouncedEvent) -> Vec<Change> {
+        let paths = match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            _ => vec![],
+        };
+        let mut changes = paths.into_iter().filter_map(|path| self.change_for(path)).collect::<Vec<Change>>();
+        changes.dedup();
+        changes
+    }
+}
+
+/// Watch the files, sending the kind of each change until sending fails.
+pub fn watch(files: &WatchedFiles, settling_time: Duration, mut send: impl FnMut(Change) -> bool) -> Result<(), String> {
+    let (transmit_end, receive_end) = channel();
+    let mut watcher = watcher(transmit_end, settling_time).map_err(|e| e.to_string())?;
+    let preferences_directory = files.preferences_file.parent().ok_or_else(|| format!("{:?} has no directory.", files.preferences_file))?;
+    for directory in &[preferences_directory, files.channels_directory.as_path()] {
+        watcher.watch(directory, RecursiveMode::NonRecursive).map_err(|e| format!("Cannot watch {:?}: {}", directory, e))?;
+    }
+    loop {
+        let event = receive_end.recv().map_err(|e| e.to_string())?;
+        if let DebouncedEvent::Error(error, path) = event {
+            warn!("Watch error on {:?}: {}", path, error);
+            continue;
+        }
+        for change in files.changes_for(&event) {
+            if ! send(change) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The main daemon for watching the files, a thread sending a message to the control
+/// window for each change.
+pub fn run(to_cw: glib::Sender<Message>) {
+    let channels_file_path = channel_names::channels_file_path();
+    let files = WatchedFiles {
+        preferences_file: preferences::get_preferences_file_path(),
+        channels_directory: channels_file_path.parent().unwrap().to_path_buf(),
+    };
+    // The GStreamer directory may not exist until there is a channels file.
+    if let Err(error) = fs::create_dir_all(&files.channels_directory) {
+        warn!("Cannot create {:?}: {}", files.channels_directory, error);
+    }
+    let result = watch(&files, SETTLING_TIME, |change| to_cw.send(match change {
+        Change::Preferences => Message::PreferencesFileChanged,
+        Change::Channels => Message::ChannelsFileChanged,
+    }).is_ok());
+    match result {
+        Ok(_) => info!("File watcher terminated."),
+        Err(message) => warn!("File watcher failed: {}", message),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn watched_files(directory: &Path) -> WatchedFiles {
+        WatchedFiles {
+            preferences_file: directory.join("me-tv").join("preferences.yml"),
+            channels_directory: directory.join("gstreamer-1.0"),
+        }
+    }
+
+    #[test]
+    fn only_the_preferences_file_and_channels_files_are_watched() {
+        let files = watched_files(Path::new("/home/me/.config"));
+        assert_eq!(files.change_for(Path::new("/home/me/.config/me-tv/preferences.yml")), Some(Change::Preferences));
+        assert_eq!(files.change_for(Path::new("/home/me/.config/me-tv/preferences.yml.version0")), None);
+        assert_eq!(files.change_for(Path::new("/home/me/.config/gstreamer-1.0/dvb-channels.conf")), Some(Change::Channels));
+        assert_eq!(files.change_for(Path::new("/home/me/.config/gstreamer-1.0/dvb-channels-DVBT2.conf")), Some(Change::Channels));
+        assert_eq!(files.change_for(Path::new("/home/me/.config/gstreamer-1.0/registry.conf")), None);
+        assert_eq!(files.change_for(Path::new("/home/me/.config/me-tv/dvb-channels.conf")), None);
+    }
+
+    #[test]
+    fn writing_creating_and_removing_files_are_changes() {
+        let files = watched_files(Path::new("/home/me/.config"));
+        let channels_file = files.channels_directory.join("dvb-channels-DVBT.conf");
+        assert_eq!(files.changes_for(&DebouncedEvent::Write(channels_file.clone())), vec![Change::Channels]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Create(channels_file.clone())), vec![Change::Channels]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Remove(channels_file.clone())), vec![Change::Channels]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Write(files.preferences_file.clone())), vec![Change::Preferences]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Write(files.channels_directory.join("registry.x86_64.bin"))), vec![]);
+    }
+
+    #[test]
+    fn notices_and_attribute_changes_are_not_changes() {
+        let files = watched_files(Path::new("/home/me/.config"));
+        assert_eq!(files.changes_for(&DebouncedEvent::NoticeWrite(files.preferences_file.clone())), vec![]);
+        assert_eq!(files.changes_for(&DebouncedEvent::NoticeRemove(files.preferences_file.clone())), vec![]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Chmod(files.preferences_file.clone())), vec![]);
+        assert_eq!(files.changes_for(&DebouncedEvent::Rescan), vec![]);
+    }
+
+    #[test]
+    fn a_file_renamed_into_place_is_a_change() {
+        let files = watched_files(Path::new("/home/me/.config"));
+        let event = DebouncedEvent::Rename(PathBuf::from("/home/me/.config/me-tv/.tmpA1b2C3"), files.preferences_file.clone());
+        assert_eq!(files.changes_for(&event), vec![Change::Preferences]);
+    }
+
+    #[test]
+    fn a_rename_between_channels_files_is_one_change() {
+        let files = watched_files(Path::new("/home/me/.config"));
+        let event = DebouncedEvent::Rename(files.channels_directory.join("dvb-channels.conf"), files.channels_directory.join("dvb-channels-DVBT.conf"));
+        assert_eq!(files.changes_for(&event), vec![Change::Channels]);
+    }
+}
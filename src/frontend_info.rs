@@ -0,0 +1,389 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Finding out what a frontend is capable of using the FE_GET_INFO and
+//! FE_GET_PROPERTY(DTV_ENUM_DELSYS) ioctls, so that each tuner can be used with the
+//! right delivery system.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::os::raw::{c_char, c_void};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use libc;
+use nix::ioctl_read;
+
+use crate::dvb::DeliverySystem;
+
+/// A struct to represent the identity of a specific frontend currently
+/// available on the system.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FrontendId {
+    pub adapter: u8,
+    pub frontend: u8,
+}
+
+impl fmt::Display for FrontendId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "adaptor{} frontend{}", self.adapter, self.frontend)
+    }
+}
+
+lazy_static! {
+    /// The capabilities of the frontends present, found when they appear.
+    static ref FRONTEND_INFOS: Mutex<HashMap<FrontendId, FrontendInfo>> = Mutex::new(HashMap::new());
+}
+
+impl FrontendId {
+    /// The capabilities of the frontend, if it is present and they could be found.
+    pub fn get_info(&self) -> Option<FrontendInfo> {
+        match FRONTEND_INFOS.lock() {
+            Ok(infos) => infos.get(self).cloned(),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Remember the capabilities of a frontend that has appeared, or forget them if `None`.
+pub fn set_info(fei: &FrontendId, info: Option<FrontendInfo>) { // Used in frontend_manager.rs
+    if let Ok(mut infos) = FRONTEND_INFOS.lock() {
+        match info {
+            Some(info) => { infos.insert(fei.clone(), info); },
+            None => { infos.remove(fei); },
+        }
+    }
+}
+
+/// What Me TV knows about a frontend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrontendInfo {
+    /// The name the driver gives the frontend.
+    pub name: String,
+    /// The delivery systems Me TV supports that the frontend can receive.
+    pub delivery_systems: Vec<DeliverySystem>,
+    /// The tunable range and step in Hz.
+    pub frequency_min: u64,
+    pub frequency_max: u64,
+    pub frequency_stepsize: u64,
+}
+
+impl fmt::Display for FrontendInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let delivery_systems = self.delivery_systems.iter().map(|d_s| d_s.to_string()).collect::<Vec<String>>();
+        write!(f, "{}\n{}\n{:.1}–{:.1} MHz",
+               self.name,
+               if delivery_systems.is_empty() { "No supported delivery systems".to_string() } else { delivery_systems.join(", ") },
+               self.frequency_min as f64 / 1.0e6,
+               self.frequency_max as f64 / 1.0e6)
+    }
+}
+
+/// The fields of the kernel's `struct dvb_frontend_info` that Me TV uses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeInfo {
+    pub name: String,
+    /// The legacy `enum fe_type`.
+    pub fe_type: u32,
+    /// In kHz for satellite frontends, in Hz for all others.
+    pub frequency_min: u32,
+    pub frequency_max: u32,
+    pub frequency_stepsize: u32,
+}
+
+/// The ioctls on a frontend, as a trait so that the code using them can be tested
+/// without DVB hardware.
+pub trait FrontendIoctls {
+    /// The result of FE_GET_INFO.
+    fn get_info(&self, path: &Path) -> Result<FeInfo, String>;
+    /// The `enum fe_delivery_system` values from DTV_ENUM_DELSYS.
+    fn enum_delivery_systems(&self, path: &Path) -> Result<Vec<u32>, String>;
+}
+
+// Values from linux/dvb/frontend.h.
+
+const FE_QPSK: u32 = 0;
+const FE_QAM: u32 = 1;
+const FE_OFDM: u32 = 2;
+const FE_ATSC: u32 = 3;
+
+const SYS_DVBC_ANNEX_A: u32 = 1;
+const SYS_DVBC_ANNEX_B: u32 = 2;
+const SYS_DVBT: u32 = 3;
+const SYS_ISDBT: u32 = 8;
+const SYS_ATSC: u32 = 11;
+const SYS_DVBT2: u32 = 16;
+
+const DTV_ENUM_DELSYS: u32 = 44;
+
+/// The Me TV delivery system for a kernel `enum fe_delivery_system` value, if Me TV
+/// supports it.
+pub fn delivery_system_from(value: u32) -> Option<DeliverySystem> {
+    match value {
+        SYS_DVBC_ANNEX_A => Some(DeliverySystem::DVBC_ANNEX_A),
+        SYS_DVBC_ANNEX_B => Some(DeliverySystem::DVBC_ANNEX_B),
+        SYS_DVBT => Some(DeliverySystem::DVBT),
+        SYS_ISDBT => Some(DeliverySystem::ISDBT),
+        SYS_ATSC => Some(DeliverySystem::ATSC),
+        SYS_DVBT2 => Some(DeliverySystem::DVBT2),
+        _ => None,
+    }
+}
+
+/// The delivery systems implied by the legacy frontend type, for drivers that do not
+/// support DTV_ENUM_DELSYS.
+fn delivery_systems_from_fe_type(fe_type: u32) -> Vec<DeliverySystem> {
+    match fe_type {
+        FE_QAM => vec![DeliverySystem::DVBC_ANNEX_A],
+        FE_OFDM => vec![DeliverySystem::DVBT],
+        FE_ATSC => vec![DeliverySystem::ATSC, DeliverySystem::DVBC_ANNEX_B],
+        _ => vec![],
+    }
+}
+
+/// Ask the frontend at `path` what it is capable of.
+pub fn query(ioctls: &dyn FrontendIoctls, path: &Path) -> Result<FrontendInfo, String> {
+    let fe_info = ioctls.get_info(path)?;
+    let delivery_systems = match ioctls.enum_delivery_systems(path) {
+        Ok(values) => {
+            let mut delivery_systems = vec![];
+            for d_s in values.into_iter().filter_map(delivery_system_from) {
+                if ! delivery_systems.contains(&d_s) {
+                    delivery_systems.push(d_s);
+                }
+            }
+            delivery_systems
+        },
+        Err(_) => delivery_systems_from_fe_type(fe_info.fe_type),
+    };
+    let scale = if fe_info.fe_type == FE_QPSK { 1000 } else { 1 };
+    Ok(FrontendInfo {
+        name: fe_info.name,
+        delivery_systems,
+        frequency_min: fe_info.frequency_min as u64 * scale,
+        frequency_max: fe_info.frequency_max as u64 * scale,
+        frequency_stepsize: fe_info.frequency_stepsize as u64 * scale,
+    })
+}
+
+/// `struct dvb_frontend_info` from linux/dvb/frontend.h.
+#[repr(C)]
+pub struct DvbFrontendInfo {
+    name: [c_char; 128],
+    fe_type: u32,
+    frequency_min: u32,
+    frequency_max: u32,
+    frequency_stepsize: u32,
+    frequency_tolerance: u32,
+    symbol_rate_min: u32,
+    symbol_rate_max: u32,
+    symbol_rate_tolerance: u32,
+    notifier_delay: u32,
+    caps: u32,
+}
+
+/// The buffer member of the union in `struct dtv_property`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DtvPropertyBuffer {
+    data: [u8; 32],
+    len: u32,
+    reserved1: [u32; 3],
+    reserved2: *mut c_void,
+}
+
+/// `struct dtv_property` from linux/dvb/frontend.h, which is packed.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DtvProperty {
+    cmd: u32,
+    reserved: [u32; 3],
+    buffer: DtvPropertyBuffer,
+    result: i32,
+}
+
+/// `struct dtv_properties` from linux/dvb/frontend.h.
+#[repr(C)]
+pub struct DtvProperties {
+    num: u32,
+    props: *mut DtvProperty,
+}
+
+ioctl_read!(ioctl_fe_get_info, b'o', 61, DvbFrontendInfo);
+ioctl_read!(ioctl_fe_get_property, b'o', 83, DtvProperties);
+
+/// The name from a `struct dvb_frontend_info`, which is NUL terminated unless it fills the array.
+fn name_from(name: &[c_char]) -> String {
+    let bytes = name.iter().map(|c| *c as u8).take_while(|b| *b != 0).collect::<Vec<u8>>();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// The ioctls on the real frontend special files.
+pub struct DeviceIoctls;
+
+impl DeviceIoctls {
+    /// Open the frontend read only, which is allowed even when it is in use, without
+    /// blocking.
+    fn open(path: &Path) -> Result<std::fs::File, String> {
+        OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path)
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))
+    }
+}
+
+impl FrontendIoctls for DeviceIoctls {
+    fn get_info(&self, path: &Path) -> Result<FeInfo, String> {
+        let file = DeviceIoctls::open(path)?;
+        let mut info = DvbFrontendInfo {
+            name: [0; 128],
+            fe_type: 0,
+            frequency_min: 0,
+            frequency_max: 0,
+            frequency_stepsize: 0,
+            frequency_tolerance: 0,
+            symbol_rate_min: 0,
+            symbol_rate_max: 0,
+            symbol_rate_tolerance: 0,
+            notifier_delay: 0,
+            caps: 0,
+        };
+        unsafe { ioctl_fe_get_info(file.as_raw_fd(), &mut info) }
+            .map_err(|e| format!("FE_GET_INFO failed on {}: {}", path.display(), e))?;
+        Ok(FeInfo {
+            name: name_from(&info.name),
+            fe_type: info.fe_type,
+            frequency_min: info.frequency_min,
+            frequency_max: info.frequency_max,
+            frequency_stepsize: info.frequency_stepsize,
+        })
+    }
+
+    fn enum_delivery_systems(&self, path: &Path) -> Result<Vec<u32>, String> {
+        let file = DeviceIoctls::open(path)?;
+        let mut property = DtvProperty {
+            cmd: DTV_ENUM_DELSYS,
+            reserved: [0; 3],
+            buffer: DtvPropertyBuffer { data: [0; 32], len: 0, reserved1: [0; 3], reserved2: std::ptr::null_mut() },
+            result: 0,
+        };
+        let mut properties = DtvProperties { num: 1, props: &mut property };
+        unsafe { ioctl_fe_get_property(file.as_raw_fd(), &mut properties) }
+            .map_err(|e| format!("DTV_ENUM_DELSYS failed on {}: {}", path.display(), e))?;
+        let buffer = property.buffer;
+        let length = (buffer.len as usize).min(buffer.data.len());
+        Ok(buffer.data[..length].iter().map(|d_s| *d_s as u32).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A frontend that answers the ioctls with fixed values.
+    struct MockIoctls {
+        info: Result<FeInfo, String>,
+        delivery_systems: Result<Vec<u32>, String>,
+    }
+
+    impl FrontendIoctls for MockIoctls {
+        fn get_info(&self, _path: &Path) -> Result<FeInfo, String> { self.info.clone() }
+        fn enum_delivery_systems(&self, _path: &Path) -> Result<Vec<u32>, String> { self.delivery_systems.clone() }
+    }
+
+    fn fe_info(fe_type: u32) -> FeInfo {
+        FeInfo {
+            name: "Silicon Labs Si2168".to_string(),
+            fe_type,
+            frequency_min: 42_000_000,
+            frequency_max: 870_000_000,
+            frequency_stepsize: 62_500,
+        }
+    }
+
+    #[test]
+    fn kernel_structures_have_the_kernel_sizes() {
+        assert_eq!(std::mem::size_of::<DvbFrontendInfo>(), 168);
+        if std::mem::size_of::<usize>() == 8 {
+            assert_eq!(std::mem::size_of::<DtvProperty>(), 76);
+            assert_eq!(std::mem::size_of::<DtvProperties>(), 16);
+        }
+    }
+
+    #[test]
+    fn the_name_stops_at_the_nul() {
+        let mut name = [0 as c_char; 128];
+        for (i, c) in b"DiBcom 7000PC".iter().enumerate() {
+            name[i] = *c as c_char;
+        }
+        assert_eq!(name_from(&name), "DiBcom 7000PC");
+        assert_eq!(name_from(&[b'A' as c_char; 128]), "A".repeat(128));
+    }
+
+    #[test]
+    fn supported_delivery_systems_are_listed_once_in_order() {
+        let ioctls = MockIoctls {
+            info: Ok(fe_info(FE_OFDM)),
+            // DVB-T2, DVB-T, DVB-C annex A, DVB-S (not supported), DVB-T again.
+            delivery_systems: Ok(vec![16, 3, 1, 5, 3]),
+        };
+        assert_eq!(query(&ioctls, Path::new("/dev/dvb/adapter0/frontend0")), Ok(FrontendInfo {
+            name: "Silicon Labs Si2168".to_string(),
+            delivery_systems: vec![DeliverySystem::DVBT2, DeliverySystem::DVBT, DeliverySystem::DVBC_ANNEX_A],
+            frequency_min: 42_000_000,
+            frequency_max: 870_000_000,
+            frequency_stepsize: 62_500,
+        }));
+    }
+
+    #[test]
+    fn the_frontend_type_is_used_when_the_delivery_systems_cannot_be_enumerated() {
+        let ioctls = MockIoctls { info: Ok(fe_info(FE_ATSC)), delivery_systems: Err("Not supported".to_string()) };
+        let info = query(&ioctls, Path::new("/dev/dvb/adapter0/frontend0")).unwrap();
+        assert_eq!(info.delivery_systems, vec![DeliverySystem::ATSC, DeliverySystem::DVBC_ANNEX_B]);
+    }
+
+    #[test]
+    fn satellite_frequencies_are_converted_from_khz() {
+        let ioctls = MockIoctls {
+            info: Ok(FeInfo { frequency_min: 950_000, frequency_max: 2_150_000, frequency_stepsize: 1000, ..fe_info(FE_QPSK) }),
+            delivery_systems: Ok(vec![5, 6]),
+        };
+        let info = query(&ioctls, Path::new("/dev/dvb/adapter0/frontend0")).unwrap();
+        assert!(info.delivery_systems.is_empty());
+        assert_eq!((info.frequency_min, info.frequency_max, info.frequency_stepsize), (950_000_000, 2_150_000_000, 1_000_000));
+    }
+
+    #[test]
+    fn a_failed_get_info_is_an_error() {
+        let ioctls = MockIoctls { info: Err("No such device".to_string()), delivery_systems: Ok(vec![3]) };
+        assert_eq!(query(&ioctls, Path::new("/dev/dvb/adapter0/frontend0")), Err("No such device".to_string()));
+    }
+
+    #[test]
+    fn plain_files_do_not_answer_the_ioctls() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(DeviceIoctls.get_info(file.path()).is_err());
+        assert!(DeviceIoctls.enum_delivery_systems(file.path()).is_err());
+    }
+}
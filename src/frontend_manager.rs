@@ -21,181 +21,355 @@
 
 use std::fs;
 use std::os::unix::fs::FileTypeExt;
-use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
 use std::thread;
+use std::time::Duration;
 
 use glib;
 //use glib::prelude::*;
 
-use notify::{Watcher, RecursiveMode, RawEvent, op, raw_watcher};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
 use regex::Regex;
+use udev;
 
 use crate::control_window::Message;
+use crate::frontend_info::{self, DeviceIoctls, FrontendIoctls};
 use crate::remote_control;
 
-/// A struct to represent the identity of a specific frontend currently
-/// available on the system.
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct FrontendId {
-    pub adapter: u8,
-    pub frontend: u8,
+pub use crate::frontend_info::FrontendId;
+
+/// The device special files, normally in /dev, but a simulated tree elsewhere for testing.
+#[derive(Clone, Debug)]
+pub struct DeviceTree {
+    root: PathBuf,
+    /// Only accept character special files as frontends, a simulated tree uses plain files.
+    character_devices_only: bool,
+}
+
+impl Default for DeviceTree {
+    fn default() -> DeviceTree {
+        DeviceTree { root: PathBuf::from("/dev"), character_devices_only: true }
+    }
+}
+
+impl DeviceTree {
+    /// A simulated device tree in which plain files stand in for the special files.
+    pub fn simulated(root: &Path) -> DeviceTree {
+        DeviceTree { root: root.to_path_buf(), character_devices_only: false }
+    }
+
+    /// The path in the filesystem to the DVB related special files.
+    pub fn dvb_base_path(&self) -> PathBuf { self.root.join("dvb") }
+
+    /// Return the path to the adapter director for a given adapter.
+    pub fn adapter_path(&self, id: u8) -> PathBuf {
+        let mut result = self.dvb_base_path();
+        result.push("adapter".to_string() + &id.to_string());
+        result
+    }
+
+    /// Return the path to the special file for a given frontend.
+    pub fn frontend_path(&self, fei: &FrontendId) -> PathBuf {
+        let mut result = self.adapter_path(fei.adapter);
+        result.push("frontend".to_string() + &fei.frontend.to_string());
+        result
+    }
+
+    /// Return the path to the special file of the demux for a given frontend.
+    pub fn demux_path(&self, fei: &FrontendId) -> PathBuf {
+        let mut result = self.adapter_path(fei.adapter);
+        result.push("demux".to_string() + &fei.frontend.to_string());
+        result
+    }
+
+    /// Return the path to the special file of the data for a given frontend.
+    pub fn dvr_path(&self, fei: &FrontendId) -> PathBuf {
+        let mut result = self.adapter_path(fei.adapter);
+        result.push("dvr".to_string() + &fei.frontend.to_string());
+        result
+    }
+
+    fn is_device(&self, metadata: &fs::Metadata) -> bool {
+        // NB metadata.is_file() is false for special files. :-(
+        // Assume the special devices were are dealing with are
+        // character devices not block devices.
+        metadata.file_type().is_char_device() || (! self.character_devices_only && metadata.is_file())
+    }
+
+    /// Ensure the name is adaptorXXX /frontendYYY where XXX and YYY are pure numeric,
+    /// and return a `FrontendId` based on these numbers.
+    fn frontend_id_from(&self, path: &str) -> Option<FrontendId> {
+        let base_path = self.dvb_base_path();
+        let regex = Regex::new(&format!(r"^{}/adapter([0-9]+)/frontend([0-9]+)$", regex::escape(base_path.to_str()?))).unwrap();
+        let captures = regex.captures(path)?;
+        let adapter_number = u8::from_str_radix(&captures[1], 10).ok()?;
+        let frontend_number= u8::from_str_radix(&captures[2], 10).ok()?;
+        Some(FrontendId{adapter: adapter_number, frontend: frontend_number})
+    }
 }
 
 /// The path in the filesystem to the DVB related special files.
-pub fn dvb_base_path() -> PathBuf { PathBuf::from("/dev/dvb") }
+pub fn dvb_base_path() -> PathBuf { DeviceTree::default().dvb_base_path() }
 
 /// Return the path to the adapter director for a given adapter.
-pub fn adapter_path(id: u8) -> PathBuf {
-    let mut result = dvb_base_path();
-    result.push("adapter".to_string() + &id.to_string());
-    result
-}
+pub fn adapter_path(id: u8) -> PathBuf { DeviceTree::default().adapter_path(id) }
 
 /// Return the path to the special file for a given frontend.
-pub fn frontend_path(fei: &FrontendId) -> PathBuf {
-    let mut result = adapter_path(fei.adapter);
-    result.push("frontend".to_string() + &fei.frontend.to_string());
-    result
-}
+pub fn frontend_path(fei: &FrontendId) -> PathBuf { DeviceTree::default().frontend_path(fei) }
 
 /// Return the path to the special file of the demux for a given frontend.
-pub fn demux_path(fei: &FrontendId) -> PathBuf {
-    let mut result = adapter_path(fei.adapter);
-    result.push("demux".to_string() + &fei.frontend.to_string());
-    result
-}
+pub fn demux_path(fei: &FrontendId) -> PathBuf { DeviceTree::default().demux_path(fei) }
 
 /// Return the path to the special file of the data for a given frontend.
-pub fn dvr_path(fei: &FrontendId) -> PathBuf {
-    let mut result = adapter_path(fei.adapter);
-    result.push("dvr".to_string() + &fei.frontend.to_string());
-    result
+pub fn dvr_path(fei: &FrontendId) -> PathBuf { DeviceTree::default().dvr_path(fei) }
+
+
+/// Find the frontends present in the device tree, in adapter and then frontend order.
+pub fn present_frontends(devices: &DeviceTree) -> Vec<FrontendId> {
+    let mut feis = vec![];
+    let adapter_entries = match fs::read_dir(devices.dvb_base_path()) {
+        Ok(entries) => entries,
+        Err(_) => return feis,
+    };
+    for adapter_entry in adapter_entries.filter_map(|e| e.ok()) {
+        let device_entries = match fs::read_dir(adapter_entry.path()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for device_entry in device_entries.filter_map(|e| e.ok()) {
+            let path = device_entry.path();
+            if let Some(fei) = path.to_str().and_then(|p| devices.frontend_id_from(p)) {
+                if let Ok(m) = fs::metadata(&path) {
+                    if devices.is_device(&m) { feis.push(fei); }
+                }
+            }
+        }
+    }
+    feis.sort_by_key(|fei| (fei.adapter, fei.frontend));
+    feis
+}
+
+/// A change in the frontends present.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrontendEvent {
+    Appeared(FrontendId),
+    Disappeared(FrontendId),
+}
+
+/// Where news of frontends appearing and disappearing comes from: udev normally, polling
+/// the device tree if udev is not available, or a mock for testing.
+pub trait FrontendEventSource {
+    /// The frontends present when watching starts.
+    fn get_present_frontends(&mut self) -> Result<Vec<FrontendId>, String>;
+    /// Wait for frontends to appear or disappear. An error means there will be no more events.
+    fn wait_for_events(&mut self) -> Result<Vec<FrontendEvent>, String>;
 }
 
-/// Search for any adapters already installed on start of the application.
+/// Return the `FrontendId` of a udev device in the dvb subsystem, if it is a frontend.
 ///
-/// Inform the GUI and the remote control manager of the presence of
-/// any adaptors and frontends.
-pub fn add_already_installed_adaptors(to_cw: &mut glib::Sender<Message>) {
-    if dvb_base_path().is_dir() {
-        let mut adapter_number = 0;
+/// The kernel gives DVB devices the DVB_DEVICE_TYPE, DVB_ADAPTER_NUM and DVB_DEVICE_NUM
+/// properties, if they are missing fall back to the system name, dvbXXX.frontendYYY.
+fn frontend_id_from_udev(device_type: Option<&str>, adapter: Option<&str>, number: Option<&str>, sysname: &str) -> Option<FrontendId> {
+    match (device_type, adapter, number) {
+        (Some("frontend"), Some(adapter), Some(number)) => Some(FrontendId{adapter: adapter.parse().ok()?, frontend: number.parse().ok()?}),
+        (Some(device_type), _, _) if device_type != "frontend" => None,
+        _ => {
+            let regex = Regex::new(r"^dvb([0-9]+)\.frontend([0-9]+)$").unwrap();
+            let captures = regex.captures(sysname)?;
+            Some(FrontendId{adapter: captures[1].parse().ok()?, frontend: captures[2].parse().ok()?})
+        },
+    }
+}
+
+fn frontend_id_from_udev_device(device: &udev::Device) -> Option<FrontendId> {
+    let property = |name: &str| device.property_value(name).and_then(|value| value.to_str());
+    frontend_id_from_udev(property("DVB_DEVICE_TYPE"), property("DVB_ADAPTER_NUM"), property("DVB_DEVICE_NUM"), device.sysname().to_str()?)
+}
+
+/// Frontends appearing and disappearing as reported by udev. udev only reports a device
+/// once its special file has been created and its permissions set, so there is no need
+/// to watch /dev.
+pub struct UdevEventSource {
+    socket: udev::MonitorSocket,
+}
+
+impl UdevEventSource {
+    pub fn new() -> Result<UdevEventSource, String> {
+        let socket = udev::MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("dvb"))
+            .and_then(|builder| builder.listen())
+            .map_err(|e| format!("Could not monitor udev: {}", e))?;
+        Ok(UdevEventSource { socket })
+    }
+}
+
+impl FrontendEventSource for UdevEventSource {
+    fn get_present_frontends(&mut self) -> Result<Vec<FrontendId>, String> {
+        let mut enumerator = udev::Enumerator::new().map_err(|e| format!("Could not enumerate udev devices: {}", e))?;
+        enumerator.match_subsystem("dvb").map_err(|e| format!("Could not enumerate udev devices: {}", e))?;
+        let devices = enumerator.scan_devices().map_err(|e| format!("Could not enumerate udev devices: {}", e))?;
+        let mut feis = devices.filter_map(|device| frontend_id_from_udev_device(&device)).collect::<Vec<FrontendId>>();
+        feis.sort_by_key(|fei| (fei.adapter, fei.frontend));
+        Ok(feis)
+    }
+
+    fn wait_for_events(&mut self) -> Result<Vec<FrontendEvent>, String> {
         loop {
-            if adapter_path(adapter_number).is_dir() {
-                let mut fei = FrontendId{adapter: adapter_number, frontend: 0};
-                loop {
-                    // TODO Is it worth doing the check for special file or just check for existence.
-                    let path = frontend_path(&fei);
-                    match fs::metadata(&path) {
-                        Ok(m) => {
-                            // NB m.is_file() is false for special files. :-(
-                            // Assume the special devices were are dealing with are
-                            // character devices not block devices.
-                            if m.file_type().is_char_device() {
-                                to_cw.send(Message::FrontendAppeared{fei: fei.clone()}).unwrap();
-                            }
-                        },
-                        Err(_) => break,
-                    };
-                    fei.frontend += 1;
+            // The socket does not block, so wait for it to be readable.
+            let mut poll_fds = [PollFd::new(self.socket.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut poll_fds, -1) {
+                Ok(_) => {},
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => return Err(format!("Could not wait for udev events: {}", e)),
+            }
+            let events = self.socket.iter().filter_map(|event| {
+                let fei = frontend_id_from_udev_device(&event.device())?;
+                match event.event_type() {
+                    udev::EventType::Add => Some(FrontendEvent::Appeared(fei)),
+                    udev::EventType::Remove => Some(FrontendEvent::Disappeared(fei)),
+                    _ => None,
                 }
-            } else {
-                break;
+            }).collect::<Vec<FrontendEvent>>();
+            if ! events.is_empty() {
+                return Ok(events);
             }
-            adapter_number += 1;
         }
     }
 }
 
-// TODO Find out how to fix this problem of notification of the frontendX files..
-//
-// Experimental evidence from Fedora Rawhide indicates that some USB DVB devices
-// (the newer ones) do not give an event for creating the frontend file.
-// Some USB devices do not give an event for the demux device. All seem to give events
-// for the dvr and net devices. All file removes are notified.
+/// The changes in going from the `old` frontends to the `new` ones, disappearances first.
+fn differences(old: &[FrontendId], new: &[FrontendId]) -> Vec<FrontendEvent> {
+    old.iter().filter(|fei| ! new.contains(fei)).map(|fei| FrontendEvent::Disappeared(fei.clone()))
+        .chain(new.iter().filter(|fei| ! old.contains(fei)).map(|fei| FrontendEvent::Appeared(fei.clone())))
+        .collect()
+}
 
-/// Ensure the name is adaptorXXX /frontendYYY where XXX and YYY are pure numeric,
-/// and return a `FrontendId` based on these numbers.
-fn frontend_id_from(path: &str) -> Option<FrontendId> {
-    let regex = Regex::new(r"/dev/dvb/adapter([0-9]+)/frontend([0-9]+)").unwrap();
-    if regex.is_match(&path) {
-        let captures = regex.captures(path).unwrap();
-        let adapter_number = u8::from_str_radix(&captures[1], 10).unwrap();
-        let frontend_number= u8::from_str_radix(&captures[2], 10).unwrap();
-        Some(FrontendId{adapter: adapter_number, frontend: frontend_number})
-    } else {
-        None
+/// How often to look at the device tree when udev is not available.
+const POLLING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Frontends appearing and disappearing found by looking at the device tree every so often,
+/// for when udev is not available.
+pub struct PollingEventSource {
+    devices: DeviceTree,
+    interval: Duration,
+    known: Vec<FrontendId>,
+}
+
+impl PollingEventSource {
+    pub fn new(devices: DeviceTree, interval: Duration) -> PollingEventSource {
+        PollingEventSource { devices, interval, known: vec![] }
+    }
+
+    /// Look at the device tree once, returning the frontends that have appeared or
+    /// disappeared since the last look.
+    fn poll(&mut self) -> Vec<FrontendEvent> {
+        let present = present_frontends(&self.devices);
+        let events = differences(&self.known, &present);
+        self.known = present;
+        events
+    }
+}
+
+impl FrontendEventSource for PollingEventSource {
+    fn get_present_frontends(&mut self) -> Result<Vec<FrontendId>, String> {
+        self.known = present_frontends(&self.devices);
+        Ok(self.known.clone())
+    }
+
+    fn wait_for_events(&mut self) -> Result<Vec<FrontendEvent>, String> {
+        loop {
+            thread::sleep(self.interval);
+            let events = self.poll();
+            if ! events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+}
+
+/// Find the capabilities of a frontend that has appeared, or forget those of one that
+/// has disappeared, so they are known before the GUI is told.
+fn update_frontend_infos(devices: &DeviceTree, ioctls: &dyn FrontendIoctls, event: &FrontendEvent) {
+    match event {
+        FrontendEvent::Appeared(fei) => match frontend_info::query(ioctls, &devices.frontend_path(fei)) {
+            Ok(info) => {
+                info!("Frontend {:?} is {:?}", fei, info);
+                frontend_info::set_info(fei, Some(info));
+            },
+            Err(e) => warn!("Could not find the capabilities of frontend {:?}: {}", fei, e),
+        },
+        FrontendEvent::Disappeared(fei) => frontend_info::set_info(fei, None),
+    }
+}
+
+/// Report the frontends already present and then the frontends appearing and
+/// disappearing, until the messages can no longer be delivered or the source fails.
+fn watch_frontends<F: FnMut(Message) -> bool>(source: &mut dyn FrontendEventSource, devices: &DeviceTree, ioctls: &dyn FrontendIoctls, mut send: F) {
+    let mut events = match source.get_present_frontends() {
+        Ok(feis) => feis.into_iter().map(FrontendEvent::Appeared).collect::<Vec<FrontendEvent>>(),
+        Err(e) => {
+            error!("Could not find the frontends present: {}", e);
+            return;
+        },
+    };
+    loop {
+        for event in events {
+            update_frontend_infos(devices, ioctls, &event);
+            let message = match event {
+                FrontendEvent::Appeared(fei) => Message::FrontendAppeared{fei},
+                FrontendEvent::Disappeared(fei) => Message::FrontendDisappeared{fei},
+            };
+            if ! send(message) { return; }
+        }
+        events = match source.wait_for_events() {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Frontend watch error: {}", e);
+                return;
+            },
+        };
     }
 }
 
 /// The entry point for the thread that is the frontend manager process.
 ///
 /// Distributes "appeared" and "disappeared" messages to the GUI whenever an
-/// adaptor/frontend state changes.
+/// adaptor/frontend state changes. udev is used to find out about changes if possible,
+/// otherwise /dev/dvb is polled.
 ///
 /// Remote controls in the adapters are handled separately, as the kernel deals with
 /// them differently. A separate daemon is spawned for this that then sends messages to
 /// the GUI as needed.
-pub fn run(mut to_cw: glib::Sender<Message>) {
+pub fn run(to_cw: glib::Sender<Message>) {
     thread::spawn({
         let tocw = to_cw.clone();
         || remote_control::run(tocw)
     });
-    add_already_installed_adaptors(&mut to_cw);
-    let (transmit_end, receive_end) = channel();
-    let mut watcher = raw_watcher(transmit_end).unwrap();
-    //  A simple:
-    //
-    //    watcher.watch("/dev", RecursiveMode::Recursive).unwrap();
-    //
-    //  used to work for everyone. However, as per, https://github.com/Me-TV/Me-TV/issues/36
-    //  and https://github.com/Me-TV/Me-TV/issues/37 there are now reported problems.
-    //  This indicates that there is genuine risk of failure so using unwrap is not appropriate,
-    //  despite the notify documentation. It is not clear why the problem only just appears
-    //  now, nor is there a real indication of what the actual problem is.
-    //
-    //  TODO How to monitor in the face of permission error?
-    match watcher.watch("/dev", RecursiveMode::Recursive) {
-        Ok(_) => loop {
-            match receive_end.recv() {
-                Ok(RawEvent{path: Some(path), op: Ok(op), cookie: _cookie}) => {
-                    match op {
-                        op::CREATE => {
-                            let path = path.to_str().unwrap();
-                            // Hack because of the lack of certainty that the frontend notifies.
-                            if path.contains("dvb") && path.contains("adapter") && path.contains("dvr") {
-                            let path = path.replace("dvr", "frontend");
-                                if let Some(fei) = frontend_id_from(&path) {
-                                    to_cw.send(Message::FrontendAppeared{fei: fei.clone()}).unwrap();
-                                }
-                            }
-                        },
-                        op::REMOVE => {
-                        let path = path.to_str().unwrap();
-                            if path.contains("dvb") && path.contains("adapter") && path.contains("frontend") {
-                                if let Some(fei) = frontend_id_from(&path) {
-                                    to_cw.send(Message::FrontendDisappeared{fei: fei.clone()}).unwrap();
-                                }
-                            }
-                        },
-                        _ => {},
-                    }
-                },
-                Ok(event) => println!("frontend_manager::run: broken event: {:?}", event),
-                Err(e) => println!("frontend_manager::run: watch error: {:?}", e),
-            }
+    let devices = DeviceTree::default();
+    let mut source: Box<dyn FrontendEventSource> = match UdevEventSource::new() {
+        Ok(source) => Box::new(source),
+        Err(e) => {
+            warn!("{}, polling {:?} instead.", e, devices.dvb_base_path());
+            Box::new(PollingEventSource::new(devices.clone(), POLLING_INTERVAL))
         },
-        Err(e) => println!("Watch on /dev/ failed: {:?}", e),  // TODO How to set up the watcher rather than terminate the daemon.
-    }
-    println!("Frontend Manager terminated.");
+    };
+    watch_frontends(source.as_mut(), &devices, &DeviceIoctls, |message| to_cw.send(message).is_ok());
+    info!("Frontend Manager terminated.");
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use std::fs::File;
+
     use quickcheck::quickcheck;
 
+    use crate::dvb;
+
     quickcheck! {
         fn adapter_path_is_correct(id: u8) -> bool {
             adapter_path(id).to_str().unwrap() == format!("/dev/dvb/adapter{}", id)
@@ -222,14 +396,235 @@ mod test {
 
     quickcheck! {
         fn check_frontend_id_from_with_correct_structure(adapter: u8, frontend: u8) -> bool {
-            Some(FrontendId{adapter: adapter, frontend: frontend}) == frontend_id_from(&format!("/dev/dvb/adapter{}/frontend{}", adapter, frontend))
+            Some(FrontendId{adapter: adapter, frontend: frontend}) == DeviceTree::default().frontend_id_from(&format!("/dev/dvb/adapter{}/frontend{}", adapter, frontend))
         }
     }
 
     quickcheck! {
         fn check_frontend_id_from_with_incorrect_structure(prefix: String, postfix: String, adapter: u8, frontend: u8) -> bool {
-            None == frontend_id_from(&format!("{}/adapter{}/frontend{}{}", prefix, adapter, frontend, postfix))
+            None == DeviceTree::default().frontend_id_from(&format!("{}/adapter{}/frontend{}{}", prefix, adapter, frontend, postfix))
          }
     }
 
+    /// A simulated /dev in a temporary directory, with plain files for the special files.
+    struct FakeDevices {
+        directory: tempfile::TempDir,
+        devices: DeviceTree,
+    }
+
+    impl FakeDevices {
+        fn new() -> FakeDevices {
+            let directory = tempfile::tempdir().unwrap();
+            let devices = DeviceTree::simulated(&directory.path().join("dev"));
+            fs::create_dir_all(devices.dvb_base_path()).unwrap();
+            FakeDevices { directory, devices }
+        }
+
+        fn add_adapter(&self, adapter: u8) {
+            fs::create_dir(self.devices.adapter_path(adapter)).unwrap();
+        }
+
+        /// Create the special files of a frontend in the order the kernel does.
+        fn add_frontend(&self, fei: &FrontendId) {
+            for path in &[self.devices.frontend_path(fei), self.devices.demux_path(fei), self.devices.dvr_path(fei)] {
+                File::create(path).unwrap();
+            }
+        }
+
+        /// Some USB devices give no notification of the frontend or demux being created,
+        /// only of the dvr, simulate this by moving the files into place.
+        fn add_usb_frontend(&self, fei: &FrontendId) {
+            let staging = self.directory.path().join("staging");
+            fs::create_dir_all(&staging).unwrap();
+            for path in &[self.devices.frontend_path(fei), self.devices.demux_path(fei)] {
+                let staged = staging.join(path.file_name().unwrap());
+                File::create(&staged).unwrap();
+                fs::rename(&staged, path).unwrap();
+            }
+            File::create(self.devices.dvr_path(fei)).unwrap();
+        }
+
+        fn remove_frontend(&self, fei: &FrontendId) {
+            for path in &[self.devices.dvr_path(fei), self.devices.demux_path(fei), self.devices.frontend_path(fei)] {
+                fs::remove_file(path).unwrap();
+            }
+        }
+
+        /// The tests look at the device tree with `poll`, so the interval is never waited.
+        fn polling_source(&self) -> PollingEventSource {
+            PollingEventSource::new(self.devices.clone(), POLLING_INTERVAL)
+        }
+    }
+
+    /// The events given in batches, and then an error as if the source had failed.
+    struct MockEventSource {
+        present: Vec<FrontendId>,
+        batches: Vec<Vec<FrontendEvent>>,
+    }
+
+    impl FrontendEventSource for MockEventSource {
+        fn get_present_frontends(&mut self) -> Result<Vec<FrontendId>, String> {
+            Ok(self.present.clone())
+        }
+
+        fn wait_for_events(&mut self) -> Result<Vec<FrontendEvent>, String> {
+            if self.batches.is_empty() {
+                Err("No more events.".to_string())
+            } else {
+                Ok(self.batches.remove(0))
+            }
+        }
+    }
+
+    /// Every frontend is the same DVB-T2 tuner.
+    struct FixedIoctls;
+
+    impl FrontendIoctls for FixedIoctls {
+        fn get_info(&self, _path: &Path) -> Result<frontend_info::FeInfo, String> {
+            Ok(frontend_info::FeInfo {
+                name: "Silicon Labs Si2168".to_string(),
+                fe_type: 2,
+                frequency_min: 42_000_000,
+                frequency_max: 870_000_000,
+                frequency_stepsize: 62_500,
+            })
+        }
+
+        fn enum_delivery_systems(&self, _path: &Path) -> Result<Vec<u32>, String> { Ok(vec![16, 3]) }
+    }
+
+    fn fei(adapter: u8, frontend: u8) -> FrontendId {
+        FrontendId{adapter, frontend}
+    }
+
+    /// Message does not implement PartialEq because TargettedKeystroke doesn't.
+    fn describe(message: Message) -> (&'static str, FrontendId) {
+        match message {
+            Message::FrontendAppeared{fei} => ("appeared", fei),
+            Message::FrontendDisappeared{fei} => ("disappeared", fei),
+            message => panic!("The frontend manager sent {:?}.", message),
+        }
+    }
+
+    /// All the messages sent while watching a source until it fails.
+    fn watch(source: &mut dyn FrontendEventSource) -> Vec<(&'static str, FrontendId)> {
+        let mut messages = vec![];
+        watch_frontends(source, &DeviceTree::default(), &FixedIoctls, |message| { messages.push(describe(message)); true });
+        messages
+    }
+
+    #[test]
+    fn present_frontends_are_found_in_order() {
+        let fake = FakeDevices::new();
+        fake.add_adapter(1);
+        fake.add_frontend(&fei(1, 0));
+        fake.add_adapter(0);
+        fake.add_frontend(&fei(0, 1));
+        fake.add_frontend(&fei(0, 0));
+        // Adapters need not be numbered contiguously, adapter 2 may have been unplugged.
+        fake.add_adapter(3);
+        fake.add_frontend(&fei(3, 0));
+        assert_eq!(present_frontends(&fake.devices), vec![fei(0, 0), fei(0, 1), fei(1, 0), fei(3, 0)]);
+    }
+
+    #[test]
+    fn plain_files_are_not_frontends_in_the_real_device_tree() {
+        let fake = FakeDevices::new();
+        fake.add_adapter(0);
+        fake.add_frontend(&fei(0, 0));
+        let devices = DeviceTree { root: fake.devices.root.clone(), character_devices_only: true };
+        assert!(present_frontends(&devices).is_empty());
+    }
+
+    #[test]
+    fn udev_properties_identify_frontends() {
+        assert_eq!(frontend_id_from_udev(Some("frontend"), Some("1"), Some("2"), "dvb1.frontend2"), Some(fei(1, 2)));
+        assert_eq!(frontend_id_from_udev(Some("dvr"), Some("1"), Some("2"), "dvb1.dvr2"), None);
+        assert_eq!(frontend_id_from_udev(Some("demux"), Some("0"), Some("0"), "dvb0.frontend0"), None);
+        assert_eq!(frontend_id_from_udev(Some("frontend"), Some("300"), Some("0"), "dvb300.frontend0"), None);
+    }
+
+    #[test]
+    fn the_udev_system_name_is_used_without_the_properties() {
+        assert_eq!(frontend_id_from_udev(None, None, None, "dvb3.frontend1"), Some(fei(3, 1)));
+        assert_eq!(frontend_id_from_udev(Some("frontend"), None, None, "dvb3.frontend1"), Some(fei(3, 1)));
+        assert_eq!(frontend_id_from_udev(None, None, None, "dvb3.net1"), None);
+        assert_eq!(frontend_id_from_udev(None, None, None, "dvb3.frontend1x"), None);
+    }
+
+    #[test]
+    fn differences_list_disappearances_first() {
+        assert_eq!(
+            differences(&[fei(0, 0), fei(0, 1)], &[fei(0, 1), fei(1, 0)]),
+            vec![FrontendEvent::Disappeared(fei(0, 0)), FrontendEvent::Appeared(fei(1, 0))]
+        );
+        assert!(differences(&[fei(0, 0)], &[fei(0, 0)]).is_empty());
+    }
+
+    #[test]
+    fn events_are_sent_in_order_until_the_source_fails() {
+        let mut source = MockEventSource {
+            present: vec![fei(0, 0), fei(1, 0)],
+            batches: vec![
+                vec![FrontendEvent::Disappeared(fei(1, 0))],
+                vec![FrontendEvent::Appeared(fei(2, 0)), FrontendEvent::Appeared(fei(2, 1))],
+            ],
+        };
+        assert_eq!(watch(&mut source), vec![
+            ("appeared", fei(0, 0)),
+            ("appeared", fei(1, 0)),
+            ("disappeared", fei(1, 0)),
+            ("appeared", fei(2, 0)),
+            ("appeared", fei(2, 1)),
+        ]);
+    }
+
+    #[test]
+    fn watching_stops_when_messages_cannot_be_delivered() {
+        let mut source = MockEventSource { present: vec![fei(0, 0), fei(0, 1)], batches: vec![vec![FrontendEvent::Appeared(fei(0, 2))]] };
+        let mut count = 0;
+        watch_frontends(&mut source, &DeviceTree::default(), &FixedIoctls, |_| { count += 1; false });
+        assert_eq!(count, 1);
+        assert_eq!(source.batches.len(), 1);
+    }
+
+    #[test]
+    fn the_capabilities_are_known_while_the_frontend_is_present() {
+        // Adapter 5 is not used by the other tests, which run in parallel.
+        let mut source = MockEventSource { present: vec![], batches: vec![vec![FrontendEvent::Appeared(fei(5, 0))]] };
+        let mut infos = vec![];
+        watch_frontends(&mut source, &DeviceTree::default(), &FixedIoctls, |message| { infos.push(describe(message).1.get_info()); true });
+        let info = infos[0].clone().unwrap();
+        assert_eq!(info.name, "Silicon Labs Si2168");
+        assert_eq!(info.delivery_systems, vec![dvb::DeliverySystem::DVBT2, dvb::DeliverySystem::DVBT]);
+        let mut source = MockEventSource { present: vec![], batches: vec![vec![FrontendEvent::Disappeared(fei(5, 0))]] };
+        watch(&mut source);
+        assert_eq!(fei(5, 0).get_info(), None);
+    }
+
+    #[test]
+    fn polling_finds_frontends_appearing_and_disappearing() {
+        let fake = FakeDevices::new();
+        fake.add_adapter(0);
+        fake.add_frontend(&fei(0, 0));
+        let mut source = fake.polling_source();
+        assert_eq!(source.get_present_frontends(), Ok(vec![fei(0, 0)]));
+        fake.add_adapter(1);
+        assert_eq!(source.poll(), vec![]);
+        fake.add_frontend(&fei(1, 0));
+        assert_eq!(source.poll(), vec![FrontendEvent::Appeared(fei(1, 0))]);
+        assert_eq!(source.poll(), vec![]);
+        fake.remove_frontend(&fei(0, 0));
+        assert_eq!(source.poll(), vec![FrontendEvent::Disappeared(fei(0, 0))]);
+    }
+
+    #[test]
+    fn polling_finds_a_usb_frontend_whose_creation_is_not_notified() {
+        let fake = FakeDevices::new();
+        fake.add_adapter(0);
+        let mut source = fake.polling_source();
+        assert_eq!(source.get_present_frontends(), Ok(vec![]));
+        fake.add_usb_frontend(&fei(0, 0));
+        assert_eq!(source.poll(), vec![FrontendEvent::Appeared(fei(0, 0))]);
+    }
 }
@@ -3,7 +3,7 @@
  *
  *  A GTK+/GStreamer client for watching and recording DVB.
  *
- *  Copyright © 2017–2019  Russel Winder
+ *  Copyright © 2017–2020  Russel Winder
  *
  *  This program is free software: you can redistribute it and/or modify
  *  it under the terms of the GNU General Public License as published by
@@ -19,25 +19,49 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-// use glib;
+use chrono::{Local, Utc};
+
+use glib;
 use glib::prelude::*;
 use gdk;
 use gdk::prelude::*;
 use gtk;
 use gtk::prelude::*;
 
+use log::{trace, warn};
+
+use xdg;
+
 use crate::channel_names::encode_to_mrl;
-use crate::control_window_button::ControlWindowButton;
+use crate::channel_numbers::MAX_DIGITS;
+use crate::control_window_button::{ControlWindowButton, channel_request};
+use crate::dialogs::display_an_error_dialog;
+use crate::epg_manager::{self, NowNext};
 use crate::gstreamer_engine::GStreamerEngine;
+use crate::input_event_codes;
+use crate::keymap;
 use crate::metvcomboboxtext::{MeTVComboBoxText, MeTVComboBoxTextExt};
+use crate::osd::{FADE_STEP, Osd, volume_text};
 use crate::preferences;
+use crate::radio_panel::RadioPanel;
+use crate::shortcuts_window;
+use crate::signal_panel::SignalPanel;
+use crate::signal_quality::HISTORY_LENGTH;
+use crate::teletext_view::TeletextView;
+use crate::tracks::{SubtitleTrack, next_audio_track, next_subtitle_track};
+use crate::tuner_pool::{Allocation, Claim, Priority, TunerPool};
+
+/// How often a recording checks whether the programme it is recording has ended.
+const RECORDING_END_CHECK_INTERVAL: u32 = 5;  // seconds
 
 /// In fullscreen mode this holds the last time there was mouse movement
 /// or key press activity: it is used to provide a timeout for hiding the fullscreen
-/// control bar. In window mode this value should always be None.
+/// control bar, and keeps the OSD showing. In window mode this value should always be None.
 //  NB  This is only ever accessed using the GUI thread so no multi-threading
 //  protection needed. Rust does though require all access to be labelled unsafe.
 static mut LAST_ACTIVITY_TIME: Option<Instant> = None;
@@ -51,11 +75,31 @@ pub struct FrontendWindow {
     fullscreen_button: gtk::Button,
     volume_adjustment: gtk::Adjustment,
     pub volume_button: gtk::VolumeButton,  // ControlWindowButton instance uses this.
+    record_button: gtk::ToggleButton,
+    audio_track_button: gtk::Button,
+    subtitle_button: gtk::Button,
+    signal_button: gtk::ToggleButton,
     pub channel_selector: MeTVComboBoxText, // ControlWindowButton instance needs access to this.
     fullscreen_toolbar: gtk::Toolbar,
     fullscreen_unfullscreen_button: gtk::Button,
     fullscreen_volume_button: gtk::VolumeButton,
+    fullscreen_record_button: gtk::ToggleButton,
+    fullscreen_audio_track_button: gtk::Button,
+    fullscreen_subtitle_button: gtk::Button,
+    fullscreen_signal_button: gtk::ToggleButton,
     pub fullscreen_channel_selector: MeTVComboBoxText, // ControlWindowButton instance needs access to this.
+    record_stop_timeout: RefCell<Option<glib::SourceId>>,
+    recording_claim: RefCell<Option<Claim>>,
+    teletext_view: TeletextView,
+    signal_panel: SignalPanel,
+    channel_number_label: gtk::Label,
+    osd: Osd,
+    osd_activity_time: Cell<Option<Instant>>,
+    osd_fade_timeout: RefCell<Option<glib::SourceId>>,
+    radio_panel: RadioPanel,
+    radio_update_timeout: RefCell<Option<glib::SourceId>>,
+    television_size: Cell<Option<(i32, i32)>>, // The window size to go back to after the compact radio layout.
+    signal_update_timeout: RefCell<Option<glib::SourceId>>,
     inhibitor: u32,
     pub engine: GStreamerEngine, // ControlWindowButton instance needs access to this.
 }
@@ -98,12 +142,38 @@ impl FrontendWindow {
         // Cannot clone engine so have to wait for construction of the frontend window
         // to be able to define the action associated with the volume_adjustment.
         let volume_button = gtk::VolumeButton::new();
+        let record_button = {
+            let r_b = gtk::ToggleButton::new();
+            r_b.set_image(Some(&gtk::Image::new_from_icon_name(Some("media-record-symbolic"), gtk::IconSize::Button.into())));
+            r_b.set_tooltip_text(Some("Record"));
+            r_b
+        };
+        let audio_track_button = {
+            let a_t_b = gtk::Button::new();
+            a_t_b.set_image(Some(&gtk::Image::new_from_icon_name(Some("audio-x-generic-symbolic"), gtk::IconSize::Button.into())));
+            a_t_b.set_tooltip_text(Some("Audio track"));
+            a_t_b
+        };
+        let subtitle_button = {
+            let s_b = gtk::Button::new();
+            s_b.set_image(Some(&gtk::Image::new_from_icon_name(Some("media-view-subtitles-symbolic"), gtk::IconSize::Button.into())));
+            s_b.set_tooltip_text(Some("Subtitles"));
+            s_b
+        };
+        let signal_button = {
+            let s_b = gtk::ToggleButton::new();
+            s_b.set_image(Some(&gtk::Image::new_from_icon_name(Some("network-wireless-signal-excellent-symbolic"), gtk::IconSize::Button.into())));
+            s_b.set_tooltip_text(Some("Signal quality"));
+            s_b
+        };
         let channel_selector = {
-            let c_s = MeTVComboBoxText::new_and_set_model(&control_window_button.control_window.channel_names_store);
+            let c_s = MeTVComboBoxText::new_and_set_model(&control_window_button.channel_names_store);
             c_s.set_active(control_window_button.channel_selector.get_active());
             c_s.connect_changed({
                 let c_w_b = control_window_button.clone();
-                move |channel_selector| ControlWindowButton::on_channel_changed(&c_w_b, channel_selector.get_active().unwrap())
+                move |channel_selector| if let Some(index) = channel_selector.get_active() {
+                    ControlWindowButton::on_channel_changed(&c_w_b, index)
+                }
             });
             c_s
         };
@@ -114,6 +184,10 @@ impl FrontendWindow {
             h_b.pack_end(&close_button);
             h_b.pack_end(&fullscreen_button);
             h_b.pack_end(&volume_button);
+            h_b.pack_end(&record_button);
+            h_b.pack_end(&audio_track_button);
+            h_b.pack_end(&subtitle_button);
+            h_b.pack_end(&signal_button);
             h_b.pack_start(&channel_selector);
             h_b.show_all();
             h_b
@@ -181,13 +255,35 @@ impl FrontendWindow {
             f_v_b.get_popup().unwrap().connect_event_after(|_, _| { add_timeout(); });
             f_v_b
         };
+        let fullscreen_record_button = {
+            let f_r_b = fullscreen_toolbar_builder.get_object::<gtk::ToggleButton>("fullscreen_record_button").unwrap();
+            f_r_b.connect_event_after(|_, _| { add_timeout(); });
+            f_r_b
+        };
+        let fullscreen_audio_track_button = {
+            let f_a_t_b = fullscreen_toolbar_builder.get_object::<gtk::Button>("fullscreen_audio_track_button").unwrap();
+            f_a_t_b.connect_event_after(|_, _| { add_timeout(); });
+            f_a_t_b
+        };
+        let fullscreen_subtitle_button = {
+            let f_s_b = fullscreen_toolbar_builder.get_object::<gtk::Button>("fullscreen_subtitle_button").unwrap();
+            f_s_b.connect_event_after(|_, _| { add_timeout(); });
+            f_s_b
+        };
+        let fullscreen_signal_button = {
+            let f_s_b = fullscreen_toolbar_builder.get_object::<gtk::ToggleButton>("fullscreen_signal_button").unwrap();
+            f_s_b.connect_event_after(|_, _| { add_timeout(); });
+            f_s_b
+        };
         let fullscreen_channel_selector = {
             let mut f_c_s = fullscreen_toolbar_builder.get_object::<MeTVComboBoxText>("fullscreen_channel_selector").unwrap();
-            f_c_s.set_new_model(&control_window_button.control_window.channel_names_store);
+            f_c_s.set_new_model(&control_window_button.channel_names_store);
             f_c_s.set_active(control_window_button.channel_selector.get_active());
             f_c_s.connect_changed({
                 let c_w_b = control_window_button.clone();
-                move |f_c_s| ControlWindowButton::on_channel_changed(&c_w_b, f_c_s.get_active().unwrap())
+                move |f_c_s| if let Some(index) = f_c_s.get_active() {
+                    ControlWindowButton::on_channel_changed(&c_w_b, index)
+                }
             });
             //
             // TODO There appear to be no 'event-after' events posted for a ComboBox or it's child.
@@ -195,13 +291,13 @@ impl FrontendWindow {
             f_c_s.connect_event_after(|_, ev| {
                 add_timeout();
                 unsafe {
-                    println!("Adding timeout from f_c_s: {:?}, {:?}, {:?}", Instant::now(), ev.get_event_type(), LAST_ACTIVITY_TIME);
+                    trace!("Adding timeout from f_c_s: {:?}, {:?}, {:?}", Instant::now(), ev.get_event_type(), LAST_ACTIVITY_TIME);
                 };
             });
             f_c_s.get_child().unwrap().connect_event_after(|_, ev| {
                 add_timeout();
                 unsafe {
-                    println!("Adding timeout from f_c_s child: {:?}, {:?}, {:?}", Instant::now(), ev.get_event_type(), LAST_ACTIVITY_TIME);
+                    trace!("Adding timeout from f_c_s child: {:?}, {:?}, {:?}", Instant::now(), ev.get_event_type(), LAST_ACTIVITY_TIME);
                 };
             });
             /*
@@ -244,10 +340,37 @@ impl FrontendWindow {
         fullscreen_volume_button.set_value(volume);
         volume_button.set_adjustment(&volume_adjustment);
         fullscreen_volume_button.set_adjustment(&volume_adjustment);
+        let teletext_view = TeletextView::new();
+        let signal_panel = SignalPanel::new();
+        let channel_number_label = {
+            let c_n_l = gtk::Label::new(None);
+            c_n_l.get_style_context().add_class("osd");
+            c_n_l.set_halign(gtk::Align::End);
+            c_n_l.set_valign(gtk::Align::Start);
+            c_n_l.set_margin_end(12);
+            c_n_l.set_margin_top(12);
+            c_n_l.set_no_show_all(true);
+            c_n_l
+        };
+        let osd = Osd::new();
+        let radio_panel = RadioPanel::new();
+        // For a radio channel the radio panel replaces the video, or goes below it if the
+        // video is a visualisation of the sound.
+        let screen = {
+            let s = gtk::Box::new(gtk::Orientation::Vertical, 0);
+            s.pack_start(&engine.video_widget, true, true, 0);
+            s.pack_start(&radio_panel.widget, false, false, 0);
+            s
+        };
         let video_overlay = {
             let v_o = gtk::Overlay::new();
-            v_o.add(&engine.video_widget);
+            v_o.add(&screen);
             v_o.show_all();
+            v_o.add_overlay(&teletext_view.drawing_area);
+            v_o.add_overlay(&signal_panel.widget);
+            v_o.add_overlay(&engine.reconnecting_overlay);
+            v_o.add_overlay(&osd.widget);
+            v_o.add_overlay(&channel_number_label);
             v_o.add_overlay(&fullscreen_toolbar);
             v_o
         };
@@ -278,7 +401,7 @@ impl FrontendWindow {
             }
         });
         let channel_name = control_window_button.channel_selector.get_active_text().unwrap();
-        engine.set_mrl(&encode_to_mrl(&channel_name));
+        engine.set_mrl(&encode_to_mrl(&channel_name), &control_window_button.get_channels_file_path());
         engine.play();
         preferences::set_last_channel(channel_name, true);
         window.show();
@@ -288,7 +411,7 @@ impl FrontendWindow {
             Some("Me TV inhibits when playing a channel."),
         );
         if inhibitor == 0 {
-            println!("Warning: could not set inhibitor.");
+            warn!("Could not set inhibitor.");
         }
         let frontend_window = Rc::new(FrontendWindow {
             control_window_button: control_window_button.clone(),
@@ -297,33 +420,577 @@ impl FrontendWindow {
             fullscreen_button,
             volume_adjustment,
             volume_button,
+            record_button,
+            audio_track_button,
+            subtitle_button,
+            signal_button,
             channel_selector,
             fullscreen_toolbar,
             fullscreen_unfullscreen_button,
             fullscreen_volume_button,
+            fullscreen_record_button,
+            fullscreen_audio_track_button,
+            fullscreen_subtitle_button,
+            fullscreen_signal_button,
             fullscreen_channel_selector,
+            record_stop_timeout: RefCell::new(None),
+            recording_claim: RefCell::new(None),
+            teletext_view,
+            signal_panel,
+            channel_number_label,
+            osd,
+            osd_activity_time: Cell::new(None),
+            osd_fade_timeout: RefCell::new(None),
+            radio_panel,
+            radio_update_timeout: RefCell::new(None),
+            television_size: Cell::new(None),
+            signal_update_timeout: RefCell::new(None),
             inhibitor,
             engine,
         });
         frontend_window.volume_adjustment.connect_value_changed({
             let f_w = frontend_window.clone();
-            move |v_a| f_w.engine.set_volume(v_a.get_value())
+            move |v_a| {
+                f_w.engine.set_volume(v_a.get_value());
+                FrontendWindow::show_osd_status(&f_w, &volume_text(v_a.get_value(), f_w.engine.get_mute_state()));
+            }
+        });
+        for button in &[&frontend_window.record_button, &frontend_window.fullscreen_record_button] {
+            button.connect_toggled({
+                let f_w = frontend_window.clone();
+                move |b| FrontendWindow::set_recording(&f_w, b.get_active())
+            });
+        }
+        for button in &[&frontend_window.audio_track_button, &frontend_window.fullscreen_audio_track_button] {
+            button.connect_clicked({
+                let f_w = frontend_window.clone();
+                move |_| FrontendWindow::present_audio_track_menu(&f_w)
+            });
+        }
+        for button in &[&frontend_window.subtitle_button, &frontend_window.fullscreen_subtitle_button] {
+            button.connect_clicked({
+                let f_w = frontend_window.clone();
+                move |_| FrontendWindow::present_subtitle_menu(&f_w)
+            });
+        }
+        for button in &[&frontend_window.signal_button, &frontend_window.fullscreen_signal_button] {
+            button.connect_toggled({
+                let f_w = frontend_window.clone();
+                move |b| FrontendWindow::set_signal_panel_showing(&f_w, b.get_active())
+            });
+        }
+        frontend_window.signal_panel.graph.connect_draw({
+            let f_w = frontend_window.clone();
+            move |_, cr| {
+                f_w.signal_panel.draw_graph(cr, &f_w.engine.get_signal_history(), HISTORY_LENGTH);
+                Inhibit(false)
+            }
+        });
+        frontend_window.signal_panel.log_button.connect_toggled({
+            let f_w = frontend_window.clone();
+            move |b| FrontendWindow::set_signal_logging(&f_w, b.get_active())
+        });
+        frontend_window.teletext_view.drawing_area.connect_draw({
+            let f_w = frontend_window.clone();
+            move |_, cr| {
+                f_w.teletext_view.draw(cr, &f_w.engine);
+                Inhibit(false)
+            }
+        });
+        frontend_window.window.connect_key_press_event({
+            let f_w = frontend_window.clone();
+            move |_, key| {
+                if let Some(keystroke) = teletext_keystroke(key.get_keyval()) {
+                    if f_w.process_teletext_keystroke(keystroke) { return Inhibit(true); }
+                }
+                // Leave keys with Control or Alt to the widgets and the accelerators.
+                if key.get_state().intersects(gdk::ModifierType::CONTROL_MASK | gdk::ModifierType::MOD1_MASK) {
+                    return Inhibit(false);
+                }
+                let keyval = gdk::keyval_to_lower(key.get_keyval());
+                if keyval == gdk::enums::key::question {
+                    shortcuts_window::present(&f_w.window, f_w.engine.can_pause());
+                    return Inhibit(true);
+                }
+                match gdk::keyval_name(keyval).and_then(|name| keymap::get_keyboard_action(&name, f_w.engine.can_pause())) {
+                    Some(action) => {
+                        ControlWindowButton::perform_action(&f_w.control_window_button, action);
+                        Inhibit(true)
+                    },
+                    None => Inhibit(false),
+                }
+            }
+        });
+        frontend_window.window.connect_focus_in_event({
+            let f_w = frontend_window.clone();
+            move |_, _| {
+                let c_w_b = &f_w.control_window_button;
+                c_w_b.control_window.set_focused_frontend(&c_w_b.frontend_id);
+                Inhibit(false)
+            }
+        });
+        frontend_window.window.add_events(gdk::EventMask::BUTTON_PRESS_MASK);
+        frontend_window.window.connect_button_press_event({
+            let f_w = frontend_window.clone();
+            move |_, event| {
+                if event.get_event_type() == gdk::EventType::DoubleButtonPress && event.get_button() == 1 {
+                    f_w.toggle_fullscreen();
+                    Inhibit(true)
+                } else {
+                    Inhibit(false)
+                }
+            }
         });
+        FrontendWindow::update_radio_mode(&frontend_window);
         Ok(frontend_window)
     }
 
+    /// Use the radio layout if the channel is a radio channel, one with no video, and the
+    /// video layout otherwise. The radio panel is kept up to date with what is on while it
+    /// is showing. Without a visualisation the window shrinks to fit the radio panel.
+    pub fn update_radio_mode(frontend_window: &Rc<FrontendWindow>) { // Used in control_window_button.rs
+        let channel_name = frontend_window.channel_selector.get_active_text().map(|name| name.to_string()).unwrap_or_default();
+        let radio = frontend_window.control_window_button.is_radio_channel(&channel_name);
+        let visualisation = radio && preferences::get_radio_visualisation();
+        frontend_window.engine.video_widget.set_visible(! radio || visualisation);
+        frontend_window.radio_panel.widget.set_visible(radio);
+        frontend_window.radio_panel.widget.set_vexpand(! visualisation);
+        let mut radio_update_timeout = frontend_window.radio_update_timeout.borrow_mut();
+        if radio {
+            frontend_window.update_radio_panel();
+            if radio_update_timeout.is_none() {
+                radio_update_timeout.replace(gtk::timeout_add_seconds(1, {
+                    let f_w = frontend_window.clone();
+                    move || {
+                        f_w.update_radio_panel();
+                        Continue(true)
+                    }
+                }));
+            }
+        } else if let Some(source_id) = radio_update_timeout.take() {
+            glib::source_remove(source_id);
+        }
+        if radio && ! visualisation {
+            if frontend_window.television_size.get().is_none() {
+                frontend_window.television_size.set(Some(frontend_window.window.get_size()));
+                frontend_window.window.resize(1, 1);
+            }
+        } else if let Some((width, height)) = frontend_window.television_size.take() {
+            frontend_window.window.resize(width, height);
+        }
+    }
+
+    fn update_radio_panel(&self) {
+        let channel_name = self.channel_selector.get_active_text().map(|name| name.to_string()).unwrap_or_default();
+        let now_next = self.get_now_next(&channel_name);
+        let radio_text = if preferences::get_radio_text() { self.engine.get_radio_text() } else { None };
+        self.radio_panel.update(&channel_name, &now_next, Utc::now(), radio_text.as_ref().map(String::as_str));
+    }
+
+    /// Pop up a menu of the audio tracks of the current channel so the user can choose one.
+    ///
+    /// The menu is created each time since the tracks change with the channel.
+    pub fn present_audio_track_menu(frontend_window: &Rc<FrontendWindow>) {
+        let menu = gtk::Menu::new();
+        let tracks = frontend_window.engine.get_audio_tracks();
+        if tracks.is_empty() {
+            let item = gtk::MenuItem::new_with_label("No audio tracks");
+            item.set_sensitive(false);
+            menu.append(&item);
+        } else {
+            let current = frontend_window.engine.get_current_audio_track();
+            for track in tracks {
+                let item = gtk::CheckMenuItem::new_with_label(&track.label());
+                item.set_draw_as_radio(true);
+                item.set_active(track.index == current);
+                item.connect_activate({
+                    let f_w = frontend_window.clone();
+                    let index = track.index;
+                    move |_| FrontendWindow::set_audio_track(&f_w, index)
+                });
+                menu.append(&item);
+            }
+        }
+        menu.show_all();
+        menu.popup_easy(0, gtk::get_current_event_time());
+    }
+
+    /// Pop up a menu of the subtitle tracks of the current channel so the user can
+    /// choose one, or turn subtitles off.
+    pub fn present_subtitle_menu(frontend_window: &Rc<FrontendWindow>) {
+        let menu = gtk::Menu::new();
+        let current = frontend_window.engine.get_current_subtitle_track();
+        let off_item = gtk::CheckMenuItem::new_with_label("Off");
+        off_item.set_draw_as_radio(true);
+        off_item.set_active(current.is_none());
+        off_item.connect_activate({
+            let f_w = frontend_window.clone();
+            move |_| FrontendWindow::set_subtitle_track(&f_w, None)
+        });
+        menu.append(&off_item);
+        let tracks = frontend_window.engine.get_subtitle_tracks();
+        if tracks.is_empty() {
+            let item = gtk::MenuItem::new_with_label("No subtitles");
+            item.set_sensitive(false);
+            menu.append(&item);
+        }
+        for track in tracks {
+            let item = gtk::CheckMenuItem::new_with_label(&track.label());
+            item.set_draw_as_radio(true);
+            item.set_active(current.as_ref() == Some(&track));
+            item.connect_activate({
+                let f_w = frontend_window.clone();
+                move |_| FrontendWindow::set_subtitle_track(&f_w, Some(&track))
+            });
+            menu.append(&item);
+        }
+        menu.show_all();
+        menu.popup_easy(0, gtk::get_current_event_time());
+    }
+
+    /// Move on to the next audio track, going back to the first after the last one.
+    pub fn cycle_audio_track(frontend_window: &Rc<FrontendWindow>) { // Used in control_window_button.rs
+        let tracks = frontend_window.engine.get_audio_tracks();
+        let current = frontend_window.engine.get_current_audio_track();
+        let current = tracks.iter().position(|track| track.index == current);
+        if let Some(index) = next_audio_track(tracks.len(), current) {
+            FrontendWindow::set_audio_track(frontend_window, tracks[index].index);
+        }
+    }
+
+    /// Change the audio track, saying which it is in the OSD.
+    fn set_audio_track(frontend_window: &Rc<FrontendWindow>, index: i32) {
+        frontend_window.engine.set_current_audio_track(index);
+        if let Some(track) = frontend_window.engine.get_audio_tracks().iter().find(|track| track.index == index) {
+            FrontendWindow::show_osd_status(frontend_window, &format!("Audio: {}", track.label()));
+        }
+    }
+
+    /// Move on to the next subtitle track, going to off after the last one.
+    pub fn cycle_subtitle_track(frontend_window: &Rc<FrontendWindow>) { // Used in control_window_button.rs
+        let tracks = frontend_window.engine.get_subtitle_tracks();
+        let current = frontend_window.engine.get_current_subtitle_track()
+            .and_then(|track| tracks.iter().position(|t| *t == track));
+        let next = next_subtitle_track(tracks.len(), current).map(|index| &tracks[index]);
+        FrontendWindow::set_subtitle_track(frontend_window, next);
+    }
+
+    /// Show a subtitle track, remembering its language so that, if subtitles are always on,
+    /// subtitles in the same language are shown on the next channel. Whether subtitles are
+    /// always on is left to the preferences.
+    fn set_subtitle_track(frontend_window: &Rc<FrontendWindow>, track: Option<&SubtitleTrack>) {
+        frontend_window.engine.set_subtitle_track(track);
+        match track {
+            Some(track) => {
+                if let Some(ref language) = track.language {
+                    preferences::set_subtitle_language(language.clone(), true);
+                }
+                FrontendWindow::show_osd_status(frontend_window, &format!("Subtitles: {}", track.label()));
+            },
+            None => FrontendWindow::show_osd_status(frontend_window, "Subtitles off"),
+        }
+    }
+
+    pub fn toggle_mute(frontend_window: &Rc<FrontendWindow>) { // Used in control_window_button.rs
+        let mute = ! frontend_window.engine.get_mute_state();
+        frontend_window.engine.set_mute_state(&mute);
+        FrontendWindow::show_osd_status(frontend_window, &volume_text(frontend_window.volume_adjustment.get_value(), mute));
+    }
+
+    /// Go into or out of fullscreen mode just as the buttons do.
+    pub fn toggle_fullscreen(&self) { // Used in control_window_button.rs
+        let fullscreen = match self.window.get_window() {
+            Some(window) => window.get_state().intersects(gdk::WindowState::FULLSCREEN),
+            None => return,
+        };
+        if fullscreen {
+            self.fullscreen_unfullscreen_button.clicked();
+        } else {
+            self.fullscreen_button.clicked();
+        }
+    }
+
+    /// Pause or resume the channel, if it can be paused.
+    pub fn toggle_pause(&self) { // Used in control_window_button.rs
+        if ! self.engine.can_pause() { return; }
+        if self.engine.is_paused() {
+            self.engine.play();
+        } else {
+            self.engine.pause();
+        }
+    }
+
+    /// Show the digits of a channel number as they are entered, `None` to stop showing them.
+    pub fn show_channel_number_entry(&self, digits: Option<&str>) { // Used in control_window_button.rs
+        match digits {
+            Some(digits) => {
+                // Dashes for the digits still to come.
+                self.channel_number_label.set_markup(&format!("<span size=\"xx-large\">{:-<width$}</span>", digits, width = MAX_DIGITS));
+                self.channel_number_label.show();
+            },
+            None => self.channel_number_label.hide(),
+        }
+    }
+
+    /// Show the number and name of the channel being watched, `None` for the number if the
+    /// channel has none, with the programmes on now and next, in the OSD.
+    pub fn show_channel_info(frontend_window: &Rc<FrontendWindow>, number: Option<u16>) { // Used in control_window_button.rs
+        let name = frontend_window.channel_selector.get_active_text().map(|name| name.to_string()).unwrap_or_default();
+        let now_next = frontend_window.get_now_next(&name);
+        frontend_window.osd.set_channel_info(&name, number, &now_next, Utc::now());
+        FrontendWindow::show_osd(frontend_window);
+    }
+
+    /// The programmes on now and next of a channel, as far as the EPG store knows them.
+    fn get_now_next(&self, channel_name: &str) -> NowNext {
+        self.control_window_button.get_service_id(channel_name)
+            .map(epg_manager::get_now_next)
+            .unwrap_or_default()
+    }
+
+    /// Show a message, such as a volume change, in the OSD.
+    fn show_osd_status(frontend_window: &Rc<FrontendWindow>, message: &str) {
+        frontend_window.osd.set_status(message);
+        FrontendWindow::show_osd(frontend_window);
+    }
+
+    /// Keep the OSD showing for a while. It fades out once there has been no activity, of
+    /// the OSD or, in fullscreen mode, of the mouse and keyboard, for a while.
+    fn show_osd(frontend_window: &Rc<FrontendWindow>) {
+        frontend_window.osd_activity_time.set(Some(Instant::now()));
+        let mut osd_fade_timeout = frontend_window.osd_fade_timeout.borrow_mut();
+        if osd_fade_timeout.is_none() {
+            osd_fade_timeout.replace(gtk::timeout_add(FADE_STEP, {
+                let f_w = frontend_window.clone();
+                move || {
+                    let last_activity_time = match (f_w.osd_activity_time.get(), unsafe { LAST_ACTIVITY_TIME }) {
+                        (Some(osd_time), Some(activity_time)) => osd_time.max(activity_time),
+                        (osd_time, activity_time) => osd_time.or(activity_time).unwrap_or_else(Instant::now),
+                    };
+                    if f_w.osd.fade(Instant::now().saturating_duration_since(last_activity_time)) {
+                        Continue(true)
+                    } else {
+                        f_w.osd_activity_time.set(None);
+                        f_w.osd_fade_timeout.replace(None);
+                        Continue(false)
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Pass a keystroke to the teletext viewer, returning true if it was used.
+    pub fn process_teletext_keystroke(&self, keystroke: u32) -> bool { // Used in control_window_button.rs
+        self.teletext_view.process_keystroke(keystroke, &self.engine)
+    }
+
+    /// Show or hide the signal quality panel, which is updated every second while shown.
+    pub fn set_signal_panel_showing(frontend_window: &Rc<FrontendWindow>, state: bool) {
+        for button in &[&frontend_window.signal_button, &frontend_window.fullscreen_signal_button] {
+            if button.get_active() != state {
+                button.set_active(state);
+            }
+        }
+        frontend_window.signal_panel.widget.set_visible(state);
+        let mut signal_update_timeout = frontend_window.signal_update_timeout.borrow_mut();
+        if state {
+            frontend_window.signal_panel.update(frontend_window.engine.get_latest_signal_stats());
+            if signal_update_timeout.is_none() {
+                signal_update_timeout.replace(gtk::timeout_add_seconds(1, {
+                    let f_w = frontend_window.clone();
+                    move || {
+                        f_w.signal_panel.update(f_w.engine.get_latest_signal_stats());
+                        Continue(true)
+                    }
+                }));
+            }
+        } else if let Some(source_id) = signal_update_timeout.take() {
+            glib::source_remove(source_id);
+        }
+    }
+
+    /// Start or stop writing the signal quality to a CSV file.
+    fn set_signal_logging(frontend_window: &Rc<FrontendWindow>, state: bool) {
+        let log_button = &frontend_window.signal_panel.log_button;
+        if ! state {
+            frontend_window.engine.stop_signal_log();
+            log_button.set_tooltip_text(None);
+            return;
+        }
+        let channel_name = match frontend_window.channel_selector.get_active_text() {
+            Some(channel_name) => channel_name,
+            None => {
+                display_an_error_dialog(Some(&frontend_window.window), "No channel is selected, so there is no signal quality to log.");
+                log_button.set_active(false);
+                return;
+            },
+        };
+        match signal_log_file_path(&channel_name).and_then(|path| frontend_window.engine.start_signal_log(&path).map(|_| path)) {
+            Ok(path) => log_button.set_tooltip_text(Some(&format!("Logging to {}", path.display()))),
+            Err(message) => {
+                display_an_error_dialog(Some(&frontend_window.window), &format!("Could not start logging the signal quality.\n\n{}", message));
+                log_button.set_active(false);
+            },
+        }
+    }
+
+    /// Claim a share of the tuner being watched for recording, so that other processes know
+    /// of the recording.
+    fn claim_tuner_for_recording(&self, channel_name: &str) {
+        let tuner = &self.control_window_button.frontend_id;
+        match TunerPool::get_default().claim_tuner(tuner.clone(), &channel_request(&self.control_window_button.get_channels_file_path(), channel_name, Priority::Recording)) {
+            Ok(Allocation::Claimed(claim)) => { self.recording_claim.replace(Some(claim)); },
+            Ok(allocation) => warn!("Could not share {} for recording: {:?}", tuner, allocation),
+            Err(message) => warn!("Could not use the tuner pool: {}", message),
+        }
+    }
+
+    fn release_recording_claim(&self) {
+        if let Some(claim) = self.recording_claim.replace(None) {
+            TunerPool::get_default().release(&claim);
+        }
+    }
+
+    pub fn toggle_recording(frontend_window: &Rc<FrontendWindow>) { // Used in control_window_button.rs
+        let state = ! frontend_window.engine.is_recording();
+        FrontendWindow::set_recording(frontend_window, state);
+    }
+
+    /// Start or stop recording the channel being watched.
+    ///
+    /// The recording is the transport stream as received, so no second frontend
+    /// is needed, the tuner being watched is shared. If the EPG knows the current programme,
+    /// the recording stops when it ends, even if the EIT changes the end while recording.
+    pub fn set_recording(frontend_window: &Rc<FrontendWindow>, state: bool) { // Used in control_window_button.rs
+        if frontend_window.record_button.get_active() != state {
+            frontend_window.record_button.set_active(state);
+        }
+        if frontend_window.fullscreen_record_button.get_active() != state {
+            frontend_window.fullscreen_record_button.set_active(state);
+        }
+        if state == frontend_window.engine.is_recording() {
+            return;
+        }
+        if state {
+            let channel_name = match frontend_window.channel_selector.get_active_text() {
+                Some(channel_name) => channel_name,
+                None => {
+                    display_an_error_dialog(Some(&frontend_window.window), "No channel is selected, so there is nothing to record.");
+                    FrontendWindow::set_recording(frontend_window, false);
+                    return;
+                },
+            };
+            match frontend_window.engine.start_recording(&recording_file_path(&channel_name)) {
+                Ok(_) => {
+                    frontend_window.claim_tuner_for_recording(&channel_name);
+                    if let Some(event) = frontend_window.control_window_button.get_service_id(&channel_name).and_then(epg_manager::get_current_event) {
+                        let mut end_time = event.end_time();
+                        let source_id = gtk::timeout_add_seconds(RECORDING_END_CHECK_INTERVAL, {
+                            let f_w = frontend_window.clone();
+                            move || {
+                                if let Some(event) = epg_manager::get_event(event.service_id, event.event_id) {
+                                    end_time = event.end_time();
+                                }
+                                if Utc::now() < end_time {
+                                    return Continue(true);
+                                }
+                                f_w.record_stop_timeout.replace(None);
+                                FrontendWindow::set_recording(&f_w, false);
+                                Continue(false)
+                            }
+                        });
+                        frontend_window.record_stop_timeout.replace(Some(source_id));
+                    }
+                },
+                Err(message) => {
+                    display_an_error_dialog(Some(&frontend_window.window), &format!("Could not start recording.\n\n{}", message));
+                    FrontendWindow::set_recording(frontend_window, false);
+                },
+            }
+        } else {
+            frontend_window.engine.stop_recording();
+            frontend_window.release_recording_claim();
+            if let Some(source_id) = frontend_window.record_stop_timeout.replace(None) {
+                glib::source_remove(source_id);
+            }
+        }
+    }
+
     pub fn stop(&self) {
         if self.inhibitor  != 0 {
             let application = self.control_window_button.control_window.window.get_application().unwrap();
             application.uninhibit(self.inhibitor);
         } else {
-            println!("Warning: inhibitor was not set.");
+            warn!("Inhibitor was not set.");
+        }
+        if let Some(source_id) = self.record_stop_timeout.replace(None) {
+            glib::source_remove(source_id);
+        }
+        self.engine.stop_recording();
+        self.release_recording_claim();
+        self.teletext_view.stop(&self.engine);
+        // The toggled handlers stop the logging and the updates of the panel, but the
+        // buttons must be reset anyway so that they are right when the window is next shown.
+        self.signal_panel.log_button.set_active(false);
+        for button in &[&self.signal_button, &self.fullscreen_signal_button] {
+            button.set_active(false);
         }
+        self.signal_panel.widget.set_visible(false);
+        if let Some(source_id) = self.signal_update_timeout.replace(None) {
+            glib::source_remove(source_id);
+        }
+        self.engine.stop_signal_log();
+        if let Some(source_id) = self.osd_fade_timeout.replace(None) {
+            glib::source_remove(source_id);
+        }
+        if let Some(source_id) = self.radio_update_timeout.replace(None) {
+            glib::source_remove(source_id);
+        }
+        self.osd.hide();
         self.window.hide();
         self.engine.stop();
     }
 }
 
+/// Map the keys of the keyboard to the remote control keys of the teletext viewer: T for
+/// text, the function keys F1 to F4 for the coloured keys. Keys the teletext viewer does
+/// not use go on to be keyboard shortcuts.
+fn teletext_keystroke(keyval: gdk::enums::key::Key) -> Option<u32> {
+    use gdk::enums::key;
+    match keyval {
+        key::t | key::T => Some(input_event_codes::KEY_TEXT),
+        key::_0 | key::KP_0 => Some(input_event_codes::KEY_0),
+        key::_1..=key::_9 => Some(input_event_codes::KEY_1 + keyval - key::_1),
+        key::KP_1..=key::KP_9 => Some(input_event_codes::KEY_1 + keyval - key::KP_1),
+        key::F1..=key::F4 => Some(input_event_codes::KEY_RED + keyval - key::F1),
+        key::Up => Some(input_event_codes::KEY_UP),
+        key::Down => Some(input_event_codes::KEY_DOWN),
+        key::Left => Some(input_event_codes::KEY_LEFT),
+        key::Right => Some(input_event_codes::KEY_RIGHT),
+        _ => None,
+    }
+}
+
+/// Return the path of a new recording file for a channel.
+///
+/// Recordings go in the user's Videos directory, or their home directory if there is no
+/// Videos directory, named with the channel name and the start time.
+fn recording_file_path(channel_name: &str) -> PathBuf {
+    let mut path = match glib::get_user_special_dir(glib::UserDirectory::Videos) {
+        Some(path) if path.is_dir() => path,
+        _ => glib::get_home_dir().expect("Could not find the home directory."),
+    };
+    path.push(format!("{} – {}.ts", channel_name, Local::now().format("%Y-%m-%d %H.%M.%S")));
+    path
+}
+
+/// Return the path of a new signal quality log file for a channel, in the Me TV data directory.
+fn signal_log_file_path(channel_name: &str) -> Result<PathBuf, String> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("me-tv").map_err(|e| e.to_string())?;
+    xdg_dirs.place_data_file(format!("signal-logs/{} – {}.csv", channel_name, Local::now().format("%Y-%m-%d %H.%M.%S")))
+        .map_err(|e| e.to_string())
+}
+
 fn hide_cursor(widget: &gtk::Widget) {
     if let Some(window) = widget.get_window() {
         window.set_cursor(gdk::Cursor::new_from_name(&widget.get_display().unwrap(), "none").as_ref());
@@ -19,8 +19,17 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::Local;
 
 //use gio;
 //use gio::prelude::*;
@@ -37,10 +46,22 @@ use gst_mpegts;
 
 use fragile::Fragile;
 
+use log::{debug, error, info, warn};
+
+use crate::channel_names;
+use crate::channel_numbers;
 use crate::control_window_button::ControlWindowButton;
 use crate::dialogs::display_an_error_dialog;
 use crate::epg_manager;
+use crate::errors::{self, MeTvError};
 use crate::preferences;
+use crate::program_map::{self, ElementaryStream, ProgramMap};
+use crate::radio_text::RadioTextDecoder;
+use crate::recovery::{Action, Recovery, RecoveryPolicy};
+use crate::signal_quality::{FrontendStats, SignalHistory, SignalLog};
+use crate::teletext_renderer::{PageImage, TeletextRenderer};
+use crate::tracks::{AudioTrack, SubtitleSource, SubtitleTrack, preferred_audio_track, preferred_subtitle_track, subtitle_tracks};
+use crate::ts_file;
 
 /// Is nouveau the device driver?
 ///
@@ -52,6 +73,39 @@ fn is_using_nouveau() -> bool {
     lsmod_output.contains("nouveau")
 }
 
+/// teletextdec has no rank and so is never autoplugged. Give it one so that teletext
+/// streams become text streams of playbin and teletext subtitles can be shown.
+pub fn enable_teletext_decoding() {
+    if let Some(feature) = gst::Registry::get().lookup_feature("teletextdec") {
+        feature.set_rank(gst::Rank::Primary);
+    }
+}
+
+fn is_teletext_decoding_available() -> bool {
+    gst::ElementFactory::find("teletextdec").is_some()
+}
+
+/// The state shared by the engine, the GStreamer streaming threads, and the bus watch.
+#[derive(Debug, Default)]
+struct SharedState {
+    recording_file: Mutex<Option<File>>,
+    program_map: Mutex<Option<ProgramMap>>,
+    teletext_decoder: Mutex<Option<glib::WeakRef<gst::Element>>>,
+    subtitle_track: Mutex<Option<SubtitleTrack>>,
+    // The teletext page being viewed, and the pipeline rendering it.
+    teletext_page: Mutex<Option<(u16, Option<u16>)>>,
+    teletext: Mutex<Option<TeletextRenderer>>,
+    signal_history: Mutex<SignalHistory>,
+    signal_log: Mutex<SignalLog>,
+    recovery: Mutex<Recovery>,
+    channels_file: Mutex<Option<PathBuf>>,
+    // The RDS radio text in the sound of the channel, and the decoder finding it.
+    radio_text: Mutex<Option<String>>,
+    radio_text_decoder: Mutex<RadioTextDecoder>,
+    // Set when the pipeline is restarted, cleared when data next arrives.
+    awaiting_data: AtomicBool,
+}
+
 /// The GStreamer elements and GTK+ widgets that are the bits that do the work of rendering
 /// the television or radio channel.
 #[derive(Debug)]
@@ -59,14 +113,28 @@ pub struct GStreamerEngine {
     playbin: gst::Element,
     video_element: gst::Element,
     pub video_widget: gtk::Widget, // FrontendWindow uses this for the overlay.
+    pub reconnecting_overlay: gtk::Box, // FrontendWindow puts this in the video overlay.
+    shared: Arc<SharedState>,
 }
 
 impl GStreamerEngine {
 
     pub fn new(control_window_button: Rc<ControlWindowButton>) -> Result<GStreamerEngine, ()> {
-        let playbin = gst::ElementFactory::make("playbin", Some("playbin")).expect("Failed to create playbin element");
+        let playbin = match gst::ElementFactory::make("playbin", Some("playbin")) {
+            Ok(playbin) => playbin,
+            Err(error) => {
+                errors::report(MeTvError::ElementCreation { element: "playbin".to_string(), message: error.to_string() });
+                display_an_error_dialog(
+                    Some(&control_window_button.control_window.window),
+                    "Could not create a 'playbin'\n\nIs the gstreamer1.0-plugins-base package installed?"
+                );
+                return Err(());
+            },
+        };
+        let shared = Arc::new(SharedState::default());
         playbin.connect("element-setup",  false, {
             let fei = control_window_button.frontend_id.clone();
+            let shared = shared.clone();
             move |values| {
                 // values[0] .get::<gst::Element>() is an Option on the playbin itself.
                 let element = values[1]
@@ -74,30 +142,72 @@ impl GStreamerEngine {
                     .expect("Failed to get a handle on the Element being created")
                     .expect("Got None rather than an Some<Element>");
                 if let Some(element_factory) = element.get_factory() {
-                    if element_factory.get_name() == "dvbsrc" {
-                        let adapter_number = element
-                            .get_property("adapter")
-                            .expect("Could not retrieve adapter number Value")
-                            .get::<i32>()
-                            .expect("Could not get the i32 value from the adapter number Value")
-                            .expect("Got None rather than Some<u32>") as u8;
-                        let frontend_number = element
-                            .get_property("frontend")
-                            .expect("Could not retrieve frontend number Value.")
-                            .get::<i32>()
-                            .expect("Could not get the i32 value from the frontend number Value")
-                            .expect("Got None rather than Some<u32>") as u8;
-                        if adapter_number != fei.adapter {
-                            element.set_property("adapter", &(fei.adapter as i32).to_value()).expect("Could not set adapter number on dvbsrc element");
-                        }
-                        if frontend_number != fei.frontend {
-                            element.set_property("frontend", &(fei.frontend as i32).to_value()).expect("Could not set frontend number of dvbsrc element");
-                        }
+                    match element_factory.get_name().as_str() {
+                        "dvbsrc" => {
+                            let adapter_number = element
+                                .get_property("adapter")
+                                .expect("Could not retrieve adapter number Value")
+                                .get::<i32>()
+                                .expect("Could not get the i32 value from the adapter number Value")
+                                .expect("Got None rather than Some<u32>") as u8;
+                            let frontend_number = element
+                                .get_property("frontend")
+                                .expect("Could not retrieve frontend number Value.")
+                                .get::<i32>()
+                                .expect("Could not get the i32 value from the frontend number Value")
+                                .expect("Got None rather than Some<u32>") as u8;
+                            if adapter_number != fei.adapter {
+                                element.set_property("adapter", &(fei.adapter as i32).to_value()).expect("Could not set adapter number on dvbsrc element");
+                            }
+                            if frontend_number != fei.frontend {
+                                element.set_property("frontend", &(fei.frontend as i32).to_value()).expect("Could not set frontend number of dvbsrc element");
+                            }
+                        },
+                        "dvbbasebin" => {
+                            // The output of dvbbasebin is the transport stream for the channel, so
+                            // recording is just writing the buffers to a file, no second tuner needed.
+                            if let Some(src_pad) = element.get_static_pad("src") {
+                                add_transport_stream_probe(&src_pad, &shared);
+                            }
+                        },
+                        // A transport stream file goes straight to tsdemux, there is no dvbbasebin.
+                        "tsdemux" if ts_file::get_ts_file().is_some() => {
+                            if let Some(sink_pad) = element.get_static_pad("sink") {
+                                add_transport_stream_probe(&sink_pad, &shared);
+                            }
+                        },
+                        "mpegaudioparse" => {
+                            if let Some(src_pad) = element.get_static_pad("src") {
+                                add_radio_text_probe(&src_pad, &shared);
+                            }
+                        },
+                        "teletextdec" => {
+                            element.set_property("subtitles-mode", &true).expect("Could not set subtitles-mode on teletextdec element");
+                            if let Ok(mut teletext_decoder) = shared.teletext_decoder.lock() {
+                                teletext_decoder.replace(element.downgrade());
+                            }
+                        },
+                        _ => {},
                     }
                 }
                 None
             }
         }).expect("Could not connect a handler to the element-setup signal.");
+        // The audio-changed and text-changed signals are emitted from a streaming thread so
+        // tell the bus watch and have the track selected in the GTK event loop thread.
+        for signal_name in &["audio-changed", "text-changed"] {
+            playbin.connect(signal_name, false, move |values| {
+                let playbin = values[0]
+                    .get::<gst::Element>()
+                    .expect("Failed to get a handle on the playbin")
+                    .expect("Got None rather than an Some<Element>");
+                let message = gst::Message::new_application(gst::Structure::new_empty(signal_name)).build();
+                if let Err(error) = playbin.post_message(&message) {
+                    warn!("Could not post {} message: {}", signal_name, error);
+                }
+                None
+            }).expect(&format!("Could not connect a handler to the {} signal.", signal_name));
+        }
         let bus = playbin.get_bus().unwrap();
         // The compiler cannot determine that the bus watch callback will be executed
         // by the same thread that the gtk::Application and ControlWindowButtons objects
@@ -105,12 +215,44 @@ impl GStreamerEngine {
         // use Fragile.
         let application = &control_window_button.control_window.window.get_application().unwrap();
         let application_clone = Fragile::new(application.clone());
+        let reconnecting_label = gtk::Label::new(Some("Reconnecting…"));
+        let reconnecting_overlay = {
+            let r_o = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            r_o.get_style_context().add_class("osd");
+            r_o.set_halign(gtk::Align::Start);
+            r_o.set_valign(gtk::Align::End);
+            r_o.set_margin_start(6);
+            r_o.set_margin_bottom(6);
+            r_o.set_border_width(6);
+            let spinner = gtk::Spinner::new();
+            spinner.start();
+            r_o.pack_start(&spinner, false, false, 0);
+            r_o.pack_start(&reconnecting_label, false, false, 0);
+            r_o.show_all();
+            r_o.set_no_show_all(true);
+            r_o.hide();
+            r_o
+        };
         bus.add_watch({
             let application_clone = Fragile::new(application.clone());
             let control_window_button_clone = Fragile::new(control_window_button.clone());
+            let playbin_weak_ref = playbin.downgrade();
+            let shared = shared.clone();
+            let reconnecting_clone = Fragile::new((reconnecting_overlay.clone(), reconnecting_label.clone()));
             move |_, msg| {
                 let application = application_clone.get();
                 let control_window_button = control_window_button_clone.get();
+                let (reconnecting_overlay, reconnecting_label) = reconnecting_clone.get();
+                let recover = |reason: &str| recover_from_failure(
+                    &playbin_weak_ref,
+                    &shared,
+                    &ReconnectingDisplay {
+                        overlay: reconnecting_overlay.clone(),
+                        label: reconnecting_label.clone(),
+                        parent: application.get_windows()[0].clone(),
+                    },
+                    reason,
+                );
                 match msg.view() {
                     gst::MessageView::Element(element) => {
                         if let Some(structure) = element.get_structure() {
@@ -122,83 +264,68 @@ impl GStreamerEngine {
                                     //   opening of a connection to an adapter.
                                 },
                                 "dvb-frontend-stats" => {
-                                    // TODO Do we need to process this at all?
-                                    //   Lots of these get sent out, but it is not clear
-                                    //   what the benefit of processing them is – at
-                                    //   least not at this time anyway.
-                                },
-                                "dvb-read-failure" => {
-                                    // TODO What should be done on a read failure?
-                                    //   For now the read fails are simply ignored.
-                                    println!("********    Got a DVB read failure.");
-                                },
-                                "eit" => {
-                                    if let Some(section) = gst_mpegts::Section::from_element(&element) {
-                                        if section.get_section_type() == gst_mpegts::SectionType::Eit {
-                                            if let Some(eit) = section.get_eit() {
-                                                for event in eit.event_iterator() {
-                                                    let event_message = epg_manager::EPGEventMessage::new(
-                                                        section.get_subtable_extension(),
-                                                        event.get_event_id(),
-                                                        event.get_start_time(),
-                                                        event.get_duration(),
-                                                        event.get_descriptors(),
-                                                    );
-                                                    control_window_button.control_window.to_epg_manager.send(event_message).unwrap();
-                                                }
-                                            } else {
-                                                //  TODO This seems to happen, and yet it shouldn't.
-                                                println!("********    Could not get an EIT from a supposed EIT Section: {:?}", section);
-                                                println!("********        Section type: {:?}", section.get_section_type());
-                                                println!("********        EIT: {:?}", section.get_eit());
-                                            }
-                                        } else {
-                                            panic!("************  EIT Section is not an EIT Section: {:?}", section);
+                                    if let Some(stats) = frontend_stats_from_structure(structure) {
+                                        if let Err(message) = shared.signal_log.lock().unwrap().log(&stats) {
+                                            warn!("Stopped logging signal quality: {}", message);
                                         }
-                                    } else {
-                                        panic!("************  Could not get a Section from an EIT Section Element: {:?}", element);
+                                        shared.signal_history.lock().unwrap().push(stats);
                                     }
                                 },
+                                "dvb-read-failure" => recover("Could not read from the DVB frontend."),
+                                "eit" => process_eit_section(&element, &control_window_button.control_window.to_epg_manager),
                                 "GstNavigationMessage" => {},
-                                "nit" => {
-                                    if let Some(section) = gst_mpegts::Section::from_element(&element) {
-                                        if section.get_section_type() == gst_mpegts::SectionType::Nit {
-                                            if let Some(nit) = section.get_nit() {
-                                                println!("========  Got a NIT {:?}", nit);
-                                            }else {
-                                                panic!("************    Could not get a NIT from a NIT Section: {:?}", section);
-                                            }
-                                        } else {
-                                            panic!("************  NIT Section is not an NIT Section: {:?}", section);
-                                        }
-                                    } else {
-                                        panic!("************  Could not get a Section from a NIT Section Element: {:?}", element);
-                                    }
+                                // Only the order of the channels depends on the numbers.
+                                "nit" => if process_nit_section(&element, &shared) && preferences::get_sort_channels_by_number() {
+                                    control_window_button.control_window.update_channels_store();
                                 },
                                 "pat" => {},
-                                "pmt" =>{},
+                                "pmt" => if let Some(program_map) = process_pmt_section(&element) {
+                                    // tsdemux posts the PMT of every programme of a file, the first is the one played.
+                                    let mut current = shared.program_map.lock().unwrap();
+                                    if current.as_ref().map_or(true, |c| c.program_number == program_map.program_number) {
+                                        current.replace(program_map);
+                                    }
+                                },
                                 "sdt" => {},
                                 "section" => {},
                                 "tdt" => {},
                                 "tot" => {},
-                                _ => println!("Unknown Element type: {:?}", element),
+                                _ => debug!("Unknown Element type: {:?}", element),
                             }
                         } else {
-                            panic!("Element has no Structure: {:?}", element);
+                            errors::report(MeTvError::UnstructuredMessage { message: format!("{:?}", element) });
                         }
                     },
-                    gst::MessageView::Eos(..) => {
-                        display_an_error_dialog(
-                            Some(&application.get_windows()[0]),
-                            "There was an end of stream in the GStreamer system"
-                        );
+                    gst::MessageView::Application(application_message) => {
+                        if let Some(structure) = application_message.get_structure() {
+                            match (structure.get_name(), playbin_weak_ref.upgrade()) {
+                                ("audio-changed", Some(playbin)) => select_preferred_audio_track(&playbin, &shared),
+                                ("text-changed", Some(playbin)) => select_preferred_subtitle_track(&playbin, &shared),
+                                ("stream-recovered", _) => {
+                                    if shared.recovery.lock().unwrap().on_data() {
+                                        info!("Stream recovered.");
+                                        reconnecting_overlay.hide();
+                                    }
+                                },
+                                _ => {},
+                            }
+                        }
                     },
-                    gst::MessageView::Error(error) => {
-                        display_an_error_dialog(
-                            Some(&application.get_windows()[0]),
-                            &format!("There was an error reported on the GStreamer bus.\n\n'{}'\n\nBest bet is to close this channel window and start a new one from the control window.", error.get_error())
-                        );
+                    gst::MessageView::Eos(..) => {
+                        let uri = playbin_weak_ref.upgrade()
+                            .and_then(|playbin| playbin.get_property("uri").ok())
+                            .and_then(|uri| uri.get::<String>().ok().and_then(|uri| uri))
+                            .unwrap_or_default();
+                        // A transport stream file comes to an end, a DVB stream never should,
+                        // so only for a DVB stream is an end of stream a failure.
+                        if ts_file::is_file_uri(&uri) {
+                            info!("End of stream of {}.", uri);
+                            reconnecting_overlay.hide();
+                        } else {
+                            recover("There was an end of stream in the GStreamer system.")
+                        }
                     },
+                    gst::MessageView::Error(error) => recover(&format!("There was an error reported on the GStreamer bus: {}", error.get_error())),
                     _ => (),
                 };
                 glib::Continue(true)
@@ -210,7 +337,8 @@ impl GStreamerEngine {
                     let widget = sink.get_property("widget").expect("Could not get 'widget' property.");
                     (Some(sink), widget.get::<gtk::Widget>().unwrap())
                 },
-                Err(_) => {
+                Err(error) => {
+                    errors::report(MeTvError::ElementCreation { element: "gtksink".to_string(), message: error.to_string() });
                     display_an_error_dialog(
                         Some(&application_clone.get().get_windows()[0]),
                         "Could not create a 'gtksink'\n\nIs the gstreamer1.0-gtk3 package installed?"
@@ -230,7 +358,8 @@ impl GStreamerEngine {
                             let widget = gtkglsink.get_property("widget").expect("Could not get 'widget' property.");
                             (Some(glsinkbin), widget.get::<gtk::Widget>().unwrap())
                         },
-                        Err(_) => {
+                        Err(error) => {
+                            errors::report(MeTvError::ElementCreation { element: "glsinkbin".to_string(), message: error.to_string() });
                             display_an_error_dialog(
                                 Some(&application_clone.get().get_windows()[0]),
                                 "Could not create a 'glsinkbin'\n\nIs the gstreamer1.0-gl package installed?."
@@ -253,6 +382,8 @@ impl GStreamerEngine {
             playbin,
             video_element: video_element.expect("'video_element' is not None, this cannot happen."),
             video_widget: video_widget.expect("'video_widget is not None, this cannot happen."),
+            reconnecting_overlay,
+            shared,
         };
         engine.video_element.set_property("force-aspect-ratio", &true.to_value()).expect("Could not set 'force-aspect-ration' property");
         engine.playbin.set_property("video-sink", &engine.video_element.to_value()).expect("Could not set 'video-sink' property");
@@ -260,29 +391,76 @@ impl GStreamerEngine {
         Ok(engine)
     }
 
-    pub fn set_mrl(&self, mrl: &str) {
+    /// Set the channel to play, a dvb:// MRL is looked up in the channels file.
+    pub fn set_mrl(&self, mrl: &str, channels_file: &Path) {
+        let mrl = ts_file::resolve_mrl(mrl, ts_file::get_ts_file().as_deref());
         self.playbin.set_property("uri", &mrl).expect("Could not set URI on playbin.");
+        self.shared.channels_file.lock().unwrap().replace(channels_file.to_path_buf());
+        // Everything known about the streams of the previous channel is now wrong.
+        self.shared.program_map.lock().unwrap().take();
+        self.shared.teletext_decoder.lock().unwrap().take();
+        self.shared.subtitle_track.lock().unwrap().take();
+        self.shared.radio_text.lock().unwrap().take();
+        *self.shared.radio_text_decoder.lock().unwrap() = RadioTextDecoder::new();
+        // A new pipeline so that nothing of the teletext of the previous channel is shown.
+        self.shared.teletext.lock().unwrap().take();
+        let teletext_page = *self.shared.teletext_page.lock().unwrap();
+        if let Err(message) = self.set_teletext_page(teletext_page) {
+            warn!("Could not restart teletext rendering: {}", message);
+        }
+        self.shared.signal_history.lock().unwrap().clear();
+        self.shared.recovery.lock().unwrap().reset(recovery_policy());
+        // playbin only shows the visualisation for channels with no video, radio channels.
+        set_playbin_flag(&self.playbin, "vis", preferences::get_radio_visualisation());
+    }
+
+    /// The state the playbin is in or is changing to. The state is not waited for, this is
+    /// called on the GTK thread and a change to the playing state can take seconds.
+    fn get_target_state(&self) -> Option<gst::State> {
+        let (rv, state, pending) = self.playbin.get_state(gst::ClockTime::from_mseconds(0));
+        match rv {
+            Ok(_) => Some(if pending == gst::State::VoidPending { state } else { pending }),
+            Err(_) => None,
+        }
+    }
+
+    /// Can the channel be paused? There is no timeshift buffer, a broadcast cannot be
+    /// paused, only a channel played from a transport stream file can.
+    pub fn can_pause(&self) -> bool {
+        self.playbin.get_property("uri").ok()
+            .and_then(|uri| uri.get::<String>().ok().and_then(|uri| uri))
+            .map_or(false, |uri| ts_file::is_file_uri(&uri))
     }
 
     pub fn pause(&self) {
-        let (rv, state, _pending) = self.playbin.get_state(gst::CLOCK_TIME_NONE);
-        assert_eq!(rv.unwrap(), gst::StateChangeSuccess::Success);
-        if state == gst::State::Playing {
-            self.playbin.set_state(gst::State::Paused).unwrap();
+        if self.get_target_state() == Some(gst::State::Playing) {
+            if let Err(error) = self.playbin.set_state(gst::State::Paused) {
+                report_state_change_error(gst::State::Paused, error);
+            }
         }
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.get_target_state() == Some(gst::State::Paused)
+    }
+
     pub fn play(&self) {
-        if let Err(_) = self.playbin.set_state(gst::State::Playing) {
+        if let Err(error) = set_playing(&self.playbin, &self.shared) {
+            report_state_change_error(gst::State::Playing, error);
             display_an_error_dialog(
                 Some(&(self.video_widget.get_toplevel().unwrap().downcast::<gtk::Window>().unwrap())),
-                "Could not set play state, perhaps the aerial isn't connected?\n\nTry running with 'GST_DEBUG=3 me-tv' for details."
+                "Could not set play state, perhaps the aerial isn't connected?\n\nTry running with 'me-tv --log gstreamer_engine=debug' for details."
             );
         }
     }
 
     pub fn stop(&self) {
-        self.playbin.set_state(gst::State::Null).unwrap();
+        // Cancel any restart that is pending.
+        self.shared.recovery.lock().unwrap().reset(recovery_policy());
+        self.reconnecting_overlay.hide();
+        if let Err(error) = self.playbin.set_state(gst::State::Null) {
+            report_state_change_error(gst::State::Null, error);
+        }
     }
 
     pub fn get_volume(&self) -> f64 {
@@ -293,28 +471,567 @@ impl GStreamerEngine {
         self.playbin.set_property("volume", &value).unwrap();
     }
 
+    pub fn get_mute_state(&self) -> bool {
+        self.playbin.get_property("mute").unwrap().get().unwrap().unwrap()
+    }
+
     pub fn set_mute_state(&self, mute: &bool) {
         self.playbin.set_property("mute", mute).unwrap();
     }
 
     pub fn get_subtitles_showing(&self) -> bool {
-        let flags = self.playbin.get_property("flags").unwrap();
-        let flags_class = glib::FlagsClass::new(flags.type_()).unwrap();
-        flags_class.is_set_by_nick(&flags,"text")
+        get_subtitles_showing(&self.playbin)
     }
 
     pub fn set_subtitles_showing(&self, state: bool) {
-        let flags = self.playbin.get_property("flags").unwrap();
-        let flags_class = glib::FlagsClass::new(flags.type_()).unwrap();
-        let flags_builder = flags_class.builder_with_value(flags).unwrap();
-        let flags = if state {
-            flags_builder.set_by_nick("text")
+        set_subtitles_showing(&self.playbin, state);
+    }
+
+    /// Start writing the transport stream of the current channel to a file.
+    pub fn start_recording(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {:?}: {}", path, e))?;
+        match self.shared.recording_file.lock() {
+            Ok(mut recording_file) => {
+                recording_file.replace(file);
+                Ok(())
+            },
+            Err(_) => Err("Could not lock the recording file.".to_string()),
+        }
+    }
+
+    /// Stop writing the transport stream to file, if it is being written.
+    pub fn stop_recording(&self) {
+        if let Ok(mut recording_file) = self.shared.recording_file.lock() {
+            if let Some(mut file) = recording_file.take() {
+                if let Err(error) = file.flush() {
+                    error!("Failed to flush the recording file: {}", error);
+                }
+            }
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        match self.shared.recording_file.lock() {
+            Ok(recording_file) => recording_file.is_some(),
+            Err(_) => false,
+        }
+    }
+
+    /// Return details of all the audio streams of the current channel.
+    pub fn get_audio_tracks(&self) -> Vec<AudioTrack> {
+        get_audio_tracks(&self.playbin, &self.shared)
+    }
+
+    pub fn get_current_audio_track(&self) -> i32 {
+        self.playbin.get_property("current-audio").unwrap().get().unwrap().unwrap()
+    }
+
+    pub fn set_current_audio_track(&self, index: i32) {
+        self.playbin.set_property("current-audio", &index).unwrap();
+    }
+
+    /// Return the DVB subtitle streams and teletext subtitle pages of the current channel.
+    pub fn get_subtitle_tracks(&self) -> Vec<SubtitleTrack> {
+        get_subtitle_tracks(&self.shared)
+    }
+
+    /// Return the subtitle track being shown, `None` if subtitles are off.
+    pub fn get_current_subtitle_track(&self) -> Option<SubtitleTrack> {
+        if self.get_subtitles_showing() {
+            self.shared.subtitle_track.lock().unwrap().clone()
+        } else {
+            None
+        }
+    }
+
+    /// Show a given subtitle track, or turn subtitles off if `None`.
+    pub fn set_subtitle_track(&self, track: Option<&SubtitleTrack>) {
+        set_subtitle_track(&self.playbin, &self.shared, track);
+    }
+
+    /// Does the current channel have teletext, as far as is known?
+    pub fn has_teletext(&self) -> bool {
+        self.shared.program_map.lock().unwrap().as_ref().map_or(false, |p| p.teletext_pid().is_some())
+    }
+
+    /// Render a teletext page of the current channel, and a subpage if a subcode is given, or
+    /// stop rendering teletext if `None`.
+    pub fn set_teletext_page(&self, page: Option<(u16, Option<u16>)>) -> Result<(), String> {
+        *self.shared.teletext_page.lock().unwrap() = page;
+        let mut teletext = self.shared.teletext.lock().unwrap();
+        match page {
+            None => { teletext.take(); },
+            Some((number, subcode)) => match *teletext {
+                Some(ref renderer) => renderer.set_page(number, subcode),
+                None => { teletext.replace(TeletextRenderer::new(number, subcode)?); },
+            },
+        }
+        Ok(())
+    }
+
+    /// Return the latest image of the teletext page being rendered.
+    pub fn get_teletext_image(&self, mix: bool) -> Option<PageImage> {
+        self.shared.teletext.lock().unwrap().as_ref().and_then(|renderer| renderer.get_image(mix))
+    }
+
+    /// Return the fast text links of a teletext page, red, green, yellow, cyan, and index.
+    pub fn get_teletext_links(&self, number: u16) -> [Option<u16>; 5] {
+        self.shared.teletext.lock().unwrap().as_ref().map_or([None; 5], |renderer| renderer.get_links(number))
+    }
+
+    pub fn get_teletext_subcodes(&self, number: u16) -> Vec<u16> {
+        self.shared.teletext.lock().unwrap().as_ref().map_or(vec![], |renderer| renderer.get_subcodes(number))
+    }
+
+    /// Return the latest RDS radio text of the channel, if it has any.
+    pub fn get_radio_text(&self) -> Option<String> {
+        self.shared.radio_text.lock().unwrap().clone()
+    }
+
+    /// Return the recent frontend statistics, oldest first.
+    pub fn get_signal_history(&self) -> Vec<FrontendStats> {
+        self.shared.signal_history.lock().unwrap().samples()
+    }
+
+    pub fn get_latest_signal_stats(&self) -> Option<FrontendStats> {
+        self.shared.signal_history.lock().unwrap().latest()
+    }
+
+    /// Start writing the frontend statistics to a CSV file.
+    pub fn start_signal_log(&self, path: &Path) -> Result<(), String> {
+        self.shared.signal_log.lock().unwrap().start(path)
+    }
+
+    pub fn stop_signal_log(&self) {
+        self.shared.signal_log.lock().unwrap().stop();
+    }
+
+    /// Return the file the frontend statistics are being written to, if any.
+    pub fn get_signal_log_path(&self) -> Option<PathBuf> {
+        self.shared.signal_log.lock().unwrap().get_path()
+    }
+
+}
+
+/// Send the events of an EIT section message to the EPG manager.
+pub fn process_eit_section(element: &gst::message::Element, to_epg_manager: &Sender<epg_manager::EPGEventMessage>) {
+    if let Some(section) = gst_mpegts::Section::from_element(element) {
+        if section.get_section_type() == gst_mpegts::SectionType::Eit {
+            if let Some(eit) = section.get_eit() {
+                for event in eit.event_iterator() {
+                    let event_message = epg_manager::EPGEventMessage::new(
+                        section.get_subtable_extension(),
+                        event.get_event_id(),
+                        event.get_start_time(),
+                        event.get_duration(),
+                        event.get_descriptors(),
+                    );
+                    if let Err(error) = to_epg_manager.send(event_message) {
+                        error!("Could not send an EIT event to the EPG manager: {}", error);
+                        return;
+                    }
+                }
+            } else {
+                //  TODO This seems to happen, and yet it shouldn't.
+                report_section_error("EIT", format!("Could not get an EIT from a supposed EIT Section: {:?}", section));
+            }
+        } else {
+            report_section_error("EIT", format!("EIT Section is not an EIT Section: {:?}", section));
+        }
+    } else {
+        report_section_error("EIT", format!("Could not get a Section from an EIT Section Element: {:?}", element));
+    }
+}
+
+/// Turn a PMT section message into the program map of the channel.
+fn process_pmt_section(element: &gst::message::Element) -> Option<ProgramMap> {
+    let section = match gst_mpegts::Section::from_element(element) {
+        Some(section) => section,
+        None => {
+            report_section_error("PMT", format!("Could not get a Section from a PMT Section Element: {:?}", element));
+            return None;
+        },
+    };
+    if section.get_section_type() != gst_mpegts::SectionType::Pmt {
+        report_section_error("PMT", format!("PMT Section is not a PMT Section: {:?}", section));
+        return None;
+    }
+    match section.get_pmt() {
+        Some(pmt) => Some(ProgramMap {
+            program_number: section.get_subtable_extension(),
+            streams: pmt.stream_iterator().map(|stream| {
+                let mut elementary_stream = ElementaryStream::new(stream.get_stream_type(), stream.get_pid());
+                for descriptor in stream.get_descriptors() {
+                    elementary_stream.add_descriptor(&descriptor.get_data());
+                }
+                elementary_stream
+            }).collect(),
+        }),
+        None => {
+            report_section_error("PMT", format!("Could not get a PMT from a PMT Section: {:?}", section));
+            None
+        },
+    }
+}
+
+/// Record the logical channel numbers of the services of the network of the channel being
+/// watched given by a NIT section message, returning whether any numbers changed.
+pub fn process_nit_section(element: &gst::message::Element, shared: &SharedState) -> bool {
+    if let Some(section) = gst_mpegts::Section::from_element(element) {
+        if section.get_section_type() == gst_mpegts::SectionType::Nit {
+            if let Some(nit) = section.get_nit() {
+                debug!("Got a NIT {:?}", nit);
+                // The NIT of other networks gives numbers that may clash with those of this network.
+                if ! nit.get_actual_network() { return false; }
+                let mut numbers = BTreeMap::new();
+                for stream in nit.stream_iterator() {
+                    for descriptor in stream.get_descriptors() {
+                        numbers.extend(program_map::logical_channel_numbers(&descriptor.get_data()));
+                    }
+                }
+                let channels_file = shared.channels_file.lock().unwrap().clone();
+                if let Some(channels_file) = channels_file {
+                    return ! numbers.is_empty() && channel_numbers::add_broadcast_numbers(&channels_file, &numbers);
+                }
+            } else {
+                report_section_error("NIT", format!("Could not get a NIT from a NIT Section: {:?}", section));
+            }
         } else {
-            flags_builder.unset_by_nick("text")
+            report_section_error("NIT", format!("NIT Section is not an NIT Section: {:?}", section));
+        }
+    } else {
+        report_section_error("NIT", format!("Could not get a Section from a NIT Section Element: {:?}", element));
+    }
+    false
+}
+
+fn report_section_error(table: &str, message: String) {
+    errors::report(MeTvError::SectionParse { table: table.to_string(), message });
+}
+
+fn report_state_change_error(state: gst::State, error: impl ToString) {
+    errors::report(MeTvError::StateChange { state: format!("{:?}", state), message: error.to_string() });
+}
+
+/// Watch the transport stream of the channel: data arriving ends a recovery, the teletext
+/// is rendered, and the stream is written to the recording file.
+fn add_transport_stream_probe(pad: &gst::Pad, shared: &Arc<SharedState>) {
+    let shared = shared.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, probe_info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = probe_info.data {
+            if shared.awaiting_data.swap(false, Ordering::SeqCst) {
+                if let Some(element) = pad.get_parent_element() {
+                    let message = gst::Message::new_application(gst::Structure::new_empty("stream-recovered")).build();
+                    if let Err(error) = element.post_message(&message) {
+                        warn!("Could not post stream-recovered message: {}", error);
+                    }
+                }
+            }
+            if let Some(map) = buffer.map_readable() {
+                if let Ok(teletext) = shared.teletext.lock() {
+                    if let Some(ref renderer) = *teletext {
+                        renderer.push(map.as_slice());
+                    }
+                }
+                if let Ok(mut recording_file) = shared.recording_file.lock() {
+                    if let Some(ref mut file) = *recording_file {
+                        if let Err(error) = file.write_all(map.as_slice()) {
+                            error!("Failed to write to the recording file: {}", error);
+                        }
+                    }
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Look for RDS radio text in the ancillary data of the parsed MPEG audio frames.
+fn add_radio_text_probe(pad: &gst::Pad, shared: &Arc<SharedState>) {
+    let shared = shared.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, probe_info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = probe_info.data {
+            if let Some(map) = buffer.map_readable() {
+                let text = shared.radio_text_decoder.lock().unwrap().push_frame(map.as_slice());
+                if let Some(text) = text {
+                    shared.radio_text.lock().unwrap().replace(text);
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Put the pipeline into the playing state. All the frontends share the file the dvbsrc
+/// plugin finds channels in, so it must be pointed at the channels file each time.
+fn set_playing(playbin: &gst::Element, shared: &SharedState) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+    if let Some(ref channels_file) = *shared.channels_file.lock().unwrap() {
+        if let Err(message) = channel_names::use_channels_file(channels_file) {
+            error!("Could not use the channels file: {}", message);
+        }
+    }
+    playbin.set_state(gst::State::Playing)
+}
+
+fn recovery_policy() -> RecoveryPolicy {
+    RecoveryPolicy { max_failures: preferences::get_max_reconnect_attempts(), ..Default::default() }
+}
+
+/// How a recovery is shown to the user.
+trait RecoveryDisplay {
+    fn show_reconnecting(&self, attempt: u32, max_failures: u32);
+    fn hide_reconnecting(&self);
+    fn give_up(&self, failures: u32, reason: &str);
+}
+
+/// The reconnecting overlay over the video, and an error dialog when recovery gives up.
+#[derive(Clone)]
+struct ReconnectingDisplay {
+    overlay: gtk::Box,
+    label: gtk::Label,
+    parent: gtk::Window,
+}
+
+impl RecoveryDisplay for ReconnectingDisplay {
+    fn show_reconnecting(&self, attempt: u32, max_failures: u32) {
+        self.label.set_text(&format!("Reconnecting… (attempt {} of {})", attempt, max_failures));
+        self.overlay.show();
+    }
+
+    fn hide_reconnecting(&self) {
+        self.overlay.hide();
+    }
+
+    fn give_up(&self, failures: u32, reason: &str) {
+        display_an_error_dialog(
+            Some(&self.parent),
+            &format!("The stream could not be recovered after {} attempts.\n\n'{}'\n\nBest bet is to close this channel window and start a new one from the control window.", failures, reason)
+        );
+    }
+}
+
+/// Deal with a failure of the stream by restarting the pipeline, at the same channel,
+/// after a delay. The user is only told if there have been too many failures.
+///
+/// The restart is run by the main context of the thread, the GTK event loop thread.
+fn recover_from_failure<D: RecoveryDisplay + Clone + 'static>(
+    playbin_weak_ref: &glib::WeakRef<gst::Element>,
+    shared: &Arc<SharedState>,
+    display: &D,
+    reason: &str,
+) {
+    let action = shared.recovery.lock().unwrap().on_failure();
+    match action {
+        Action::Ignore => {},
+        Action::Retry { attempt, delay } => {
+            warn!("Stream failure, restarting in {:?}: {}", delay, reason);
+            let max_failures = shared.recovery.lock().unwrap().get_max_failures();
+            display.show_reconnecting(attempt, max_failures);
+            // Stop straight away to release the frontend.
+            if let Some(playbin) = playbin_weak_ref.upgrade() {
+                let _ = playbin.set_state(gst::State::Null);
+            }
+            // The display is not Send, but the source is only ever run by this thread.
+            let display = Fragile::new(display.clone());
+            let source = glib::timeout_source_new(delay.as_millis() as u32, None, glib::PRIORITY_DEFAULT, {
+                let playbin_weak_ref = playbin_weak_ref.clone();
+                let shared = shared.clone();
+                let reason = reason.to_string();
+                move || {
+                    if shared.recovery.lock().unwrap().on_retry() {
+                        if let Some(playbin) = playbin_weak_ref.upgrade() {
+                            shared.awaiting_data.store(true, Ordering::SeqCst);
+                            if set_playing(&playbin, &shared).is_err() {
+                                recover_from_failure(&playbin_weak_ref, &shared, display.get(), &reason);
+                            }
+                        }
+                    }
+                    glib::Continue(false)
+                }
+            });
+            source.attach(Some(&glib::MainContext::ref_thread_default()));
+        },
+        Action::GiveUp { failures } => {
+            display.hide_reconnecting();
+            if let Some(playbin) = playbin_weak_ref.upgrade() {
+                let _ = playbin.set_state(gst::State::Null);
+            }
+            display.give_up(failures, reason);
+        },
+    }
+}
+
+/// Extract the statistics from a dvb-frontend-stats message of dvbsrc.
+fn frontend_stats_from_structure(structure: &gst::StructureRef) -> Option<FrontendStats> {
+    Some(FrontendStats {
+        time: Local::now(),
+        status: structure.get_some("status").ok()?,
+        signal: structure.get_some("signal").ok()?,
+        snr: structure.get_some("snr").ok()?,
+        ber: structure.get_some("ber").ok()?,
+        ucb: structure.get_some("unc").ok()?,
+        lock: structure.get_some("lock").ok()?,
+    })
+}
+
+fn get_subtitles_showing(playbin: &gst::Element) -> bool {
+    let flags = playbin.get_property("flags").unwrap();
+    let flags_class = glib::FlagsClass::new(flags.type_()).unwrap();
+    flags_class.is_set_by_nick(&flags,"text")
+}
+
+fn set_subtitles_showing(playbin: &gst::Element, state: bool) {
+    set_playbin_flag(playbin, "text", state);
+}
+
+fn set_playbin_flag(playbin: &gst::Element, nick: &str, state: bool) {
+    let flags = playbin.get_property("flags").unwrap();
+    let flags_class = glib::FlagsClass::new(flags.type_()).unwrap();
+    let flags_builder = flags_class.builder_with_value(flags).unwrap();
+    let flags = if state {
+        flags_builder.set_by_nick(nick)
+    } else {
+        flags_builder.unset_by_nick(nick)
+    }
+        .build()
+        .unwrap();
+    playbin.set_property("flags", &flags).unwrap();
+}
+
+/// Use the tags playbin has for each audio stream, and the PMT, to describe the streams.
+fn get_audio_tracks(playbin: &gst::Element, shared: &SharedState) -> Vec<AudioTrack> {
+    let count: i32 = playbin.get_property("n-audio").unwrap().get().unwrap().unwrap();
+    let program_map = shared.program_map.lock().unwrap().clone();
+    let audio_streams = match program_map {
+        Some(ref program_map) => program_map.audio_streams(),
+        None => vec![],
+    };
+    (0..count).map(|index| {
+        let tags = playbin.emit("get-audio-tags", &[&index])
+            .unwrap()
+            .and_then(|value| value.get::<gst::TagList>().unwrap());
+        let (language, codec) = match tags {
+            Some(tags) => (
+                tags.get::<gst::tags::LanguageCode>().and_then(|v| v.get().map(|s| s.to_string())),
+                tags.get::<gst::tags::AudioCodec>().and_then(|v| v.get().map(|s| s.to_string())),
+            ),
+            None => (None, None),
+        };
+        // Only trust the PMT if it agrees with playbin about the number of audio streams.
+        let stream = if audio_streams.len() == count as usize { audio_streams.get(index as usize) } else { None };
+        let pmt_language = stream.and_then(|s| s.language());
+        AudioTrack {
+            index,
+            language: language.or_else(|| pmt_language.map(|l| l.code.clone())),
+            codec: codec.or_else(|| stream.and_then(|s| s.codec.map(|c| c.to_string()))),
+            audio_description: pmt_language.map(|l| l.audio_type == 0x03).unwrap_or(false),
         }
-            .build()
-            .unwrap();
-        self.playbin.set_property("flags", &flags).unwrap();
+    }).collect()
+}
+
+/// Switch to the audio stream best matching the user's language preferences, if there is one.
+fn select_preferred_audio_track(playbin: &gst::Element, shared: &SharedState) {
+    if let Some(index) = preferred_audio_track(&get_audio_tracks(playbin, shared), &preferences::get_audio_languages()) {
+        playbin.set_property("current-audio", &index).unwrap();
     }
+}
 
+fn get_subtitle_tracks(shared: &SharedState) -> Vec<SubtitleTrack> {
+    match *shared.program_map.lock().unwrap() {
+        Some(ref program_map) => subtitle_tracks(program_map, is_teletext_decoding_available()),
+        None => vec![],
+    }
+}
+
+fn set_subtitle_track(playbin: &gst::Element, shared: &SharedState, track: Option<&SubtitleTrack>) {
+    match track {
+        Some(track) => {
+            playbin.set_property("current-text", &track.text_index).unwrap();
+            if let SubtitleSource::Teletext{page} = track.source {
+                if let Some(teletext_decoder) = shared.teletext_decoder.lock().unwrap().as_ref().and_then(|t_d| t_d.upgrade()) {
+                    teletext_decoder.set_property("page", &(page as i32)).unwrap();
+                }
+            }
+            shared.subtitle_track.lock().unwrap().replace(track.clone());
+            set_subtitles_showing(playbin, true);
+        },
+        None => {
+            shared.subtitle_track.lock().unwrap().take();
+            set_subtitles_showing(playbin, false);
+        },
+    }
+}
+
+/// If the user wants subtitles always on, show the subtitle track best matching their
+/// preferred language.
+fn select_preferred_subtitle_track(playbin: &gst::Element, shared: &SharedState) {
+    if preferences::get_subtitles_always_on() {
+        let tracks = get_subtitle_tracks(shared);
+        if let Some(index) = preferred_subtitle_track(&tracks, &preferences::get_subtitle_language()) {
+            set_subtitle_track(playbin, shared, Some(&tracks[index]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Shown {
+        Reconnecting { attempt: u32, max_failures: u32 },
+        Hidden,
+        GaveUp { failures: u32 },
+    }
+
+    /// Record what would have been shown to the user.
+    #[derive(Clone, Default)]
+    struct RecordingDisplay {
+        shown: Rc<RefCell<Vec<Shown>>>,
+    }
+
+    impl RecoveryDisplay for RecordingDisplay {
+        fn show_reconnecting(&self, attempt: u32, max_failures: u32) {
+            self.shown.borrow_mut().push(Shown::Reconnecting { attempt, max_failures });
+        }
+
+        fn hide_reconnecting(&self) {
+            self.shown.borrow_mut().push(Shown::Hidden);
+        }
+
+        fn give_up(&self, failures: u32, _reason: &str) {
+            self.shown.borrow_mut().push(Shown::GaveUp { failures });
+        }
+    }
+
+    #[test]
+    fn a_source_that_always_fails_is_restarted_until_recovery_gives_up() {
+        gst::init().unwrap();
+        // filesrc fails to start when there is no file, as dvbsrc does when there is no signal.
+        let pipeline = gst::parse_launch("filesrc location=/nonexistent/me-tv.ts ! fakesink").unwrap();
+        let shared = Arc::new(SharedState::default());
+        shared.recovery.lock().unwrap().reset(RecoveryPolicy { max_failures: 2, initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) });
+        let display = RecordingDisplay::default();
+        let context = glib::MainContext::new();
+        context.push_thread_default();
+        recover_from_failure(&pipeline.downgrade(), &shared, &display, "The test source failed.");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while ! display.shown.borrow().iter().any(|shown| if let Shown::GaveUp { .. } = shown { true } else { false }) && Instant::now() < deadline {
+            if ! context.iteration(false) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        context.pop_thread_default();
+        assert_eq!(*display.shown.borrow(), vec![
+            Shown::Reconnecting { attempt: 1, max_failures: 2 },
+            Shown::Reconnecting { attempt: 2, max_failures: 2 },
+            Shown::Hidden,
+            Shown::GaveUp { failures: 2 },
+        ]);
+        assert_eq!(pipeline.get_state(gst::ClockTime::from_mseconds(0)).1, gst::State::Null);
+    }
 }
@@ -0,0 +1,665 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The mapping of remote control keys to the actions Me TV performs, read from a file
+//! that can be edited by hand or in the preferences dialog.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+
+use crate::input_event_codes::*;
+use crate::preferences;
+
+/// The things a remote control key can do.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Action {
+    ChannelUp,
+    ChannelDown,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Fullscreen,
+    Epg,
+    Record,
+    Subtitles,
+    AudioTrack,
+    /// Pause or resume, the timeshift action: only a transport stream file can be paused
+    /// so far, a broadcast cannot be.
+    Pause,
+    Info,
+    /// Change to the channel with a number.
+    Channel(u16),
+    /// A digit of a channel number.
+    Digit(u8),
+}
+
+/// The actions that have no parameter, in the order they are presented.
+const SIMPLE_ACTIONS: [(Action, &str); 12] = [
+    (Action::ChannelUp, "channel-up"),
+    (Action::ChannelDown, "channel-down"),
+    (Action::VolumeUp, "volume-up"),
+    (Action::VolumeDown, "volume-down"),
+    (Action::Mute, "mute"),
+    (Action::Fullscreen, "fullscreen"),
+    (Action::Epg, "epg"),
+    (Action::Record, "record"),
+    (Action::Subtitles, "subtitles"),
+    (Action::AudioTrack, "audio-track"),
+    (Action::Pause, "pause"),
+    (Action::Info, "info"),
+];
+
+impl Action {
+    /// The names of the actions for choosing from, `channel-N` stands for all the
+    /// channel actions.
+    pub fn names() -> Vec<String> {  // Used in preferences_dialog.rs
+        let mut names = SIMPLE_ACTIONS.iter().map(|(_, name)| name.to_string()).collect::<Vec<String>>();
+        names.extend((0..10).map(|digit| Action::Digit(digit).to_string()));
+        names.push("channel-N".to_string());
+        names
+    }
+
+    /// What the action does, for presenting to the user.
+    pub fn description(&self) -> String { // Used in shortcuts_window.rs
+        match self {
+            Action::ChannelUp => "Next channel".to_string(),
+            Action::ChannelDown => "Previous channel".to_string(),
+            Action::VolumeUp => "Volume up".to_string(),
+            Action::VolumeDown => "Volume down".to_string(),
+            Action::Mute => "Mute or unmute".to_string(),
+            Action::Fullscreen => "Enter or leave fullscreen".to_string(),
+            Action::Epg => "Electronic programme guide".to_string(),
+            Action::Record => "Start or stop recording".to_string(),
+            Action::Subtitles => "Next subtitle track".to_string(),
+            Action::AudioTrack => "Next audio track".to_string(),
+            Action::Pause => "Pause or resume".to_string(),
+            Action::Info => "Channel information".to_string(),
+            Action::Channel(number) => format!("Channel {}", number),
+            Action::Digit(_) => "Enter a channel number".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Channel(number) => write!(f, "channel-{}", number),
+            Action::Digit(digit) => write!(f, "digit-{}", digit),
+            action => {
+                let (_, name) = SIMPLE_ACTIONS.iter().find(|(a, _)| a == action).unwrap();
+                write!(f, "{}", name)
+            },
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((action, _)) = SIMPLE_ACTIONS.iter().find(|(_, name)| *name == s) {
+            return Ok(*action);
+        }
+        // Pause stands in for timeshift, so a keymap can use either name.
+        if s == "timeshift" {
+            return Ok(Action::Pause);
+        }
+        if let Some(number) = s.strip_prefix("channel-") {
+            if let Ok(number) = number.parse::<u16>() {
+                return Ok(Action::Channel(number));
+            }
+        }
+        if let Some(digit) = s.strip_prefix("digit-") {
+            if let Ok(digit) = digit.parse::<u8>() {
+                if digit < 10 {
+                    return Ok(Action::Digit(digit));
+                }
+            }
+        }
+        Err(format!("'{}' is not an action.", s))
+    }
+}
+
+/// Associate the names of the key codes with the codes. Where a code has more than one name
+/// the first name is used when writing.
+macro_rules! key_names {
+    ($($name:ident,)*) => { &[$((stringify!($name), $name),)*] }
+}
+
+static KEY_NAMES: &[(&str, u32)] = key_names!(
+    KEY_RESERVED, KEY_ESC, KEY_1, KEY_2, KEY_3, KEY_4, KEY_5, KEY_6, KEY_7, KEY_8, KEY_9, KEY_0,
+    KEY_MINUS, KEY_EQUAL, KEY_BACKSPACE, KEY_TAB, KEY_Q, KEY_W, KEY_E, KEY_R, KEY_T, KEY_Y, KEY_U,
+    KEY_I, KEY_O, KEY_P, KEY_LEFTBRACE, KEY_RIGHTBRACE, KEY_ENTER, KEY_LEFTCTRL, KEY_A, KEY_S,
+    KEY_D, KEY_F, KEY_G, KEY_H, KEY_J, KEY_K, KEY_L, KEY_SEMICOLON, KEY_APOSTROPHE, KEY_GRAVE,
+    KEY_LEFTSHIFT, KEY_BACKSLASH, KEY_Z, KEY_X, KEY_C, KEY_V, KEY_B, KEY_N, KEY_M, KEY_COMMA,
+    KEY_DOT, KEY_SLASH, KEY_RIGHTSHIFT, KEY_KPASTERISK, KEY_LEFTALT, KEY_SPACE, KEY_CAPSLOCK,
+    KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6, KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_NUMLOCK,
+    KEY_SCROLLLOCK, KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPMINUS, KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS,
+    KEY_KP1, KEY_KP2, KEY_KP3, KEY_KP0, KEY_KPDOT, KEY_ZENKAKUHANKAKU, KEY_102ND, KEY_F11, KEY_F12,
+    KEY_RO, KEY_KATAKANA, KEY_HIRAGANA, KEY_HENKAN, KEY_KATAKANAHIRAGANA, KEY_MUHENKAN,
+    KEY_KPJPCOMMA, KEY_KPENTER, KEY_RIGHTCTRL, KEY_KPSLASH, KEY_SYSRQ, KEY_RIGHTALT, KEY_LINEFEED,
+    KEY_HOME, KEY_UP, KEY_PAGEUP, KEY_LEFT, KEY_RIGHT, KEY_END, KEY_DOWN, KEY_PAGEDOWN, KEY_INSERT,
+    KEY_DELETE, KEY_MACRO, KEY_MUTE, KEY_VOLUMEDOWN, KEY_VOLUMEUP, KEY_POWER, KEY_KPEQUAL,
+    KEY_KPPLUSMINUS, KEY_PAUSE, KEY_SCALE, KEY_KPCOMMA, KEY_HANGEUL, KEY_HANGUEL, KEY_HANJA,
+    KEY_YEN, KEY_LEFTMETA, KEY_RIGHTMETA, KEY_COMPOSE, KEY_STOP, KEY_AGAIN, KEY_PROPS, KEY_UNDO,
+    KEY_FRONT, KEY_COPY, KEY_OPEN, KEY_PASTE, KEY_FIND, KEY_CUT, KEY_HELP, KEY_MENU, KEY_CALC,
+    KEY_SETUP, KEY_SLEEP, KEY_WAKEUP, KEY_FILE, KEY_SENDFILE, KEY_DELETEFILE, KEY_XFER, KEY_PROG1,
+    KEY_PROG2, KEY_WWW, KEY_MSDOS, KEY_COFFEE, KEY_SCREENLOCK, KEY_ROTATE_DISPLAY, KEY_DIRECTION,
+    KEY_CYCLEWINDOWS, KEY_MAIL, KEY_BOOKMARKS, KEY_COMPUTER, KEY_BACK, KEY_FORWARD, KEY_CLOSECD,
+    KEY_EJECTCD, KEY_EJECTCLOSECD, KEY_NEXTSONG, KEY_PLAYPAUSE, KEY_PREVIOUSSONG, KEY_STOPCD,
+    KEY_RECORD, KEY_REWIND, KEY_PHONE, KEY_ISO, KEY_CONFIG, KEY_HOMEPAGE, KEY_REFRESH, KEY_EXIT,
+    KEY_MOVE, KEY_EDIT, KEY_SCROLLUP, KEY_SCROLLDOWN, KEY_KPLEFTPAREN, KEY_KPRIGHTPAREN, KEY_NEW,
+    KEY_REDO, KEY_F13, KEY_F14, KEY_F15, KEY_F16, KEY_F17, KEY_F18, KEY_F19, KEY_F20, KEY_F21,
+    KEY_F22, KEY_F23, KEY_F24, KEY_PLAYCD, KEY_PAUSECD, KEY_PROG3, KEY_PROG4, KEY_DASHBOARD,
+    KEY_SUSPEND, KEY_CLOSE, KEY_PLAY, KEY_FASTFORWARD, KEY_BASSBOOST, KEY_PRINT, KEY_HP, KEY_CAMERA,
+    KEY_SOUND, KEY_QUESTION, KEY_EMAIL, KEY_CHAT, KEY_SEARCH, KEY_CONNECT, KEY_FINANCE, KEY_SPORT,
+    KEY_SHOP, KEY_ALTERASE, KEY_CANCEL, KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP, KEY_MEDIA,
+    KEY_SWITCHVIDEOMODE, KEY_KBDILLUMTOGGLE, KEY_KBDILLUMDOWN, KEY_KBDILLUMUP, KEY_SEND, KEY_REPLY,
+    KEY_FORWARDMAIL, KEY_SAVE, KEY_DOCUMENTS, KEY_BATTERY, KEY_BLUETOOTH, KEY_WLAN, KEY_UWB,
+    KEY_UNKNOWN, KEY_VIDEO_NEXT, KEY_VIDEO_PREV, KEY_BRIGHTNESS_CYCLE, KEY_BRIGHTNESS_AUTO,
+    KEY_BRIGHTNESS_ZERO, KEY_DISPLAY_OFF, KEY_WWAN, KEY_WIMAX, KEY_RFKILL, KEY_MICMUTE, KEY_OK,
+    KEY_SELECT, KEY_GOTO, KEY_CLEAR, KEY_POWER2, KEY_OPTION, KEY_INFO, KEY_TIME, KEY_VENDOR,
+    KEY_ARCHIVE, KEY_PROGRAM, KEY_CHANNEL, KEY_FAVORITES, KEY_EPG, KEY_PVR, KEY_MHP, KEY_LANGUAGE,
+    KEY_TITLE, KEY_SUBTITLE, KEY_ANGLE, KEY_ZOOM, KEY_MODE, KEY_KEYBOARD, KEY_SCREEN, KEY_PC,
+    KEY_TV, KEY_TV2, KEY_VCR, KEY_VCR2, KEY_SAT, KEY_SAT2, KEY_CD, KEY_TAPE, KEY_RADIO, KEY_TUNER,
+    KEY_PLAYER, KEY_TEXT, KEY_DVD, KEY_AUX, KEY_MP3, KEY_AUDIO, KEY_VIDEO, KEY_DIRECTORY, KEY_LIST,
+    KEY_MEMO, KEY_CALENDAR, KEY_RED, KEY_GREEN, KEY_YELLOW, KEY_BLUE, KEY_CHANNELUP,
+    KEY_CHANNELDOWN, KEY_FIRST, KEY_LAST, KEY_AB, KEY_NEXT, KEY_RESTART, KEY_SLOW, KEY_SHUFFLE,
+    KEY_BREAK, KEY_PREVIOUS, KEY_DIGITS, KEY_TEEN, KEY_TWEN, KEY_VIDEOPHONE, KEY_GAMES, KEY_ZOOMIN,
+    KEY_ZOOMOUT, KEY_ZOOMRESET, KEY_WORDPROCESSOR, KEY_EDITOR, KEY_SPREADSHEET, KEY_GRAPHICSEDITOR,
+    KEY_PRESENTATION, KEY_DATABASE, KEY_NEWS, KEY_VOICEMAIL, KEY_ADDRESSBOOK, KEY_MESSENGER,
+    KEY_DISPLAYTOGGLE, KEY_BRIGHTNESS_TOGGLE, KEY_SPELLCHECK, KEY_LOGOFF, KEY_DOLLAR, KEY_EURO,
+    KEY_FRAMEBACK, KEY_FRAMEFORWARD, KEY_CONTEXT_MENU, KEY_MEDIA_REPEAT, KEY_10CHANNELSUP,
+    KEY_10CHANNELSDOWN, KEY_IMAGES, KEY_DEL_EOL, KEY_DEL_EOS, KEY_INS_LINE, KEY_DEL_LINE, KEY_FN,
+    KEY_FN_ESC, KEY_FN_F1, KEY_FN_F2, KEY_FN_F3, KEY_FN_F4, KEY_FN_F5, KEY_FN_F6, KEY_FN_F7,
+    KEY_FN_F8, KEY_FN_F9, KEY_FN_F10, KEY_FN_F11, KEY_FN_F12, KEY_FN_1, KEY_FN_2, KEY_FN_D,
+    KEY_FN_E, KEY_FN_F, KEY_FN_S, KEY_FN_B, KEY_BRL_DOT1, KEY_BRL_DOT2, KEY_BRL_DOT3, KEY_BRL_DOT4,
+    KEY_BRL_DOT5, KEY_BRL_DOT6, KEY_BRL_DOT7, KEY_BRL_DOT8, KEY_BRL_DOT9, KEY_BRL_DOT10,
+    KEY_NUMERIC_0, KEY_NUMERIC_1, KEY_NUMERIC_2, KEY_NUMERIC_3, KEY_NUMERIC_4, KEY_NUMERIC_5,
+    KEY_NUMERIC_6, KEY_NUMERIC_7, KEY_NUMERIC_8, KEY_NUMERIC_9, KEY_NUMERIC_STAR, KEY_NUMERIC_POUND,
+    KEY_NUMERIC_A, KEY_NUMERIC_B, KEY_NUMERIC_C, KEY_NUMERIC_D, KEY_CAMERA_FOCUS, KEY_WPS_BUTTON,
+    KEY_TOUCHPAD_TOGGLE, KEY_TOUCHPAD_ON, KEY_TOUCHPAD_OFF, KEY_CAMERA_ZOOMIN, KEY_CAMERA_ZOOMOUT,
+    KEY_CAMERA_UP, KEY_CAMERA_DOWN, KEY_CAMERA_LEFT, KEY_CAMERA_RIGHT, KEY_ATTENDANT_ON,
+    KEY_ATTENDANT_OFF, KEY_ATTENDANT_TOGGLE, KEY_LIGHTS_TOGGLE, KEY_ALS_TOGGLE,
+    KEY_ROTATE_LOCK_TOGGLE, KEY_BUTTONCONFIG, KEY_TASKMANAGER, KEY_JOURNAL, KEY_CONTROLPANEL,
+    KEY_APPSELECT, KEY_SCREENSAVER, KEY_VOICECOMMAND, KEY_ASSISTANT, KEY_BRIGHTNESS_MIN,
+    KEY_BRIGHTNESS_MAX, KEY_KBDINPUTASSIST_PREV, KEY_KBDINPUTASSIST_NEXT,
+    KEY_KBDINPUTASSIST_PREVGROUP, KEY_KBDINPUTASSIST_NEXTGROUP, KEY_KBDINPUTASSIST_ACCEPT,
+    KEY_KBDINPUTASSIST_CANCEL, KEY_RIGHT_UP, KEY_RIGHT_DOWN, KEY_LEFT_UP, KEY_LEFT_DOWN,
+    KEY_ROOT_MENU, KEY_MEDIA_TOP_MENU, KEY_NUMERIC_11, KEY_NUMERIC_12, KEY_AUDIO_DESC, KEY_3D_MODE,
+    KEY_NEXT_FAVORITE, KEY_STOP_RECORD, KEY_PAUSE_RECORD, KEY_VOD, KEY_UNMUTE, KEY_FASTREVERSE,
+    KEY_SLOWREVERSE, KEY_DATA, KEY_ONSCREEN_KEYBOARD,
+);
+
+/// The name of a key code, the number if the code has no name.
+pub fn key_name(code: u32) -> String {
+    match KEY_NAMES.iter().find(|(_, c)| *c == code) {
+        Some((name, _)) => name.to_string(),
+        None => code.to_string(),
+    }
+}
+
+/// The key code of a name, a number is taken to be the code.
+pub fn key_code(name: &str) -> Option<u32> {
+    let name = name.trim();
+    match KEY_NAMES.iter().find(|(n, _)| *n == name) {
+        Some((_, code)) => Some(*code),
+        None => name.parse::<u32>().ok(),
+    }
+}
+
+/// The keyboard shortcuts of a frontend window, the keys named as GDK names them, lower
+/// case. The digits enter channel numbers just as on a remote control.
+pub const KEYBOARD_SHORTCUTS: [(&str, Action); 36] = [
+    ("f", Action::Fullscreen),
+    ("m", Action::Mute),
+    ("Up", Action::ChannelUp),
+    ("Page_Up", Action::ChannelUp),
+    ("Down", Action::ChannelDown),
+    ("Page_Down", Action::ChannelDown),
+    ("plus", Action::VolumeUp),
+    ("equal", Action::VolumeUp),
+    ("KP_Add", Action::VolumeUp),
+    ("minus", Action::VolumeDown),
+    ("KP_Subtract", Action::VolumeDown),
+    ("s", Action::Subtitles),
+    ("a", Action::AudioTrack),
+    ("i", Action::Info),
+    ("e", Action::Epg),
+    ("r", Action::Record),
+    ("0", Action::Digit(0)),
+    ("1", Action::Digit(1)),
+    ("2", Action::Digit(2)),
+    ("3", Action::Digit(3)),
+    ("4", Action::Digit(4)),
+    ("5", Action::Digit(5)),
+    ("6", Action::Digit(6)),
+    ("7", Action::Digit(7)),
+    ("8", Action::Digit(8)),
+    ("9", Action::Digit(9)),
+    ("KP_0", Action::Digit(0)),
+    ("KP_1", Action::Digit(1)),
+    ("KP_2", Action::Digit(2)),
+    ("KP_3", Action::Digit(3)),
+    ("KP_4", Action::Digit(4)),
+    ("KP_5", Action::Digit(5)),
+    ("KP_6", Action::Digit(6)),
+    ("KP_7", Action::Digit(7)),
+    ("KP_8", Action::Digit(8)),
+    ("KP_9", Action::Digit(9)),
+];
+
+/// The keyboard shortcut for pausing, only a shortcut when the channel can be paused.
+pub const PAUSE_SHORTCUT: (&str, Action) = ("space", Action::Pause);
+
+/// The action of a key of the keyboard, if it has one.
+pub fn get_keyboard_action(key_name: &str, can_pause: bool) -> Option<Action> {
+    if can_pause && key_name == PAUSE_SHORTCUT.0 {
+        return Some(PAUSE_SHORTCUT.1);
+    }
+    KEYBOARD_SHORTCUTS.iter().find(|(name, _)| *name == key_name).map(|(_, action)| *action)
+}
+
+/// The prefix of a key in the keymap file for the action of holding the key down.
+const LONG_PRESS_PREFIX: &str = "long ";
+
+/// The prefix of a LIRC button name in the keymap file.
+const LIRC_BUTTON_PREFIX: &str = "lirc ";
+
+/// The codes given to the LIRC buttons of the keymap, above those of all the keys.
+const LIRC_BUTTON_CODE_BASE: u32 = 0x1_0000;
+
+/// A mapping from key codes to actions, a key has at most one action but an action may
+/// have many keys. A key may also have an action for when it is held down, a long press.
+/// LIRC buttons that are not named for keys may also have actions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Keymap {
+    bindings: BTreeMap<u32, Action>,
+    long_press_bindings: BTreeMap<u32, Action>,
+    lirc_bindings: BTreeMap<String, Action>,
+}
+
+impl Keymap {
+    /// A keymap with no bindings.
+    pub fn new() -> Keymap {
+        Keymap { bindings: BTreeMap::new(), long_press_bindings: BTreeMap::new(), lirc_bindings: BTreeMap::new() }
+    }
+
+    pub fn get_action(&self, code: u32) -> Option<Action> {
+        match code.checked_sub(LIRC_BUTTON_CODE_BASE) {
+            Some(index) => self.lirc_bindings.values().nth(index as usize).cloned(),
+            None => self.bindings.get(&code).cloned(),
+        }
+    }
+
+    pub fn bind_lirc_button(&mut self, button: &str, action: Action) {
+        self.lirc_bindings.insert(button.to_string(), action);
+    }
+
+    /// The code of a LIRC button that has an action, for passing on as a keystroke.
+    pub fn get_lirc_button_code(&self, button: &str) -> Option<u32> { // Used in lirc.rs
+        self.lirc_bindings.keys().position(|b| b == button).map(|index| LIRC_BUTTON_CODE_BASE + index as u32)
+    }
+
+    /// The LIRC button bindings in button name order.
+    pub fn get_lirc_bindings(&self) -> Vec<(String, Action)> {
+        self.lirc_bindings.iter().map(|(button, action)| (button.clone(), *action)).collect()
+    }
+
+    pub fn bind(&mut self, code: u32, action: Action) {
+        self.bindings.insert(code, action);
+    }
+
+    pub fn unbind(&mut self, code: u32) {
+        self.bindings.remove(&code);
+    }
+
+    pub fn get_long_press_action(&self, code: u32) -> Option<Action> {
+        self.long_press_bindings.get(&code).cloned()
+    }
+
+    pub fn bind_long_press(&mut self, code: u32, action: Action) {
+        self.long_press_bindings.insert(code, action);
+    }
+
+    pub fn unbind_long_press(&mut self, code: u32) {
+        self.long_press_bindings.remove(&code);
+    }
+
+    /// Add the bindings of another keymap for the digit and channel actions that have no
+    /// key here, if their keys are free, returning whether any were added. Keymap files
+    /// written before there were channel numbers have none.
+    pub fn add_missing_number_bindings(&mut self, other: &Keymap) -> bool {
+        let mut added = false;
+        for (code, action) in other.get_bindings() {
+            let is_number = match action {
+                Action::Digit(_) | Action::Channel(_) => true,
+                _ => false,
+            };
+            if is_number && ! self.bindings.contains_key(&code) && ! self.bindings.values().any(|a| *a == action) {
+                self.bindings.insert(code, action);
+                added = true;
+            }
+        }
+        added
+    }
+
+    /// The bindings in action order, so that the keys for an action are together.
+    pub fn get_bindings(&self) -> Vec<(u32, Action)> {
+        sorted_by_action(&self.bindings)
+    }
+
+    /// The long press bindings in action order.
+    pub fn get_long_press_bindings(&self) -> Vec<(u32, Action)> {
+        sorted_by_action(&self.long_press_bindings)
+    }
+
+    /// Read a keymap from its file format: lines of `key = action`, `long key = action`
+    /// for holding the key down, or `lirc button = action` for a LIRC button, blank lines
+    /// and lines starting with # are ignored.
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let (key, action) = match (parts.next(), parts.next()) {
+                (Some(key), Some(action)) => (key, action),
+                _ => return Err(format!("Line {}: expected key = action.", number + 1)),
+            };
+            if let Some(button) = key.trim().strip_prefix(LIRC_BUTTON_PREFIX) {
+                let action = action.parse::<Action>().map_err(|e| format!("Line {}: {}", number + 1, e))?;
+                keymap.bind_lirc_button(button.trim(), action);
+                continue;
+            }
+            let (key, long_press) = match key.trim().strip_prefix(LONG_PRESS_PREFIX) {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            let code = key_code(key).ok_or_else(|| format!("Line {}: '{}' is not a key.", number + 1, key.trim()))?;
+            let action = action.parse::<Action>().map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            if long_press {
+                keymap.bind_long_press(code, action);
+            } else {
+                keymap.bind(code, action);
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Write the keymap in its file format.
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# The Me TV remote control keymap, one key = action per line, or\n");
+        text.push_str("# long key = action for the action of holding the key down, or\n");
+        text.push_str("# lirc button = action for a LIRC button that is not named for a key.\n");
+        text.push_str(&format!("# The actions are: {}.\n", Action::names().join(", ")));
+        for (code, action) in self.get_bindings() {
+            text.push_str(&format!("{} = {}\n", key_name(code), action));
+        }
+        for (code, action) in self.get_long_press_bindings() {
+            text.push_str(&format!("{}{} = {}\n", LONG_PRESS_PREFIX, key_name(code), action));
+        }
+        for (button, action) in self.get_lirc_bindings() {
+            text.push_str(&format!("{}{} = {}\n", LIRC_BUTTON_PREFIX, button, action));
+        }
+        text
+    }
+}
+
+impl Default for Keymap {
+    /// The keymap shipped with Me TV, using the keys found on most DVB remote controls.
+    fn default() -> Self {
+        let mut keymap = Keymap::new();
+        for (code, action) in &[
+            (KEY_CHANNELUP, Action::ChannelUp),
+            (KEY_CHANNELDOWN, Action::ChannelDown),
+            (KEY_VOLUMEUP, Action::VolumeUp),
+            (KEY_VOLUMEDOWN, Action::VolumeDown),
+            (KEY_MUTE, Action::Mute),
+            (KEY_ZOOM, Action::Fullscreen),
+            (KEY_SCREEN, Action::Fullscreen),
+            (KEY_EPG, Action::Epg),
+            (KEY_PROGRAM, Action::Epg),
+            (KEY_RECORD, Action::Record),
+            (KEY_SUBTITLE, Action::Subtitles),
+            (KEY_AUDIO, Action::AudioTrack),
+            (KEY_LANGUAGE, Action::AudioTrack),
+            (KEY_PAUSE, Action::Pause),
+            (KEY_PLAYPAUSE, Action::Pause),
+            (KEY_INFO, Action::Info),
+        ] {
+            keymap.bind(*code, *action);
+        }
+        keymap.bind(KEY_0, Action::Digit(0));
+        for digit in 1..10 {
+            keymap.bind(KEY_1 + digit as u32 - 1, Action::Digit(digit));
+        }
+        for digit in 0..10 {
+            keymap.bind(KEY_NUMERIC_0 + digit as u32, Action::Digit(digit));
+        }
+        keymap.bind_long_press(KEY_OK, Action::Epg);
+        keymap
+    }
+}
+
+fn sorted_by_action(bindings: &BTreeMap<u32, Action>) -> Vec<(u32, Action)> {
+    let mut bindings = bindings.iter().map(|(code, action)| (*code, *action)).collect::<Vec<(u32, Action)>>();
+    bindings.sort_by_key(|(code, action)| (*action, *code));
+    bindings
+}
+
+lazy_static! {
+    static ref KEYMAP: Mutex<Keymap> = Mutex::new(Keymap::default());
+}
+
+pub fn get_keymap_file_path() -> PathBuf {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("me-tv").expect("Cannot set XDG prefix.");
+    let mut path_buf = xdg_dirs.get_config_home();
+    path_buf.push("keymap");
+    path_buf
+}
+
+fn load_keymap(path: &Path) -> Result<Keymap, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+    Keymap::parse(&text).map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// Write the keymap to a file, replacing the file in one step.
+fn write_keymap_to(path: &Path, keymap: &Keymap) -> Result<(), String> {
+    preferences::write_file_atomically(path, &keymap.to_text())
+}
+
+/// Read the keymap file, writing the default keymap to it if there is no file so that
+/// there is a file to edit. The default digit bindings are added to a file that has none.
+/// Must be called after preferences::init, which creates the directory, and after
+/// logging::init.
+pub fn init() {
+    let path = get_keymap_file_path();
+    if path.is_file() {
+        match load_keymap(&path) {
+            Ok(mut keymap) => {
+                if keymap.add_missing_number_bindings(&Keymap::default()) {
+                    info!("Adding the default channel number keys to {:?}.", path);
+                    if let Err(message) = write_keymap_to(&path, &keymap) {
+                        warn!("Cannot write {:?}: {}", path, message);
+                    }
+                }
+                if let Ok(mut current) = KEYMAP.lock() {
+                    *current = keymap;
+                }
+            },
+            Err(message) => warn!("{}, using the default keymap.", message),
+        }
+    } else if let Err(message) = write_keymap_to(&path, &Keymap::default()) {
+        error!("Cannot write {:?}: {}", path, message);
+    }
+}
+
+pub fn get_keymap() -> Keymap {
+    match KEYMAP.lock() {
+        Ok(keymap) => keymap.clone(),
+        Err(_) => Keymap::default(),
+    }
+}
+
+pub fn set_keymap(keymap: Keymap, write_back: bool) {
+    if let Ok(mut current) = KEYMAP.lock() {
+        *current = keymap.clone();
+    }
+    if write_back {
+        let path = get_keymap_file_path();
+        if let Err(message) = write_keymap_to(&path, &keymap) {
+            warn!("Cannot write {:?}: {}", path, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn action_names_round_trip() {
+        for action in SIMPLE_ACTIONS.iter().map(|(action, _)| *action).chain(vec![Action::Channel(1), Action::Channel(101), Action::Digit(0)]) {
+            assert_eq!(action.to_string().parse::<Action>(), Ok(action));
+        }
+        assert_eq!(" audio-track ".parse::<Action>(), Ok(Action::AudioTrack));
+        assert_eq!("timeshift".parse::<Action>(), Ok(Action::Pause));
+        assert!("channel-N".parse::<Action>().is_err());
+        assert!("digit-10".parse::<Action>().is_err());
+        assert!("teleport".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn key_names_and_codes() {
+        assert_eq!(key_name(KEY_MUTE), "KEY_MUTE");
+        assert_eq!(key_code("KEY_MUTE"), Some(KEY_MUTE));
+        assert_eq!(key_code("KEY_NUMERIC_0"), Some(KEY_NUMERIC_0));
+        // KEY_HANGUEL is another name for KEY_HANGEUL.
+        assert_eq!(key_code("KEY_HANGUEL"), Some(KEY_HANGEUL));
+        assert_eq!(key_name(KEY_HANGEUL), "KEY_HANGEUL");
+        assert_eq!(key_name(700), "700");
+        assert_eq!(key_code("700"), Some(700));
+        assert_eq!(key_code("KEY_NONSUCH"), None);
+    }
+
+    #[test]
+    fn the_default_keymap_covers_the_usual_keys() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.get_action(KEY_CHANNELUP), Some(Action::ChannelUp));
+        assert_eq!(keymap.get_action(KEY_VOLUMEDOWN), Some(Action::VolumeDown));
+        assert_eq!(keymap.get_action(KEY_MUTE), Some(Action::Mute));
+        assert_eq!(keymap.get_action(KEY_EPG), Some(Action::Epg));
+        assert_eq!(keymap.get_action(KEY_RECORD), Some(Action::Record));
+        assert_eq!(keymap.get_action(KEY_3), Some(Action::Digit(3)));
+        assert_eq!(keymap.get_action(KEY_0), Some(Action::Digit(0)));
+        assert_eq!(keymap.get_action(KEY_NUMERIC_9), Some(Action::Digit(9)));
+        assert_eq!(keymap.get_action(KEY_A), None);
+        assert_eq!(keymap.get_long_press_action(KEY_OK), Some(Action::Epg));
+    }
+
+    #[test]
+    fn keyboard_shortcuts_are_unique_and_cover_the_usual_keys() {
+        let mut names = KEYBOARD_SHORTCUTS.iter().map(|(name, _)| *name).collect::<Vec<&str>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), KEYBOARD_SHORTCUTS.len());
+        assert!(! names.contains(&PAUSE_SHORTCUT.0));
+        assert_eq!(get_keyboard_action("f", false), Some(Action::Fullscreen));
+        assert_eq!(get_keyboard_action("Page_Down", false), Some(Action::ChannelDown));
+        assert_eq!(get_keyboard_action("KP_Add", false), Some(Action::VolumeUp));
+        assert_eq!(get_keyboard_action("KP_7", false), Some(Action::Digit(7)));
+        assert_eq!(get_keyboard_action("q", false), None);
+    }
+
+    #[test]
+    fn space_pauses_only_when_pausing_is_possible() {
+        assert_eq!(get_keyboard_action("space", true), Some(Action::Pause));
+        assert_eq!(get_keyboard_action("space", false), None);
+        assert_eq!(get_keyboard_action("f", true), Some(Action::Fullscreen));
+    }
+
+    #[test]
+    fn a_keymap_round_trips_through_text() {
+        let keymap = Keymap::default();
+        assert_eq!(Keymap::parse(&keymap.to_text()), Ok(keymap));
+    }
+
+    #[test]
+    fn parse_a_hand_written_keymap() {
+        let keymap = Keymap::parse("# My remote\n\nKEY_RED = record\n  KEY_BLUE=channel-12  \nKEY_RED = mute\nlong KEY_RED = epg\n").unwrap();
+        assert_eq!(keymap.get_bindings(), vec![(KEY_RED, Action::Mute), (KEY_BLUE, Action::Channel(12))]);
+        assert_eq!(keymap.get_long_press_bindings(), vec![(KEY_RED, Action::Epg)]);
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        assert_eq!(Keymap::parse("KEY_MUTE = mute\nKEY_RED\n"), Err("Line 2: expected key = action.".to_string()));
+        assert_eq!(Keymap::parse("KEY_PURPLE = mute\n"), Err("Line 1: 'KEY_PURPLE' is not a key.".to_string()));
+        assert_eq!(Keymap::parse("\nKEY_RED = explode\n"), Err("Line 2: 'explode' is not an action.".to_string()));
+    }
+
+    #[test]
+    fn the_keymap_file_is_written_whole() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("keymap");
+        let mut keymap = Keymap::new();
+        keymap.bind(KEY_OK, Action::Fullscreen);
+        write_keymap_to(&path, &keymap).unwrap();
+        assert_eq!(load_keymap(&path), Ok(keymap));
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn missing_digit_bindings_are_added_to_a_keymap_from_before_channel_numbers() {
+        let mut keymap = Keymap::parse("KEY_CHANNELUP = channel-up\nKEY_1 = volume-up\nKEY_NUMERIC_2 = digit-2\n").unwrap();
+        assert!(keymap.add_missing_number_bindings(&Keymap::default()));
+        // Keys with actions of their own, and digits that have keys, are left alone.
+        assert_eq!(keymap.get_action(KEY_1), Some(Action::VolumeUp));
+        assert_eq!(keymap.get_action(KEY_NUMERIC_1), Some(Action::Digit(1)));
+        assert_eq!(keymap.get_action(KEY_2), None);
+        assert_eq!(keymap.get_action(KEY_0), Some(Action::Digit(0)));
+        assert_eq!(keymap.get_action(KEY_CHANNELDOWN), None);
+        assert!(! keymap.add_missing_number_bindings(&Keymap::default()));
+    }
+
+    #[test]
+    fn lirc_buttons_have_actions_and_codes_of_their_own() {
+        let keymap = Keymap::parse("KEY_MUTE = mute\nlirc vol+ = volume-up\nlirc Guide = epg\n").unwrap();
+        assert_eq!(keymap.get_lirc_bindings(), vec![("Guide".to_string(), Action::Epg), ("vol+".to_string(), Action::VolumeUp)]);
+        let code = keymap.get_lirc_button_code("vol+").unwrap();
+        assert!(code > KEY_MAX);
+        assert_eq!(keymap.get_action(code), Some(Action::VolumeUp));
+        assert_eq!(keymap.get_action(keymap.get_lirc_button_code("Guide").unwrap()), Some(Action::Epg));
+        assert_eq!(keymap.get_lirc_button_code("mute"), None);
+        assert_eq!(Keymap::parse(&keymap.to_text()), Ok(keymap));
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2017–2019  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Remote controls handled by LIRC: the button presses lircd reports on its socket become
+//! keystrokes just as those of the remote controls of the adapters. LIRC buttons are
+//! mapped to actions by the keymap: a button may have an action of its own in the keymap,
+//! otherwise its name must be that of a Linux input event code, KEY_OK say, or the same
+//! without the KEY_ in lower case, ok say.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::control_window::Message;
+use crate::keymap::{self, Keymap, key_code};
+use crate::preferences;
+use crate::remote_control::TargettedKeystroke;
+use crate::remote_input::{KEY_PRESS, KEY_RELEASE, KEY_REPEAT};
+
+/// lircd reports no key releases, a key is taken to be released when it has not been
+/// reported for this long. lircd repeats every 110 milliseconds or so.
+const RELEASE_TIME: Duration = Duration::from_millis(250);
+
+/// How long to wait before trying to connect to lircd again.
+const RECONNECT_TIME: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref REMOTE_NAMES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+    static ref UNMAPPED_BUTTONS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+}
+
+/// A button press reported by lircd: a line of code, repeat count, button, and remote.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ButtonPress {
+    pub repeat: u32,
+    pub button: String,
+    pub remote: String,
+}
+
+/// A keystroke from a LIRC remote control.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LircKeystroke {
+    pub remote: String,
+    pub keystroke: u32,
+    pub value: u32,
+}
+
+/// Parse a line from lircd, `None` for a line that is not a button press, such as the
+/// replies to commands.
+pub fn parse_line(line: &str) -> Option<ButtonPress> {
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 4 || u64::from_str_radix(fields[0], 16).is_err() {
+        return None;
+    }
+    Some(ButtonPress {
+        repeat: u32::from_str_radix(fields[1], 16).ok()?,
+        button: fields[2].to_string(),
+        remote: fields[3].to_string(),
+    })
+}
+
+/// The key code of a LIRC button name: the code the keymap gives it if it has an action
+/// of its own there, otherwise the code of the key it is the name of, if it is one.
+pub fn button_key_code(keymap: &Keymap, button: &str) -> Option<u32> {
+    if let Some(code) = keymap.get_lirc_button_code(button) {
+        Some(code)
+    } else if button.starts_with("KEY_") {
+        key_code(button)
+    } else {
+        key_code(&format!("KEY_{}", button.to_uppercase()))
+    }
+}
+
+/// The name a LIRC remote control has in the preferences for its routing.
+pub fn remote_control_name(remote: &str) -> String {
+    format!("lirc:{}", remote)
+}
+
+/// The names of the LIRC remote controls that have been used.
+pub fn get_remote_control_names() -> Vec<String> { // Used in remote_control.rs
+    match REMOTE_NAMES.lock() {
+        Ok(names) => names.iter().map(|remote| remote_control_name(remote)).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Read the button presses from lircd until the connection closes or sending fails,
+/// sending a press, repeats, and a release for each key, a key held when the connection
+/// closes is released. Buttons that the keymap does not map are ignored, they are logged
+/// the first time they are pressed.
+pub fn read_keystrokes(stream: UnixStream, release_time: Duration, mut send: impl FnMut(LircKeystroke) -> bool) -> Result<(), String> {
+    stream.set_read_timeout(Some(release_time)).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    let mut held: Option<LircKeystroke> = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                if let Some(keystroke) = held.take() {
+                    send(LircKeystroke { value: KEY_RELEASE, ..keystroke });
+                }
+                return Ok(());
+            },
+            Ok(_) => {},
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                // A read timing out may lose a partial line, lircd writes whole lines so
+                // there is not one.
+                if let Some(keystroke) = held.take() {
+                    if ! send(LircKeystroke { value: KEY_RELEASE, ..keystroke }) { return Ok(()); }
+                }
+                continue;
+            },
+            Err(e) => return Err(e.to_string()),
+        }
+        let press = match parse_line(&line) {
+            Some(press) => press,
+            None => continue,
+        };
+        let keystroke = match button_key_code(&keymap::get_keymap(), &press.button) {
+            Some(keystroke) => keystroke,
+            None => {
+                if UNMAPPED_BUTTONS.lock().map(|mut buttons| buttons.insert(press.button.clone())).unwrap_or(false) {
+                    info!("LIRC button {} of {} is not a key, add 'lirc {} = action' to the keymap to use it.", press.button, press.remote, press.button);
+                }
+                continue;
+            },
+        };
+        if let Ok(mut names) = REMOTE_NAMES.lock() {
+            names.insert(press.remote.clone());
+        }
+        let is_repeat = press.repeat > 0 && held.as_ref().map(|h| h.remote == press.remote && h.keystroke == keystroke).unwrap_or(false);
+        if ! is_repeat {
+            if let Some(keystroke) = held.take() {
+                if ! send(LircKeystroke { value: KEY_RELEASE, ..keystroke }) { return Ok(()); }
+            }
+        }
+        let keystroke = LircKeystroke { remote: press.remote, keystroke, value: if is_repeat { KEY_REPEAT } else { KEY_PRESS } };
+        held = Some(keystroke.clone());
+        if ! send(keystroke) { return Ok(()); }
+    }
+}
+
+fn now_in_milliseconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// The main daemon for LIRC remote controls: when LIRC is used, connect to the lircd
+/// socket, reconnecting if the connection is lost, and send the keystrokes to the control
+/// window routed as set in the preferences.
+pub fn run(to_cw: glib::Sender<Message>) {
+    loop {
+        if ! preferences::get_use_lirc() {
+            thread::sleep(RECONNECT_TIME);
+            continue;
+        }
+        let socket_path = preferences::get_lirc_socket();
+        match UnixStream::connect(&socket_path) {
+            Ok(stream) => {
+                info!("Connected to lircd at {:?}.", socket_path);
+                let result = read_keystrokes(stream, RELEASE_TIME, |lk| {
+                    // Stop using LIRC as soon as it is turned off.
+                    if ! preferences::get_use_lirc() { return false; }
+                    let routing = preferences::get_remote_control_routing(&remote_control_name(&lk.remote));
+                    to_cw.send(Message::TargettedKeystrokeReceived {
+                        tk: TargettedKeystroke { frontend_id: routing.target(&[]), keystroke: lk.keystroke, value: lk.value, time: now_in_milliseconds() },
+                    }).is_ok()
+                });
+                match result {
+                    Ok(_) => info!("The connection to lircd at {:?} closed.", socket_path),
+                    Err(message) => warn!("Lost the connection to lircd at {:?}: {}", socket_path, message),
+                }
+            },
+            Err(e) => debug!("Cannot connect to lircd at {:?}: {}", socket_path, e),
+        }
+        thread::sleep(RECONNECT_TIME);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+
+    use crate::input_event_codes::*;
+    use crate::keymap::Action;
+
+    fn keystroke(keystroke: u32, value: u32) -> LircKeystroke {
+        LircKeystroke { remote: "mceusb".to_string(), keystroke, value }
+    }
+
+    /// Run a stand-in lircd that writes some lines, with pauses where a line is empty, and
+    /// return the keystrokes read from it.
+    fn keystrokes_from_stand_in(lines: &'static [&'static str]) -> Vec<LircKeystroke> {
+        let directory = tempfile::tempdir().unwrap();
+        let socket_path = directory.path().join("lircd");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for line in lines {
+                if line.is_empty() {
+                    thread::sleep(Duration::from_millis(200));
+                } else {
+                    stream.write_all(line.as_bytes()).unwrap();
+                    stream.write_all(b"\n").unwrap();
+                }
+            }
+        });
+        let (sender, receiver) = channel();
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        read_keystrokes(stream, Duration::from_millis(100), |keystroke| sender.send(keystroke).is_ok()).unwrap();
+        server.join().unwrap();
+        receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn lines_from_lircd_are_parsed() {
+        assert_eq!(parse_line("0000000000f40bf0 00 KEY_UP mceusb\n"), Some(ButtonPress { repeat: 0, button: "KEY_UP".to_string(), remote: "mceusb".to_string() }));
+        assert_eq!(parse_line("000000037ff07bfe 0a ok Hauppauge_350\n").map(|p| p.repeat), Some(10));
+        assert_eq!(parse_line("BEGIN\n"), None);
+        assert_eq!(parse_line("SIGHUP\n"), None);
+        assert_eq!(parse_line("0000000000f40bf0 00 KEY_UP\n"), None);
+    }
+
+    #[test]
+    fn button_names_are_key_names() {
+        let keymap = Keymap::default();
+        assert_eq!(button_key_code(&keymap, "KEY_VOLUMEUP"), Some(KEY_VOLUMEUP));
+        assert_eq!(button_key_code(&keymap, "volumeup"), Some(KEY_VOLUMEUP));
+        assert_eq!(button_key_code(&keymap, "ok"), Some(KEY_OK));
+        assert_eq!(button_key_code(&keymap, "KEY_NONSUCH"), None);
+        assert_eq!(button_key_code(&keymap, "Vol+"), None);
+    }
+
+    #[test]
+    fn buttons_with_actions_in_the_keymap_have_their_own_codes() {
+        let mut keymap = Keymap::default();
+        keymap.bind_lirc_button("Vol+", Action::VolumeUp);
+        keymap.bind_lirc_button("ok", Action::Info);
+        let code = button_key_code(&keymap, "Vol+").unwrap();
+        assert_eq!(keymap.get_action(code), Some(Action::VolumeUp));
+        // The keymap takes precedence over the button being named for a key.
+        assert_eq!(keymap.get_action(button_key_code(&keymap, "ok").unwrap()), Some(Action::Info));
+    }
+
+    #[test]
+    fn a_held_button_is_pressed_repeated_and_released() {
+        let keystrokes = keystrokes_from_stand_in(&[
+            "0000000000f40bf0 00 KEY_VOLUMEUP mceusb",
+            "0000000000f40bf0 01 KEY_VOLUMEUP mceusb",
+            "0000000000f40bf0 02 KEY_VOLUMEUP mceusb",
+            "",
+        ]);
+        assert_eq!(keystrokes, vec![
+            keystroke(KEY_VOLUMEUP, KEY_PRESS),
+            keystroke(KEY_VOLUMEUP, KEY_REPEAT),
+            keystroke(KEY_VOLUMEUP, KEY_REPEAT),
+            keystroke(KEY_VOLUMEUP, KEY_RELEASE),
+        ]);
+    }
+
+    #[test]
+    fn another_button_releases_the_held_one() {
+        let keystrokes = keystrokes_from_stand_in(&[
+            "0000000000f40bf0 00 KEY_OK mceusb",
+            "0000000000f40bf1 00 mute mceusb",
+            "0000000000f40bf2 00 Vol+ mceusb",
+            "BEGIN",
+            "VERSION",
+            "END",
+        ]);
+        assert_eq!(keystrokes, vec![
+            keystroke(KEY_OK, KEY_PRESS),
+            keystroke(KEY_OK, KEY_RELEASE),
+            keystroke(KEY_MUTE, KEY_PRESS),
+            keystroke(KEY_MUTE, KEY_RELEASE),
+        ]);
+        assert!(get_remote_control_names().contains(&"lirc:mceusb".to_string()));
+    }
+}
@@ -0,0 +1,336 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Logging, with a level specification such as "info,gstreamer_engine=debug" giving a
+//! default level and levels for some modules, to the terminal or to a log file in the
+//! XDG state directory that is rotated when it gets big.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Local;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// The modules that can be given their own logging level.
+pub const MODULES: [&str; 5] = ["epg_manager", "frontend_manager", "gstreamer_engine", "lirc", "remote_control"];
+
+/// The GStreamer debug categories of the elements used to play DVB, these get the
+/// level of the gstreamer_engine module.
+const GST_CATEGORIES: [&str; 7] = ["dvbsrc", "dvbbasebin", "mpegtsbase", "mpegtspacketizer", "tsdemux", "playbin", "teletextdec"];
+
+const LOG_FILE_NAME: &str = "me-tv.log";
+const GST_LOG_FILE_NAME: &str = "gstreamer.log";
+
+/// The size a log file can get to before it is rotated.
+pub const MAX_LOG_FILE_SIZE: u64 = 1024 * 1024;
+
+/// The number of old log files kept, me-tv.log.1 being the most recent.
+pub const LOG_FILES_KEPT: usize = 3;
+
+/// The logging levels: a default and levels for specific modules.
+///
+/// Written as a comma separated list of levels for modules, and a default level, for
+/// example "info,gstreamer_engine=debug".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Levels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Levels {
+    fn default() -> Levels {
+        Levels { default: LevelFilter::Warn, modules: vec![] }
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level.trim()).map_err(|_| format!("'{}' is not a logging level, use one of off, error, warn, info, debug, or trace.", level.trim()))
+}
+
+impl Levels {
+    pub fn parse(specification: &str) -> Result<Levels, String> {
+        let mut levels = Levels::default();
+        for directive in specification.split(',').map(|d| d.trim()).filter(|d| ! d.is_empty()) {
+            match directive.find('=') {
+                Some(index) => {
+                    let module = directive[..index].trim();
+                    if ! MODULES.contains(&module) {
+                        return Err(format!("'{}' is not a module with a logging level, use one of {}.", module, MODULES.join(", ")));
+                    }
+                    let level = parse_level(&directive[index + 1..])?;
+                    levels.modules.retain(|(m, _)| m != module);
+                    levels.modules.push((module.to_string(), level));
+                },
+                None => levels.default = parse_level(directive)?,
+            }
+        }
+        Ok(levels)
+    }
+
+    /// The level for a log target, which is a module path such as "me_tv::gstreamer_engine".
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let module = target.split("::").nth(1).unwrap_or(target);
+        self.modules.iter()
+            .find(|(m, _)| m == module)
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.default, |a, b| a.max(b))
+    }
+}
+
+/// The GST_DEBUG setting giving the DVB elements the same level as the gstreamer_engine
+/// module, `None` if logging is off.
+pub fn gst_debug_specification(level: LevelFilter) -> Option<String> {
+    let gst_level = match level {
+        LevelFilter::Off => return None,
+        LevelFilter::Error => 1,
+        LevelFilter::Warn => 2,
+        LevelFilter::Info => 4,
+        LevelFilter::Debug => 5,
+        LevelFilter::Trace => 6,
+    };
+    Some(GST_CATEGORIES.iter().map(|c| format!("{}:{}", c, gst_level)).collect::<Vec<String>>().join(","))
+}
+
+/// The XDG state directory for Me TV, not provided by the xdg crate.
+pub fn state_directory(xdg_state_home: Option<OsString>, home: Option<PathBuf>) -> Option<PathBuf> {
+    // The specification says relative paths are to be ignored.
+    let state_home = match xdg_state_home.map(PathBuf::from) {
+        Some(ref path) if path.is_absolute() => path.clone(),
+        _ => home?.join(".local").join("state"),
+    };
+    Some(state_home.join("me-tv"))
+}
+
+fn rotated_path(path: &Path, number: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", number));
+    PathBuf::from(name)
+}
+
+/// A log file that is moved aside when it gets too big, keeping a number of old ones.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    kept: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, kept: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), max_size, kept, file, size })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.kept == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for number in (1..self.kept).rev() {
+                let from = rotated_path(&self.path, number);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, number + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes log records to standard error and, optionally, a rotating log file.
+#[derive(Debug)]
+struct Logger {
+    levels: Levels,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+fn format_record(record: &Record) -> String {
+    let target = record.target();
+    format!(
+        "{} {:5} {}: {}",
+        Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+        record.level(),
+        target.split("::").nth(1).unwrap_or(target),
+        record.args()
+    )
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if ! self.enabled(record.metadata()) { return; }
+        let line = format_record(record);
+        eprintln!("{}", line);
+        if let Some(ref file) = self.file {
+            if let Ok(mut file) = file.lock() {
+                // There is nowhere to log a failure to log.
+                let _ = file.write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref file) = self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+/// Start logging, returning the path of the log file if there is one.
+///
+/// Unless the user has set GST_DEBUG themselves, the DVB elements of GStreamer are set
+/// to log at the level of the gstreamer_engine module, to a file of their own next to
+/// the log file if logging to file. So this must be called before GStreamer is initialised.
+pub fn init(levels: Levels, log_to_file: bool) -> Result<Option<PathBuf>, String> {
+    let directory = if log_to_file {
+        let directory = state_directory(env::var_os("XDG_STATE_HOME"), env::var_os("HOME").map(PathBuf::from))
+            .ok_or_else(|| "Could not find the XDG state directory.".to_string())?;
+        fs::create_dir_all(&directory).map_err(|e| format!("Could not create {:?}: {}", directory, e))?;
+        Some(directory)
+    } else {
+        None
+    };
+    if env::var_os("GST_DEBUG").is_none() {
+        if let Some(specification) = gst_debug_specification(levels.level_for("me_tv::gstreamer_engine")) {
+            env::set_var("GST_DEBUG", specification);
+            if let Some(ref directory) = directory {
+                env::set_var("GST_DEBUG_FILE", directory.join(GST_LOG_FILE_NAME));
+                env::set_var("GST_DEBUG_NO_COLOR", "1");
+            }
+        }
+    }
+    let path = directory.map(|d| d.join(LOG_FILE_NAME));
+    let file = match path {
+        Some(ref path) => Some(Mutex::new(RotatingFile::open(path, MAX_LOG_FILE_SIZE, LOG_FILES_KEPT).map_err(|e| format!("Could not open {:?}: {}", path, e))?)),
+        None => None,
+    };
+    log::set_max_level(levels.max_level());
+    log::set_boxed_logger(Box::new(Logger { levels, file })).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    fn read(path: &Path) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn levels_are_parsed_per_module() {
+        let levels = Levels::parse("info, gstreamer_engine=debug,remote_control=off").unwrap();
+        assert_eq!(levels.level_for("me_tv::gstreamer_engine"), LevelFilter::Debug);
+        assert_eq!(levels.level_for("me_tv::remote_control"), LevelFilter::Off);
+        assert_eq!(levels.level_for("me_tv::epg_manager"), LevelFilter::Info);
+        assert_eq!(levels.level_for("gst"), LevelFilter::Info);
+        assert_eq!(levels.max_level(), LevelFilter::Debug);
+        assert_eq!(Levels::parse("").unwrap(), Levels::default());
+    }
+
+    #[test]
+    fn the_last_level_for_a_module_wins() {
+        let levels = Levels::parse("epg_manager=trace,epg_manager=error").unwrap();
+        assert_eq!(levels.level_for("me_tv::epg_manager"), LevelFilter::Error);
+        assert_eq!(levels.level_for("me_tv::frontend_manager"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn bad_levels_and_modules_are_rejected() {
+        assert!(Levels::parse("loud").unwrap_err().contains("'loud' is not a logging level"));
+        assert!(Levels::parse("teletext=debug").unwrap_err().contains("'teletext' is not a module"));
+    }
+
+    #[test]
+    fn gst_debug_covers_the_dvb_elements() {
+        assert_eq!(gst_debug_specification(LevelFilter::Off), None);
+        let specification = gst_debug_specification(LevelFilter::Debug).unwrap();
+        assert!(specification.starts_with("dvbsrc:5,dvbbasebin:5,"));
+        assert_eq!(specification.split(',').count(), GST_CATEGORIES.len());
+    }
+
+    #[test]
+    fn state_directory_follows_the_xdg_specification() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(state_directory(None, home.clone()), Some(PathBuf::from("/home/me/.local/state/me-tv")));
+        assert_eq!(state_directory(Some("/var/state".into()), home.clone()), Some(PathBuf::from("/var/state/me-tv")));
+        assert_eq!(state_directory(Some("relative".into()), home), Some(PathBuf::from("/home/me/.local/state/me-tv")));
+        assert_eq!(state_directory(None, None), None);
+    }
+
+    #[test]
+    fn log_files_are_rotated_keeping_a_number_of_old_ones() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(LOG_FILE_NAME);
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["line 1", "line 2", "line 3", "line 4"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read(&path), "line 4\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "line 3\n");
+        assert_eq!(read(&rotated_path(&path, 2)), "line 2\n");
+        assert!(! rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn an_existing_log_file_is_appended_to() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(LOG_FILE_NAME);
+        RotatingFile::open(&path, 100, 1).unwrap().write_line("first").unwrap();
+        RotatingFile::open(&path, 100, 1).unwrap().write_line("second").unwrap();
+        assert_eq!(read(&path), "first\nsecond\n");
+    }
+}
@@ -36,26 +36,52 @@ use gst;
 #[cfg(not(test))]
 use gst_mpegts;
 
+// The parts shared with the command line programs are in the library.
+use me_tv::{channel_names, dvb, frontend_info, tuner_pool};
+
 mod about;
-mod channel_names;
+mod channel_numbers;
 mod control_window;
 mod control_window_button;
+mod diagnostics_dialog;
 mod dialogs;
-mod dvb;
 mod epg_manager;
+mod errors;
+mod file_watcher;
 mod frontend_manager;
 mod frontend_window;
 mod gstreamer_engine;
 pub mod input_event_codes; // Make this module public to avoid all the unused warnings.
+mod keymap;
+mod lirc;
+mod logging;
 mod metvcomboboxtext;
+mod osd;
 mod preferences;
 mod preferences_dialog;
+mod program_map;
+mod radio_panel;
+mod radio_text;
+mod recovery;
 mod remote_control;
+mod remote_input;
+mod shortcuts_window;
+mod signal_panel;
+mod signal_quality;
+mod teletext;
+mod teletext_renderer;
+mod teletext_view;
+mod tracks;
 mod transmitter_dialog;
+mod ts_file;
 
 #[cfg(not(test))]
 fn main() {
-    preferences::init();
+    // Set before any thread is started, the environment is not safe to change afterwards.
+    channel_names::init_channels_conf();
+    // Logging is set up from the preferences, so they are read first and any problem with
+    // them logged once logging has started.
+    let preferences_result = preferences::init();
     /*
      *  As at 2018-12-26 gtk-rs seems not to allow connecting to the GTK+ handle_local_options signal,
      *  though it does now allow connecting to the GTK+ command_line signal. Thus gtk-rs still does not
@@ -72,12 +98,53 @@ fn main() {
         .arg(clap::Arg::with_name("no_gl")
             .long("no-gl")
             .help("Do not try to use OpenGL."))
+        .arg(clap::Arg::with_name("log")
+            .long("log")
+            .value_name("LEVELS")
+            .help("Set the logging levels, e.g. 'info,gstreamer_engine=debug'. The modules are epg_manager,\nfrontend_manager, gstreamer_engine, lirc, and remote_control."))
+        .arg(clap::Arg::with_name("log_file")
+            .long("log-file")
+            .help("Also log to a file in the XDG state directory."))
+        .arg(clap::Arg::with_name("ts_file")
+            .long("ts-file")
+            .value_name("PATH")
+            .help("Play a transport stream file in real time whatever channel is selected. A single\nfrontend is shown whether or not there are DVB adapters, for testing without one."))
         .get_matches();
     if cli_matches.is_present("no_gl") {
         preferences::set_use_opengl(false, false);
     }
+    if let Some(path) = cli_matches.value_of("ts_file") {
+        match std::fs::canonicalize(path) {
+            Ok(path) => ts_file::set_ts_file(Some(path)),
+            Err(error) => {
+                eprintln!("Cannot play {}: {}", path, error);
+                std::process::exit(exitcode::NOINPUT);
+            },
+        }
+    }
+    let log_levels = match logging::Levels::parse(&cli_matches.value_of("log").map(|l| l.to_string()).unwrap_or_else(preferences::get_log_levels)) {
+        Ok(levels) => levels,
+        Err(message) => {
+            eprintln!("{}", message);
+            logging::Levels::default()
+        },
+    };
+    // Must be done before GStreamer is initialised so as to set GST_DEBUG.
+    match logging::init(log_levels, cli_matches.is_present("log_file") || preferences::get_log_to_file()) {
+        Ok(Some(path)) => log::info!("Logging to {:?}.", path),
+        Ok(None) => {},
+        Err(message) => eprintln!("Could not start logging: {}", message),
+    }
+    if let Err(message) = preferences_result {
+        log::error!("{}", message);
+    }
+    // The channels file of earlier versions of Me TV was for the single delivery system.
+    channel_names::migrate_channels_file(&preferences::get_default_delivery_system());
+    keymap::init();
+    channel_numbers::init();
     gst::init().unwrap();
     gst_mpegts::initialise();
+    gstreamer_engine::enable_teletext_decoding();
     let application = gtk::Application::new(Some("uk.org.russel.me-tv"), gio::ApplicationFlags::empty()).expect("Application creation failed");
     glib::set_application_name("Me TV");
     application.connect_startup(move |app| {
@@ -87,13 +154,30 @@ fn main() {
         //    removed, but the `ControlWindow` instance must be created at this time.
         //    Or is there a better way of doing this?
         let _control_window = control_window::ControlWindow::new(&app, from_manager, to_epg_manager);
+        if ts_file::get_ts_file().is_some() {
+            // Pretend there is a frontend to play the file with.
+            to_control_window.send(control_window::Message::FrontendAppeared{fei: frontend_manager::FrontendId{adapter: 0, frontend: 0}}).unwrap();
+        } else {
+            thread::spawn({
+                let t_c_w = to_control_window.clone();
+                move ||{ frontend_manager::run(t_c_w); }
+            });
+        }
         thread::spawn({
             let t_c_w = to_control_window.clone();
-            move ||{ frontend_manager::run(t_c_w); }
+            move ||{ epg_manager::run(t_c_w, from_gstreamer); }
         });
         thread::spawn({
             let t_c_w = to_control_window.clone();
-            move ||{ epg_manager::run(t_c_w, from_gstreamer); }
+            move ||{ file_watcher::run(t_c_w); }
+        });
+        thread::spawn({
+            let t_c_w = to_control_window.clone();
+            move ||{ lirc::run(t_c_w); }
+        });
+        thread::spawn({
+            let t_c_w = to_control_window.clone();
+            move ||{ control_window::run(t_c_w); }
         });
     });
     // Get a glib-gio warning if activate is not handled.
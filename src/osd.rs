@@ -0,0 +1,244 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2017–2019  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+
+use glib;
+use gtk;
+use gtk::prelude::*;
+
+use crate::epg_manager::{EPGEvent, NowNext};
+
+/// How long the OSD stays after the last activity before it starts to fade.
+const OSD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the fade takes.
+const FADE_TIME: Duration = Duration::from_millis(500);
+
+/// How often the fade is updated, in milliseconds.
+pub const FADE_STEP: u32 = 50;
+
+/// The on screen display over the video: the channel being watched with the programmes
+/// on now and next, and messages about changes of volume and tracks.
+#[derive(Debug)]
+pub struct Osd {
+    pub widget: gtk::Box, // FrontendWindow puts this in the video overlay.
+    info_box: gtk::Box,
+    channel_label: gtk::Label,
+    now_label: gtk::Label,
+    progress_bar: gtk::ProgressBar,
+    next_label: gtk::Label,
+    status_label: gtk::Label,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        let create_label = || {
+            let label = gtk::Label::new(None);
+            label.set_halign(gtk::Align::Start);
+            label
+        };
+        let channel_label = create_label();
+        let now_label = create_label();
+        let progress_bar = gtk::ProgressBar::new();
+        let next_label = create_label();
+        let status_label = create_label();
+        let info_box = {
+            let i_b = gtk::Box::new(gtk::Orientation::Vertical, 6);
+            i_b.pack_start(&channel_label, false, false, 0);
+            i_b.pack_start(&now_label, false, false, 0);
+            i_b.pack_start(&progress_bar, false, false, 0);
+            i_b.pack_start(&next_label, false, false, 0);
+            i_b.show_all();
+            i_b.set_no_show_all(true);
+            i_b
+        };
+        status_label.set_no_show_all(true);
+        let widget = {
+            let w = gtk::Box::new(gtk::Orientation::Vertical, 6);
+            w.get_style_context().add_class("osd");
+            w.set_valign(gtk::Align::End);
+            w.set_margin_start(12);
+            w.set_margin_end(12);
+            w.set_margin_bottom(12);
+            w.set_border_width(12);
+            w.pack_start(&info_box, false, false, 0);
+            w.pack_start(&status_label, false, false, 0);
+            w.set_no_show_all(true);
+            w
+        };
+        Osd {
+            widget,
+            info_box,
+            channel_label,
+            now_label,
+            progress_bar,
+            next_label,
+            status_label,
+        }
+    }
+
+    /// Show the channel, with its number if it has one, and the programmes on now and next.
+    pub fn set_channel_info(&self, name: &str, number: Option<u16>, now_next: &NowNext, time: DateTime<Utc>) {
+        let channel = match number {
+            Some(number) => format!("{}  {}", number, name),
+            None => name.to_string(),
+        };
+        self.channel_label.set_markup(&format!("<span size=\"x-large\" weight=\"bold\">{}</span>", glib::markup_escape_text(&channel)));
+        self.now_label.set_text(&programme_text("Now", now_next.now.as_ref(), &Local));
+        match now_next.now.as_ref().and_then(|programme| programme_progress(programme, time)) {
+            Some(fraction) => {
+                self.progress_bar.set_fraction(fraction);
+                self.progress_bar.show();
+            },
+            None => self.progress_bar.hide(),
+        }
+        self.next_label.set_text(&programme_text("Next", now_next.next.as_ref(), &Local));
+        self.info_box.show();
+        self.show();
+    }
+
+    /// Show a short message, below the channel information if that is showing.
+    pub fn set_status(&self, message: &str) {
+        self.status_label.set_text(message);
+        self.status_label.show();
+        self.show();
+    }
+
+    fn show(&self) {
+        self.widget.set_opacity(1.0);
+        self.widget.show();
+    }
+
+    /// Fade the OSD for the time since the last activity, hiding it once it has faded out.
+    /// Returns whether the OSD is still showing.
+    pub fn fade(&self, idle: Duration) -> bool {
+        let opacity = opacity(idle);
+        if opacity > 0.0 {
+            self.widget.set_opacity(opacity);
+            true
+        } else {
+            self.hide();
+            false
+        }
+    }
+
+    pub fn hide(&self) {
+        self.widget.hide();
+        self.info_box.hide();
+        self.status_label.hide();
+    }
+}
+
+/// The opacity of the OSD after a time without activity.
+fn opacity(idle: Duration) -> f64 {
+    if idle <= OSD_TIMEOUT {
+        1.0
+    } else if idle < OSD_TIMEOUT + FADE_TIME {
+        1.0 - (idle - OSD_TIMEOUT).as_secs_f64() / FADE_TIME.as_secs_f64()
+    } else {
+        0.0
+    }
+}
+
+/// How far through a programme a time is, `None` if the programme has no duration.
+pub fn programme_progress(programme: &EPGEvent, time: DateTime<Utc>) -> Option<f64> { // Used in radio_panel.rs
+    if programme.duration == 0 { return None; }
+    let elapsed = time.signed_duration_since(programme.start_time).num_seconds() as f64;
+    Some((elapsed / programme.duration as f64).max(0.0).min(1.0))
+}
+
+/// A line of the OSD for a programme: the start and end times in a time zone and the name.
+pub fn programme_text<Tz: TimeZone>(label: &str, programme: Option<&EPGEvent>, time_zone: &Tz) -> String where Tz::Offset: std::fmt::Display { // Used in radio_panel.rs
+    let programme = match programme {
+        Some(programme) => programme,
+        None => return format!("{}  –", label),
+    };
+    format!(
+        "{}  {}–{}  {}",
+        label,
+        programme.start_time.with_timezone(time_zone).format("%H:%M"),
+        programme.end_time().with_timezone(time_zone).format("%H:%M"),
+        programme.name).trim_end().to_string()
+}
+
+/// The message for the volume, 0.0 to 1.0, or that the sound is muted.
+pub fn volume_text(volume: f64, mute: bool) -> String {
+    if mute {
+        "Muted".to_string()
+    } else {
+        format!("Volume {:.0}%", volume * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::FixedOffset;
+
+    fn create_programme(name: &str) -> EPGEvent {
+        EPGEvent {
+            service_id: 4164,
+            event_id: 1,
+            start_time: Utc.ymd(2020, 5, 10).and_hms(19, 0, 0),
+            duration: 1800,
+            name: name.to_string(),
+            text: String::new(),
+        }
+    }
+
+    #[test]
+    fn osd_fades_after_the_timeout() {
+        assert_eq!(opacity(Duration::from_secs(0)), 1.0);
+        assert_eq!(opacity(OSD_TIMEOUT), 1.0);
+        assert!((opacity(OSD_TIMEOUT + FADE_TIME / 2) - 0.5).abs() < 1e-9);
+        assert_eq!(opacity(OSD_TIMEOUT + FADE_TIME), 0.0);
+    }
+
+    #[test]
+    fn progress_through_a_programme() {
+        let programme = create_programme("News");
+        assert_eq!(programme_progress(&programme, Utc.ymd(2020, 5, 10).and_hms(18, 0, 0)), Some(0.0));
+        assert_eq!(programme_progress(&programme, Utc.ymd(2020, 5, 10).and_hms(19, 15, 0)), Some(0.5));
+        assert_eq!(programme_progress(&programme, Utc.ymd(2020, 5, 10).and_hms(20, 0, 0)), Some(1.0));
+        let programme = EPGEvent { duration: 0, ..programme };
+        assert_eq!(programme_progress(&programme, Utc.ymd(2020, 5, 10).and_hms(19, 15, 0)), None);
+    }
+
+    #[test]
+    fn programme_lines() {
+        let time_zone = FixedOffset::east(3600);
+        let programme = create_programme("News");
+        assert_eq!(programme_text("Now", Some(&programme), &time_zone), "Now  20:00–20:30  News");
+        assert_eq!(programme_text("Next", None, &time_zone), "Next  –");
+        let programme = create_programme("");
+        assert_eq!(programme_text("Now", Some(&programme), &time_zone), "Now  20:00–20:30");
+    }
+
+    #[test]
+    fn volume_messages() {
+        assert_eq!(volume_text(0.2, false), "Volume 20%");
+        assert_eq!(volume_text(0.2, true), "Muted");
+    }
+}
@@ -20,87 +20,279 @@
  */
 
 use std::cell::RefCell;
-use std::fs::{File, OpenOptions, create_dir_all};
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use lazy_static::lazy_static;
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
 use serde_yaml ;
+use tempfile;
 use xdg;
 
+use crate::channel_names::ChannelFilter;
 use crate::dvb;
+use crate::frontend_manager::FrontendId;
+use crate::remote_input::RepeatSettings;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct Preferences {
+/// The version of the preferences file written by this version of Me TV.
+///
+/// Version 0 is a file with no version, as written by Me TV 3.0.x. Version 1 has
+/// settings for each frontend, the single delivery system became the delivery system for
+/// frontends with no settings of their own.
+const PREFERENCES_VERSION: u32 = 1;
+
+/// The settings of one frontend.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+struct FrontendPreferences {
     delivery_system: dvb::DeliverySystem,
+}
+
+/// Where the keys of a remote control go, set for each remote control.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Routing {
+    /// The frontend of the adapter the remote control is part of, or the focused frontend
+    /// window for a remote control that is not part of an adapter.
+    Adapter,
+    /// The frontend window that has, or last had, the focus.
+    Focused,
+    /// A chosen frontend whatever window has the focus.
+    Frontend { adapter: u8, frontend: u8 },
+}
+
+impl Default for Routing {
+    fn default() -> Self { Routing::Adapter }
+}
+
+impl Routing {
+    /// The frontend to send keys to for a remote control that is part of the given
+    /// frontends, `None` for the focused frontend window.
+    pub fn target(&self, frontend_ids: &[FrontendId]) -> Option<FrontendId> {
+        match self {
+            Routing::Adapter => frontend_ids.first().cloned(),
+            Routing::Focused => None,
+            Routing::Frontend { adapter, frontend } => Some(FrontendId { adapter: *adapter, frontend: *frontend }),
+        }
+    }
+}
+
+/// Any setting missing from the preferences file, as will be the case for settings added
+/// since the file was written, takes its default value rather than the file being rejected.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+struct Preferences {
+    version: u32,
+    default_delivery_system: dvb::DeliverySystem, // For frontends that have no settings of their own yet.
+    frontends: BTreeMap<String, FrontendPreferences>,
     use_opengl: bool,
     immediate_tv: bool,
     use_last_channel: bool,
     default_channel: String,
     last_channel: String,
+    channel_numbers: BTreeMap<String, u16>, // Numbers the user has given channels, by channel name.
+    sort_channels_by_number: bool,
+    channel_filter: ChannelFilter,
+    radio_visualisation: bool,
+    radio_text: bool,
+    remote_controls: BTreeMap<String, Routing>, // Where the keys of each remote control go, by event device.
+    input_remote_controls: Vec<PathBuf>, // Input devices that are remote controls though not part of an adapter.
+    remote_control_repeat: RepeatSettings,
+    use_lirc: bool,
+    lirc_socket: PathBuf,
+    audio_languages: Vec<String>,
+    subtitle_language: String,
+    subtitles_always_on: bool,
+    max_reconnect_attempts: u32,
+    log_levels: String,
+    log_to_file: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Preferences {
+        Preferences {
+            version: PREFERENCES_VERSION,
+            default_delivery_system: dvb::DeliverySystem::DVBT,
+            frontends: BTreeMap::new(),
+            use_opengl: true,
+            immediate_tv: false,
+            use_last_channel: false,
+            default_channel: String::from(""),
+            last_channel: String::from(""),
+            channel_numbers: BTreeMap::new(),
+            sort_channels_by_number: false,
+            channel_filter: ChannelFilter::All,
+            radio_visualisation: false,
+            radio_text: true,
+            remote_controls: BTreeMap::new(),
+            input_remote_controls: vec![],
+            remote_control_repeat: RepeatSettings::default(),
+            use_lirc: false,
+            lirc_socket: PathBuf::from("/var/run/lirc/lircd"),
+            audio_languages: vec![],
+            subtitle_language: String::from(""),
+            subtitles_always_on: false,
+            max_reconnect_attempts: 5,
+            log_levels: String::from(""),
+            log_to_file: false,
+        }
+    }
 }
 
 lazy_static! {
-    static ref PREFERENCES: Mutex<RefCell<Preferences>> = Mutex::new(RefCell::new(Preferences{
-        delivery_system: dvb::DeliverySystem::DVBT,
-        use_opengl: true,
-        immediate_tv: false,
-        use_last_channel: false,
-        default_channel: String::from(""),
-        last_channel: String::from(""),
-    }));
+    static ref PREFERENCES: Mutex<RefCell<Preferences>> = Mutex::new(RefCell::new(Preferences::default()));
 }
 
 /// Return a `PathBuf` to the Me TV preferences file location.
 /// Currently use a YAML file to store the preferences rather than
 /// getting involved with the DConf
-fn get_preferences_file_path() -> PathBuf {
+pub fn get_preferences_file_path() -> PathBuf {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("me-tv").expect("Cannot set XDG prefix.");
     let mut path_buf = xdg_dirs.get_config_home();
     path_buf.push("preferences.yml");
     path_buf
 }
 
-/// Write the current `Preferences` instance, serialised to YAML, to the preferences
-/// file location overwriting whatever was there.
-fn write_preferences() {
-    match OpenOptions::new().write(true).truncate(true).create(true).open(get_preferences_file_path()) {
-        Ok(mut file) => {
-            if let Ok(preferences) = PREFERENCES.lock() {
-                let buffer = serde_yaml::to_string(&*preferences.borrow()).unwrap();
-                file.write(&buffer.into_bytes()).unwrap();
-                file.flush().unwrap();
+/// Return the path of the copy of a preferences file made before migrating it from a version.
+fn get_backup_file_path(path: &Path, version: u32) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".version{}", version));
+    path.with_file_name(file_name)
+}
+
+/// Migrate a version 0 file to version 1: the single delivery system becomes the delivery
+/// system for frontends with no settings of their own, each frontend gets its own settings
+/// when it next appears.
+fn migrate_from_version_0(preferences: &mut serde_yaml::Mapping) {
+    if let Some(delivery_system) = preferences.remove(&"delivery_system".into()) {
+        preferences.insert("default_delivery_system".into(), delivery_system);
+    }
+}
+
+/// The migrations, the one at index n migrates a version n file to version n + 1.
+static MIGRATIONS: [fn(&mut serde_yaml::Mapping); PREFERENCES_VERSION as usize] = [
+    migrate_from_version_0,
+];
+
+/// Read the text of a preferences file of any version, returning the preferences and
+/// the version of the file.
+///
+/// Migrations work on the YAML rather than on `Preferences` so that settings that are
+/// renamed or changed in type are carried forward.
+fn read_preferences(buffer: &str) -> Result<(Preferences, u32), String> {
+    let mut value = serde_yaml::from_str::<serde_yaml::Value>(buffer).map_err(|e| e.to_string())?;
+    let mapping = match value {
+        serde_yaml::Value::Mapping(ref mut mapping) => mapping,
+        serde_yaml::Value::Null => return Ok((Preferences::default(), PREFERENCES_VERSION)),
+        _ => return Err("The preferences are not a mapping.".to_string()),
+    };
+    let version = match mapping.get(&"version".into()) {
+        Some(version) => version.as_u64().ok_or_else(|| format!("The version {:?} is not a number.", version))? as u32,
+        None => 0,
+    };
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        migration(mapping);
+    }
+    // A file from a later version of Me TV is read as well as possible, the version
+    // records that settings may have been lost.
+    mapping.insert("version".into(), PREFERENCES_VERSION.max(version).into());
+    let preferences = serde_yaml::from_value(value).map_err(|e| e.to_string())?;
+    Ok((preferences, version))
+}
+
+/// Write a file in full to a temporary file in the same directory which is then renamed,
+/// so a failure part way through cannot lose the previous contents.
+///
+/// Used in keymap.rs for the keymap file.
+pub fn write_file_atomically(path: &Path, contents: &str) -> Result<(), String> {
+    let directory = path.parent().ok_or_else(|| format!("{:?} has no directory.", path))?;
+    let mut file = tempfile::NamedTempFile::new_in(directory).map_err(|e| e.to_string())?;
+    file.write_all(contents.as_bytes()).map_err(|e| e.to_string())?;
+    file.as_file().sync_all().map_err(|e| e.to_string())?;
+    file.persist(path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Write preferences, serialised to YAML, to a file.
+fn write_preferences_to(path: &Path, preferences: &Preferences) -> Result<(), String> {
+    let buffer = serde_yaml::to_string(preferences).map_err(|e| e.to_string())?;
+    write_file_atomically(path, &buffer)
+}
+
+/// Read the preferences from a file, migrating it to the current version if need be. The
+/// file is copied before it is migrated. If the file cannot be read it is copied and the
+/// default preferences used.
+fn load_preferences(path: &Path) -> Result<Preferences, String> {
+    let buffer = fs::read_to_string(path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+    match read_preferences(&buffer) {
+        Ok((preferences, version)) => {
+            if version < PREFERENCES_VERSION {
+                let backup_path = get_backup_file_path(path, version);
+                fs::copy(path, &backup_path).map_err(|e| format!("Cannot copy {:?} to {:?}: {}", path, backup_path, e))?;
+                write_preferences_to(path, &preferences)?;
             }
+            Ok(preferences)
         },
-        Err(error) => panic!("Cannot create or open {:?} {:?}", get_preferences_file_path(), error),
+        Err(message) => {
+            let backup_path = path.with_extension("yml.unreadable");
+            fs::copy(path, &backup_path).map_err(|e| format!("Cannot copy {:?} to {:?}: {}", path, backup_path, e))?;
+            Err(format!("Cannot read {:?}, using the default preferences, the file is kept as {:?}: {}", path, backup_path, message))
+        }
+    }
+}
+
+/// Write the current `Preferences` instance, serialised to YAML, to the preferences
+/// file location replacing whatever was there.
+fn write_preferences() {
+    if let Ok(preferences) = PREFERENCES.lock() {
+        if let Err(message) = write_preferences_to(&get_preferences_file_path(), &preferences.borrow()) {
+            panic!("Cannot write {:?}: {}", get_preferences_file_path(), message);
+        }
     }
 }
 
 /// Initialise the preferences system. Ensures the XDG config directory exists then
-/// reads the preferences file if it exists and swaps the deserialized `Preferences`
-/// instance with the hard-coded default.
-pub fn init() {
+/// reads the preferences file if it exists, migrating it if it is from an earlier
+/// version of Me TV, and swaps the deserialized `Preferences` instance with the
+/// hard-coded default.
+///
+/// Logging is set up from the preferences so this is done before logging is started, any
+/// problem with the file is returned to be logged once it has been.
+pub fn init() -> Result<(), String> {
     let path = get_preferences_file_path();
     if let Err(error) = create_dir_all(path.parent().unwrap()) {
         panic!("create_dir_all({:?}) failed: {:?}", path.parent().unwrap(), error);
     }
     if path.is_file() {
-        if let Ok(mut file) = File::open(path) {
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer).unwrap();
-            match serde_yaml::from_str(&buffer) {
-                Ok(new_preferences) => if let Ok(preferences) = PREFERENCES.lock() {
-                    preferences.replace(new_preferences);
-                },
-                Err(_) => {
-                    // TODO Missing field. Should not just assume default, need to carry
-                    // forward the options that could be picked up
-                }
-            }
+        match load_preferences(&path) {
+            Ok(new_preferences) => if let Ok(preferences) = PREFERENCES.lock() {
+                preferences.replace(new_preferences);
+            },
+            Err(message) => return Err(message),
         }
     }
+    Ok(())
+}
+
+/// Reread the preferences file after it has been changed by something other than this
+/// instance of Me TV. Returns whether the preferences are different.
+pub fn reload() -> bool {
+    let path = get_preferences_file_path();
+    if ! path.is_file() {
+        return false;
+    }
+    match load_preferences(&path) {
+        Ok(new_preferences) => match PREFERENCES.lock() {
+            Ok(preferences) => preferences.replace(new_preferences.clone()) != new_preferences,
+            Err(_) => false,
+        },
+        Err(message) => {
+            warn!("{}", message);
+            false
+        },
+    }
 }
 
 macro_rules! create_getter {
@@ -138,13 +330,65 @@ macro_rules! create_setter {
     }
 }
 
-pub fn get_delivery_system() -> dvb::DeliverySystem {
+pub fn get_default_delivery_system() -> dvb::DeliverySystem {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().default_delivery_system.clone(),  //  Must clone here so can't use the macro.
+        Err(_) => dvb::DeliverySystem::DVBT,
+    }
+}
+create_setter!(set_default_delivery_system, default_delivery_system, dvb::DeliverySystem);
+
+/// The key of the settings of a frontend in the preferences file.
+fn frontend_key(fei: &FrontendId) -> String {
+    format!("adapter{}.frontend{}", fei.adapter, fei.frontend)
+}
+
+/// The delivery system for a frontend that has no settings of its own: the one that
+/// was used for all frontends if the frontend can use it, otherwise the first one the
+/// frontend can use. If what the frontend can use is not known, assume it can use any.
+fn initial_delivery_system(default: &dvb::DeliverySystem, capabilities: &[dvb::DeliverySystem]) -> dvb::DeliverySystem {
+    if capabilities.is_empty() || capabilities.contains(default) { default.clone() }
+    else { capabilities[0].clone() }
+}
+
+/// Return the delivery system a frontend is used with.
+///
+/// A frontend with no settings of its own, as is the case for all frontends when the
+/// preferences file is from a version of Me TV with a single delivery system, is used
+/// with the delivery system it would be given by `add_frontend_preferences`.
+pub fn get_frontend_delivery_system(fei: &FrontendId, capabilities: &[dvb::DeliverySystem]) -> dvb::DeliverySystem {
     match PREFERENCES.lock() {
-        Ok(preferences) => preferences.borrow().delivery_system.clone(),  //  Must clone here so can't use the macro.
+        Ok(preferences) => {
+            let preferences = preferences.borrow();
+            match preferences.frontends.get(&frontend_key(fei)) {
+                Some(frontend_preferences) => frontend_preferences.delivery_system.clone(),
+                None => initial_delivery_system(&preferences.default_delivery_system, capabilities),
+            }
+        },
         Err(_) => dvb::DeliverySystem::DVBT,
     }
 }
-create_setter!(set_delivery_system, delivery_system, dvb::DeliverySystem);
+
+/// Give a frontend with no settings of its own settings, writing the preferences file,
+/// so that the user can see and change them.
+pub fn add_frontend_preferences(fei: &FrontendId, capabilities: &[dvb::DeliverySystem]) { // Used in control_window_button.rs
+    let has_preferences = match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().frontends.contains_key(&frontend_key(fei)),
+        Err(_) => return,
+    };
+    if ! has_preferences {
+        set_frontend_delivery_system(fei, get_frontend_delivery_system(fei, capabilities), true);
+    }
+}
+
+pub fn set_frontend_delivery_system(fei: &FrontendId, delivery_system: dvb::DeliverySystem, write_back: bool) {
+    if let Ok(preferences) = PREFERENCES.lock() {
+        let mut new_preferences = preferences.borrow().clone();
+        new_preferences.frontends.insert(frontend_key(fei), FrontendPreferences { delivery_system });
+        preferences.replace(new_preferences);
+    }
+    if write_back { write_preferences(); }
+}
 
 create_getter!(get_use_opengl, use_opengl, bool, true);
 create_setter!(set_use_opengl, use_opengl, bool);
@@ -160,3 +404,299 @@ create_setter!(set_default_channel, default_channel, String);
 
 create_option_getter!(get_last_channel, last_channel, String, None);
 create_setter!(set_last_channel, last_channel, String);
+
+/// The number the user has given a channel, if they have given it one.
+pub fn get_channel_number(channel_name: &str) -> Option<u16> {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().channel_numbers.get(channel_name).cloned(),
+        Err(_) => None,
+    }
+}
+
+/// Give a channel a number, or with `None` go back to the number broadcast for it.
+pub fn set_channel_number(channel_name: &str, number: Option<u16>, write_back: bool) {
+    if let Ok(preferences) = PREFERENCES.lock() {
+        let mut new_preferences = preferences.borrow().clone();
+        match number {
+            Some(number) => new_preferences.channel_numbers.insert(channel_name.to_string(), number),
+            None => new_preferences.channel_numbers.remove(channel_name),
+        };
+        preferences.replace(new_preferences);
+    }
+    if write_back { write_preferences(); }
+}
+
+create_getter!(get_sort_channels_by_number, sort_channels_by_number, bool, false);
+create_setter!(set_sort_channels_by_number, sort_channels_by_number, bool);
+
+create_getter!(get_channel_filter, channel_filter, ChannelFilter, ChannelFilter::All);
+create_setter!(set_channel_filter, channel_filter, ChannelFilter);
+
+create_getter!(get_radio_visualisation, radio_visualisation, bool, false);
+create_setter!(set_radio_visualisation, radio_visualisation, bool);
+
+create_getter!(get_radio_text, radio_text, bool, true);
+create_setter!(set_radio_text, radio_text, bool);
+
+/// Where the keys of a remote control go, to its own adapter unless set otherwise.
+pub fn get_remote_control_routing(name: &str) -> Routing {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().remote_controls.get(name).cloned().unwrap_or_default(),
+        Err(_) => Routing::default(),
+    }
+}
+
+pub fn set_remote_control_routing(name: &str, routing: Routing, write_back: bool) {
+    if let Ok(preferences) = PREFERENCES.lock() {
+        let mut new_preferences = preferences.borrow().clone();
+        new_preferences.remote_controls.insert(name.to_string(), routing);
+        preferences.replace(new_preferences);
+    }
+    if write_back { write_preferences(); }
+}
+
+pub fn get_input_remote_controls() -> Vec<PathBuf> {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().input_remote_controls.clone(),
+        Err(_) => vec![],
+    }
+}
+create_setter!(set_input_remote_controls, input_remote_controls, Vec<PathBuf>);
+
+pub fn get_remote_control_repeat() -> RepeatSettings {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().remote_control_repeat.clone(),
+        Err(_) => RepeatSettings::default(),
+    }
+}
+create_setter!(set_remote_control_repeat, remote_control_repeat, RepeatSettings);
+
+create_getter!(get_use_lirc, use_lirc, bool, false);
+create_setter!(set_use_lirc, use_lirc, bool);
+
+pub fn get_lirc_socket() -> PathBuf {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().lirc_socket.clone(),
+        Err(_) => PathBuf::from("/var/run/lirc/lircd"),
+    }
+}
+create_setter!(set_lirc_socket, lirc_socket, PathBuf);
+
+pub fn get_audio_languages() -> Vec<String> {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().audio_languages.clone(),
+        Err(_) => vec![],
+    }
+}
+create_setter!(set_audio_languages, audio_languages, Vec<String>);
+
+pub fn get_subtitle_language() -> String {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().subtitle_language.clone(),
+        Err(_) => String::from(""),
+    }
+}
+create_setter!(set_subtitle_language, subtitle_language, String);
+
+create_getter!(get_subtitles_always_on, subtitles_always_on, bool, false);
+create_setter!(set_subtitles_always_on, subtitles_always_on, bool);
+
+create_getter!(get_max_reconnect_attempts, max_reconnect_attempts, u32, 5);
+create_setter!(set_max_reconnect_attempts, max_reconnect_attempts, u32);
+
+pub fn get_log_levels() -> String {
+    match PREFERENCES.lock() {
+        Ok(preferences) => preferences.borrow().log_levels.clone(),
+        Err(_) => String::from(""),
+    }
+}
+create_setter!(set_log_levels, log_levels, String);
+
+create_getter!(get_log_to_file, log_to_file, bool, false);
+create_setter!(set_log_to_file, log_to_file, bool);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("preferences").join(name)
+    }
+
+    fn read_fixture(name: &str) -> (Preferences, u32) {
+        read_preferences(&fs::read_to_string(fixture(name)).unwrap()).unwrap()
+    }
+
+    /// Put a copy of a fixture in a temporary directory as the preferences file.
+    fn preferences_file(directory: &tempfile::TempDir, name: &str) -> PathBuf {
+        let path = directory.path().join("preferences.yml");
+        fs::copy(fixture(name), &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_me_tv_3_0_7_to_3_0_9_file_is_read() {
+        let (preferences, version) = read_fixture("version0-3.0.9.yml");
+        assert_eq!(version, 0);
+        assert_eq!(preferences, Preferences {
+            default_delivery_system: dvb::DeliverySystem::DVBC_ANNEX_A,
+            use_opengl: false,
+            immediate_tv: true,
+            use_last_channel: true,
+            default_channel: "BBC ONE Lon".to_string(),
+            last_channel: "BBC TWO".to_string(),
+            ..Preferences::default()
+        });
+    }
+
+    #[test]
+    fn a_me_tv_3_0_0_file_is_read() {
+        let (preferences, version) = read_fixture("version0-3.0.0.yml");
+        assert_eq!(version, 0);
+        assert_eq!(preferences, Preferences {
+            immediate_tv: true,
+            default_channel: "BBC ONE Lon".to_string(),
+            ..Preferences::default()
+        });
+    }
+
+    #[test]
+    fn a_me_tv_3_0_1_to_3_0_6_file_is_read() {
+        let (preferences, version) = read_fixture("version0-3.0.1-3.0.6.yml");
+        assert_eq!(version, 0);
+        assert_eq!(preferences, Preferences {
+            use_opengl: false,
+            immediate_tv: true,
+            use_last_channel: true,
+            default_channel: "BBC ONE Lon".to_string(),
+            last_channel: "BBC TWO".to_string(),
+            ..Preferences::default()
+        });
+    }
+
+    #[test]
+    fn old_files_are_migrated_whatever_the_release() {
+        for name in &["version0-3.0.0.yml", "version0-3.0.1-3.0.6.yml", "version0-3.0.9.yml"] {
+            let directory = tempfile::tempdir().unwrap();
+            let path = preferences_file(&directory, name);
+            let preferences = load_preferences(&path).unwrap();
+            assert_eq!(preferences.version, PREFERENCES_VERSION, "{}", name);
+            assert!(directory.path().join("preferences.yml.version0").exists(), "{}", name);
+            assert_eq!(read_preferences(&fs::read_to_string(&path).unwrap()).unwrap(), (preferences, PREFERENCES_VERSION), "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_version_1_file_is_read() {
+        let (preferences, version) = read_fixture("version1.yml");
+        assert_eq!(version, 1);
+        assert_eq!(preferences.default_delivery_system, dvb::DeliverySystem::DVBT2);
+        assert_eq!(preferences.frontends.get("adapter1.frontend0"), Some(&FrontendPreferences { delivery_system: dvb::DeliverySystem::DVBC_ANNEX_A }));
+        assert_eq!(preferences.audio_languages, vec!["eng", "fra"]);
+        assert_eq!(preferences.max_reconnect_attempts, 3);
+        assert_eq!(preferences.log_levels, "info,gstreamer_engine=debug");
+        assert!(preferences.log_to_file);
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults_and_unknown_ones_are_ignored() {
+        let (preferences, version) = read_preferences("---\nversion: 1\nuse_opengl: false\nsome_later_setting: 3\n").unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(preferences, Preferences { use_opengl: false, ..Preferences::default() });
+    }
+
+    #[test]
+    fn a_file_from_a_later_version_keeps_its_version() {
+        let (preferences, version) = read_preferences("---\nversion: 7\nuse_opengl: false\n").unwrap();
+        assert_eq!(version, 7);
+        assert_eq!(preferences.version, 7);
+        assert!(! preferences.use_opengl);
+    }
+
+    #[test]
+    fn an_old_file_is_backed_up_then_migrated() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = preferences_file(&directory, "version0-3.0.9.yml");
+        let preferences = load_preferences(&path).unwrap();
+        assert_eq!(preferences.version, PREFERENCES_VERSION);
+        let backup_path = directory.path().join("preferences.yml.version0");
+        assert_eq!(fs::read_to_string(&backup_path).unwrap(), fs::read_to_string(fixture("version0-3.0.9.yml")).unwrap());
+        let (migrated, version) = read_preferences(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(version, PREFERENCES_VERSION);
+        assert_eq!(migrated, preferences);
+        assert_eq!(migrated.default_delivery_system, dvb::DeliverySystem::DVBC_ANNEX_A);
+    }
+
+    #[test]
+    fn a_current_file_is_not_backed_up_or_rewritten() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = preferences_file(&directory, "version1.yml");
+        load_preferences(&path).unwrap();
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), fs::read_to_string(fixture("version1.yml")).unwrap());
+    }
+
+    #[test]
+    fn an_unreadable_file_is_kept() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("preferences.yml");
+        fs::write(&path, "---\nuse_opengl: [true\n").unwrap();
+        assert!(load_preferences(&path).is_err());
+        assert_eq!(fs::read_to_string(directory.path().join("preferences.yml.unreadable")).unwrap(), "---\nuse_opengl: [true\n");
+    }
+
+    #[test]
+    fn writing_replaces_the_file_leaving_nothing_else() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = preferences_file(&directory, "version0-3.0.9.yml");
+        let preferences = Preferences { last_channel: "BBC FOUR".to_string(), ..Preferences::default() };
+        write_preferences_to(&path, &preferences).unwrap();
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+        assert_eq!(read_preferences(&fs::read_to_string(&path).unwrap()).unwrap(), (preferences, PREFERENCES_VERSION));
+    }
+
+    #[test]
+    fn frontend_settings_are_keyed_by_adapter_and_frontend() {
+        let mut preferences = Preferences::default();
+        preferences.frontends.insert(frontend_key(&FrontendId { adapter: 1, frontend: 0 }), FrontendPreferences { delivery_system: dvb::DeliverySystem::DVBT2 });
+        let buffer = serde_yaml::to_string(&preferences).unwrap();
+        assert!(buffer.contains("adapter1.frontend0:"), "{}", buffer);
+        let read_back: Preferences = serde_yaml::from_str(&buffer).unwrap();
+        assert_eq!(read_back.frontends, preferences.frontends);
+    }
+
+    #[test]
+    fn routing_to_a_frontend() {
+        let frontend_ids = vec![FrontendId { adapter: 1, frontend: 0 }, FrontendId { adapter: 1, frontend: 1 }];
+        assert_eq!(Routing::Adapter.target(&frontend_ids), Some(FrontendId { adapter: 1, frontend: 0 }));
+        assert_eq!(Routing::Adapter.target(&[]), None);
+        assert_eq!(Routing::Focused.target(&frontend_ids), None);
+        assert_eq!(Routing::Frontend { adapter: 0, frontend: 0 }.target(&frontend_ids), Some(FrontendId { adapter: 0, frontend: 0 }));
+    }
+
+    #[test]
+    fn remote_control_routing_round_trips() {
+        let mut preferences = Preferences::default();
+        preferences.remote_controls.insert("/dev/input/by-path/pci-0000:00:14.0-usb-0:1:1.0-event-ir".to_string(), Routing::Focused);
+        preferences.remote_controls.insert("/dev/input/by-id/usb-Microsoft_eHome-event-if00".to_string(), Routing::Frontend { adapter: 1, frontend: 0 });
+        preferences.input_remote_controls.push(PathBuf::from("/dev/input/by-id/usb-Microsoft_eHome-event-if00"));
+        let buffer = serde_yaml::to_string(&preferences).unwrap();
+        assert_eq!(read_preferences(&buffer).unwrap(), (preferences, PREFERENCES_VERSION));
+    }
+
+    #[test]
+    fn radio_settings_round_trip() {
+        let preferences = Preferences { channel_filter: ChannelFilter::Radio, radio_visualisation: true, radio_text: false, ..Preferences::default() };
+        let buffer = serde_yaml::to_string(&preferences).unwrap();
+        assert!(buffer.contains("channel_filter: Radio"), "{}", buffer);
+        assert_eq!(read_preferences(&buffer).unwrap(), (preferences, PREFERENCES_VERSION));
+    }
+
+    #[test]
+    fn a_new_frontend_keeps_the_single_delivery_system_if_it_can() {
+        use dvb::DeliverySystem::*;
+        assert_eq!(initial_delivery_system(&DVBT, &[DVBT, DVBT2, DVBC_ANNEX_A]), DVBT);
+        assert_eq!(initial_delivery_system(&DVBT, &[DVBC_ANNEX_A, DVBC_ANNEX_B]), DVBC_ANNEX_A);
+        assert_eq!(initial_delivery_system(&DVBT2, &[]), DVBT2);
+    }
+}
@@ -20,36 +20,206 @@
  */
 
 use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Mutex;
 
 use lazy_static::lazy_static;
+use log::warn;
 
 use gtk;
 use gtk::prelude::*;
 
 use crate::control_window::ControlWindow;
 use crate::dvb;
+use crate::frontend_manager::FrontendId;
+use crate::keymap::{self, Action, Keymap, key_code, key_name};
+use crate::logging;
 use crate::metvcomboboxtext::MeTVComboBoxText;
 use crate::metvcomboboxtext::MeTVComboBoxTextExt;
-use crate::preferences;
+use crate::preferences::{self, Routing};
+use crate::remote_control;
+use crate::remote_input::RepeatSettings;
+use crate::tracks::parse_language_list;
 
 lazy_static! {
     static ref PREFERENCES: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
 }
 
-fn create(control_window: &ControlWindow) -> gtk::Window {
+/// Show a warning icon with a message in an entry, or no icon if there is no message.
+fn set_entry_warning(entry: &gtk::Entry, message: Option<&str>) {
+    match message {
+        Some(message) => {
+            entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary, Some("dialog-warning-symbolic"));
+            entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, Some(message));
+        },
+        None => entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary, None),
+    }
+}
+
+/// Offer the action names in a combobox with an entry, the channel actions have to be
+/// typed in the entry.
+fn append_action_names(comboboxtext: &gtk::ComboBoxText) {
+    for name in Action::names() {
+        comboboxtext.append_text(&name);
+    }
+}
+
+fn get_combobox_entry(comboboxtext: &gtk::ComboBoxText) -> gtk::Entry {
+    comboboxtext.get_child().unwrap().downcast::<gtk::Entry>().unwrap()
+}
+
+/// Bind a key, or its long press, in the keymap.
+fn bind_key(keymap: &mut Keymap, code: u32, long_press: bool, action: Action) {
+    if long_press { keymap.bind_long_press(code, action); } else { keymap.bind(code, action); }
+}
+
+/// Put a row in the grid for each key of the keymap: the key, its action, which can be
+/// changed, and a button to remove the key from the keymap. Long presses follow the keys.
+/// Any changes are written to the keymap file as they are made.
+fn fill_key_bindings_grid(grid: &gtk::Grid) {
+    for child in grid.get_children() {
+        grid.remove(&child);
+    }
+    let keymap = keymap::get_keymap();
+    let bindings = keymap.get_bindings().into_iter().map(|(code, action)| (code, false, action))
+        .chain(keymap.get_long_press_bindings().into_iter().map(|(code, action)| (code, true, action)));
+    for (row, (code, long_press, action)) in bindings.enumerate() {
+        let label = gtk::Label::new(Some(&if long_press { format!("long {}", key_name(code)) } else { key_name(code) }));
+        label.set_halign(gtk::Align::Start);
+        let comboboxtext = gtk::ComboBoxText::new_with_entry();
+        append_action_names(&comboboxtext);
+        get_combobox_entry(&comboboxtext).set_text(&action.to_string());
+        comboboxtext.connect_changed(move |c| {
+            let entry = get_combobox_entry(c);
+            match entry.get_text().unwrap().parse::<Action>() {
+                Ok(action) => {
+                    set_entry_warning(&entry, None);
+                    let mut keymap = keymap::get_keymap();
+                    bind_key(&mut keymap, code, long_press, action);
+                    keymap::set_keymap(keymap, true);
+                },
+                Err(message) => set_entry_warning(&entry, Some(&message)),
+            }
+        });
+        let remove_button = {
+            let button = gtk::Button::new_from_icon_name(Some("list-remove-symbolic"), gtk::IconSize::Button);
+            button.set_tooltip_text(Some("Remove the key"));
+            button.connect_clicked({
+                let g = grid.clone();
+                move |_| {
+                    let mut keymap = keymap::get_keymap();
+                    if long_press { keymap.unbind_long_press(code); } else { keymap.unbind(code); }
+                    keymap::set_keymap(keymap, true);
+                    fill_key_bindings_grid(&g);
+                }
+            });
+            button
+        };
+        grid.attach(&label, 0, row as i32, 1, 1);
+        grid.attach(&comboboxtext, 1, row as i32, 1, 1);
+        grid.attach(&remove_button, 2, row as i32, 1, 1);
+    }
+    grid.show_all();
+}
+
+/// The name of a device for presenting, the file name of its path.
+fn device_name(path: &str) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// The routings that can be chosen for a remote control, with their descriptions.
+fn routing_choices(frontend_ids: &[FrontendId]) -> Vec<(Routing, String)> {
+    let mut choices = vec![
+        (Routing::Adapter, "Its own adapter, or the focused window".to_string()),
+        (Routing::Focused, "The focused window".to_string()),
+    ];
+    choices.extend(frontend_ids.iter().map(|fei| (
+        Routing::Frontend { adapter: fei.adapter, frontend: fei.frontend },
+        format!("Adapter {} frontend {}", fei.adapter, fei.frontend),
+    )));
+    choices
+}
+
+/// Put a row in the grid for each remote control in use: its device and where its keys go,
+/// which can be changed. Changes are written to the preferences as they are made.
+fn fill_remote_controls_grid(grid: &gtk::Grid, frontend_ids: &[FrontendId]) {
+    for child in grid.get_children() {
+        grid.remove(&child);
+    }
+    let names = remote_control::get_remote_control_names();
+    if names.is_empty() {
+        grid.attach(&gtk::Label::new(Some("No remote controls found.")), 0, 0, 1, 1);
+    }
+    for (row, name) in names.into_iter().enumerate() {
+        let label = gtk::Label::new(Some(&device_name(&name)));
+        label.set_halign(gtk::Align::Start);
+        label.set_tooltip_text(Some(&name));
+        let current = preferences::get_remote_control_routing(&name);
+        let mut choices = routing_choices(frontend_ids);
+        if ! choices.iter().any(|(routing, _)| *routing == current) {
+            // A frontend not present at the moment.
+            if let Routing::Frontend { adapter, frontend } = current {
+                choices.push((current.clone(), format!("Adapter {} frontend {}", adapter, frontend)));
+            }
+        }
+        let comboboxtext = gtk::ComboBoxText::new();
+        for (_, description) in &choices {
+            comboboxtext.append_text(description);
+        }
+        comboboxtext.set_active(choices.iter().position(|(routing, _)| *routing == current).map(|index| index as u32));
+        comboboxtext.connect_changed(move |c| {
+            if let Some(index) = c.get_active() {
+                preferences::set_remote_control_routing(&name, choices[index as usize].0.clone(), true);
+            }
+        });
+        grid.attach(&label, 0, row as i32, 1, 1);
+        grid.attach(&comboboxtext, 1, row as i32, 1, 1);
+    }
+    grid.show_all();
+}
+
+fn create(control_window: &Rc<ControlWindow>) -> gtk::Window {
     let menu_builder = gtk::Builder::new_from_string(include_str!("resources/preferences_dialog.glade.xml"));
     let _delivery_system_comboboxtext = {
         let comboboxtext = menu_builder.get_object::<gtk::ComboBoxText>("delivery_system").unwrap();
         for delivery_system in dvb::DeliverySystem::iterator() {
             comboboxtext.append_text(&delivery_system.to_string());
         }
-        comboboxtext.set_active(Some(preferences::get_delivery_system().get_index()));
+        comboboxtext.set_active(Some(preferences::get_default_delivery_system().get_index()));
         comboboxtext.connect_changed(
-            move |selector| preferences::set_delivery_system(selector.get_active_text().unwrap().as_str().into(), true)
+            move |selector| preferences::set_default_delivery_system(selector.get_active_text().unwrap().as_str().into(), true)
         );
         comboboxtext
     };
+    let _frontend_delivery_systems_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("frontend_delivery_systems").unwrap();
+        for (row, (fei, delivery_system)) in control_window.get_frontend_delivery_systems().into_iter().enumerate() {
+            let label = gtk::Label::new(Some(&fei.to_string()));
+            let comboboxtext = gtk::ComboBoxText::new();
+            let delivery_systems = match fei.get_info() {
+                Some(info) if ! info.delivery_systems.is_empty() => info.delivery_systems,
+                _ => dvb::DeliverySystem::iterator().cloned().collect(),
+            };
+            for d_s in &delivery_systems {
+                comboboxtext.append_text(&d_s.to_string());
+            }
+            comboboxtext.set_active(delivery_systems.iter().position(|d_s| *d_s == delivery_system).map(|index| index as u32));
+            comboboxtext.connect_changed({
+                let c_w = control_window.clone();
+                move |selector| {
+                    preferences::set_frontend_delivery_system(&fei, selector.get_active_text().unwrap().as_str().into(), true);
+                    c_w.update_channels_store();
+                }
+            });
+            grid.attach(&label, 0, row as i32, 1, 1);
+            grid.attach(&comboboxtext, 1, row as i32, 1, 1);
+        }
+        grid
+    };
     let _use_opengl_button = {
         let button = menu_builder.get_object::<gtk::CheckButton>("use_opengl").unwrap();
         button.set_active(preferences::get_use_opengl());
@@ -82,19 +252,275 @@ fn create(control_window: &ControlWindow) -> gtk::Window {
     );
     let _default_channel_selector = {
         let mut combobox = menu_builder.get_object::<MeTVComboBoxText>("channel_name").unwrap();
-        combobox.set_new_model(&control_window.channel_names_store);
-        if let Some(channel_name) = preferences::get_default_channel() {
-            if channel_name != "" {
-                if ! combobox.set_active_text(channel_name.clone()) {
-                    panic!("Could not set the default channel to {}.", channel_name);
+        // The default channel is for adaptor0 frontend0, there is no list of channels without it.
+        match control_window.get_default_channel_names_store() {
+            Some(store) => {
+                combobox.set_new_model(&store);
+                if let Some(channel_name) = preferences::get_default_channel() {
+                    if channel_name != "" {
+                        if ! combobox.set_active_text(channel_name.clone()) {
+                            warn!("The default channel {} is not a channel of adaptor0 frontend0.", channel_name);
+                        }
+                    }
                 }
-            }
+            },
+            None => combobox.set_sensitive(false),
         }
         combobox.connect_changed(
             move |selector: &MeTVComboBoxText| preferences::set_default_channel(selector.get_active_text().unwrap(), true)
         );
         combobox
     };
+    let _audio_languages_entry = {
+        let entry = menu_builder.get_object::<gtk::Entry>("audio_languages").unwrap();
+        entry.set_text(&preferences::get_audio_languages().join(", "));
+        entry.connect_changed(
+            move |e| preferences::set_audio_languages(parse_language_list(&e.get_text().unwrap()), true)
+        );
+        entry
+    };
+    let _subtitles_always_on_button = {
+        let button = menu_builder.get_object::<gtk::CheckButton>("subtitles_always_on").unwrap();
+        button.set_active(preferences::get_subtitles_always_on());
+        button.connect_toggled(
+            move |b| preferences::set_subtitles_always_on(b.get_active(), true)
+        );
+        button
+    };
+    let _subtitle_language_entry = {
+        let entry = menu_builder.get_object::<gtk::Entry>("subtitle_language").unwrap();
+        entry.set_text(&preferences::get_subtitle_language());
+        entry.connect_changed(
+            move |e| preferences::set_subtitle_language(e.get_text().unwrap().trim().to_lowercase(), true)
+        );
+        entry
+    };
+    let _max_reconnect_attempts_spin_button = {
+        let spin_button = menu_builder.get_object::<gtk::SpinButton>("max_reconnect_attempts").unwrap();
+        spin_button.set_value(preferences::get_max_reconnect_attempts() as f64);
+        spin_button.connect_value_changed(
+            move |s| preferences::set_max_reconnect_attempts(s.get_value_as_int() as u32, true)
+        );
+        spin_button
+    };
+    let _log_levels_entry = {
+        let entry = menu_builder.get_object::<gtk::Entry>("log_levels").unwrap();
+        entry.set_text(&preferences::get_log_levels());
+        entry.connect_changed(
+            move |e| {
+                let text = e.get_text().unwrap().trim().to_string();
+                // Only keep levels that will work at the next start.
+                match logging::Levels::parse(&text) {
+                    Ok(_) => {
+                        set_entry_warning(e, None);
+                        preferences::set_log_levels(text, true);
+                    },
+                    Err(message) => set_entry_warning(e, Some(&message)),
+                }
+            }
+        );
+        entry
+    };
+    let _log_to_file_button = {
+        let button = menu_builder.get_object::<gtk::CheckButton>("log_to_file").unwrap();
+        button.set_active(preferences::get_log_to_file());
+        button.connect_toggled(
+            move |b| preferences::set_log_to_file(b.get_active(), true)
+        );
+        button
+    };
+    let _sort_channels_by_number_button = {
+        let button = menu_builder.get_object::<gtk::CheckButton>("sort_channels_by_number").unwrap();
+        button.set_active(preferences::get_sort_channels_by_number());
+        button.connect_toggled({
+            let c_w = control_window.clone();
+            move |b| {
+                preferences::set_sort_channels_by_number(b.get_active(), true);
+                c_w.update_channels_store();
+            }
+        });
+        button
+    };
+    let _radio_visualisation_button = {
+        let button = menu_builder.get_object::<gtk::CheckButton>("radio_visualisation").unwrap();
+        button.set_active(preferences::get_radio_visualisation());
+        button.connect_toggled(
+            move |b| preferences::set_radio_visualisation(b.get_active(), true)
+        );
+        button
+    };
+    let _radio_text_button = {
+        let button = menu_builder.get_object::<gtk::CheckButton>("radio_text").unwrap();
+        button.set_active(preferences::get_radio_text());
+        button.connect_toggled(
+            move |b| preferences::set_radio_text(b.get_active(), true)
+        );
+        button
+    };
+    let _channel_numbers_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("channel_numbers").unwrap();
+        for (row, (channel_name, broadcast_number)) in control_window.get_broadcast_channel_numbers().into_iter().enumerate() {
+            let label = gtk::Label::new(Some(&channel_name));
+            label.set_halign(gtk::Align::Start);
+            let entry = gtk::Entry::new();
+            entry.set_width_chars(6);
+            if let Some(number) = broadcast_number {
+                entry.set_placeholder_text(Some(&number.to_string()));
+            }
+            if let Some(number) = preferences::get_channel_number(&channel_name) {
+                entry.set_text(&number.to_string());
+            }
+            entry.connect_changed({
+                let c_w = control_window.clone();
+                move |e| {
+                    let text = e.get_text().unwrap().trim().to_string();
+                    let number = if text.is_empty() { Ok(None) } else { text.parse::<u16>().map(Some) };
+                    match number {
+                        Ok(number) => {
+                            set_entry_warning(e, None);
+                            preferences::set_channel_number(&channel_name, number, true);
+                            if preferences::get_sort_channels_by_number() {
+                                c_w.update_channels_store();
+                            }
+                        },
+                        Err(_) => set_entry_warning(e, Some("A channel number is a whole number from 0 to 65535.")),
+                    }
+                }
+            });
+            grid.attach(&label, 0, row as i32, 1, 1);
+            grid.attach(&entry, 1, row as i32, 1, 1);
+        }
+        grid
+    };
+    let remote_controls_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("remote_controls").unwrap();
+        fill_remote_controls_grid(&grid, &control_window.get_frontend_ids());
+        grid
+    };
+    let _input_devices_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("input_devices").unwrap();
+        let mut devices = remote_control::get_input_devices();
+        for path in preferences::get_input_remote_controls() {
+            if ! devices.contains(&path) {
+                devices.push(path);
+            }
+        }
+        if devices.is_empty() {
+            grid.attach(&gtk::Label::new(Some("No input devices found.")), 0, 0, 1, 1);
+        }
+        for (row, path) in devices.into_iter().enumerate() {
+            let check_button = gtk::CheckButton::new_with_label(&device_name(&path.to_string_lossy()));
+            check_button.set_tooltip_text(Some(&path.to_string_lossy()));
+            check_button.set_active(preferences::get_input_remote_controls().contains(&path));
+            check_button.connect_toggled({
+                let c_w = control_window.clone();
+                let r_c_g = remote_controls_grid.clone();
+                move |c_b| {
+                    let mut paths = preferences::get_input_remote_controls();
+                    paths.retain(|p| *p != path);
+                    if c_b.get_active() {
+                        paths.push(path.clone());
+                    }
+                    preferences::set_input_remote_controls(paths, true);
+                    remote_control::update_input_remote_controls();
+                    fill_remote_controls_grid(&r_c_g, &c_w.get_frontend_ids());
+                }
+            });
+            grid.attach(&check_button, 0, row as i32, 1, 1);
+        }
+        grid
+    };
+    let _use_lirc_checkbutton = {
+        let checkbutton = menu_builder.get_object::<gtk::CheckButton>("use_lirc").unwrap();
+        checkbutton.set_active(preferences::get_use_lirc());
+        checkbutton.connect_toggled(|button| preferences::set_use_lirc(button.get_active(), true));
+        checkbutton
+    };
+    let _lirc_socket_entry = {
+        let entry = menu_builder.get_object::<gtk::Entry>("lirc_socket").unwrap();
+        entry.set_text(&preferences::get_lirc_socket().to_string_lossy());
+        entry.connect_changed(|e| preferences::set_lirc_socket(PathBuf::from(e.get_text().unwrap().as_str()), true));
+        entry
+    };
+    let key_bindings_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("key_bindings").unwrap();
+        fill_key_bindings_grid(&grid);
+        grid
+    };
+    let new_key_entry = menu_builder.get_object::<gtk::Entry>("new_key").unwrap();
+    let new_key_action_comboboxtext = {
+        let comboboxtext = menu_builder.get_object::<gtk::ComboBoxText>("new_key_action").unwrap();
+        append_action_names(&comboboxtext);
+        comboboxtext
+    };
+    let _add_key_binding_button = {
+        let button = menu_builder.get_object::<gtk::Button>("add_key_binding").unwrap();
+        button.connect_clicked({
+            let g = key_bindings_grid.clone();
+            let n_k_e = new_key_entry.clone();
+            let n_k_a_c = new_key_action_comboboxtext.clone();
+            move |_| {
+                let key_text = n_k_e.get_text().unwrap();
+                let (key_text, long_press) = match key_text.trim().strip_prefix("long ") {
+                    Some(key_text) => (key_text.to_string(), true),
+                    None => (key_text.to_string(), false),
+                };
+                let code = key_code(&key_text);
+                set_entry_warning(&n_k_e, if code.is_none() { Some("Not the name or number of a key.") } else { None });
+                let action_entry = get_combobox_entry(&n_k_a_c);
+                let action = action_entry.get_text().unwrap().parse::<Action>();
+                set_entry_warning(&action_entry, action.as_ref().err().map(|message| message.as_str()));
+                if let (Some(code), Ok(action)) = (code, action) {
+                    let mut keymap = keymap::get_keymap();
+                    bind_key(&mut keymap, code, long_press, action);
+                    keymap::set_keymap(keymap, true);
+                    n_k_e.set_text("");
+                    action_entry.set_text("");
+                    fill_key_bindings_grid(&g);
+                }
+            }
+        });
+        button
+    };
+    let _restore_default_key_bindings_button = {
+        let button = menu_builder.get_object::<gtk::Button>("restore_default_key_bindings").unwrap();
+        button.connect_clicked({
+            let g = key_bindings_grid.clone();
+            move |_| {
+                keymap::set_keymap(Keymap::default(), true);
+                fill_key_bindings_grid(&g);
+            }
+        });
+        button
+    };
+    let _repeat_settings_grid = {
+        let grid = menu_builder.get_object::<gtk::Grid>("repeat_settings").unwrap();
+        let settings = preferences::get_remote_control_repeat();
+        let rows: [(&str, u32, f64, fn(&mut RepeatSettings, u32)); 5] = [
+            ("Delay before volume and channel keys repeat (ms)", settings.delay, 5000.0, |s, v| s.delay = v),
+            ("Time between the first repeats (ms)", settings.interval, 5000.0, |s, v| s.interval = v),
+            ("Speed up with each repeat (%)", settings.acceleration, 100.0, |s, v| s.acceleration = v),
+            ("Shortest time between repeats (ms)", settings.minimum_interval, 5000.0, |s, v| s.minimum_interval = v),
+            ("Time held for a long press (ms)", settings.long_press_time, 5000.0, |s, v| s.long_press_time = v),
+        ];
+        for (row, (text, value, maximum, set)) in rows.iter().enumerate() {
+            let label = gtk::Label::new(Some(text));
+            label.set_halign(gtk::Align::Start);
+            let spin_button = gtk::SpinButton::new_with_range(0.0, *maximum, 10.0);
+            spin_button.set_value(*value as f64);
+            spin_button.connect_value_changed({
+                let set = *set;
+                move |s_b| {
+                    let mut settings = preferences::get_remote_control_repeat();
+                    set(&mut settings, s_b.get_value_as_int() as u32);
+                    preferences::set_remote_control_repeat(settings, true);
+                }
+            });
+            grid.attach(&label, 0, row as i32, 1, 1);
+            grid.attach(&spin_button, 1, row as i32, 1, 1);
+        }
+        grid
+    };
     let preferences_dialog = {
         let window = menu_builder.get_object::<gtk::Window>("preferences_dialog").unwrap();
         window.set_transient_for(Some(&control_window.window));
@@ -105,7 +531,7 @@ fn create(control_window: &ControlWindow) -> gtk::Window {
 }
 
 /// Display a preferences dialog in a non-modal way, but only if one is not already being displayed.
-pub fn present(control_window: &ControlWindow) {
+pub fn present(control_window: &Rc<ControlWindow>) {
     if let Ok(active) = PREFERENCES.lock() {
         if ! active.get() {
             let dialog = create(control_window);
@@ -0,0 +1,269 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The program map, the PMT, of a channel.
+//!
+//! tsdemux does not pass on the audio type of ISO 639 language descriptors nor the
+//! subtitling and teletext descriptors, so the descriptors of the PMT sections posted on
+//! the bus are decoded here to get at them, as are the logical channel numbers of the NIT.
+
+const ISO_639_LANGUAGE_DESCRIPTOR: u8 = 0x0a;
+const TELETEXT_DESCRIPTOR: u8 = 0x56;
+const SUBTITLING_DESCRIPTOR: u8 = 0x59;
+const AC3_DESCRIPTOR: u8 = 0x6a;
+const ENHANCED_AC3_DESCRIPTOR: u8 = 0x7a;
+const AAC_DESCRIPTOR: u8 = 0x7c;
+const LOGICAL_CHANNEL_DESCRIPTOR: u8 = 0x83;
+
+/// The language of a stream as given by an ISO 639 language descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Language {
+    pub code: String,
+    pub audio_type: u8,
+}
+
+/// An entry in a DVB subtitling descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subtitling {
+    pub code: String,
+    pub subtitling_type: u8,
+    pub composition_page_id: u16,
+    pub ancillary_page_id: u16,
+}
+
+impl Subtitling {
+    /// Subtitling types 0x20 to 0x25 are the "for the hard of hearing" ones.
+    pub fn is_hearing_impaired(&self) -> bool {
+        self.subtitling_type >= 0x20 && self.subtitling_type <= 0x25
+    }
+}
+
+/// An entry in a teletext descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TeletextPage {
+    pub code: String,
+    pub teletext_type: u8,
+    pub magazine: u8,
+    pub page: u8,
+}
+
+impl TeletextPage {
+    /// The page number as the user sees it, magazine 0 is shown as 8.
+    pub fn page_number(&self) -> u16 {
+        let magazine = if self.magazine == 0 { 8 } else { self.magazine as u16 };
+        magazine * 100 + (self.page >> 4) as u16 * 10 + (self.page & 0x0f) as u16
+    }
+
+    pub fn is_subtitles(&self) -> bool {
+        self.teletext_type == 0x02 || self.teletext_type == 0x05
+    }
+
+    pub fn is_hearing_impaired(&self) -> bool {
+        self.teletext_type == 0x05
+    }
+}
+
+/// An entry in the elementary stream loop of the PMT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ElementaryStream {
+    pub stream_type: u8,
+    pub pid: u16,
+    pub languages: Vec<Language>,
+    pub subtitling: Vec<Subtitling>,
+    pub teletext_pages: Vec<TeletextPage>,
+    pub codec: Option<&'static str>,
+}
+
+impl ElementaryStream {
+    pub fn new(stream_type: u8, pid: u16) -> ElementaryStream {
+        ElementaryStream { stream_type, pid, ..Default::default() }
+    }
+
+    /// Add what a descriptor of the stream says about it. The descriptor is as in the
+    /// PMT: the tag, the length, and the body.
+    pub fn add_descriptor(&mut self, descriptor: &[u8]) {
+        if descriptor.len() < 2 || descriptor.len() < 2 + descriptor[1] as usize { return; }
+        let body = &descriptor[2..2 + descriptor[1] as usize];
+        match descriptor[0] {
+            ISO_639_LANGUAGE_DESCRIPTOR => {
+                self.languages.extend(body.chunks_exact(4).map(|c| Language { code: language_code(c), audio_type: c[3] }));
+            },
+            SUBTITLING_DESCRIPTOR => {
+                self.subtitling.extend(body.chunks_exact(8).map(|c| Subtitling {
+                    code: language_code(c),
+                    subtitling_type: c[3],
+                    composition_page_id: (c[4] as u16) << 8 | c[5] as u16,
+                    ancillary_page_id: (c[6] as u16) << 8 | c[7] as u16,
+                }));
+            },
+            TELETEXT_DESCRIPTOR => {
+                self.teletext_pages.extend(body.chunks_exact(5).map(|c| TeletextPage {
+                    code: language_code(c),
+                    teletext_type: c[3] >> 3,
+                    magazine: c[3] & 0x07,
+                    page: c[4],
+                }));
+            },
+            AC3_DESCRIPTOR => self.codec = Some("AC-3"),
+            ENHANCED_AC3_DESCRIPTOR => self.codec = Some("E-AC-3"),
+            AAC_DESCRIPTOR => self.codec = Some("AAC"),
+            _ => {},
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        match self.stream_type {
+            0x01 | 0x02 | 0x10 | 0x1b | 0x24 => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_audio(&self) -> bool {
+        match self.stream_type {
+            0x03 | 0x04 | 0x0f | 0x11 | 0x81 => true,
+            0x06 => self.codec.is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn is_dvb_subtitles(&self) -> bool {
+        self.stream_type == 0x06 && ! self.subtitling.is_empty()
+    }
+
+    pub fn is_teletext(&self) -> bool {
+        self.stream_type == 0x06 && ! self.teletext_pages.is_empty()
+    }
+
+    /// The ISO 639 language of the stream, if there is one.
+    pub fn language(&self) -> Option<&Language> {
+        self.languages.first()
+    }
+}
+
+/// The program map of a single service.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramMap {
+    pub program_number: u16,
+    pub streams: Vec<ElementaryStream>,
+}
+
+impl ProgramMap {
+    /// The audio streams, in the order that tsdemux, and so playbin, presents them.
+    pub fn audio_streams(&self) -> Vec<&ElementaryStream> {
+        self.streams.iter().filter(|s| s.is_audio()).collect()
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.streams.iter().any(|s| s.is_video())
+    }
+
+    /// The PID of the teletext stream, if there is one.
+    pub fn teletext_pid(&self) -> Option<u16> {
+        self.streams.iter().find(|s| s.is_teletext()).map(|s| s.pid)
+    }
+}
+
+fn language_code(data: &[u8]) -> String {
+    String::from_utf8_lossy(&data[0..3]).to_string()
+}
+
+/// The logical channel numbers of the visible services, by service ID, in a descriptor of
+/// a transport stream in the NIT, none if it is not a logical channel descriptor.
+///
+/// The logical channel descriptor is a private descriptor, this is the form used by
+/// EACEM, Freeview, and many others: a service ID, a visible flag, and a ten bit number.
+pub fn logical_channel_numbers(descriptor: &[u8]) -> Vec<(u16, u16)> {
+    if descriptor.len() < 2 || descriptor[0] != LOGICAL_CHANNEL_DESCRIPTOR { return vec![]; }
+    let length = (descriptor[1] as usize).min(descriptor.len() - 2);
+    descriptor[2..2 + length].chunks_exact(4)
+        .filter(|c| c[2] & 0x80 != 0)
+        .map(|c| ((c[0] as u16) << 8 | c[1] as u16, (c[2] as u16 & 0x03) << 8 | c[3] as u16))
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// The program map of a typical UK DVB-T service with audio description, DVB subtitles
+    /// and teletext subtitles, as given by the descriptors of its PMT.
+    pub fn create_test_program_map() -> ProgramMap {
+        let stream = |stream_type, pid, descriptors: &[&[u8]]| {
+            let mut stream = ElementaryStream::new(stream_type, pid);
+            for descriptor in descriptors {
+                stream.add_descriptor(descriptor);
+            }
+            stream
+        };
+        ProgramMap {
+            program_number: 0x1044,
+            streams: vec![
+                stream(0x02, 0x65, &[]),
+                stream(0x03, 0x66, &[&[0x0a, 0x04, b'e', b'n', b'g', 0x01]]),
+                stream(0x06, 0x6a, &[&[0x0a, 0x04, b'e', b'n', b'g', 0x03], &[0x6a, 0x01, 0x00]]),
+                stream(0x06, 0x98, &[&[0x59, 0x08, b'e', b'n', b'g', 0x20, 0x00, 0x01, 0x00, 0x01]]),
+                stream(0x06, 0x69, &[&[0x56, 0x0a, b'e', b'n', b'g', 0x09, 0x00, b'e', b'n', b'g', 0x10, 0x88]]),
+            ],
+        }
+    }
+
+    #[test]
+    fn streams_are_described_by_their_descriptors() {
+        let program_map = create_test_program_map();
+        assert_eq!(program_map.streams.len(), 5);
+        assert!(program_map.has_video());
+        let audio = program_map.audio_streams();
+        assert_eq!(audio.len(), 2);
+        assert_eq!(audio[0].language().unwrap(), &Language { code: "eng".to_string(), audio_type: 1 });
+        assert_eq!(audio[1].language().unwrap().audio_type, 3);
+        assert_eq!(audio[1].codec, Some("AC-3"));
+        assert!(program_map.streams[3].is_dvb_subtitles());
+        assert!(program_map.streams[3].subtitling[0].is_hearing_impaired());
+        assert!(program_map.streams[4].is_teletext());
+        assert_eq!(program_map.teletext_pid(), Some(0x69));
+        let pages = &program_map.streams[4].teletext_pages;
+        assert_eq!(pages[0].page_number(), 100);
+        assert!(! pages[0].is_subtitles());
+        assert_eq!(pages[1].page_number(), 888);
+        assert!(pages[1].is_subtitles());
+    }
+
+    #[test]
+    fn truncated_descriptors_are_ignored() {
+        let mut stream = ElementaryStream::new(0x06, 0x98);
+        stream.add_descriptor(&[0x59, 0x08, b'e', b'n', b'g', 0x20]);
+        stream.add_descriptor(&[0x59]);
+        assert!(! stream.is_dvb_subtitles());
+    }
+
+    #[test]
+    fn logical_channel_numbers_are_those_of_visible_services() {
+        let descriptor = [LOGICAL_CHANNEL_DESCRIPTOR, 12, 0x10, 0x44, 0xfc, 0x01, 0x10, 0xbf, 0xfc, 0x65, 0x10, 0xc0, 0x7c, 0x66];
+        assert_eq!(logical_channel_numbers(&descriptor), vec![(0x1044, 1), (0x10bf, 101)]);
+    }
+
+    #[test]
+    fn other_and_truncated_descriptors_have_no_logical_channel_numbers() {
+        assert_eq!(logical_channel_numbers(&[0x41, 0x03, 0x10, 0x44, 0x01]), vec![]);
+        assert_eq!(logical_channel_numbers(&[LOGICAL_CHANNEL_DESCRIPTOR, 8, 0x10, 0x44, 0xfc, 0x01, 0x10]), vec![(0x1044, 1)]);
+        assert_eq!(logical_channel_numbers(&[LOGICAL_CHANNEL_DESCRIPTOR]), vec![]);
+    }
+}
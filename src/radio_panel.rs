@@ -0,0 +1,98 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2017–2019  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Local, Utc};
+
+use glib;
+use gtk;
+use gtk::prelude::*;
+
+use crate::osd::{programme_progress, programme_text};
+use crate::epg_manager::NowNext;
+
+/// The compact layout of a frontend window showing a radio channel: the station with the
+/// programmes on now and next and, optionally, the radio text broadcast with the audio.
+#[derive(Debug)]
+pub struct RadioPanel {
+    pub widget: gtk::Box, // FrontendWindow puts this in place of, or below, the video.
+    station_label: gtk::Label,
+    now_label: gtk::Label,
+    progress_bar: gtk::ProgressBar,
+    next_label: gtk::Label,
+    text_label: gtk::Label,
+}
+
+impl RadioPanel {
+    pub fn new() -> RadioPanel {
+        let create_label = || {
+            let label = gtk::Label::new(None);
+            label.set_line_wrap(true);
+            label.set_justify(gtk::Justification::Center);
+            label
+        };
+        let station_label = create_label();
+        let now_label = create_label();
+        let progress_bar = gtk::ProgressBar::new();
+        progress_bar.set_no_show_all(true);
+        let next_label = create_label();
+        let text_label = create_label();
+        text_label.set_no_show_all(true);
+        let widget = {
+            let w = gtk::Box::new(gtk::Orientation::Vertical, 6);
+            w.set_valign(gtk::Align::Center);
+            w.set_border_width(12);
+            w.pack_start(&station_label, false, false, 0);
+            w.pack_start(&now_label, false, false, 0);
+            w.pack_start(&progress_bar, false, false, 0);
+            w.pack_start(&next_label, false, false, 0);
+            w.pack_start(&text_label, false, false, 0);
+            w.show_all();
+            w.set_no_show_all(true);
+            w.hide();
+            w
+        };
+        RadioPanel {
+            widget,
+            station_label,
+            now_label,
+            progress_bar,
+            next_label,
+            text_label,
+        }
+    }
+
+    /// Show the station and what is on, with the radio text if there is some to show.
+    pub fn update(&self, station: &str, now_next: &NowNext, time: DateTime<Utc>, radio_text: Option<&str>) {
+        self.station_label.set_markup(&format!("<span size=\"xx-large\" weight=\"bold\">{}</span>", glib::markup_escape_text(station)));
+        self.now_label.set_text(&programme_text("Now", now_next.now.as_ref(), &Local));
+        match now_next.now.as_ref().and_then(|programme| programme_progress(programme, time)) {
+            Some(fraction) => {
+                self.progress_bar.set_fraction(fraction);
+                self.progress_bar.show();
+            },
+            None => self.progress_bar.hide(),
+        }
+        self.next_label.set_text(&programme_text("Next", now_next.next.as_ref(), &Local));
+        let text = radio_text.unwrap_or("");
+        self.text_label.set_text(text);
+        self.text_label.set_visible(! text.is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The RDS radio text broadcast with the sound of a radio channel.
+//!
+//! Stations carry the RDS data of their FM service in the ancillary data at the end of each
+//! MPEG audio frame as UECP messages, with the bytes in reverse order. The radio text
+//! messages are picked out here on their way from mpegaudioparse to the audio decoder.
+
+/// The byte after the ancillary data that marks it as carrying RDS data.
+const RDS_MARKER: u8 = 0xfd;
+
+/// The UECP message start and stop bytes, and the byte that starts a stuffed byte.
+const START: u8 = 0xfe;
+const STOP: u8 = 0xff;
+const STUFFING: u8 = 0xfd;
+
+/// The message element code of a radio text message.
+const RADIO_TEXT: u8 = 0x0a;
+
+/// The longest UECP message: the address, sequence counter and length, a message of up
+/// to 255 bytes, and the CRC.
+const MAXIMUM_MESSAGE_LENGTH: usize = 4 + 255 + 2;
+
+/// Collects the UECP messages of the frames of an MPEG audio stream, a message can be split
+/// across frames.
+#[derive(Debug, Default)]
+pub struct RadioTextDecoder {
+    message: Vec<u8>,
+    receiving: bool,
+    stuffing: bool,
+}
+
+impl RadioTextDecoder {
+    pub fn new() -> RadioTextDecoder {
+        Default::default()
+    }
+
+    /// Process an MPEG audio frame, returning the text of the last radio text message it
+    /// completes, if any.
+    pub fn push_frame(&mut self, frame: &[u8]) -> Option<String> {
+        let n = frame.len();
+        // The last byte of the frame is not part of the RDS data, the marker and the
+        // length of the data come before it.
+        if n < 3 || frame[n - 2] != RDS_MARKER { return None; }
+        let length = frame[n - 3] as usize;
+        if length + 3 > n { return None; }
+        let mut text = None;
+        for &byte in frame[n - 3 - length..n - 3].iter().rev() {
+            if let Some(t) = self.push_byte(byte) {
+                text = Some(t);
+            }
+        }
+        text
+    }
+
+    fn push_byte(&mut self, byte: u8) -> Option<String> {
+        if byte == START {
+            self.message.clear();
+            self.receiving = true;
+            self.stuffing = false;
+            return None;
+        }
+        if ! self.receiving { return None; }
+        if byte == STOP {
+            self.receiving = false;
+            return radio_text(&self.message);
+        }
+        if byte == STUFFING && ! self.stuffing {
+            self.stuffing = true;
+            return None;
+        }
+        let byte = if self.stuffing {
+            self.stuffing = false;
+            match byte {
+                0x00 => 0xfd,
+                0x01 => 0xfe,
+                0x02 => 0xff,
+                _ => byte,
+            }
+        } else {
+            byte
+        };
+        self.message.push(byte);
+        if self.message.len() > MAXIMUM_MESSAGE_LENGTH {
+            self.receiving = false;
+        }
+        None
+    }
+}
+
+/// Return the text of a UECP message, without the start and stop bytes, if it is a radio
+/// text message. The message is the two address bytes, the sequence counter, the length
+/// of the message element, the message element, and the CRC. A radio text message element
+/// is the element code, the data set and programme service numbers, the length of the
+/// rest, a byte of flags, and the text.
+fn radio_text(message: &[u8]) -> Option<String> {
+    let element_length = *message.get(3)? as usize;
+    let element = message.get(4..4 + element_length)?;
+    if element.len() < 4 || element[0] != RADIO_TEXT { return None; }
+    let text = element.get(5..4 + element[3] as usize).unwrap_or(&[]);
+    // The text is padded with spaces, or ended with a carriage return.
+    let text = text.iter().take_while(|&&c| c != 0x0d).filter_map(|&c| rds_character(c)).collect::<String>();
+    Some(text.trim_end().to_string())
+}
+
+/// The letters of the RDS character set, EN 50067 annex E, from 0x80 to 0x9f.
+const LOWER_CASE_LETTERS: &str = "áàéèíìóòúùÑÇŞß¡Ĳâäêëîïôöûüñçşğıĳ";
+
+/// The letters of the RDS character set from 0xc0 to 0xdf.
+const UPPER_CASE_LETTERS: &str = "ÁÀÉÈÍÌÓÒÚÙŘČŠŽĐĿÂÄÊËÎÏÔÖÛÜřčšžđŀ";
+
+/// Return the character of an RDS character code, the codes that are not letters or the
+/// ASCII punctuation are dropped.
+fn rds_character(code: u8) -> Option<char> {
+    match code {
+        0x24 => Some('¤'),
+        0x20..=0x7d => Some(code as char),
+        0x80..=0x9f => LOWER_CASE_LETTERS.chars().nth((code - 0x80) as usize),
+        0xc0..=0xdf => UPPER_CASE_LETTERS.chars().nth((code - 0xc0) as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A UECP radio text message, with the start and stop bytes, for some text.
+    fn radio_text_message(text: &[u8]) -> Vec<u8> {
+        let mut element = vec![RADIO_TEXT, 0x00, 0x00, (text.len() + 1) as u8, 0x00];
+        element.extend_from_slice(text);
+        let mut message = vec![START, 0x00, 0x00, 0x00, element.len() as u8];
+        message.extend(element);
+        message.extend_from_slice(&[0x12, 0x34, STOP]);
+        message
+    }
+
+    /// An MPEG audio frame with some RDS data in its ancillary data.
+    fn frame(rds_data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfd, 0x00, 0x00];
+        frame.extend(rds_data.iter().rev());
+        frame.extend_from_slice(&[rds_data.len() as u8, RDS_MARKER, 0x00]);
+        frame
+    }
+
+    #[test]
+    fn the_radio_text_of_a_frame_is_decoded() {
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&frame(&radio_text_message(b"Now playing: Blue Monday   "))), Some("Now playing: Blue Monday".to_string()));
+    }
+
+    #[test]
+    fn a_message_split_across_frames_is_decoded() {
+        let message = radio_text_message(b"The Archers\r");
+        let (first, second) = message.split_at(7);
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&frame(first)), None);
+        assert_eq!(decoder.push_frame(&frame(second)), Some("The Archers".to_string()));
+    }
+
+    #[test]
+    fn stuffed_bytes_are_unstuffed() {
+        let mut message = radio_text_message(b"abc");
+        // The CRC bytes contain the start, stop and stuffing bytes.
+        let crc = message.len() - 3;
+        message.splice(crc..crc + 2, vec![STUFFING, 0x00, STUFFING, 0x01, STUFFING, 0x02]);
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&frame(&message)), Some("abc".to_string()));
+    }
+
+    #[test]
+    fn a_frame_without_rds_data_has_no_radio_text() {
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&[0xff, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00]), None);
+        assert_eq!(decoder.push_frame(&[]), None);
+    }
+
+    #[test]
+    fn other_messages_have_no_radio_text() {
+        let mut message = radio_text_message(b"abc");
+        message[5] = 0x02; // The programme service name.
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&frame(&message)), None);
+    }
+
+    #[test]
+    fn rds_letters_are_decoded() {
+        let mut decoder = RadioTextDecoder::new();
+        assert_eq!(decoder.push_frame(&frame(&radio_text_message(&[b'C', 0x82, b'l', 0x97, 0x8d, 0xd7]))), Some("CélößÖ".to_string()));
+    }
+}
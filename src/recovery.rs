@@ -0,0 +1,237 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::cmp::min;
+use std::time::Duration;
+
+/// How to go about recovering from failures of the stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecoveryPolicy {
+    /// The number of consecutive failed restarts before giving up.
+    pub max_failures: u32,
+    /// The delay before the first restart, doubled for each following one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> RecoveryPolicy {
+        RecoveryPolicy {
+            max_failures: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What to do about a failure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// A restart is already scheduled, the failure is part of the same problem.
+    Ignore,
+    /// Restart the pipeline after the delay.
+    Retry { attempt: u32, delay: Duration },
+    /// There have been too many failures, tell the user.
+    GiveUp { failures: u32 },
+}
+
+/// Keeps track of the failures of a stream and decides when to restart the pipeline.
+///
+/// A read failure or bus error leads to a restart after a delay, the delay doubling
+/// with each restart that does not get data flowing again. Once data flows the
+/// stream is recovered and the failure count goes back to zero.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    policy: RecoveryPolicy,
+    failures: u32,
+    retry_pending: bool,
+}
+
+impl Recovery {
+    pub fn new(policy: RecoveryPolicy) -> Recovery {
+        Recovery { policy, failures: 0, retry_pending: false }
+    }
+
+    /// Forget about any failures, for when the channel is changed or the stream stopped.
+    pub fn reset(&mut self, policy: RecoveryPolicy) {
+        *self = Recovery::new(policy);
+    }
+
+    pub fn on_failure(&mut self) -> Action {
+        if self.retry_pending { return Action::Ignore; }
+        if self.failures >= self.policy.max_failures {
+            let failures = self.failures;
+            self.failures = 0;
+            return Action::GiveUp { failures };
+        }
+        self.failures += 1;
+        self.retry_pending = true;
+        let factor = 1u32.checked_shl(self.failures - 1).unwrap_or(u32::max_value());
+        let delay = self.policy.initial_delay.checked_mul(factor).unwrap_or(self.policy.max_delay);
+        Action::Retry { attempt: self.failures, delay: min(delay, self.policy.max_delay) }
+    }
+
+    /// The delay of a retry is over, returns false if the retry has been cancelled by a reset.
+    pub fn on_retry(&mut self) -> bool {
+        let pending = self.retry_pending;
+        self.retry_pending = false;
+        pending
+    }
+
+    /// Data is flowing, returns true if that ends a recovery.
+    pub fn on_data(&mut self) -> bool {
+        let recovering = self.is_recovering();
+        self.failures = 0;
+        recovering
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        self.failures > 0
+    }
+
+    pub fn get_max_failures(&self) -> u32 {
+        self.policy.max_failures
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// What a source does each time the pipeline is started.
+    #[derive(Clone, Copy, Debug)]
+    enum Behaviour {
+        Works,
+        /// Report a number of read failures in a burst.
+        Fails(u32),
+    }
+
+    /// A stand-in for dvbsrc that injects faults according to a script, one entry per
+    /// start of the pipeline.
+    struct FaultInjectingSource {
+        script: Vec<Behaviour>,
+        starts: usize,
+    }
+
+    /// What the engine would have done.
+    #[derive(Debug, Default, PartialEq)]
+    struct Outcome {
+        restarts: u32,
+        delays: Vec<Duration>,
+        recovered: u32,
+        gave_up: bool,
+    }
+
+    impl FaultInjectingSource {
+        fn new(script: &[Behaviour]) -> FaultInjectingSource {
+            FaultInjectingSource { script: script.to_vec(), starts: 0 }
+        }
+
+        /// Run the source, restarting as recovery decides, until it works or recovery
+        /// gives up.
+        fn run(&mut self, recovery: &mut Recovery) -> Outcome {
+            let mut outcome = Outcome::default();
+            loop {
+                let behaviour = self.script.get(self.starts).cloned().unwrap_or(Behaviour::Works);
+                self.starts += 1;
+                match behaviour {
+                    Behaviour::Works => {
+                        if recovery.on_data() { outcome.recovered += 1; }
+                        return outcome;
+                    },
+                    Behaviour::Fails(count) => {
+                        let mut retry = None;
+                        for _ in 0..count {
+                            match recovery.on_failure() {
+                                Action::Ignore => {},
+                                Action::Retry { delay, .. } => retry = Some(delay),
+                                Action::GiveUp { .. } => {
+                                    outcome.gave_up = true;
+                                    return outcome;
+                                },
+                            }
+                        }
+                        match retry {
+                            Some(delay) => {
+                                outcome.delays.push(delay);
+                                assert!(recovery.on_retry());
+                                outcome.restarts += 1;
+                            },
+                            None => panic!("A failure did not lead to a retry."),
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_working_source_needs_no_recovery() {
+        let mut recovery = Recovery::default();
+        let outcome = FaultInjectingSource::new(&[Behaviour::Works]).run(&mut recovery);
+        assert_eq!(outcome, Outcome::default());
+    }
+
+    #[test]
+    fn bursts_of_failures_cause_one_restart_each() {
+        let mut recovery = Recovery::default();
+        let outcome = FaultInjectingSource::new(&[Behaviour::Fails(10), Behaviour::Fails(3), Behaviour::Works]).run(&mut recovery);
+        assert_eq!(outcome.restarts, 2);
+        assert_eq!(outcome.recovered, 1);
+        assert!(! outcome.gave_up);
+        assert!(! recovery.is_recovering());
+    }
+
+    #[test]
+    fn delays_back_off_up_to_the_maximum() {
+        let mut recovery = Recovery::new(RecoveryPolicy { max_failures: 8, initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(10) });
+        let outcome = FaultInjectingSource::new(&[Behaviour::Fails(1); 6]).run(&mut recovery);
+        let seconds = outcome.delays.iter().map(|d| d.as_secs()).collect::<Vec<u64>>();
+        assert_eq!(seconds, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn gives_up_after_the_maximum_number_of_failures() {
+        let mut recovery = Recovery::new(RecoveryPolicy { max_failures: 3, ..Default::default() });
+        let outcome = FaultInjectingSource::new(&[Behaviour::Fails(2); 10]).run(&mut recovery);
+        assert_eq!(outcome.restarts, 3);
+        assert!(outcome.gave_up);
+    }
+
+    #[test]
+    fn recovery_resets_the_failure_count() {
+        let mut recovery = Recovery::new(RecoveryPolicy { max_failures: 2, ..Default::default() });
+        let mut source = FaultInjectingSource::new(&[Behaviour::Fails(1), Behaviour::Fails(1), Behaviour::Works, Behaviour::Fails(1), Behaviour::Fails(1), Behaviour::Works]);
+        assert_eq!(source.run(&mut recovery).recovered, 1);
+        let outcome = source.run(&mut recovery);
+        assert_eq!(outcome.restarts, 2);
+        assert!(! outcome.gave_up);
+    }
+
+    #[test]
+    fn reset_cancels_a_pending_retry() {
+        let mut recovery = Recovery::default();
+        assert_eq!(recovery.on_failure(), Action::Retry { attempt: 1, delay: Duration::from_secs(1) });
+        recovery.reset(RecoveryPolicy::default());
+        assert!(! recovery.on_retry());
+        assert!(! recovery.is_recovering());
+    }
+}
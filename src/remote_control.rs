@@ -19,7 +19,7 @@
  *  along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -30,6 +30,7 @@ use std::time::Duration;
 use glob::glob;
 use lazy_static::lazy_static;
 use libc;
+use log::{debug, error, info, warn};
 use nix::ioctl_write_int;
 use notify::{Watcher, RecursiveMode, RawEvent, op, raw_watcher};
 use regex::Regex;
@@ -37,12 +38,16 @@ use regex::Regex;
 use crate::control_window::Message;
 use crate::frontend_manager::FrontendId;
 use crate::input_event_codes;
+use crate::lirc;
+use crate::preferences;
+use crate::remote_input::event_time;
 
 #[derive(Debug)]
 pub struct RemoteControl {
+    pub name: String, // The by-path event device path if there is one, the key for the routing in the preferences.
     pub frontend_ids: Vec<FrontendId>,
-    pub lirc_path: PathBuf,
-    pub sys_rc_path: PathBuf,  // Cache this even though it is refindable.
+    pub lirc_path: Option<PathBuf>, // None for an input device that is not part of an adapter.
+    pub sys_rc_path: Option<PathBuf>,  // Cache this even though it is refindable.
     pub device_event_path: PathBuf,  // Cache this even though it is refindable.
     pub device_file: File,
 }
@@ -69,32 +74,51 @@ fn get_sys_path_from_lirc_path(lirc_path: &PathBuf) -> Result<PathBuf, String> {
     }
 }
 
-/// Name of the IR event file.
-///
-/// PC-TV 282e, PC-TV 292e and WinTV-soloHD create a remote control control file with
-/// event as the final component but WinTV-dualHD creates a remote control control file
-/// with event-ir as the final component.
-fn get_rc_event_file_final_component(base: &str) -> &'static str {
-    let extension = "-event-ir";
-    if Path::new(&(base.to_string() + extension)).exists() { extension }
-    else { "-event" }
+/// The directories in a directory with names that are a prefix followed by a number, in
+/// number order.
+fn numbered_children(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(directory).map_err(|e| format!("Cannot read {:?}: {}", directory, e))?;
+    let mut children = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            let number = name.strip_prefix(prefix)?.parse::<u32>().ok()?;
+            Some((number, entry.path()))
+        })
+        .filter(|(_, path)| path.is_dir())
+        .collect::<Vec<(u32, PathBuf)>>();
+    children.sort();
+    Ok(children.into_iter().map(|(_, path)| path).collect())
 }
 
-/// Create an /dev/inputs/by-path event `PathBuf` from the /sys/class/rc/rcY `PathBuf`.
+/// Find the event device of a remote control from its /sys/class/rc/rcY directory.
 ///
-/// This has been constructed from the data observed on Debian Sid.
-/// It is assumed that all Linux post 4.6 will be the same.
-fn create_event_path_from_sys_path(path: &PathBuf) -> PathBuf {
-    let components = path.components().map(|x| x.as_os_str().to_str().unwrap()).collect::<Vec<&str>>();
-    assert_eq!(components[0], "..");
-    assert_eq!(components[1], "..");
-    assert_eq!(components[components.len() -2], "rc");
-    let mut event_path_string = String::from("/dev/input/by-path/pci-");
-    event_path_string += components[4];
-    event_path_string += "-usb-0:";
-    event_path_string += components[components.len() - 3].split("-").collect::<Vec<&str>>()[1]; // TODO Seems overcomplicated.
-    event_path_string += get_rc_event_file_final_component(&event_path_string);
-    PathBuf::from(event_path_string)
+/// The kernel creates an input device for a remote control as a child of the rc device,
+/// input15 say, with the event device as its child, event15 say, so the device is
+/// /dev/input/event15. This is so whatever the bus the remote control is on.
+fn find_event_device_name(sys_rc_path: &Path) -> Result<String, String> {
+    for input_path in numbered_children(sys_rc_path, "input")? {
+        if let Some(event_path) = numbered_children(&input_path, "event")?.first() {
+            return Ok(event_path.file_name().unwrap().to_string_lossy().to_string());
+        }
+    }
+    Err(format!("There is no input*/event* device in {:?}.", sys_rc_path))
+}
+
+/// The path of an event device in /dev/input/by-path, if there is one, as that does not
+/// change when devices come and go, otherwise the path given.
+fn stable_event_path(event_path: &Path, by_path_directory: &Path) -> PathBuf {
+    let target = match event_path.canonicalize() {
+        Ok(target) => target,
+        Err(_) => return event_path.to_path_buf(),
+    };
+    let mut links = match fs::read_dir(by_path_directory) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+            .filter(|path| path.canonicalize().ok().as_ref() == Some(&target))
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => vec![],
+    };
+    links.sort();
+    links.into_iter().next().unwrap_or_else(|| event_path.to_path_buf())
 }
 
 /// Parse the dvb `PathBuf` entries in a `Vec` to return a `Vec` of `FrontendId`
@@ -124,6 +148,26 @@ fn find_frontends_for_remote_control(sys_rc_path: &PathBuf) -> Vec<FrontendId> {
 
 ioctl_write_int!(ioctl_eviocgrab, b'E', 0x90);
 
+const DEV_INPUT: &str = "/dev/input";
+
+/// How many times to look for the event device of a remote control, half a second apart.
+const EVENT_DEVICE_ATTEMPTS: u32 = 10;
+
+/// Open an event device and grab it so that its key presses go only to Me TV.
+fn open_event_device(device_event_path: &Path) -> Result<File, String> {
+    let device_file = match OpenOptions::new().read(true).open(device_event_path) {
+        Ok(d_f) => d_f,
+        Err(_) => return Err(format!("Cannot open the event stream {}", device_event_path.to_str().unwrap())),
+    };
+    unsafe {
+        match ioctl_eviocgrab(device_file.as_raw_fd(), 1) {
+            Ok(_) => {},
+            Err(e) => return Err(format!("Failed to apply grab to {:?}: {}", device_file, e)),
+        }
+    }
+    Ok(device_file)
+}
+
 impl RemoteControl {
     fn new(lirc_path: &PathBuf) -> Result<RemoteControl, String> {
         let sys_rc_path = match get_sys_path_from_lirc_path(lirc_path) {
@@ -131,74 +175,103 @@ impl RemoteControl {
             Err(e) => return Err(format!("Failed to get sys path for {:?}: {}", lirc_path, e)),
         };
         let frontend_ids = find_frontends_for_remote_control(&sys_rc_path);
-        let device_event_path= match sys_rc_path.read_link() {
-            Ok(path) => create_event_path_from_sys_path(&path),
-            Err(e) => return Err(format!("Could not read symbolic link for remote control: {}", e)),
-        };
-        while ! device_event_path.exists() {
-            // TODO Need to avoid an infinite loop here.
-            //   Is there a timeout value that makes sense for the file not going to be created?
+        // The event device may appear a little after the lirc device.
+        let mut attempts = 0;
+        let device_event_path = loop {
+            let event_path = find_event_device_name(&sys_rc_path).map(|name| Path::new(DEV_INPUT).join(name));
+            match event_path {
+                Ok(ref path) if path.exists() => break path.clone(),
+                _ if attempts < EVENT_DEVICE_ATTEMPTS => attempts += 1,
+                Ok(path) => return Err(format!("{:?} did not appear.", path)),
+                Err(e) => return Err(e),
+            }
             thread::sleep(Duration::from_millis(500));
-        }
-        let device_file = match OpenOptions::new().read(true).open(&device_event_path) {
-            Ok(d_f) => d_f,
-            Err(_) => return Err(format!("Cannot open the event stream {}", device_event_path.to_str().unwrap())),
         };
-        unsafe {
-            match ioctl_eviocgrab(device_file.as_raw_fd(), 1) {
-                Ok(_) => {},
-                Err(e) => return Err(format!("Failed to apply grab to {:?}", device_file)),
-            }
-        }
+        let device_file = open_event_device(&device_event_path)?;
         Ok(RemoteControl {
+            name: stable_event_path(&device_event_path, &Path::new(DEV_INPUT).join("by-path")).to_string_lossy().to_string(),
             frontend_ids,
-            lirc_path: lirc_path.to_path_buf(),
-            sys_rc_path: sys_rc_path.to_path_buf(),
+            lirc_path: Some(lirc_path.to_path_buf()),
+            sys_rc_path: Some(sys_rc_path.to_path_buf()),
             device_event_path,
             device_file,
         })
     }
+
+    /// A remote control that is an input device not part of an adapter, a USB infrared
+    /// receiver for example.
+    fn new_from_input_device(device_event_path: &Path) -> Result<RemoteControl, String> {
+        let device_file = open_event_device(device_event_path)?;
+        Ok(RemoteControl {
+            name: device_event_path.to_string_lossy().to_string(),
+            frontend_ids: vec![],
+            lirc_path: None,
+            sys_rc_path: None,
+            device_event_path: device_event_path.to_path_buf(),
+            device_file,
+        })
+    }
 }
 
-/// A keystroke intended for a given frontend for use in sending messages between the
-/// remote controller daemon and the GUI.
+/// A keystroke intended for a given frontend, or for the focused frontend window if there
+/// is no frontend, for use in sending messages between the remote controller daemon and
+/// the GUI.
 #[derive(Clone, Debug)]
 pub struct TargettedKeystroke {
-    pub frontend_id: FrontendId, // Used in control_window
+    pub frontend_id: Option<FrontendId>, // Used in control_window
     pub keystroke: u32, // Used in control_window
     pub value: u32, // Used in control_window
+    pub time: u64, // Milliseconds, used in control_window_button.rs for key repeat and long presses.
 }
 
 /// Process some remote control events.
 ///
 /// Find all the events posted for this device, and send messages to the GUI so that it
-/// can act on the data. .
-fn process_events_for_device(remote_control: &Arc<RemoteControl>, to_cw: &mut glib::Sender<Message>) {
+/// can act on the data. Return false if the device has gone, it was unplugged.
+fn process_events_for_device(remote_control: &Arc<RemoteControl>, to_cw: &mut glib::Sender<Message>) -> bool {
     // TODO is it reasonable to assume less than 64 events?
     let buffer = [libc::input_event{time: libc::timeval{tv_sec: 0, tv_usec: 0}, type_: 0, code: 0, value: 0}; 64];
     let item_size = std::mem::size_of::<libc::input_event>();
     let rc = unsafe {
         libc::read(remote_control.device_file.as_raw_fd(), buffer.as_ptr() as *mut libc::c_void, item_size * 64)
     };
+    if rc < 0 {
+        return std::io::Error::last_os_error().raw_os_error() != Some(libc::ENODEV);
+    }
     if rc > 0 {
+        let frontend_id = preferences::get_remote_control_routing(&remote_control.name).target(&remote_control.frontend_ids);
         let event_count = rc as usize / item_size;
         assert_eq!(item_size * event_count, rc as usize);
         for i in 0..event_count {
             let item = buffer[i];
             if item.type_ == input_event_codes::EV_KEY as u16 {
                 to_cw.send(Message::TargettedKeystrokeReceived {
-                    tk: TargettedKeystroke { frontend_id: remote_control.frontend_ids[0].clone(), keystroke: item.code as u32, value: item.value as u32 },
+                    tk: TargettedKeystroke { frontend_id: frontend_id.clone(), keystroke: item.code as u32, value: item.value as u32, time: event_time(&item) },
                 }).unwrap();
             }
         }
     }
+    true
+}
+
+/// Whether the poll of a device says it is no longer there.
+fn is_device_gone(revents: libc::c_short) -> bool {
+    revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0
+}
+
+/// Remove a remote control whose device has gone. An input device chosen in the preferences
+/// is added again by `update_input_remote_controls` when it comes back.
+fn remove_gone_remote_control(remote_control: &Arc<RemoteControl>) {
+    info!("Remote control {} has gone.", remote_control.name);
+    match REMOTES.lock() {
+        Ok(mut data) => data.retain(|r_c| ! Arc::ptr_eq(r_c, remote_control)),
+        Err(_) => panic!("Failed to lock REMOTES for removal."),
+    }
 }
 
 /// The function that becomes the remote control event listener.
 pub fn rc_event_listener(mut to_cw: glib::Sender<Message>) {
     loop {
-        // TODO What happens if a new adapter is inserted or an existing remote removed
-        //   before a remote control event happens.
         let remote_controls = match REMOTES.lock() {
             Ok(data) => data.iter().map(|x| x.clone()).collect::<Vec<Arc<RemoteControl>>>(),
             Err(_) => vec![],
@@ -206,17 +279,29 @@ pub fn rc_event_listener(mut to_cw: glib::Sender<Message>) {
         let mut pollfds = remote_controls.iter().map(|device| {
             libc::pollfd{fd: device.device_file.as_raw_fd(), events: libc::POLLIN, revents: 0}
         }).collect::<Vec<libc::pollfd>>();
+        // Wait at most a second for events so that remote controls added or removed are
+        // picked up, and a removed one released, promptly.
         if pollfds.len() > 0 {
-            unsafe {
-                // TODO Switch this to not being fully blocking but instead to have a timeout to allow a remote control refresh?
-                let count = libc::poll(pollfds.as_mut_ptr(), pollfds.len() as u64, -1);
-                assert!(count > 0);
-                for i in 0..pollfds.len() {
-                    if pollfds[i].revents != 0 {
-                        process_events_for_device(&remote_controls[i], &mut to_cw);
-                    }
+            let count = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as u64, 1000) };
+            if count < 0 {
+                let error = std::io::Error::last_os_error();
+                // A signal interrupting the wait is not a problem, just wait again.
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    error!("Could not wait for remote control events: {}", error);
+                    thread::sleep(Duration::from_secs(1));
                 }
+                continue;
             }
+            for i in 0..pollfds.len() {
+                // A device that has gone makes poll return straight away, so it must be
+                // removed rather than polled again.
+                let revents = pollfds[i].revents;
+                if is_device_gone(revents) || (revents != 0 && ! process_events_for_device(&remote_controls[i], &mut to_cw)) {
+                    remove_gone_remote_control(&remote_controls[i]);
+                }
+            }
+        } else {
+            thread::sleep(Duration::from_secs(1));
         }
     }
 }
@@ -234,16 +319,16 @@ fn add_already_installed_remotes() {
             lirc_devices.iter()
                 .filter(|lirc_path| match get_sys_path_from_lirc_path(lirc_path) {
                     Ok(rc_path) => true,
-                    Err(e) => { println!("get_sys_path_from_lirc_path failed on {:?}", lirc_path); false },
+                    Err(e) => { warn!("get_sys_path_from_lirc_path failed on {:?}: {}", lirc_path, e); false },
                 })
                 .map(|lirc_path| {
                     // TODO deal with -event → -event-ir name change in Linux.
-                    println!("###### {:?}", lirc_path);
+                    debug!("Adding remote control {:?}", lirc_path);
                     let r_c = match RemoteControl::new(lirc_path) {
                         Ok(rc) => Some(rc),
-                        Err(e) => { println!("Failed to create a remote control: {:?}.\nEither the dynamic filename is wrong or maybe the user is not in group input.", e); None},
+                        Err(e) => { warn!("Failed to create a remote control: {:?}. Either the dynamic filename is wrong or maybe the user is not in group input.", e); None},
                     };
-                    println!("====== {:?}", r_c);
+                    debug!("Created remote control {:?}", r_c);
                     r_c
                 })
                 .for_each(|rc|{
@@ -267,7 +352,7 @@ fn add_appeared_remote_control(lirc_path: PathBuf) {
             Ok(mut data) => {
                 match RemoteControl::new(&lirc_path) {
                     Ok(rc) => data.push(Arc::new(rc)),
-                    Err(e) => println!("Error adding a remote control: {}\nPerhaps the user is not in group input?", e),
+                    Err(e) => warn!("Error adding a remote control: {}. Perhaps the user is not in group input?", e),
                 }
             },
             Err(_) => panic!("Failed to lock REMOTES for addition."),
@@ -280,22 +365,68 @@ fn remove_disappeared_remote_control(lirc_path: PathBuf) {
     match REMOTES.lock() {
         Ok(mut data) => {
             //  TODO ensure that this properly tidies up all the things such as EVIOCGRAB.
-            data.retain(|d| d.lirc_path != lirc_path)
+            data.retain(|d| d.lirc_path.as_ref() != Some(&lirc_path))
         },
         Err(_) => panic!("Failed to lock REMOTES for removal."),
     };
 }
 
+/// The input devices that could be used as remote controls, by their stable paths.
+pub fn get_input_devices() -> Vec<PathBuf> { // Used in preferences_dialog.rs
+    match glob::glob("/dev/input/by-id/*-event-*") {
+        Ok(paths) => paths.filter_map(|x| x.ok()).collect(),
+        Err(e) => panic!("Glob failure: {}", e),
+    }
+}
+
+/// The names of the remote controls in use, including those of LIRC that have been used,
+/// the key for their routing in the preferences.
+pub fn get_remote_control_names() -> Vec<String> { // Used in preferences_dialog.rs
+    let mut names = match REMOTES.lock() {
+        Ok(data) => data.iter().map(|r_c| r_c.name.clone()).collect(),
+        Err(_) => vec![],
+    };
+    names.extend(lirc::get_remote_control_names());
+    names
+}
+
+/// Make the input devices used as remote controls, as opposed to those that are part of
+/// an adapter, those set in the preferences.
+pub fn update_input_remote_controls() { // Used in control_window.rs and preferences_dialog.rs
+    let wanted = preferences::get_input_remote_controls();
+    match REMOTES.lock() {
+        Ok(mut data) => {
+            data.retain(|r_c| r_c.lirc_path.is_some() || wanted.contains(&r_c.device_event_path));
+            for path in wanted {
+                // A device that is unplugged is added when it comes back.
+                if ! data.iter().any(|r_c| r_c.device_event_path == path) && path.exists() {
+                    debug!("Adding remote control {:?}", path);
+                    match RemoteControl::new_from_input_device(&path) {
+                        Ok(rc) => data.push(Arc::new(rc)),
+                        Err(e) => warn!("Error adding a remote control: {}. Perhaps the user is not in group input?", e),
+                    }
+                }
+            }
+        },
+        Err(_) => panic!("Failed to lock REMOTES for update."),
+    }
+}
+
 /// The main daemon for remote control management.
 ///
 /// Add all remote controls already present. Set of the event listener as a separate daemon.
 /// Settle to listening for added and removed remote controls.
 pub fn run(to_cw: glib::Sender<Message>) {
     add_already_installed_remotes();
+    update_input_remote_controls();
     thread::spawn(|| rc_event_listener(to_cw));
     let (transmit_end, receive_end) = channel();
     let mut watcher = raw_watcher(transmit_end).unwrap();
     watcher.watch("/dev", RecursiveMode::NonRecursive).unwrap();
+    // The stable names of input devices appear in subdirectories of /dev/input.
+    if let Err(e) = watcher.watch(DEV_INPUT, RecursiveMode::Recursive) {
+        warn!("Could not watch {} for input devices coming back: {:?}", DEV_INPUT, e);
+    }
     loop {
         match receive_end.recv() {
             Ok(RawEvent { path: Some(path), op: Ok(op), cookie: _cookie }) => {
@@ -303,6 +434,8 @@ pub fn run(to_cw: glib::Sender<Message>) {
                     op::CREATE => {
                         if path.file_name().unwrap().to_str().unwrap().starts_with("lirc") {
                             add_appeared_remote_control(path);
+                        } else if path.starts_with(DEV_INPUT) {
+                            update_input_remote_controls();
                         }
                     },
                     op::REMOVE => {
@@ -313,8 +446,8 @@ pub fn run(to_cw: glib::Sender<Message>) {
                     _ => {},
                 }
             },
-            Ok(event) => println!("remote_control::run: broken event: {:?}", event),
-            Err(e) => println!("remote_control::run: watch error: {:?}", e),
+            Ok(event) => warn!("Broken event: {:?}", event),
+            Err(e) => error!("Watch error: {:?}", e),
         }
     }
 }
@@ -342,43 +475,106 @@ mod test {
         }
     }
 
-    fn create_rc_event_file_name(base: &str) -> String {
-        base.to_string() + get_rc_event_file_final_component(base)
+    /// Reproduce the sysfs tree of a remote control under `root`: the rc device at
+    /// `device_path` with its lirc and input children, and the /sys/class/rc link to it.
+    fn create_sys_rc_tree(root: &Path, device_path: &str, rc: &str, children: &[&str]) -> PathBuf {
+        let rc_path = root.join("devices").join(device_path).join("rc").join(rc);
+        fs::create_dir_all(&rc_path).unwrap();
+        fs::write(rc_path.join("protocols"), "rc-5 nec rc-6 jvc sony [lirc]\n").unwrap();
+        for child in children {
+            fs::create_dir_all(rc_path.join(child)).unwrap();
+            fs::write(rc_path.join(child).join("uevent"), "").unwrap();
+        }
+        let class_path = root.join("class/rc");
+        fs::create_dir_all(&class_path).unwrap();
+        let link_target = PathBuf::from("../../devices").join(device_path).join("rc").join(rc);
+        std::os::unix::fs::symlink(&link_target, class_path.join(rc)).unwrap();
+        class_path.join(rc)
     }
 
     #[test]
     fn rc0_on_anglides_debian_linux() {
-        assert_eq!(
-            create_event_path_from_sys_path(&PathBuf::from("../../devices/pci0000:00/0000:00:1d.7/usb4/4-5/4-5.2/4-5.2.4/4-5.2.4.1/4-5.2.4.1.1/4-5.2.4.1.1:1.0/rc/rc0")),
-            PathBuf::from(create_rc_event_file_name("/dev/input/by-path/pci-0000:00:1d.7-usb-0:5.2.4.1.1:1.0")));
+        // A USB receiver behind a chain of hubs.
+        let root = tempfile::tempdir().unwrap();
+        let sys_rc_path = create_sys_rc_tree(
+            root.path(),
+            "pci0000:00/0000:00:1d.7/usb4/4-5/4-5.2/4-5.2.4/4-5.2.4.1/4-5.2.4.1.1/4-5.2.4.1.1:1.0",
+            "rc0",
+            &["lirc0", "input21", "input21/event18"]);
+        assert_eq!(find_event_device_name(&sys_rc_path), Ok("event18".to_string()));
     }
 
     #[test]
-    fn rc0_on_lavaine_debian_linux() {
-        assert_eq!(
-            create_event_path_from_sys_path(&PathBuf::from("../../devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/rc/rc0")),
-            PathBuf::from(create_rc_event_file_name("/dev/input/by-path/pci-0000:00:14.0-usb-0:1:1.0")));
+    fn rc0_and_rc1_on_lavaine_debian_linux() {
+        let root = tempfile::tempdir().unwrap();
+        let rc0 = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0", "rc0",
+            &["lirc0", "input15", "input15/event12"]);
+        let rc1 = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:14.0/usb2/2-2/2-2:1.0", "rc1",
+            &["lirc1", "input16", "input16/event13"]);
+        assert_eq!(find_event_device_name(&rc0), Ok("event12".to_string()));
+        assert_eq!(find_event_device_name(&rc1), Ok("event13".to_string()));
     }
 
     #[test]
-    fn rc1_on_lavaine_debian_linux() {
-        assert_eq!(
-            create_event_path_from_sys_path(&PathBuf::from("../../devices/pci0000:00/0000:00:14.0/usb2/2-2/2-2:1.0/rc/rc1")),
-            PathBuf::from(create_rc_event_file_name("/dev/input/by-path/pci-0000:00:14.0-usb-0:2:1.0")));
+    fn rc0_and_rc1_on_lynet_debian_linux() {
+        let root = tempfile::tempdir().unwrap();
+        let rc0 = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0", "rc0",
+            &["lirc0", "input9", "input9/event6"]);
+        let rc1 = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:14.0/usb2/2-3/2-3:1.0", "rc1",
+            &["lirc1", "input10", "input10/event7"]);
+        assert_eq!(find_event_device_name(&rc0), Ok("event6".to_string()));
+        assert_eq!(find_event_device_name(&rc1), Ok("event7".to_string()));
     }
 
     #[test]
-    fn rc0_on_lynet_debian_linux() {
-        assert_eq!(
-            create_event_path_from_sys_path(&PathBuf::from("../../devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/rc/rc0")),
-            PathBuf::from(create_rc_event_file_name("/dev/input/by-path/pci-0000:00:14.0-usb-0:1:1.0")));
+    fn rc0_on_an_onboard_pci_card() {
+        // A PCIe card behind a root port, no USB in the path at all.
+        let root = tempfile::tempdir().unwrap();
+        let sys_rc_path = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:1c.0/0000:03:00.0", "rc0",
+            &["lirc0", "input7", "input7/event4"]);
+        assert_eq!(find_event_device_name(&sys_rc_path), Ok("event4".to_string()));
+    }
+
+    #[test]
+    fn rc0_on_a_platform_device() {
+        // A GPIO receiver on a single board computer, the IR mouse handler makes a mouse
+        // device as well as the event device.
+        let root = tempfile::tempdir().unwrap();
+        let sys_rc_path = create_sys_rc_tree(
+            root.path(), "platform/ir-receiver@12", "rc0",
+            &["lirc0", "input0", "input0/mouse0", "input0/event0"]);
+        assert_eq!(find_event_device_name(&sys_rc_path), Ok("event0".to_string()));
+    }
+
+    #[test]
+    fn rc0_with_no_input_device_yet() {
+        let root = tempfile::tempdir().unwrap();
+        let sys_rc_path = create_sys_rc_tree(
+            root.path(), "pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0", "rc0", &["lirc0"]);
+        assert!(find_event_device_name(&sys_rc_path).is_err());
+        fs::create_dir(sys_rc_path.join("input15")).unwrap();
+        assert!(find_event_device_name(&sys_rc_path).is_err());
+        fs::create_dir(sys_rc_path.join("input15/event12")).unwrap();
+        assert_eq!(find_event_device_name(&sys_rc_path), Ok("event12".to_string()));
     }
 
     #[test]
-    fn rc1_on_lynet_debian_linux() {
+    fn stable_event_path_is_the_by_path_link() {
+        let root = tempfile::tempdir().unwrap();
+        let by_path = root.path().join("by-path");
+        fs::create_dir(&by_path).unwrap();
+        fs::write(root.path().join("event12"), "").unwrap();
+        fs::write(root.path().join("event13"), "").unwrap();
+        std::os::unix::fs::symlink("../event12", by_path.join("pci-0000:00:14.0-usb-0:1:1.0-event-ir")).unwrap();
         assert_eq!(
-            create_event_path_from_sys_path(&PathBuf::from("../../devices/pci0000:00/0000:00:14.0/usb2/2-3/2-3:1.0/rc/rc1")),
-            PathBuf::from(create_rc_event_file_name("/dev/input/by-path/pci-0000:00:14.0-usb-0:3:1.0")));
+            stable_event_path(&root.path().join("event12"), &by_path),
+            by_path.join("pci-0000:00:14.0-usb-0:1:1.0-event-ir"));
+        assert_eq!(stable_event_path(&root.path().join("event13"), &by_path), root.path().join("event13"));
     }
 
     #[test]
@@ -403,5 +599,14 @@ mod test {
         assert_eq!(*result.get(0).unwrap(), FrontendId{adapter: 0, frontend: 0});
         assert_eq!(*result.get(1).unwrap(), FrontendId{adapter: 1, frontend: 0});
     }
+
+    #[test]
+    fn a_device_hung_up_or_in_error_has_gone() {
+        assert!(is_device_gone(libc::POLLHUP));
+        assert!(is_device_gone(libc::POLLIN | libc::POLLERR));
+        assert!(is_device_gone(libc::POLLNVAL));
+        assert!(! is_device_gone(libc::POLLIN));
+        assert!(! is_device_gone(0));
+    }
 }
 
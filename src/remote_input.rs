@@ -0,0 +1,275 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2017–2019  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Turning the press, repeat, and release events of remote control keys into actions.
+//! Holding down a volume or channel key repeats its action, faster the longer it is held,
+//! other keys do not repeat. A key with a long press action does that if held down and its
+//! usual action if not.
+
+use libc;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::keymap::{Action, Keymap};
+
+/// The values of key events.
+pub const KEY_RELEASE: u32 = 0;
+pub const KEY_PRESS: u32 = 1;
+pub const KEY_REPEAT: u32 = 2;
+
+/// How held keys behave, the times are in milliseconds.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RepeatSettings {
+    /// How long a key is held before its action repeats.
+    pub delay: u32,
+    /// The time between the first repeats.
+    pub interval: u32,
+    /// The percentage by which the time between repeats shrinks with each repeat.
+    pub acceleration: u32,
+    /// The shortest time between repeats.
+    pub minimum_interval: u32,
+    /// How long a key is held for its long press action.
+    pub long_press_time: u32,
+}
+
+impl Default for RepeatSettings {
+    fn default() -> Self {
+        RepeatSettings {
+            delay: 500,
+            interval: 250,
+            acceleration: 20,
+            minimum_interval: 50,
+            long_press_time: 800,
+        }
+    }
+}
+
+/// The time of an input event in milliseconds.
+pub fn event_time(event: &libc::input_event) -> u64 { // Used in remote_control.rs
+    event.time.tv_sec as u64 * 1000 + event.time.tv_usec as u64 / 1000
+}
+
+/// Whether holding down the key of an action repeats the action.
+fn is_repeated(action: Action) -> bool {
+    match action {
+        Action::ChannelUp | Action::ChannelDown | Action::VolumeUp | Action::VolumeDown => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+struct HeldKey {
+    code: u32,
+    pressed_at: u64,
+    next_repeat_at: u64,
+    interval: u32,
+    long_press_done: bool,
+}
+
+/// The state of the keys of a remote control: which key is held down and since when.
+#[derive(Debug, Default)]
+pub struct RemoteInput {
+    held: Option<HeldKey>,
+}
+
+impl RemoteInput {
+    pub fn new() -> RemoteInput {
+        RemoteInput { held: None }
+    }
+
+    /// Process a key event, returning the actions to perform.
+    pub fn process(&mut self, code: u32, value: u32, time: u64, keymap: &Keymap, settings: &RepeatSettings) -> Vec<Action> {
+        match value {
+            KEY_PRESS => {
+                // A key still held down when another is pressed has been let go of.
+                let mut actions = self.release(time, keymap, settings);
+                if keymap.get_long_press_action(code).is_none() {
+                    actions.extend(keymap.get_action(code));
+                }
+                self.held = Some(HeldKey {
+                    code,
+                    pressed_at: time,
+                    next_repeat_at: time + settings.delay as u64,
+                    interval: settings.interval,
+                    long_press_done: false,
+                });
+                actions
+            },
+            KEY_REPEAT => {
+                let held = match self.held {
+                    Some(ref mut held) if held.code == code => held,
+                    _ => return vec![],
+                };
+                if let Some(action) = keymap.get_long_press_action(code) {
+                    if ! held.long_press_done && time.saturating_sub(held.pressed_at) >= settings.long_press_time as u64 {
+                        held.long_press_done = true;
+                        return vec![action];
+                    }
+                    return vec![];
+                }
+                match keymap.get_action(code) {
+                    Some(action) if is_repeated(action) && time >= held.next_repeat_at => {
+                        held.next_repeat_at = time + held.interval as u64;
+                        held.interval = (held.interval * (100 - settings.acceleration.min(100)) / 100).max(settings.minimum_interval);
+                        vec![action]
+                    },
+                    _ => vec![],
+                }
+            },
+            KEY_RELEASE => match self.held {
+                Some(ref held) if held.code == code => self.release(time, keymap, settings),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Let go of the key held down, if there is one. A key with a long press action has
+    /// not acted yet unless it was held long enough for the long press action.
+    fn release(&mut self, time: u64, keymap: &Keymap, settings: &RepeatSettings) -> Vec<Action> {
+        let held = match self.held.take() {
+            Some(held) => held,
+            None => return vec![],
+        };
+        match keymap.get_long_press_action(held.code) {
+            Some(_) if held.long_press_done => vec![],
+            Some(action) if time.saturating_sub(held.pressed_at) >= settings.long_press_time as u64 => vec![action],
+            Some(_) => keymap.get_action(held.code).into_iter().collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::input_event_codes::*;
+
+    fn key_event(code: u32, value: u32, time: u64) -> libc::input_event {
+        libc::input_event {
+            time: libc::timeval { tv_sec: (time / 1000) as libc::time_t, tv_usec: (time % 1000 * 1000) as libc::suseconds_t },
+            type_: EV_KEY as u16,
+            code: code as u16,
+            value: value as i32,
+        }
+    }
+
+    /// A key pressed at a time, repeating every `period` milliseconds until released at
+    /// another time, as the kernel reports a key held down.
+    fn held_key(code: u32, from: u64, to: u64, period: u64) -> Vec<libc::input_event> {
+        let mut events = vec![key_event(code, KEY_PRESS, from)];
+        let mut time = from + period;
+        while time < to {
+            events.push(key_event(code, KEY_REPEAT, time));
+            time += period;
+        }
+        events.push(key_event(code, KEY_RELEASE, to));
+        events
+    }
+
+    fn actions_for(events: &[libc::input_event], keymap: &Keymap) -> Vec<Action> {
+        let settings = RepeatSettings::default();
+        let mut remote_input = RemoteInput::new();
+        events.iter()
+            .filter(|event| event.type_ == EV_KEY as u16)
+            .flat_map(|event| remote_input.process(event.code as u32, event.value as u32, event_time(event), keymap, &settings))
+            .collect()
+    }
+
+    fn keymap() -> Keymap {
+        let mut keymap = Keymap::new();
+        keymap.bind(KEY_VOLUMEUP, Action::VolumeUp);
+        keymap.bind(KEY_MUTE, Action::Mute);
+        keymap.bind(KEY_OK, Action::Fullscreen);
+        keymap.bind_long_press(KEY_OK, Action::Epg);
+        keymap.bind_long_press(KEY_INFO, Action::Epg);
+        keymap
+    }
+
+    #[test]
+    fn a_press_acts_once() {
+        let events = held_key(KEY_VOLUMEUP, 10_000, 10_100, 100);
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::VolumeUp]);
+        assert_eq!(actions_for(&held_key(KEY_RED, 10_000, 10_100, 100), &keymap()), vec![]);
+    }
+
+    #[test]
+    fn keys_that_do_not_repeat_act_once_however_long_held() {
+        let events = held_key(KEY_MUTE, 10_000, 13_000, 100);
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Mute]);
+    }
+
+    #[test]
+    fn held_volume_keys_repeat_faster_and_faster() {
+        // The kernel repeats every 10 milliseconds, the actions are at the first repeat on
+        // or after the press plus the delay then each shrinking interval.
+        let settings = RepeatSettings::default();
+        let keymap = keymap();
+        let mut remote_input = RemoteInput::new();
+        let times = held_key(KEY_VOLUMEUP, 10_000, 11_700, 10).iter()
+            .filter(|event| ! remote_input.process(event.code as u32, event.value as u32, event_time(event), &keymap, &settings).is_empty())
+            .map(|event| event_time(event) - 10_000)
+            .collect::<Vec<u64>>();
+        assert_eq!(times, vec![0, 500, 750, 950, 1110, 1240, 1350, 1440, 1510, 1570, 1620, 1670]);
+    }
+
+    #[test]
+    fn a_short_press_of_a_key_with_a_long_press_action_acts_on_release() {
+        let events = held_key(KEY_OK, 10_000, 10_300, 100);
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Fullscreen]);
+        assert_eq!(actions_for(&events[..events.len() - 1], &keymap()), vec![]);
+    }
+
+    #[test]
+    fn a_long_press_acts_once_while_held() {
+        let events = held_key(KEY_OK, 10_000, 12_000, 100);
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Epg]);
+        let events = held_key(KEY_OK, 10_000, 10_900, 100);
+        assert_eq!(actions_for(&events[..events.len() - 1], &keymap()), vec![Action::Epg]);
+    }
+
+    #[test]
+    fn a_long_press_without_repeats_acts_on_release() {
+        let events = vec![key_event(KEY_INFO, KEY_PRESS, 10_000), key_event(KEY_INFO, KEY_RELEASE, 11_000)];
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Epg]);
+        let events = vec![key_event(KEY_INFO, KEY_PRESS, 10_000), key_event(KEY_INFO, KEY_RELEASE, 10_200)];
+        assert_eq!(actions_for(&events, &keymap()), vec![]);
+    }
+
+    #[test]
+    fn pressing_another_key_lets_go_of_the_held_one() {
+        let events = vec![
+            key_event(KEY_OK, KEY_PRESS, 10_000),
+            key_event(KEY_MUTE, KEY_PRESS, 10_100),
+            key_event(KEY_OK, KEY_RELEASE, 10_200),
+            key_event(KEY_MUTE, KEY_RELEASE, 10_300),
+        ];
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Fullscreen, Action::Mute]);
+    }
+
+    #[test]
+    fn synchronisation_events_are_ignored() {
+        let mut events = held_key(KEY_MUTE, 10_000, 10_100, 100);
+        events.insert(1, libc::input_event { type_: EV_SYN as u16, code: 0, value: 0, ..key_event(0, 0, 10_000) });
+        assert_eq!(actions_for(&events, &keymap()), vec![Action::Mute]);
+    }
+}
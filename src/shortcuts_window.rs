@@ -0,0 +1,153 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2017–2019  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The help window listing the keyboard shortcuts of a frontend window, built from the
+//! table of shortcuts so the two cannot disagree.
+
+use std::cell::Cell;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use glib;
+use gtk;
+use gtk::prelude::*;
+
+use crate::keymap::{Action, KEYBOARD_SHORTCUTS, PAUSE_SHORTCUT};
+
+lazy_static! {
+    static ref SHORTCUTS: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+}
+
+/// The shortcuts not in the table of actions, as accelerator and title.
+const OTHER_SHORTCUTS: [(&str, &str); 5] = [
+    ("Escape", "Leave fullscreen"),
+    ("question", "Keyboard shortcuts"),
+    ("t", "Teletext"),
+    ("F1...F4", "Teletext red, green, yellow, and blue keys"),
+    ("Left Right Return", "Teletext navigation"),
+];
+
+fn shortcut_element(accelerator: &str, title: &str) -> String {
+    format!(r#"
+            <child>
+              <object class="GtkShortcutsShortcut">
+                <property name="visible">True</property>
+                <property name="accelerator">{}</property>
+                <property name="title">{}</property>
+              </object>
+            </child>"#, accelerator, glib::markup_escape_text(title))
+}
+
+/// The GtkBuilder description of the shortcuts window: the keys of each action together,
+/// the digits as a range. Pausing is only shown if it is possible.
+fn create_ui_description(can_pause: bool) -> String {
+    let mut actions = Vec::<Action>::new();
+    for (_, action) in KEYBOARD_SHORTCUTS.iter() {
+        if let Action::Digit(_) = action {
+            continue;
+        }
+        if ! actions.contains(action) {
+            actions.push(*action);
+        }
+    }
+    let mut watching = String::new();
+    for action in &actions {
+        let accelerator = KEYBOARD_SHORTCUTS.iter()
+            .filter(|(_, a)| a == action)
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let title = if *action == Action::Fullscreen { format!("{}, or double click", action.description()) } else { action.description() };
+        watching.push_str(&shortcut_element(&accelerator, &title));
+    }
+    if can_pause {
+        watching.push_str(&shortcut_element(PAUSE_SHORTCUT.0, &PAUSE_SHORTCUT.1.description()));
+    }
+    watching.push_str(&shortcut_element("0...9", &Action::Digit(0).description()));
+    let other = OTHER_SHORTCUTS.iter().map(|(accelerator, title)| shortcut_element(accelerator, title)).collect::<String>();
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<interface>
+  <object class="GtkShortcutsWindow" id="shortcuts_window">
+    <property name="modal">False</property>
+    <child>
+      <object class="GtkShortcutsSection">
+        <property name="visible">True</property>
+        <property name="section-name">frontend-window</property>
+        <child>
+          <object class="GtkShortcutsGroup">
+            <property name="visible">True</property>
+            <property name="title">Watching</property>{}
+          </object>
+        </child>
+        <child>
+          <object class="GtkShortcutsGroup">
+            <property name="visible">True</property>
+            <property name="title">Other</property>{}
+          </object>
+        </child>
+      </object>
+    </child>
+  </object>
+</interface>
+"#, watching, other)
+}
+
+/// Present the shortcuts window in a non-modal way, but only if one is not already displaying.
+pub fn present(parent: &gtk::Window, can_pause: bool) { // Used in control_window.rs and frontend_window.rs
+    if let Ok(active) = SHORTCUTS.lock() {
+        if ! active.get() {
+            let builder = gtk::Builder::new_from_string(&create_ui_description(can_pause));
+            let window = builder.get_object::<gtk::Window>("shortcuts_window").unwrap();
+            window.set_transient_for(Some(parent));
+            window.connect_destroy(move |_| {
+                if let Ok(active) = SHORTCUTS.lock() {
+                    active.set(false);
+                }
+            });
+            window.show();
+            active.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_keys_of_an_action_are_together() {
+        let description = create_ui_description(false);
+        assert!(description.contains("<property name=\"accelerator\">Up Page_Up</property>"));
+        assert!(description.contains("<property name=\"accelerator\">plus equal KP_Add</property>"));
+        assert!(description.contains("<property name=\"accelerator\">0...9</property>"));
+        assert!(! description.contains("KP_7"));
+        assert_eq!(description.matches("GtkShortcutsShortcut").count(), 12 + OTHER_SHORTCUTS.len());
+    }
+
+    #[test]
+    fn pausing_is_only_shown_when_possible() {
+        assert!(! create_ui_description(false).contains("<property name=\"accelerator\">space</property>"));
+        let description = create_ui_description(true);
+        assert!(description.contains("<property name=\"accelerator\">space</property>"));
+        assert_eq!(description.matches("GtkShortcutsShortcut").count(), 13 + OTHER_SHORTCUTS.len());
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use cairo;
+use gtk;
+use gtk::prelude::*;
+
+use crate::signal_quality::FrontendStats;
+
+const GRAPH_WIDTH: i32 = 300;
+const GRAPH_HEIGHT: i32 = 100;
+
+/// A panel over the video showing the signal quality of the frontend and a graph of
+/// the recent signal strength and SNR, for use when pointing an aerial.
+#[derive(Debug)]
+pub struct SignalPanel {
+    pub widget: gtk::Box, // FrontendWindow puts this in the video overlay.
+    lock_label: gtk::Label,
+    signal_label: gtk::Label,
+    snr_label: gtk::Label,
+    ber_label: gtk::Label,
+    ucb_label: gtk::Label,
+    pub graph: gtk::DrawingArea, // FrontendWindow connects the draw handler.
+    pub log_button: gtk::ToggleButton, // FrontendWindow connects this to the engine.
+}
+
+impl SignalPanel {
+    pub fn new() -> SignalPanel {
+        let grid = gtk::Grid::new();
+        grid.set_column_spacing(12);
+        let mut labels = vec![];
+        for (row, name) in ["Lock", "Signal", "SNR", "BER", "UCB"].iter().enumerate() {
+            let name_label = gtk::Label::new(Some(*name));
+            name_label.set_halign(gtk::Align::Start);
+            grid.attach(&name_label, 0, row as i32, 1, 1);
+            let value_label = gtk::Label::new(Some("–"));
+            value_label.set_halign(gtk::Align::End);
+            grid.attach(&value_label, 1, row as i32, 1, 1);
+            labels.push(value_label);
+        }
+        let graph = gtk::DrawingArea::new();
+        graph.set_size_request(GRAPH_WIDTH, GRAPH_HEIGHT);
+        let log_button = gtk::ToggleButton::new_with_label("Log to file");
+        let widget = {
+            let w = gtk::Box::new(gtk::Orientation::Vertical, 6);
+            w.get_style_context().add_class("osd");
+            w.set_halign(gtk::Align::End);
+            w.set_valign(gtk::Align::Start);
+            w.set_margin_top(6);
+            w.set_margin_end(6);
+            w.set_border_width(6);
+            w.pack_start(&grid, false, false, 0);
+            w.pack_start(&graph, false, false, 0);
+            w.pack_start(&log_button, false, false, 0);
+            w.show_all();
+            w.set_no_show_all(true);
+            w.hide();
+            w
+        };
+        SignalPanel {
+            widget,
+            ucb_label: labels.pop().unwrap(),
+            ber_label: labels.pop().unwrap(),
+            snr_label: labels.pop().unwrap(),
+            signal_label: labels.pop().unwrap(),
+            lock_label: labels.pop().unwrap(),
+            graph,
+            log_button,
+        }
+    }
+
+    /// Show the latest statistics and redraw the graph.
+    pub fn update(&self, latest: Option<FrontendStats>) {
+        match latest {
+            Some(stats) => {
+                self.lock_label.set_text(if stats.lock { "Yes" } else { "No" });
+                self.signal_label.set_text(&format!("{:.0}%", stats.signal_percentage()));
+                self.snr_label.set_text(&format!("{:.0}%", stats.snr_percentage()));
+                self.ber_label.set_text(&stats.ber.to_string());
+                self.ucb_label.set_text(&stats.ucb.to_string());
+            },
+            None => {
+                for label in &[&self.lock_label, &self.signal_label, &self.snr_label, &self.ber_label, &self.ucb_label] {
+                    label.set_text("–");
+                }
+            },
+        }
+        self.graph.queue_draw();
+    }
+
+    /// Draw signal strength in green and SNR in yellow with the newest sample at the right,
+    /// marking samples without lock in red.
+    pub fn draw_graph(&self, cr: &cairo::Context, samples: &[FrontendStats], capacity: usize) {
+        let width = self.graph.get_allocated_width() as f64;
+        let height = self.graph.get_allocated_height() as f64;
+        cr.set_source_rgba(0.0, 0.0, 0.0, 0.5);
+        cr.paint();
+        cr.set_source_rgba(1.0, 1.0, 1.0, 0.3);
+        cr.set_line_width(1.0);
+        for quarter in 1..4 {
+            let y = (height * quarter as f64 / 4.0).round() + 0.5;
+            cr.move_to(0.0, y);
+            cr.line_to(width, y);
+        }
+        cr.stroke();
+        if samples.is_empty() || capacity < 2 { return; }
+        let step = width / (capacity - 1) as f64;
+        let x_of = |index: usize| width - (samples.len() - 1 - index) as f64 * step;
+        cr.set_source_rgba(1.0, 0.0, 0.0, 0.5);
+        for (index, stats) in samples.iter().enumerate() {
+            if ! stats.lock {
+                cr.rectangle(x_of(index) - step / 2.0, 0.0, step, height);
+            }
+        }
+        cr.fill();
+        cr.set_line_width(2.0);
+        let plot = |red: f64, green: f64, value: &dyn Fn(&FrontendStats) -> f64| {
+            cr.set_source_rgb(red, green, 0.0);
+            for (index, stats) in samples.iter().enumerate() {
+                let y = height * (1.0 - value(stats) / 100.0);
+                if index == 0 { cr.move_to(x_of(index), y); } else { cr.line_to(x_of(index), y); }
+            }
+            cr.stroke();
+        };
+        plot(0.0, 1.0, &|stats| stats.signal_percentage());
+        plot(1.0, 1.0, &|stats| stats.snr_percentage());
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+
+/// The number of samples kept for the signal quality graph.
+pub const HISTORY_LENGTH: usize = 300;
+
+/// The first line of a signal quality log file.
+pub const CSV_HEADER: &str = "time,lock,signal,snr,ber,ucb,status";
+
+/// The signal quality of a frontend as reported by dvbsrc in a dvb-frontend-stats message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrontendStats {
+    pub time: DateTime<Local>,
+    /// The fe_status bits.
+    pub status: i32,
+    /// Signal strength, 0 to 65535.
+    pub signal: i32,
+    /// Signal to noise ratio, 0 to 65535 for most drivers.
+    pub snr: i32,
+    /// Bit error rate.
+    pub ber: i32,
+    /// Uncorrected block count.
+    pub ucb: i32,
+    pub lock: bool,
+}
+
+fn percentage(value: i32) -> f64 {
+    value.max(0).min(0xffff) as f64 * 100.0 / 65535.0
+}
+
+impl FrontendStats {
+    pub fn signal_percentage(&self) -> f64 {
+        percentage(self.signal)
+    }
+
+    pub fn snr_percentage(&self) -> f64 {
+        percentage(self.snr)
+    }
+
+    /// The line for these statistics in a signal quality log file.
+    pub fn csv_record(&self) -> String {
+        format!("{},{},{},{},{},{},{}", self.time.to_rfc3339(), self.lock, self.signal, self.snr, self.ber, self.ucb, self.status)
+    }
+}
+
+/// The most recent frontend statistics, oldest first.
+#[derive(Debug)]
+pub struct SignalHistory {
+    samples: VecDeque<FrontendStats>,
+    capacity: usize,
+}
+
+impl Default for SignalHistory {
+    fn default() -> SignalHistory {
+        SignalHistory::new(HISTORY_LENGTH)
+    }
+}
+
+impl SignalHistory {
+    pub fn new(capacity: usize) -> SignalHistory {
+        SignalHistory { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn push(&mut self, stats: FrontendStats) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(stats);
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn latest(&self) -> Option<FrontendStats> {
+        self.samples.back().cloned()
+    }
+
+    pub fn samples(&self) -> Vec<FrontendStats> {
+        self.samples.iter().cloned().collect()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// A CSV file that frontend statistics are written to, for diagnosing reception over time.
+#[derive(Debug, Default)]
+pub struct SignalLog {
+    file: Option<File>,
+    path: Option<PathBuf>,
+}
+
+impl SignalLog {
+    pub fn start(&mut self, path: &Path) -> Result<(), String> {
+        let mut file = File::create(path).map_err(|e| format!("Could not create {:?}: {}", path, e))?;
+        writeln!(file, "{}", CSV_HEADER).map_err(|e| format!("Could not write to {:?}: {}", path, e))?;
+        self.file = Some(file);
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.file = None;
+        self.path = None;
+    }
+
+    /// The file being written to, if statistics are being logged.
+    pub fn get_path(&self) -> Option<PathBuf> {
+        self.path.clone()
+    }
+
+    /// Write the statistics to the file, if there is one. On error logging stops.
+    pub fn log(&mut self, stats: &FrontendStats) -> Result<(), String> {
+        let result = match self.file {
+            Some(ref mut file) => writeln!(file, "{}", stats.csv_record()).map_err(|e| format!("Could not write to {:?}: {}", self.path, e)),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    use chrono::TimeZone;
+
+    fn stats(signal: i32, snr: i32) -> FrontendStats {
+        FrontendStats {
+            time: Local.ymd(2020, 5, 17).and_hms(20, 15, 0),
+            status: 0x1f,
+            signal,
+            snr,
+            ber: 12,
+            ucb: 3,
+            lock: true,
+        }
+    }
+
+    #[test]
+    fn percentages_are_clamped() {
+        assert_eq!(stats(0xffff, 0).signal_percentage(), 100.0);
+        assert_eq!(stats(0x10000, -1).signal_percentage(), 100.0);
+        assert_eq!(stats(0, -1).snr_percentage(), 0.0);
+        assert!((stats(0x8000, 0).signal_percentage() - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn history_keeps_the_most_recent_samples() {
+        let mut history = SignalHistory::new(3);
+        assert_eq!(history.latest(), None);
+        for signal in 1..=5 {
+            history.push(stats(signal, 0));
+        }
+        assert_eq!(history.samples().iter().map(|s| s.signal).collect::<Vec<i32>>(), vec![3, 4, 5]);
+        assert_eq!(history.latest().unwrap().signal, 5);
+        history.clear();
+        assert!(history.samples().is_empty());
+    }
+
+    #[test]
+    fn log_is_csv_with_a_header() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("signal.csv");
+        let mut log = SignalLog::default();
+        log.log(&stats(1, 2)).unwrap();
+        log.start(&path).unwrap();
+        assert_eq!(log.get_path(), Some(path.clone()));
+        log.log(&stats(40000, 30000)).unwrap();
+        log.stop();
+        log.log(&stats(3, 4)).unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let lines = contents.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("2020-05-17T20:15:00"));
+        assert!(lines[1].ends_with(",true,40000,30000,12,3,31"));
+    }
+}
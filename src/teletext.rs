@@ -0,0 +1,477 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The teletext viewer: the page being viewed and the navigation between pages.
+//!
+//! teletextdec renders the pages, but does not give the fast text links or the subpages
+//! of a page, so these are collected here from the teletext data units (EN 300 472) on
+//! their way from tsdemux to teletextdec.
+//!
+//! Page numbers are as transmitted: the magazine and the two page digits as hex digits,
+//! so page 100 is 0x100. Magazine 0 is held as magazine 8.
+
+use std::collections::{BTreeSet, HashMap};
+
+/// The page number of a header that only serves to end the transmission of a page.
+const TIME_FILLING_PAGE: u16 = 0xff;
+
+/// The Hamming 8/4 codewords for 0 to 15, in transmission bit order.
+const HAMMING_8_4: [u8; 16] = [
+    0x15, 0x02, 0x49, 0x5e, 0x64, 0x73, 0x38, 0x2f, 0xd0, 0xc7, 0x8c, 0x9b, 0xa1, 0xb6, 0xfd, 0xea,
+];
+
+/// Decode a Hamming 8/4 protected byte, correcting a single bit error.
+fn hamming_8_4(byte: u8) -> Option<u8> {
+    HAMMING_8_4.iter().position(|&codeword| (codeword ^ byte).count_ones() <= 1).map(|value| value as u8)
+}
+
+/// The fast text links and the subpages of the pages of a teletext service.
+#[derive(Debug, Default)]
+pub struct Navigation {
+    /// The fast text links of each page: red, green, yellow, blue, and the index page.
+    links: HashMap<u16, [Option<u16>; 5]>,
+    subcodes: HashMap<u16, BTreeSet<u16>>,
+    /// The page being received for each magazine, indexed by magazine number modulo 8.
+    receiving: [Option<u16>; 8],
+}
+
+impl Navigation {
+    pub fn new() -> Navigation {
+        Default::default()
+    }
+
+    /// Process the data field of a teletext PES packet: the data identifier followed by
+    /// the data units.
+    pub fn push(&mut self, data: &[u8]) {
+        // The data identifier for EBU data is 0x10 to 0x1f.
+        if data.first().map_or(true, |identifier| identifier & 0xf0 != 0x10) { return; }
+        let mut i = 1;
+        while i + 2 <= data.len() {
+            let data_unit_id = data[i];
+            let length = data[i + 1] as usize;
+            if i + 2 + length > data.len() { break; }
+            let data_unit = &data[i + 2..i + 2 + length];
+            // Teletext and teletext subtitle data units, the framing code being the second byte.
+            if (data_unit_id == 0x02 || data_unit_id == 0x03) && length == 44 && data_unit[1] == 0xe4 {
+                let packet = data_unit[2..].iter().map(|b| b.reverse_bits()).collect::<Vec<u8>>();
+                self.process_teletext_packet(&packet);
+            }
+            i += 2 + length;
+        }
+    }
+
+    /// The fast text links of a page: red, green, yellow, blue, and the index page.
+    pub fn get_links(&self, number: u16) -> [Option<u16>; 5] {
+        self.links.get(&number).cloned().unwrap_or([None; 5])
+    }
+
+    /// Return the subcodes of the subpages of a page in ascending order.
+    pub fn get_subcodes(&self, number: u16) -> Vec<u16> {
+        match self.subcodes.get(&number) {
+            Some(subcodes) => subcodes.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    fn process_teletext_packet(&mut self, packet: &[u8]) {
+        let address = match (hamming_8_4(packet[0]), hamming_8_4(packet[1])) {
+            (Some(low), Some(high)) => low | high << 4,
+            _ => return,
+        };
+        let magazine = address & 0x07;
+        match address >> 3 {
+            0 => self.process_header(magazine, packet),
+            27 => self.process_links(magazine, packet),
+            _ => {},
+        }
+    }
+
+    fn process_header(&mut self, magazine: u8, packet: &[u8]) {
+        let mut values = [0u8; 8];
+        for (value, &byte) in values.iter_mut().zip(&packet[2..10]) {
+            match hamming_8_4(byte) {
+                Some(v) => *value = v,
+                None => return,
+            }
+        }
+        let serial = values[7] & 0x01 != 0;
+        if serial {
+            self.receiving = [None; 8];
+        } else {
+            self.receiving[magazine as usize] = None;
+        }
+        let page_units_and_tens = (values[1] as u16) << 4 | values[0] as u16;
+        if page_units_and_tens == TIME_FILLING_PAGE { return; }
+        let number = (if magazine == 0 { 8 } else { magazine as u16 }) << 8 | page_units_and_tens;
+        let subcode = values[2] as u16 | ((values[3] & 0x07) as u16) << 4 | (values[4] as u16) << 8 | ((values[5] & 0x03) as u16) << 12;
+        self.subcodes.entry(number).or_insert_with(BTreeSet::new).insert(subcode);
+        self.receiving[magazine as usize] = Some(number);
+    }
+
+    fn process_links(&mut self, magazine: u8, packet: &[u8]) {
+        if hamming_8_4(packet[2]) != Some(0) { return; }
+        let mut links = [None; 5];
+        for (index, link) in links.iter_mut().enumerate() {
+            let mut values = [0u8; 6];
+            for (value, &byte) in values.iter_mut().zip(&packet[3 + 6 * index..]) {
+                match hamming_8_4(byte) {
+                    Some(v) => *value = v,
+                    None => return,
+                }
+            }
+            let page_units_and_tens = (values[1] as u16) << 4 | values[0] as u16;
+            if page_units_and_tens == TIME_FILLING_PAGE { continue; }
+            // The magazine of a link is relative to the magazine of the page.
+            let relative_magazine = values[3] >> 3 | (values[5] & 0x0c) >> 1;
+            let link_magazine = (magazine ^ relative_magazine) as u16;
+            *link = Some((if link_magazine == 0 { 8 } else { link_magazine }) << 8 | page_units_and_tens);
+        }
+        if let Some(number) = self.receiving[magazine as usize] {
+            self.links.insert(number, links);
+        }
+    }
+}
+
+/// Make the black pixels of a page image transparent, so that in mix mode the video
+/// shows through the backgrounds. The image is 32 bit pixels with premultiplied alpha
+/// last, as cairo has them on little endian machines.
+pub fn make_black_transparent(image: &mut [u8]) {
+    for pixel in image.chunks_exact_mut(4) {
+        if pixel[0..3] == [0, 0, 0] {
+            pixel[3] = 0;
+        }
+    }
+}
+
+/// How teletext is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    /// The page on a black background, hiding the video.
+    Opaque,
+    /// The page over the video, with no backgrounds drawn.
+    Mix,
+}
+
+/// Convert a page number to the number the user sees, hex digits being treated as 9.
+pub fn to_decimal(number: u16) -> u16 {
+    let digit = |shift: u16| std::cmp::min((number >> shift) & 0x0f, 9);
+    digit(8) * 100 + digit(4) * 10 + digit(0)
+}
+
+fn from_decimal(number: u16) -> u16 {
+    (number / 100) << 8 | (number / 10 % 10) << 4 | (number % 10)
+}
+
+/// The state of a teletext viewer: the mode, the page and subpage being shown, and
+/// any page number being entered.
+#[derive(Clone, Debug)]
+pub struct Viewer {
+    pub mode: Mode,
+    pub page: u16,
+    /// The subcode of the subpage being shown, `None` to show subpages as they arrive.
+    pub subcode: Option<u16>,
+    digits: Vec<u8>,
+}
+
+impl Default for Viewer {
+    fn default() -> Viewer {
+        Viewer { mode: Mode::Off, page: 0x100, subcode: None, digits: vec![] }
+    }
+}
+
+impl Viewer {
+    /// Go from off to opaque to mix and back to off, as the text key of a television does.
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            Mode::Off => Mode::Opaque,
+            Mode::Opaque => Mode::Mix,
+            Mode::Mix => Mode::Off,
+        };
+        self.digits.clear();
+    }
+
+    pub fn set_page(&mut self, page: u16) {
+        self.page = page;
+        self.subcode = None;
+        self.digits.clear();
+    }
+
+    /// Add a digit to the page number being entered, returning true if that completed
+    /// a page number. Page numbers start with 1 to 8.
+    pub fn enter_digit(&mut self, digit: u8) -> bool {
+        if digit > 9 || (self.digits.is_empty() && (digit == 0 || digit == 9)) { return false; }
+        self.digits.push(digit);
+        if self.digits.len() < 3 { return false; }
+        let page = self.digits.iter().fold(0u16, |page, &d| page << 4 | d as u16);
+        self.set_page(page);
+        true
+    }
+
+    /// The text for the start of the header row: the page number, or the digits
+    /// entered so far.
+    pub fn page_entry_text(&self) -> String {
+        if self.digits.is_empty() {
+            format!("P{:03X}", self.page)
+        } else {
+            let mut text = "P".to_string();
+            for i in 0..3 {
+                match self.digits.get(i) {
+                    Some(digit) => text += &digit.to_string(),
+                    None => text.push('-'),
+                }
+            }
+            text
+        }
+    }
+
+    /// The eight characters at the start of the header row: the page number, or the
+    /// digits entered so far, and which subpage is shown if there are several.
+    pub fn header_text(&self, subpage: Option<(usize, usize)>) -> String {
+        let mut text = self.page_entry_text();
+        if let Some((index, count)) = subpage {
+            let position = format!("{}/{}", index, count);
+            text += &if text.len() + 1 + position.len() <= 8 { format!(" {}", position) } else { format!(" {}", index) };
+        }
+        format!("{:<8.8}", text)
+    }
+
+    pub fn is_entering_page(&self) -> bool {
+        ! self.digits.is_empty()
+    }
+
+    pub fn next_page(&mut self) {
+        let number = to_decimal(self.page);
+        self.set_page(from_decimal(if number >= 899 { 100 } else { number + 1 }));
+    }
+
+    pub fn previous_page(&mut self) {
+        let number = to_decimal(self.page);
+        self.set_page(from_decimal(if number <= 100 { 899 } else { number - 1 }));
+    }
+
+    /// Follow a fast text link, if there is one.
+    pub fn follow_link(&mut self, link: Option<u16>) {
+        if let Some(page) = link {
+            self.set_page(page);
+        }
+    }
+
+    pub fn next_subpage(&mut self, subcodes: &[u16]) {
+        self.step_subpage(subcodes, true);
+    }
+
+    pub fn previous_subpage(&mut self, subcodes: &[u16]) {
+        self.step_subpage(subcodes, false);
+    }
+
+    fn step_subpage(&mut self, subcodes: &[u16], forward: bool) {
+        if subcodes.is_empty() { return; }
+        let count = subcodes.len();
+        let index = match self.subcode.and_then(|s| subcodes.iter().position(|&c| c == s)) {
+            Some(index) if forward => (index + 1) % count,
+            Some(index) => (index + count - 1) % count,
+            None if forward => 0,
+            None => count - 1,
+        };
+        self.subcode = Some(subcodes[index]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hamming(value: u8) -> u8 { HAMMING_8_4[value as usize] }
+
+    fn address(magazine: u8, row: u8) -> [u8; 2] {
+        let address = row << 3 | (magazine & 0x07);
+        [hamming(address & 0x0f), hamming(address >> 4)]
+    }
+
+    fn header_packet(page: u16, subcode: u16, control: u8) -> Vec<u8> {
+        let mut packet = address((page >> 8) as u8, 0).to_vec();
+        packet.extend_from_slice(&[
+            hamming((page & 0x0f) as u8), hamming(((page >> 4) & 0x0f) as u8),
+            hamming((subcode & 0x0f) as u8), hamming(((subcode >> 4) & 0x07) as u8 | (control & 0x08)),
+            hamming(((subcode >> 8) & 0x0f) as u8), hamming(((subcode >> 12) & 0x03) as u8),
+            hamming(0), hamming(control & 0x07),
+        ]);
+        packet.resize(42, 0x20);
+        packet
+    }
+
+    fn links_packet(magazine: u8, links: &[u16; 6]) -> Vec<u8> {
+        let mut packet = address(magazine, 27).to_vec();
+        packet.push(hamming(0));
+        for link in links {
+            let relative_magazine = ((link >> 8) as u8 ^ magazine) & 0x07;
+            packet.extend_from_slice(&[
+                hamming((link & 0x0f) as u8), hamming(((link >> 4) & 0x0f) as u8),
+                hamming(0x0f), hamming(0x07 | (relative_magazine & 0x01) << 3),
+                hamming(0x0f), hamming(0x03 | (relative_magazine & 0x06) << 1),
+            ]);
+        }
+        packet.resize(42, hamming(0));
+        packet
+    }
+
+    /// Put teletext packets in the data field of a PES packet, as tsdemux passes it on.
+    fn navigation_with(packets: &[Vec<u8>]) -> Navigation {
+        let mut data = vec![0x10];
+        for packet in packets {
+            data.extend_from_slice(&[0x02, 0x2c, 0xe0, 0xe4]);
+            data.extend(packet.iter().map(|b| b.reverse_bits()));
+        }
+        let mut navigation = Navigation::new();
+        navigation.push(&data);
+        navigation
+    }
+
+    #[test]
+    fn hamming_8_4_corrects_single_bit_errors() {
+        for value in 0..16 {
+            assert_eq!(hamming_8_4(hamming(value)), Some(value));
+            for bit in 0..8 {
+                assert_eq!(hamming_8_4(hamming(value) ^ 1 << bit), Some(value));
+            }
+        }
+        assert_eq!(hamming_8_4(hamming(5) ^ 0x03), None);
+    }
+
+    #[test]
+    fn fast_text_links_are_decoded() {
+        let navigation = navigation_with(&[
+            header_packet(0x100, 0, 0),
+            links_packet(1, &[0x101, 0x200, 0x8ff, 0x888, 0x100, 0x100]),
+        ]);
+        assert_eq!(navigation.get_links(0x100), [Some(0x101), Some(0x200), None, Some(0x888), Some(0x100)]);
+        assert_eq!(navigation.get_links(0x200), [None; 5]);
+    }
+
+    #[test]
+    fn links_go_to_the_page_of_their_magazine() {
+        let navigation = navigation_with(&[
+            header_packet(0x100, 0, 0),
+            header_packet(0x200, 0, 0),
+            links_packet(1, &[0x101, 0x102, 0x103, 0x104, 0x100, 0x100]),
+        ]);
+        assert_eq!(navigation.get_links(0x100)[0], Some(0x101));
+        assert_eq!(navigation.get_links(0x200)[0], None);
+    }
+
+    #[test]
+    fn serial_mode_header_ends_all_pages() {
+        let navigation = navigation_with(&[
+            header_packet(0x100, 0, 0x01),
+            header_packet(0x2ff, 0, 0x01),
+            links_packet(1, &[0x101, 0x102, 0x103, 0x104, 0x100, 0x100]),
+        ]);
+        assert_eq!(navigation.get_links(0x100), [None; 5]);
+    }
+
+    #[test]
+    fn subpages_are_collected() {
+        let navigation = navigation_with(&[
+            header_packet(0x150, 2, 0),
+            header_packet(0x150, 1, 0),
+            header_packet(0x150, 2, 0),
+        ]);
+        assert_eq!(navigation.get_subcodes(0x150), vec![1, 2]);
+        assert_eq!(navigation.get_subcodes(0x151), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn other_data_is_ignored() {
+        let mut navigation = Navigation::new();
+        navigation.push(&[]);
+        navigation.push(&[0x99, 0x02, 0x2c]);
+        assert_eq!(navigation.get_subcodes(0x100), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn only_black_is_made_transparent() {
+        let mut image = vec![0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0x80, 0xff];
+        make_black_transparent(&mut image);
+        assert_eq!(image, vec![0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0x80, 0xff]);
+    }
+
+    #[test]
+    fn page_number_entry() {
+        let mut viewer = Viewer::default();
+        assert!(! viewer.enter_digit(0));
+        assert!(! viewer.enter_digit(3));
+        assert_eq!(viewer.page_entry_text(), "P3--");
+        assert!(! viewer.enter_digit(0));
+        assert!(viewer.enter_digit(2));
+        assert_eq!(viewer.page, 0x302);
+        assert_eq!(viewer.page_entry_text(), "P302");
+    }
+
+    #[test]
+    fn header_text_shows_subpage() {
+        let mut viewer = Viewer::default();
+        assert_eq!(viewer.header_text(None), "P100    ");
+        assert_eq!(viewer.header_text(Some((2, 4))), "P100 2/4");
+        assert_eq!(viewer.header_text(Some((12, 15))), "P100 12 ");
+        viewer.enter_digit(2);
+        assert!(viewer.is_entering_page());
+        assert_eq!(viewer.header_text(Some((2, 4))), "P2-- 2/4");
+    }
+
+    #[test]
+    fn page_stepping_skips_hex_pages_and_wraps() {
+        let mut viewer = Viewer::default();
+        viewer.set_page(0x199);
+        viewer.next_page();
+        assert_eq!(viewer.page, 0x200);
+        viewer.set_page(0x899);
+        viewer.next_page();
+        assert_eq!(viewer.page, 0x100);
+        viewer.previous_page();
+        assert_eq!(viewer.page, 0x899);
+    }
+
+    #[test]
+    fn subpage_stepping_wraps() {
+        let mut viewer = Viewer::default();
+        let subcodes = [1, 2, 3];
+        viewer.next_subpage(&subcodes);
+        assert_eq!(viewer.subcode, Some(1));
+        viewer.previous_subpage(&subcodes);
+        assert_eq!(viewer.subcode, Some(3));
+        viewer.next_subpage(&subcodes);
+        assert_eq!(viewer.subcode, Some(1));
+        viewer.follow_link(Some(0x200));
+        assert_eq!(viewer.subcode, None);
+    }
+
+    #[test]
+    fn text_key_cycles_modes() {
+        let mut viewer = Viewer::default();
+        viewer.toggle_mode();
+        assert_eq!(viewer.mode, Mode::Opaque);
+        viewer.toggle_mode();
+        assert_eq!(viewer.mode, Mode::Mix);
+        viewer.toggle_mode();
+        assert_eq!(viewer.mode, Mode::Off);
+    }
+}
@@ -0,0 +1,193 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Render the teletext pages of a channel with teletextdec.
+//!
+//! The teletextdec of playbin only renders subtitles, so for the teletext viewer the
+//! transport stream of the channel is also fed to a pipeline of its own: appsrc ! tsdemux
+//! ! teletextdec ! videoconvert ! appsink, the pages coming out as images to draw over the
+//! video. The teletext data units on the way to teletextdec also go to the navigation, for
+//! the fast text links and subpages that teletextdec does not give.
+
+use std::sync::{Arc, Mutex};
+
+use glib;
+use glib::prelude::*;
+use gst;
+use gst::prelude::*;
+
+use log::warn;
+
+use crate::teletext::{Navigation, make_black_transparent, to_decimal};
+
+/// A rendered teletext page: 32 bit pixels with premultiplied alpha, as cairo has them.
+#[derive(Clone, Debug)]
+pub struct PageImage {
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub data: Vec<u8>,
+}
+
+/// The pipeline rendering the teletext of the transport stream pushed into it.
+#[derive(Debug)]
+pub struct TeletextRenderer {
+    pipeline: gst::Pipeline,
+    source: gst::Element,
+    decoder: gst::Element,
+    image: Arc<Mutex<Option<PageImage>>>,
+    navigation: Arc<Mutex<Navigation>>,
+}
+
+impl TeletextRenderer {
+    /// Create the pipeline, rendering a page, and a subpage if a subcode is given.
+    pub fn new(page: u16, subcode: Option<u16>) -> Result<TeletextRenderer, String> {
+        let make = |name: &str| gst::ElementFactory::make(name, None).map_err(|_| format!("Could not create a '{}'.", name));
+        let source = make("appsrc")?;
+        let demux = make("tsdemux")?;
+        let decoder = make("teletextdec")?;
+        let convert = make("videoconvert")?;
+        let sink = make("appsink")?;
+        let caps = gst::Caps::new_simple("video/mpegts", &[("systemstream", &true), ("packetsize", &188i32)]);
+        source.set_property("caps", &caps).map_err(|e| e.to_string())?;
+        source.set_property("is-live", &true).map_err(|e| e.to_string())?;
+        let caps = gst::Caps::new_simple("video/x-raw", &[("format", &"BGRA")]);
+        sink.set_property("caps", &caps).map_err(|e| e.to_string())?;
+        sink.set_property("emit-signals", &true).map_err(|e| e.to_string())?;
+        // Only the latest page matters, and as soon as it arrives.
+        sink.set_property("sync", &false).map_err(|e| e.to_string())?;
+        sink.set_property("max-buffers", &1u32).map_err(|e| e.to_string())?;
+        sink.set_property("drop", &true).map_err(|e| e.to_string())?;
+        let pipeline = gst::Pipeline::new(Some("teletext"));
+        pipeline.add_many(&[&source, &demux, &decoder, &convert, &sink]).map_err(|e| e.to_string())?;
+        source.link(&demux).map_err(|e| e.to_string())?;
+        gst::Element::link_many(&[&decoder, &convert, &sink]).map_err(|e| e.to_string())?;
+        demux.connect_pad_added({
+            let decoder = decoder.downgrade();
+            move |_, pad| {
+                let caps = pad.get_current_caps().unwrap_or_else(|| pad.query_caps(None));
+                let is_teletext = caps.get_structure(0).map_or(false, |s| s.get_name() == "application/x-teletext");
+                if let (true, Some(sink_pad)) = (is_teletext, decoder.upgrade().and_then(|d| d.get_static_pad("sink"))) {
+                    if ! sink_pad.is_linked() {
+                        if let Err(error) = pad.link(&sink_pad) {
+                            warn!("Could not link the teletext stream to teletextdec: {:?}", error);
+                        }
+                    }
+                }
+            }
+        });
+        let navigation = Arc::new(Mutex::new(Navigation::new()));
+        if let Some(sink_pad) = decoder.get_static_pad("sink") {
+            let navigation = navigation.clone();
+            sink_pad.add_probe(gst::PadProbeType::BUFFER, move |_, probe_info| {
+                if let Some(gst::PadProbeData::Buffer(ref buffer)) = probe_info.data {
+                    if let Some(map) = buffer.map_readable() {
+                        if let Ok(mut navigation) = navigation.lock() {
+                            navigation.push(map.as_slice());
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+        let image = Arc::new(Mutex::new(None));
+        sink.connect("new-sample", false, {
+            let image = image.clone();
+            move |values| {
+                let sink = values[0].get::<gst::Element>().ok()??;
+                let sample = sink.emit("pull-sample", &[]).ok()??.get::<gst::Sample>().ok()??;
+                if let Some(page_image) = page_image_from_sample(&sample) {
+                    if let Ok(mut image) = image.lock() {
+                        image.replace(page_image);
+                    }
+                }
+                Some(gst::FlowReturn::Ok.to_value())
+            }
+        }).map_err(|e| e.to_string())?;
+        let renderer = TeletextRenderer { pipeline, source, decoder, image, navigation };
+        renderer.set_page(page, subcode);
+        renderer.pipeline.set_state(gst::State::Playing).map_err(|e| e.to_string())?;
+        Ok(renderer)
+    }
+
+    /// Process a buffer of the transport stream of the channel.
+    pub fn push(&self, data: &[u8]) {
+        let buffer = gst::Buffer::from_mut_slice(data.to_vec());
+        if let Err(error) = self.source.emit("push-buffer", &[&buffer]) {
+            warn!("Could not push to the teletext pipeline: {}", error);
+        }
+    }
+
+    /// Render a page, and a subpage if a subcode is given, rather than the one being rendered.
+    pub fn set_page(&self, page: u16, subcode: Option<u16>) {
+        if let Err(error) = self.decoder.set_property("page", &(to_decimal(page) as i32)) {
+            warn!("Could not set the teletext page: {}", error);
+        }
+        if let Err(error) = self.decoder.set_property("subpage", &subcode.map_or(-1, |subcode| subcode as i32)) {
+            warn!("Could not set the teletext subpage: {}", error);
+        }
+        // The page being shown is no longer the one asked for.
+        if let Ok(mut image) = self.image.lock() {
+            image.take();
+        }
+    }
+
+    /// Return the latest image of the page being rendered, with the black made transparent
+    /// if the page is to be mixed with the video.
+    pub fn get_image(&self, mix: bool) -> Option<PageImage> {
+        let mut image = self.image.lock().ok()?.clone()?;
+        if mix {
+            make_black_transparent(&mut image.data);
+        }
+        Some(image)
+    }
+
+    pub fn get_links(&self, page: u16) -> [Option<u16>; 5] {
+        match self.navigation.lock() {
+            Ok(navigation) => navigation.get_links(page),
+            Err(_) => [None; 5],
+        }
+    }
+
+    pub fn get_subcodes(&self, page: u16) -> Vec<u16> {
+        match self.navigation.lock() {
+            Ok(navigation) => navigation.get_subcodes(page),
+            Err(_) => vec![],
+        }
+    }
+}
+
+impl Drop for TeletextRenderer {
+    fn drop(&mut self) {
+        if let Err(error) = self.pipeline.set_state(gst::State::Null) {
+            warn!("Could not stop the teletext pipeline: {}", error);
+        }
+    }
+}
+
+fn page_image_from_sample(sample: &gst::Sample) -> Option<PageImage> {
+    let structure = sample.get_caps()?.get_structure(0)?;
+    let width = structure.get_some::<i32>("width").ok()?;
+    let height = structure.get_some::<i32>("height").ok()?;
+    let map = sample.get_buffer()?.map_readable()?;
+    if height <= 0 { return None; }
+    Some(PageImage { width, height, stride: map.get_size() as i32 / height, data: map.as_slice().to_vec() })
+}
@@ -0,0 +1,172 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The drawing area of the teletext viewer over the video, and the handling of the
+//! keystrokes that navigate it.
+
+use std::cell::RefCell;
+
+use cairo;
+use glib;
+use gtk;
+use gtk::prelude::*;
+
+use crate::gstreamer_engine::GStreamerEngine;
+use crate::input_event_codes;
+use crate::teletext::{Mode, Viewer};
+
+/// How often the page is redrawn, to pick up updates.
+const REFRESH_INTERVAL: u32 = 500;  // milliseconds
+
+/// A teletext page viewer drawn over the video of a frontend window.
+#[derive(Debug)]
+pub struct TeletextView {
+    pub drawing_area: gtk::DrawingArea, // FrontendWindow puts this in the video overlay.
+    viewer: RefCell<Viewer>,
+    // Why the page cannot be rendered, if it cannot.
+    error: RefCell<Option<String>>,
+    refresh_timeout: RefCell<Option<glib::SourceId>>,
+}
+
+impl TeletextView {
+    pub fn new() -> TeletextView {
+        TeletextView {
+            drawing_area: gtk::DrawingArea::new(),
+            viewer: RefCell::new(Viewer::default()),
+            error: RefCell::new(None),
+            refresh_timeout: RefCell::new(None),
+        }
+    }
+
+    /// Act on a remote control keystroke, returning true if it was a teletext one.
+    /// Apart from the text key, keystrokes are only used when teletext is shown.
+    pub fn process_keystroke(&self, keystroke: u32, engine: &GStreamerEngine) -> bool {
+        let mut viewer = self.viewer.borrow_mut();
+        if keystroke == input_event_codes::KEY_TEXT {
+            viewer.toggle_mode();
+        } else if viewer.mode == Mode::Off {
+            return false;
+        } else {
+            match keystroke {
+                input_event_codes::KEY_1..=input_event_codes::KEY_9 => { viewer.enter_digit((keystroke - input_event_codes::KEY_1 + 1) as u8); },
+                input_event_codes::KEY_0 => { viewer.enter_digit(0); },
+                input_event_codes::KEY_RED | input_event_codes::KEY_GREEN | input_event_codes::KEY_YELLOW | input_event_codes::KEY_BLUE => {
+                    let link = engine.get_teletext_links(viewer.page)[(keystroke - input_event_codes::KEY_RED) as usize];
+                    viewer.follow_link(link);
+                },
+                input_event_codes::KEY_UP => viewer.next_page(),
+                input_event_codes::KEY_DOWN => viewer.previous_page(),
+                input_event_codes::KEY_RIGHT => viewer.next_subpage(&engine.get_teletext_subcodes(viewer.page)),
+                input_event_codes::KEY_LEFT => viewer.previous_subpage(&engine.get_teletext_subcodes(viewer.page)),
+                input_event_codes::KEY_EXIT | input_event_codes::KEY_BACK => viewer.mode = Mode::Off,
+                _ => return false,
+            }
+        }
+        let page = if viewer.mode == Mode::Off { None } else { Some((viewer.page, viewer.subcode)) };
+        drop(viewer);
+        self.error.replace(engine.set_teletext_page(page).err());
+        self.set_showing(page.is_some());
+        true
+    }
+
+    /// Hide the page and stop redrawing it.
+    pub fn stop(&self, engine: &GStreamerEngine) {
+        self.viewer.borrow_mut().mode = Mode::Off;
+        if let Err(message) = engine.set_teletext_page(None) {
+            self.error.replace(Some(message));
+        }
+        self.set_showing(false);
+    }
+
+    fn set_showing(&self, showing: bool) {
+        self.drawing_area.set_visible(showing);
+        let mut refresh_timeout = self.refresh_timeout.borrow_mut();
+        if showing {
+            self.drawing_area.queue_draw();
+            if refresh_timeout.is_none() {
+                refresh_timeout.replace(gtk::timeout_add(REFRESH_INTERVAL, {
+                    let drawing_area = self.drawing_area.clone();
+                    move || {
+                        drawing_area.queue_draw();
+                        Continue(true)
+                    }
+                }));
+            }
+        } else if let Some(source_id) = refresh_timeout.take() {
+            glib::source_remove(source_id);
+        }
+    }
+
+    /// Draw the page being viewed, as rendered by teletextdec, scaled to fill the video,
+    /// with the page number and subpage, or the page number being entered, over the start
+    /// of the header row.
+    pub fn draw(&self, cr: &cairo::Context, engine: &GStreamerEngine) {
+        let viewer = self.viewer.borrow();
+        let width = self.drawing_area.get_allocated_width() as f64;
+        let height = self.drawing_area.get_allocated_height() as f64;
+        let cell_width = width / 40.0;
+        let cell_height = height / 25.0;
+        if viewer.mode == Mode::Opaque {
+            cr.set_source_rgb(0.0, 0.0, 0.0);
+            cr.paint();
+        }
+        // In mix mode the video is the background.
+        let image = if viewer.is_entering_page() { None } else { engine.get_teletext_image(viewer.mode == Mode::Mix) };
+        let message = match image {
+            Some(image) => {
+                match cairo::ImageSurface::create_for_data(image.data, cairo::Format::ARgb32, image.width, image.height, image.stride) {
+                    Ok(surface) => {
+                        cr.save();
+                        cr.scale(width / image.width as f64, height / image.height as f64);
+                        cr.set_source_surface(&surface, 0.0, 0.0);
+                        cr.paint();
+                        cr.restore();
+                        None
+                    },
+                    Err(status) => Some(format!("Could not draw the page: {:?}", status)),
+                }
+            },
+            None => match *self.error.borrow() {
+                Some(ref error) => Some(error.clone()),
+                None if ! engine.has_teletext() => Some("No teletext on this channel".to_string()),
+                None => None,
+            },
+        };
+        let subcodes = engine.get_teletext_subcodes(viewer.page);
+        let subpage = match viewer.subcode {
+            Some(subcode) if subcodes.len() > 1 => subcodes.iter().position(|&s| s == subcode).map(|index| (index + 1, subcodes.len())),
+            _ => None,
+        };
+        let header = viewer.header_text(subpage);
+        cr.set_source_rgb(0.0, 0.0, 0.0);
+        cr.rectangle(0.0, 0.0, header.len() as f64 * cell_width, cell_height);
+        cr.fill();
+        cr.select_font_face("Monospace", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+        cr.set_font_size(cell_height);
+        cr.set_source_rgb(1.0, 1.0, 1.0);
+        cr.move_to(0.0, 0.8 * cell_height);
+        cr.show_text(&header);
+        if let Some(message) = message {
+            cr.move_to(header.len() as f64 * cell_width, 0.8 * cell_height);
+            cr.show_text(&message);
+        }
+    }
+}
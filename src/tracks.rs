@@ -0,0 +1,338 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::program_map::ProgramMap;
+
+// The languages likely to be seen in DVB broadcasts. Both the bibliographic
+// and terminological codes are present where they differ.
+static LANGUAGE_NAMES: [(&str, &str); 36] = [
+    ("ara", "Arabic"),
+    ("chi", "Chinese"),
+    ("cym", "Welsh"),
+    ("cze", "Czech"),
+    ("dan", "Danish"),
+    ("deu", "German"),
+    ("dut", "Dutch"),
+    ("ell", "Greek"),
+    ("eng", "English"),
+    ("fin", "Finnish"),
+    ("fra", "French"),
+    ("fre", "French"),
+    ("ger", "German"),
+    ("gla", "Gaelic"),
+    ("gle", "Irish"),
+    ("gre", "Greek"),
+    ("heb", "Hebrew"),
+    ("hin", "Hindi"),
+    ("hun", "Hungarian"),
+    ("ita", "Italian"),
+    ("jpn", "Japanese"),
+    ("kor", "Korean"),
+    ("nld", "Dutch"),
+    ("nor", "Norwegian"),
+    ("pol", "Polish"),
+    ("por", "Portuguese"),
+    ("rus", "Russian"),
+    ("slk", "Slovak"),
+    ("slo", "Slovak"),
+    ("spa", "Spanish"),
+    ("swe", "Swedish"),
+    ("tur", "Turkish"),
+    ("ukr", "Ukrainian"),
+    ("urd", "Urdu"),
+    ("wel", "Welsh"),
+    ("zho", "Chinese"),
+];
+
+/// Return a human readable name for an ISO 639-2 language code, as found in the ISO 639
+/// language descriptors of the PMT, or the code itself if the language is not known.
+pub fn language_name(code: &str) -> String {
+    let code = code.trim().to_lowercase();
+    match LANGUAGE_NAMES.iter().find(|(c, _)| *c == code) {
+        Some((_, name)) => name.to_string(),
+        None => code,
+    }
+}
+
+/// Broadcasters without a separate audio type mark audio description tracks using
+/// one of these codes rather than a real language.
+pub fn is_audio_description(code: &str) -> bool {
+    match code.trim().to_lowercase().as_str() {
+        "nar" | "qad" => true,
+        _ => false,
+    }
+}
+
+/// Do two ISO 639-2 codes represent the same language, allowing for the bibliographic
+/// and terminological codes being different for some languages.
+fn is_same_language(a: &str, b: &str) -> bool {
+    let a = a.trim().to_lowercase();
+    let b = b.trim().to_lowercase();
+    a == b || (language_name(&a) != a && language_name(&a) == language_name(&b))
+}
+
+/// The information about an audio stream that playbin has from the tags, and that
+/// the PMT has in the ISO 639 descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioTrack {
+    pub index: i32,
+    pub language: Option<String>,
+    pub codec: Option<String>,
+    pub audio_description: bool,
+}
+
+impl AudioTrack {
+    /// The text to use for the track in the audio track menus.
+    pub fn label(&self) -> String {
+        let name = match self.language {
+            Some(ref language) if is_audio_description(language) => "Audio description".to_string(),
+            Some(ref language) if self.audio_description => format!("{} audio description", language_name(language)),
+            Some(ref language) => language_name(language),
+            None => format!("Track {}", self.index + 1),
+        };
+        match self.codec {
+            Some(ref codec) => format!("{} – {}", name, codec),
+            None => name,
+        }
+    }
+}
+
+/// Return the index of the track to use given the ordered list of preferred languages.
+///
+/// Audio description tracks are only chosen if explicitly asked for.
+pub fn preferred_audio_track(tracks: &[AudioTrack], preferred_languages: &[String]) -> Option<i32> {
+    preferred_languages.iter()
+        .filter_map(|preferred| tracks.iter().find(|track| match track.language {
+            Some(ref language) => if is_audio_description(language) {
+                is_audio_description(preferred)
+            } else {
+                ! track.audio_description && is_same_language(language, preferred)
+            },
+            None => false,
+        }))
+        .map(|track| track.index)
+        .next()
+}
+
+/// Where the subtitles of a subtitle track come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubtitleSource {
+    DVB,
+    Teletext{page: u16},
+}
+
+/// A subtitle track: a DVB subtitle stream or a subtitle page of a teletext stream. Each
+/// teletext page of a stream has the same text stream index in playbin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubtitleTrack {
+    pub text_index: i32,
+    pub language: Option<String>,
+    pub hearing_impaired: bool,
+    pub source: SubtitleSource,
+}
+
+impl SubtitleTrack {
+    /// The text to use for the track in the subtitle menus.
+    pub fn label(&self) -> String {
+        let mut label = match self.language {
+            Some(ref language) => language_name(language),
+            None => format!("Subtitles {}", self.text_index + 1),
+        };
+        if self.hearing_impaired {
+            label += " (hard of hearing)";
+        }
+        match self.source {
+            SubtitleSource::DVB => label + " – DVB",
+            SubtitleSource::Teletext{page} => format!("{} – teletext {}", label, page),
+        }
+    }
+}
+
+/// Return the subtitle tracks described by the PMT.
+///
+/// playbin numbers its text streams in the order tsdemux creates them, which is the
+/// order of the streams in the PMT. Teletext streams are only text streams if there
+/// is a teletext decoder.
+pub fn subtitle_tracks(program_map: &ProgramMap, teletext_decoding: bool) -> Vec<SubtitleTrack> {
+    let mut tracks = vec![];
+    let mut text_index = 0;
+    for stream in program_map.streams.iter() {
+        if stream.is_dvb_subtitles() {
+            let subtitling = &stream.subtitling[0];
+            tracks.push(SubtitleTrack {
+                text_index,
+                language: Some(subtitling.code.clone()),
+                hearing_impaired: subtitling.is_hearing_impaired(),
+                source: SubtitleSource::DVB,
+            });
+            text_index += 1;
+        } else if stream.is_teletext() && teletext_decoding {
+            for page in stream.teletext_pages.iter().filter(|p| p.is_subtitles()) {
+                tracks.push(SubtitleTrack {
+                    text_index,
+                    language: Some(page.code.clone()),
+                    hearing_impaired: page.is_hearing_impaired(),
+                    source: SubtitleSource::Teletext{page: page.page_number()},
+                });
+            }
+            text_index += 1;
+        }
+    }
+    tracks
+}
+
+/// Return the index of the subtitle track to use given the preferred language: a track
+/// in the preferred language, not for the hard of hearing if possible, or failing that
+/// the first track.
+pub fn preferred_subtitle_track(tracks: &[SubtitleTrack], preferred_language: &str) -> Option<usize> {
+    let is_preferred = |track: &SubtitleTrack| match track.language {
+        Some(ref language) => is_same_language(language, preferred_language),
+        None => false,
+    };
+    tracks.iter().position(|t| is_preferred(t) && ! t.hearing_impaired)
+        .or_else(|| tracks.iter().position(|t| is_preferred(t)))
+        .or_else(|| if tracks.is_empty() { None } else { Some(0) })
+}
+
+/// The audio track to change to when cycling through the tracks, `None` if there are none.
+pub fn next_audio_track(track_count: usize, current: Option<usize>) -> Option<usize> {
+    match current {
+        _ if track_count == 0 => None,
+        None => Some(0),
+        Some(index) => Some((index + 1) % track_count),
+    }
+}
+
+/// The subtitle track to change to when cycling through the tracks, where `None` means
+/// subtitles off.
+pub fn next_subtitle_track(track_count: usize, current: Option<usize>) -> Option<usize> {
+    match current {
+        None => if track_count > 0 { Some(0) } else { None },
+        Some(index) => if index + 1 < track_count { Some(index + 1) } else { None },
+    }
+}
+
+/// Split a comma separated list of language codes, as entered in the preferences dialog.
+pub fn parse_language_list(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| ! s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::program_map::test::create_test_program_map;
+
+    fn create_track(index: i32, language: Option<&str>) -> AudioTrack {
+        AudioTrack { index, language: language.map(|s| s.to_string()), codec: None, audio_description: false }
+    }
+
+    #[test]
+    fn known_language_name() {
+        assert_eq!(language_name("eng"), "English");
+        assert_eq!(language_name("CYM"), "Welsh");
+    }
+
+    #[test]
+    fn unknown_language_name_is_the_code() {
+        assert_eq!(language_name("xyz"), "xyz");
+    }
+
+    #[test]
+    fn bibliographic_and_terminological_codes_are_the_same_language() {
+        assert!(is_same_language("ger", "deu"));
+        assert!(! is_same_language("ger", "fra"));
+        assert!(! is_same_language("xyz", "abc"));
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(AudioTrack { index: 0, language: Some("eng".to_string()), codec: Some("AC-3".to_string()), audio_description: false }.label(), "English – AC-3");
+        assert_eq!(AudioTrack { index: 1, language: Some("eng".to_string()), codec: None, audio_description: true }.label(), "English audio description");
+        assert_eq!(create_track(1, Some("nar")).label(), "Audio description");
+        assert_eq!(create_track(2, None).label(), "Track 3");
+    }
+
+    #[test]
+    fn preferred_track_in_preference_order() {
+        let tracks = vec![create_track(0, Some("eng")), create_track(1, Some("fra")), create_track(2, Some("deu"))];
+        assert_eq!(preferred_audio_track(&tracks, &["ger".to_string(), "fra".to_string()]), Some(2));
+        assert_eq!(preferred_audio_track(&tracks, &["spa".to_string(), "fra".to_string()]), Some(1));
+        assert_eq!(preferred_audio_track(&tracks, &["spa".to_string()]), None);
+        assert_eq!(preferred_audio_track(&tracks, &[]), None);
+    }
+
+    #[test]
+    fn audio_description_only_if_asked_for() {
+        let tracks = vec![create_track(0, Some("nar")), create_track(1, Some("eng"))];
+        assert_eq!(preferred_audio_track(&tracks, &["nar".to_string()]), Some(0));
+        assert_eq!(preferred_audio_track(&tracks, &["qad".to_string()]), Some(0));
+        assert_eq!(preferred_audio_track(&tracks, &["eng".to_string()]), Some(1));
+        let tracks = vec![AudioTrack { index: 0, language: Some("eng".to_string()), codec: None, audio_description: true }, create_track(1, Some("eng"))];
+        assert_eq!(preferred_audio_track(&tracks, &["eng".to_string()]), Some(1));
+    }
+
+    #[test]
+    fn subtitle_tracks_from_program_map() {
+        let program_map = create_test_program_map();
+        let tracks = subtitle_tracks(&program_map, true);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].label(), "English (hard of hearing) – DVB");
+        assert_eq!(tracks[1].text_index, 1);
+        assert_eq!(tracks[1].label(), "English – teletext 888");
+        assert_eq!(subtitle_tracks(&program_map, false).len(), 1);
+    }
+
+    #[test]
+    fn preferred_subtitle_track_avoids_hard_of_hearing() {
+        let program_map = create_test_program_map();
+        let tracks = subtitle_tracks(&program_map, true);
+        assert_eq!(preferred_subtitle_track(&tracks, "eng"), Some(1));
+        assert_eq!(preferred_subtitle_track(&tracks[0..1], "eng"), Some(0));
+        assert_eq!(preferred_subtitle_track(&tracks, "fra"), Some(0));
+        assert_eq!(preferred_subtitle_track(&[], "eng"), None);
+    }
+
+    #[test]
+    fn cycle_audio_tracks() {
+        assert_eq!(next_audio_track(2, None), Some(0));
+        assert_eq!(next_audio_track(2, Some(0)), Some(1));
+        assert_eq!(next_audio_track(2, Some(1)), Some(0));
+        assert_eq!(next_audio_track(0, None), None);
+    }
+
+    #[test]
+    fn cycle_subtitle_tracks() {
+        assert_eq!(next_subtitle_track(2, None), Some(0));
+        assert_eq!(next_subtitle_track(2, Some(0)), Some(1));
+        assert_eq!(next_subtitle_track(2, Some(1)), None);
+        assert_eq!(next_subtitle_track(0, None), None);
+    }
+
+    #[test]
+    fn language_list() {
+        assert_eq!(parse_language_list(" eng, Fra ,,deu"), vec!["eng", "fra", "deu"]);
+        assert!(parse_language_list("").is_empty());
+    }
+}
@@ -27,17 +27,18 @@ use gtk::prelude::*;
 
 use crate::dialogs::display_an_error_dialog;
 use crate::dvb;
-use crate::preferences;
+use crate::frontend_manager::FrontendId;
 
 struct TransmitterSelector {
+    frontend: gtk::ComboBoxText,
     transmitter: gtk::ComboBoxText,
     dialog: gtk::Dialog,
 }
 
-/// Return the path to the directory of transmitter files if present.
+/// Return the path to the directory of transmitter files for a delivery system if present.
 /// On Fedora DVBT/DVBT2 files are in /usr/share/dvbv5/dvb-t
 /// On Debian DVBT/DVBT2 files are in /usr/share/dvb/dvb-t
-fn dvbt_transmitter_files_directory_path() -> Option<path::PathBuf> {
+fn dvbt_transmitter_files_directory_path(delivery_system: &dvb::DeliverySystem) -> Option<path::PathBuf> {
     let mut path = path::PathBuf::new();
     path.push("/usr");
     path.push("share");
@@ -46,7 +47,7 @@ fn dvbt_transmitter_files_directory_path() -> Option<path::PathBuf> {
         path.pop();
         path.push("dvb");
     }
-    path.push(match preferences::get_delivery_system() {
+    path.push(match delivery_system {
         dvb::DeliverySystem::ATSC => "atsc",
         dvb::DeliverySystem::DVBC_ANNEX_A => "atsc",
         dvb::DeliverySystem::DVBC_ANNEX_B => "dvb-c",
@@ -58,62 +59,90 @@ fn dvbt_transmitter_files_directory_path() -> Option<path::PathBuf> {
     else { None }
 }
 
-/// Create a dialog to allow the user to select the transmitter file they wish to
-/// generate a channels file for – if a list of transmitter files is present in the
-/// directory presented as the location of them..
-fn create(parent: Option<&gtk::ApplicationWindow>, transmitter_files_directory_path: &path::Path) -> Option<TransmitterSelector> {
+/// Put the names of the transmitter files for a delivery system into the selector,
+/// returning whether there are any.
+fn fill_transmitter_selector(transmitter: &gtk::ComboBoxText, delivery_system: &dvb::DeliverySystem) -> bool {
+    transmitter.remove_all();
+    let mut transmitter_files = match dvbt_transmitter_files_directory_path(delivery_system).map(fs::read_dir) {
+        Some(Ok(iterator)) => iterator.map(|item| item.unwrap().file_name().to_str().unwrap().to_string()).collect::<Vec<String>>(),
+        _ => vec![],
+    };
+    transmitter_files.sort();
+    for name in &transmitter_files {
+        transmitter.append_text(name)
+    }
+    transmitter.set_active(Some(0));
+    ! transmitter_files.is_empty()
+}
+
+/// Create a dialog to allow the user to select the frontend to scan with, and the
+/// transmitter file they wish to generate a channels file for from those for the
+/// delivery system of the frontend.
+fn create(parent: Option<&gtk::ApplicationWindow>, frontends: &[(FrontendId, dvb::DeliverySystem)]) -> TransmitterSelector {
     let dialog = gtk::Dialog::new_with_buttons(
         Some("Me TV Transmitter Chooser"),
         parent,
         gtk::DialogFlags::MODAL,
         &[],
     );
+    let frontend_label = gtk::Label::new(Some("Select the frontend\nto scan with."));
+    let frontend = gtk::ComboBoxText::new();
+    for (fei, delivery_system) in frontends {
+        frontend.append_text(&format!("{} ({})", fei, delivery_system));
+    }
+    frontend.set_active(Some(0));
     let label = gtk::Label::new(Some("Select the transmitter\nyou get signal from."));
     let transmitter = gtk::ComboBoxText::new();
-    let mut transmitter_files = match fs::read_dir(transmitter_files_directory_path) {
-        Ok(iterator) => iterator.map(|item| item.unwrap().file_name().to_str().unwrap().to_string()).collect::<Vec<String>>(),
-        Err(_) => return None
-    };
-    transmitter_files.sort();
-    for name in transmitter_files {
-        transmitter.append_text(&name)
-    }
-    transmitter.set_active(Some(0));
+    fill_transmitter_selector(&transmitter, &frontends[0].1);
+    frontend.connect_changed({
+        let t = transmitter.clone();
+        let delivery_systems = frontends.iter().map(|(_, delivery_system)| delivery_system.clone()).collect::<Vec<dvb::DeliverySystem>>();
+        move |f| {
+            if let Some(index) = f.get_active() {
+                fill_transmitter_selector(&t, &delivery_systems[index as usize]);
+            }
+        }
+    });
     let content_area = dialog.get_content_area();
+    if frontends.len() > 1 {
+        content_area.pack_start(&frontend_label, false, false, 10);
+        content_area.pack_start(&frontend, false, false, 10);
+    }
     content_area.pack_start(&label, false, false, 10);
     // TODO Make the ComboBoxText more easily scrollable?
     content_area.pack_start(&transmitter, false, false, 10);
     dialog.show_all();
-    Some(TransmitterSelector {
+    TransmitterSelector {
+        frontend,
         transmitter,
         dialog,
-    })
+    }
 }
 
-/// Present a dialog to the user to allow them to select the transmitter file to
-/// use to scan to create a channels file.
+/// Present a dialog to the user to allow them to select the frontend and the transmitter
+/// file to use to scan to create a channels file.
 ///
-/// Returns an `Option` with the path on success.
+/// Returns an `Option` with the frontend, its delivery system, and the path on success.
 ///
 /// If there are problems finding a transmitter file, tell the user via message dialog
 /// and return `None`.
-pub fn present(parent: Option<&gtk::ApplicationWindow>) -> Option<path::PathBuf> {
-    match dvbt_transmitter_files_directory_path() {
-        Some(transmitter_files_directory_path) =>  match create(parent, transmitter_files_directory_path.as_path()) {
-            Some(dialog) => {
-                dialog.dialog.run();
-                let mut path = transmitter_files_directory_path;
-                path.push(dialog.transmitter.get_active_text().unwrap().as_str());
-                dialog.dialog.destroy();
-                Some(path)
-            },
-            None => {
-                display_an_error_dialog(parent, "There appear to be no transmitter files,\nperhaps the dtv-scan-tables package is not correctly installed.");
-                None
-            }
+pub fn present(parent: Option<&gtk::ApplicationWindow>, frontends: &[(FrontendId, dvb::DeliverySystem)]) -> Option<(FrontendId, dvb::DeliverySystem, path::PathBuf)> {
+    if frontends.iter().all(|(_, delivery_system)| dvbt_transmitter_files_directory_path(delivery_system).is_none()) {
+        display_an_error_dialog(parent, "There appear to be no transmitter files directory ,\nperhaps the dtv-scan-tables package is not installed.");
+        return None;
+    }
+    let selector = create(parent, frontends);
+    selector.dialog.run();
+    let (fei, delivery_system) = frontends[selector.frontend.get_active().unwrap_or(0) as usize].clone();
+    let transmitter = selector.transmitter.get_active_text();
+    selector.dialog.destroy();
+    match (dvbt_transmitter_files_directory_path(&delivery_system), transmitter) {
+        (Some(mut path), Some(name)) => {
+            path.push(name.as_str());
+            Some((fei, delivery_system, path))
         },
-        None => {
-            display_an_error_dialog(parent, "There appear to be no transmitter files directory ,\nperhaps the dtv-scan-tables package is not installed.");
+        _ => {
+            display_an_error_dialog(parent, &format!("There appear to be no {} transmitter files,\nperhaps the dtv-scan-tables package is not correctly installed.", delivery_system));
             None
         }
     }
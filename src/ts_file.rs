@@ -0,0 +1,212 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Playing a recorded transport stream file instead of the broadcast, so that the
+//! GStreamer side of Me TV can be exercised on a machine with no DVB adapter.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+lazy_static! {
+    static ref TS_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Set the transport stream file to play instead of the broadcast, the path must be absolute.
+pub fn set_ts_file(path: Option<PathBuf>) {
+    if let Ok(mut ts_file) = TS_FILE.lock() {
+        *ts_file = path;
+    }
+}
+
+pub fn get_ts_file() -> Option<PathBuf> {
+    match TS_FILE.lock() {
+        Ok(ts_file) => ts_file.clone(),
+        Err(_) => None,
+    }
+}
+
+/// Return the MRL to play: a dvb:// MRL is replaced by the URI of the transport stream
+/// file, if there is one. The first programme in the file is played whatever the channel.
+pub fn resolve_mrl(mrl: &str, ts_file: Option<&Path>) -> String {
+    match ts_file {
+        Some(path) if mrl.starts_with("dvb://") => match glib::filename_to_uri(path, None) {
+            Ok(uri) => uri.to_string(),
+            Err(_) => mrl.to_string(),
+        },
+        _ => mrl.to_string(),
+    }
+}
+
+/// Is the URI that of a file, which unlike a broadcast comes to an end?
+pub fn is_file_uri(uri: &str) -> bool {
+    uri.starts_with("file:")
+}
+
+/// Play a URI with no display, in real time, passing each message on the bus to a handler,
+/// until the end of the stream or the timeout. GStreamer must have been initialised.
+pub fn play_headless<F: FnMut(&gst::Message)>(uri: &str, timeout: Duration, mut handler: F) -> Result<(), String> {
+    let playbin = gst::ElementFactory::make("playbin", None).map_err(|e| e.to_string())?;
+    playbin.set_property("uri", &uri).map_err(|e| e.to_string())?;
+    for sink_property in &["video-sink", "audio-sink"] {
+        let sink = gst::ElementFactory::make("fakesink", None).map_err(|e| e.to_string())?;
+        // Synchronise with the clock so the stream plays in real time.
+        sink.set_property("sync", &true).map_err(|e| e.to_string())?;
+        playbin.set_property(*sink_property, &sink).map_err(|e| e.to_string())?;
+    }
+    let bus = playbin.get_bus().ok_or_else(|| "The playbin has no bus.".to_string())?;
+    playbin.set_state(gst::State::Playing).map_err(|e| e.to_string())?;
+    let end = Instant::now() + timeout;
+    let result = loop {
+        let remaining = end.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            break Err(format!("Timed out playing {}.", uri));
+        }
+        let message = match bus.timed_pop(gst::ClockTime::from_mseconds(remaining.as_millis() as u64)) {
+            Some(message) => message,
+            None => continue,
+        };
+        handler(&message);
+        match message.view() {
+            gst::MessageView::Eos(..) => break Ok(()),
+            gst::MessageView::Error(error) => break Err(error.get_error().to_string()),
+            _ => {},
+        }
+    };
+    let _ = playbin.set_state(gst::State::Null);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use chrono::{TimeZone, Utc};
+
+    use gst_mpegts;
+
+    use crate::epg_manager::epg_event_from_message;
+    use crate::gstreamer_engine::process_eit_section;
+
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name);
+        resolve_mrl("dvb://Me%20TV%20Radio", Some(&path))
+    }
+
+    /// Playing transport streams needs GStreamer and its MPEG-TS elements, the tests that
+    /// play them are ignored unless asked for with `cargo test -- --ignored`.
+    fn init_gstreamer() {
+        gst::init().unwrap();
+        gst_mpegts::initialise();
+        for name in &["playbin", "tsdemux", "fakesink"] {
+            assert!(gst::ElementFactory::find(name).is_some(), "There is no {} element.", name);
+        }
+    }
+
+    fn element_message_names(uri: &str) -> (Result<(), String>, Vec<String>) {
+        let mut names = vec![];
+        let result = play_headless(uri, Duration::from_secs(10), |message| {
+            if let gst::MessageView::Element(element) = message.view() {
+                if let Some(structure) = element.get_structure() {
+                    names.push(structure.get_name().to_string());
+                }
+            }
+        });
+        (result, names)
+    }
+
+    #[test]
+    fn only_dvb_mrls_are_resolved_to_the_ts_file() {
+        let path = Path::new("/tmp/my recordings/test.ts");
+        assert_eq!(resolve_mrl("dvb://BBC%20ONE", Some(path)), "file:///tmp/my%20recordings/test.ts");
+        assert_eq!(resolve_mrl("dvb://BBC%20ONE", None), "dvb://BBC%20ONE");
+        assert_eq!(resolve_mrl("file:///tmp/other.ts", Some(path)), "file:///tmp/other.ts");
+    }
+
+    #[test]
+    fn only_files_come_to_an_end() {
+        assert!(is_file_uri(&fixture("audio_service.ts")));
+        assert!(! is_file_uri("dvb://BBC%20ONE"));
+    }
+
+    #[test]
+    #[ignore]
+    fn the_tables_are_posted_on_the_bus() {
+        init_gstreamer();
+        let (result, names) = element_message_names(&fixture("audio_service.ts"));
+        assert_eq!(result, Ok(()));
+        for table in &["pat", "pmt", "sdt", "nit", "eit"] {
+            assert!(names.iter().any(|name| name == table), "No {} in {:?}", table, names);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn the_stream_plays_in_real_time() {
+        init_gstreamer();
+        let start = Instant::now();
+        play_headless(&fixture("audio_service.ts"), Duration::from_secs(10), |_| {}).unwrap();
+        // The stream is two seconds long.
+        assert!(start.elapsed() >= Duration::from_millis(1500), "Played in {:?}", start.elapsed());
+    }
+
+    #[test]
+    #[ignore]
+    fn eit_events_reach_the_epg() {
+        init_gstreamer();
+        let (to_epg_manager, from_gstreamer) = channel();
+        play_headless(&fixture("audio_service.ts"), Duration::from_secs(10), |message| {
+            if let gst::MessageView::Element(element) = message.view() {
+                if element.get_structure().map(|s| s.get_name() == "eit").unwrap_or(false) {
+                    process_eit_section(&element, &to_epg_manager);
+                }
+            }
+        }).unwrap();
+        let mut events = from_gstreamer.try_iter().filter_map(|message| epg_event_from_message(&message)).collect::<Vec<_>>();
+        events.sort_by_key(|e| e.event_id);
+        events.dedup();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].service_id, 4164);
+        assert_eq!(events[0].start_time, Utc.ymd(2020, 5, 17).and_hms(20, 0, 0));
+        assert_eq!(events[0].duration, 3600);
+        assert_eq!(events[1].event_id, 2);
+        assert_eq!(events[1].start_time, Utc.ymd(2020, 5, 17).and_hms(21, 0, 0));
+        assert_eq!(events[1].duration, 1800);
+    }
+
+    #[test]
+    #[ignore]
+    fn a_damaged_stream_is_survived() {
+        init_gstreamer();
+        // Whether GStreamer reports an error or the end of the stream does not matter,
+        // what matters is that the good tables before the damage are seen.
+        let (_, names) = element_message_names(&fixture("truncated.ts"));
+        assert!(names.iter().any(|name| name == "pat"), "No pat in {:?}", names);
+    }
+}
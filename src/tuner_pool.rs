@@ -0,0 +1,611 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The pool of tuners shared by the GUI, me-tv-record and so the recordings scheduled by
+//! me-tv-schedule.
+//!
+//! Each use of a tuner is a claim, a file in a directory shared by all the Me TV
+//! processes of a user, so that each process knows what the others are doing. A frontend
+//! can only be opened by one process, so claims share a tuner only if they are from the same
+//! process and for the same multiplex, for example the GUI recording the channel being
+//! watched. A claim of higher priority can take a tuner from claims of lower priority, but
+//! only after the process holding them has asked its user: the process wanting the tuner
+//! asks for it to be given up, and the process holding it either releases its claims or
+//! refuses.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use crate::dvb::DeliverySystem;
+use crate::frontend_info::FrontendId;
+
+/// How long a process holding a tuner has to answer a request to give it up. If there is
+/// no answer in this time the process should give up the tuner, the user may not be there.
+pub const PREEMPTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a process with a user looks for requests to give up its tuners.
+pub const PREEMPTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A tuner and the delivery systems it can receive, an empty list if they are not known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunerCapabilities {
+    pub tuner: FrontendId,
+    pub delivery_systems: Vec<DeliverySystem>,
+}
+
+impl TunerCapabilities {
+    fn can_receive(&self, delivery_system: &Option<DeliverySystem>) -> bool {
+        match delivery_system {
+            Some(d_s) => self.delivery_systems.is_empty() || self.delivery_systems.contains(d_s),
+            None => true,
+        }
+    }
+}
+
+/// What a tuner is being used for, in increasing order of priority.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Viewing,
+    Recording,
+    ScheduledRecording,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Priority {
+    fn from_name(name: &str) -> Option<Priority> {
+        match name {
+            "Viewing" => Some(Priority::Viewing),
+            "Recording" => Some(Priority::Recording),
+            "ScheduledRecording" => Some(Priority::ScheduledRecording),
+            _ => None,
+        }
+    }
+}
+
+/// What is wanted of a tuner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub delivery_system: Option<DeliverySystem>,
+    /// The frequency of the multiplex, if known. Claims can only share a tuner if it is.
+    pub multiplex: Option<u64>,
+    pub priority: Priority,
+    /// What the tuner is wanted for, to tell users of other claims.
+    pub description: String,
+}
+
+/// A use of a tuner by a process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claim {
+    pub id: String,
+    pub tuner: FrontendId,
+    pub multiplex: Option<u64>,
+    pub priority: Priority,
+    pub pid: u32,
+    pub description: String,
+}
+
+impl Claim {
+    fn to_file_contents(&self) -> String {
+        let mut contents = format!("ADAPTER = {}\nFRONTEND = {}\n", self.tuner.adapter, self.tuner.frontend);
+        if let Some(multiplex) = self.multiplex {
+            contents += &format!("MULTIPLEX = {}\n", multiplex);
+        }
+        contents + &format!("PRIORITY = {}\nPID = {}\nDESCRIPTION = {}\n", self.priority, self.pid, self.description)
+    }
+
+    /// The claim from the contents of a claim file, the same key = value format as the
+    /// channels file.
+    fn from_file_contents(id: &str, contents: &str) -> Option<Claim> {
+        let value = |key: &str| contents.lines()
+            .map(|line| line.splitn(2, '=').map(|x| x.trim()).collect::<Vec<&str>>())
+            .find(|parts| parts.len() == 2 && parts[0] == key)
+            .map(|parts| parts[1].to_string());
+        Some(Claim {
+            id: id.to_string(),
+            tuner: FrontendId { adapter: value("ADAPTER")?.parse().ok()?, frontend: value("FRONTEND")?.parse().ok()? },
+            multiplex: match value("MULTIPLEX") {
+                Some(m) => Some(m.parse().ok()?),
+                None => None,
+            },
+            priority: Priority::from_name(&value("PRIORITY")?)?,
+            pid: value("PID")?.parse().ok()?,
+            description: value("DESCRIPTION").unwrap_or_default(),
+        })
+    }
+}
+
+/// The tuner a request should use.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Choice {
+    /// Share a tuner already tuned to the multiplex by the process.
+    Share(FrontendId),
+    /// Use a tuner no-one is using.
+    Free(FrontendId),
+    /// Take a tuner from claims of lower priority.
+    Preempt(FrontendId, Vec<Claim>),
+    Unavailable,
+}
+
+/// Choose a tuner for a request by a process, given the claims on the tuners.
+///
+/// Sharing is preferred to using a free tuner so as to leave tuners for others, and taking
+/// a tuner is the last resort. Of the tuners that could be taken, the one whose claims
+/// have the lowest priority is chosen.
+pub fn choose(tuners: &[TunerCapabilities], claims: &[Claim], request: &Request, pid: u32) -> Choice {
+    let mut candidates = tuners.iter().filter(|t| t.can_receive(&request.delivery_system)).map(|t| t.tuner.clone()).collect::<Vec<FrontendId>>();
+    candidates.sort();
+    let claims_on = |tuner: &FrontendId| claims.iter().filter(|c| c.tuner == *tuner).cloned().collect::<Vec<Claim>>();
+    if request.multiplex.is_some() {
+        if let Some(tuner) = candidates.iter().find(|t| {
+            let on = claims_on(t);
+            ! on.is_empty() && on.iter().all(|c| c.pid == pid && c.multiplex == request.multiplex)
+        }) {
+            return Choice::Share(tuner.clone());
+        }
+    }
+    if let Some(tuner) = candidates.iter().find(|t| claims_on(t).is_empty()) {
+        return Choice::Free(tuner.clone());
+    }
+    let highest_priority = |tuner: &FrontendId| claims_on(tuner).iter().map(|c| c.priority).max();
+    match candidates.iter()
+        .filter(|t| highest_priority(t).map(|p| p < request.priority).unwrap_or(false))
+        .min_by_key(|t| highest_priority(t)) {
+        Some(tuner) => Choice::Preempt(tuner.clone(), claims_on(tuner)),
+        None => Choice::Unavailable,
+    }
+}
+
+/// The result of asking the pool for a tuner.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Allocation {
+    Claimed(Claim),
+    /// The tuner could be had if the claims on it were given up.
+    Busy(FrontendId, Vec<Claim>),
+    Unavailable,
+}
+
+static CLAIM_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a process is still running, if not its claims are stale.
+fn is_alive(pid: u32) -> bool {
+    match kill(Pid::from_raw(pid as i32), None) {
+        Ok(_) => true,
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => false,
+        Err(_) => true,
+    }
+}
+
+/// The claims of all the Me TV processes of the user.
+#[derive(Clone, Debug)]
+pub struct TunerPool {
+    directory: PathBuf,
+    pid: u32,
+}
+
+impl TunerPool {
+    pub fn new(directory: &Path) -> TunerPool {
+        TunerPool { directory: directory.to_path_buf(), pid: process::id() }
+    }
+
+    /// The user's pool: the claims are kept in the XDG runtime directory, or the temporary
+    /// directory if there isn't one.
+    pub fn get_default() -> TunerPool {
+        let directory = match xdg::BaseDirectories::with_prefix("me-tv").ok().and_then(|dirs| dirs.get_runtime_directory().ok().map(|d| d.join("me-tv"))) {
+            Some(directory) => directory,
+            None => std::env::temp_dir().join(format!("me-tv-{}", nix::unistd::getuid())),
+        };
+        TunerPool::new(&directory.join("tuners"))
+    }
+
+    /// The pool as seen by another process, for testing.
+    #[cfg(test)]
+    fn with_pid(&self, pid: u32) -> TunerPool {
+        TunerPool { directory: self.directory.clone(), pid }
+    }
+
+    fn claim_path(&self, id: &str) -> PathBuf { self.directory.join(format!("{}.claim", id)) }
+
+    fn preemption_path(&self, id: &str) -> PathBuf { self.directory.join(format!("{}.preempt", id)) }
+
+    fn refusal_path(&self, id: &str) -> PathBuf { self.directory.join(format!("{}.refused", id)) }
+
+    /// Lock the pool against changes by other processes until the returned file is dropped.
+    fn lock(&self) -> Result<File, String> {
+        fs::create_dir_all(&self.directory).map_err(|e| format!("Could not create {}: {}", self.directory.display(), e))?;
+        let lock_file = OpenOptions::new().create(true).write(true).open(self.directory.join("lock"))
+            .map_err(|e| format!("Could not open the tuner pool lock: {}", e))?;
+        flock(lock_file.as_raw_fd(), FlockArg::LockExclusive).map_err(|e| format!("Could not lock the tuner pool: {}", e))?;
+        Ok(lock_file)
+    }
+
+    /// Read the claims, removing those of processes that have ended. The pool must be locked.
+    fn read_claims(&self) -> Vec<Claim> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut claims = vec![];
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().map(|e| e != "claim").unwrap_or(true) { continue; }
+            let id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            match fs::read_to_string(&path).ok().and_then(|contents| Claim::from_file_contents(&id, &contents)) {
+                Some(claim) if is_alive(claim.pid) => claims.push(claim),
+                _ => self.remove_files(&id),
+            }
+        }
+        claims.sort_by(|a, b| (&a.tuner, &a.id).cmp(&(&b.tuner, &b.id)));
+        claims
+    }
+
+    fn remove_files(&self, id: &str) {
+        for path in &[self.claim_path(id), self.preemption_path(id), self.refusal_path(id)] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Write a claim file, atomically so that a partly written claim is never read.
+    fn write_claim(&self, claim: &Claim) -> Result<(), String> {
+        let temporary_path = self.directory.join(format!("{}.tmp", claim.id));
+        fs::write(&temporary_path, claim.to_file_contents())
+            .and_then(|_| fs::rename(&temporary_path, self.claim_path(&claim.id)))
+            .map_err(|e| format!("Could not write the claim on {}: {}", claim.tuner, e))
+    }
+
+    fn new_claim(&self, tuner: FrontendId, request: &Request) -> Result<Claim, String> {
+        let claim = Claim {
+            id: format!("{}-{}", self.pid, CLAIM_COUNT.fetch_add(1, Ordering::SeqCst)),
+            tuner,
+            multiplex: request.multiplex,
+            priority: request.priority,
+            pid: self.pid,
+            description: request.description.clone(),
+        };
+        self.write_claim(&claim)?;
+        Ok(claim)
+    }
+
+    pub fn get_claims(&self) -> Result<Vec<Claim>, String> {
+        let _lock = self.lock()?;
+        Ok(self.read_claims())
+    }
+
+    /// Claim the best tuner for the request of those given.
+    pub fn claim(&self, tuners: &[TunerCapabilities], request: &Request) -> Result<Allocation, String> {
+        let _lock = self.lock()?;
+        Ok(match choose(tuners, &self.read_claims(), request, self.pid) {
+            Choice::Share(tuner) | Choice::Free(tuner) => Allocation::Claimed(self.new_claim(tuner, request)?),
+            Choice::Preempt(tuner, victims) => Allocation::Busy(tuner, victims),
+            Choice::Unavailable => Allocation::Unavailable,
+        })
+    }
+
+    /// Claim a particular tuner.
+    pub fn claim_tuner(&self, tuner: FrontendId, request: &Request) -> Result<Allocation, String> {
+        self.claim(&[TunerCapabilities { tuner, delivery_systems: vec![] }], &Request { delivery_system: None, ..request.clone() })
+    }
+
+    /// Change the multiplex of a claim, when the channel being watched changes.
+    pub fn retune(&self, claim: &mut Claim, multiplex: Option<u64>) -> Result<(), String> {
+        let _lock = self.lock()?;
+        claim.multiplex = multiplex;
+        self.write_claim(claim)
+    }
+
+    pub fn release(&self, claim: &Claim) {
+        if let Ok(_lock) = self.lock() {
+            self.remove_files(&claim.id);
+        }
+    }
+
+    /// Ask the processes holding claims to give them up.
+    pub fn request_preemption(&self, victims: &[Claim], description: &str) -> Result<(), String> {
+        let _lock = self.lock()?;
+        for victim in victims {
+            fs::write(self.preemption_path(&victim.id), description).map_err(|e| format!("Could not ask for {}: {}", victim.tuner, e))?;
+        }
+        Ok(())
+    }
+
+    /// The claims of this process that another process has asked to be given up, with the
+    /// description of what the other process wants the tuner for.
+    pub fn get_preemption_requests(&self) -> Vec<(Claim, String)> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(_) => return vec![],
+        };
+        self.read_claims().into_iter()
+            .filter(|claim| claim.pid == self.pid && ! self.refusal_path(&claim.id).exists())
+            .filter_map(|claim| {
+                let description = fs::read_to_string(self.preemption_path(&claim.id)).ok()?;
+                Some((claim, description))
+            })
+            .collect()
+    }
+
+    /// Look for requests to give up the claims of this process every so often, passing them
+    /// on whenever they change, until they can no longer be passed on.
+    pub fn watch_preemption_requests<F: FnMut(Vec<(Claim, String)>) -> bool>(&self, interval: Duration, mut send: F) {
+        let mut previous = vec![];
+        loop {
+            let requests = self.get_preemption_requests();
+            if requests != previous {
+                if ! send(requests.clone()) { return; }
+                previous = requests;
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    /// Refuse to give up a claim.
+    pub fn refuse_preemption(&self, claim: &Claim) {
+        if let Ok(_lock) = self.lock() {
+            if let Ok(mut file) = File::create(self.refusal_path(&claim.id)) {
+                let _ = file.write_all(b"refused\n");
+            }
+        }
+    }
+
+    /// Having asked for the claims on a tuner to be given up, wait for them to be, and then
+    /// claim it. An error if any are refused, or they are not given up in time.
+    pub fn wait_for_preemption(&self, tuner: FrontendId, victims: &[Claim], request: &Request, timeout: Duration) -> Result<Claim, String> {
+        let end = Instant::now() + timeout;
+        let result = loop {
+            {
+                let _lock = self.lock()?;
+                if let Some(victim) = victims.iter().find(|v| self.refusal_path(&v.id).exists()) {
+                    break Err(format!("{} is not available, it is being used for {}.", tuner, victim.description));
+                }
+                let claims = self.read_claims();
+                if ! victims.iter().any(|v| claims.iter().any(|c| c.id == v.id)) {
+                    match choose(&[TunerCapabilities { tuner: tuner.clone(), delivery_systems: vec![] }], &claims, &Request { delivery_system: None, ..request.clone() }, self.pid) {
+                        Choice::Share(tuner) | Choice::Free(tuner) => break self.new_claim(tuner, request),
+                        _ => break Err(format!("{} was taken by another claim.", tuner)),
+                    }
+                }
+            }
+            if Instant::now() > end {
+                break Err(format!("{} was not given up in time.", tuner));
+            }
+            thread::sleep(Duration::from_millis(250));
+        };
+        if let Ok(_lock) = self.lock() {
+            for victim in victims {
+                let _ = fs::remove_file(self.preemption_path(&victim.id));
+                let _ = fs::remove_file(self.refusal_path(&victim.id));
+            }
+        }
+        result
+    }
+
+    /// Claim a tuner for a process with no user interface of its own: if the best tuner is
+    /// busy with claims of lower priority, ask for them to be given up and wait.
+    pub fn claim_waiting(&self, tuners: &[TunerCapabilities], request: &Request, timeout: Duration) -> Result<Claim, String> {
+        match self.claim(tuners, request)? {
+            Allocation::Claimed(claim) => Ok(claim),
+            Allocation::Busy(tuner, victims) => {
+                self.request_preemption(&victims, &request.description)?;
+                self.wait_for_preemption(tuner, &victims, request, timeout)
+            },
+            Allocation::Unavailable => Err("No tuner is available.".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tuner(adapter: u8, frontend: u8) -> FrontendId {
+        FrontendId { adapter, frontend }
+    }
+
+    fn dvbt(adapter: u8) -> TunerCapabilities {
+        TunerCapabilities { tuner: tuner(adapter, 0), delivery_systems: vec![DeliverySystem::DVBT, DeliverySystem::DVBT2] }
+    }
+
+    fn dvbc(adapter: u8) -> TunerCapabilities {
+        TunerCapabilities { tuner: tuner(adapter, 0), delivery_systems: vec![DeliverySystem::DVBC_ANNEX_A] }
+    }
+
+    fn request(multiplex: u64, priority: Priority) -> Request {
+        Request { delivery_system: Some(DeliverySystem::DVBT), multiplex: Some(multiplex), priority, description: format!("{:?} {}", priority, multiplex) }
+    }
+
+    fn claim(id: &str, adapter: u8, multiplex: u64, priority: Priority, pid: u32) -> Claim {
+        Claim { id: id.to_string(), tuner: tuner(adapter, 0), multiplex: Some(multiplex), priority, pid, description: id.to_string() }
+    }
+
+    #[test]
+    fn only_tuners_for_the_delivery_system_are_chosen() {
+        let tuners = [dvbc(0), dvbt(1)];
+        assert_eq!(choose(&tuners, &[], &request(490, Priority::Viewing), 1), Choice::Free(tuner(1, 0)));
+        let unknown = TunerCapabilities { tuner: tuner(2, 0), delivery_systems: vec![] };
+        assert_eq!(choose(&[dvbc(0), unknown], &[], &request(490, Priority::Viewing), 1), Choice::Free(tuner(2, 0)));
+    }
+
+    #[test]
+    fn a_tuner_on_the_multiplex_in_the_same_process_is_shared() {
+        let tuners = [dvbt(0), dvbt(1)];
+        let claims = [claim("a", 1, 490, Priority::Viewing, 1)];
+        assert_eq!(choose(&tuners, &claims, &request(490, Priority::Recording), 1), Choice::Share(tuner(1, 0)));
+        assert_eq!(choose(&tuners, &claims, &request(506, Priority::Recording), 1), Choice::Free(tuner(0, 0)));
+    }
+
+    #[test]
+    fn tuners_are_not_shared_between_processes() {
+        let tuners = [dvbt(0), dvbt(1)];
+        let claims = [claim("a", 0, 490, Priority::Viewing, 2)];
+        assert_eq!(choose(&tuners, &claims, &request(490, Priority::Recording), 1), Choice::Free(tuner(1, 0)));
+    }
+
+    #[test]
+    fn a_tuner_is_taken_only_from_lower_priority_claims() {
+        let tuners = [dvbt(0), dvbt(1)];
+        let claims = [claim("a", 0, 490, Priority::Recording, 2), claim("b", 1, 506, Priority::Viewing, 2)];
+        assert_eq!(
+            choose(&tuners, &claims, &request(522, Priority::ScheduledRecording), 1),
+            Choice::Preempt(tuner(1, 0), vec![claims[1].clone()])
+        );
+        assert_eq!(choose(&tuners, &claims, &request(522, Priority::Recording), 1), Choice::Preempt(tuner(1, 0), vec![claims[1].clone()]));
+        assert_eq!(choose(&tuners, &claims, &request(522, Priority::Viewing), 1), Choice::Unavailable);
+    }
+
+    #[test]
+    fn claims_survive_the_file_format() {
+        let stored = claim("12-3", 2, 490_000_000, Priority::ScheduledRecording, 12);
+        assert_eq!(Claim::from_file_contents("12-3", &stored.to_file_contents()), Some(stored));
+        let no_multiplex = Claim { multiplex: None, ..claim("12-4", 0, 0, Priority::Viewing, 12) };
+        assert_eq!(Claim::from_file_contents("12-4", &no_multiplex.to_file_contents()), Some(no_multiplex));
+        assert_eq!(Claim::from_file_contents("12-5", "ADAPTER = 0\n"), None);
+    }
+
+    #[test]
+    fn claims_are_seen_by_other_processes_until_released() {
+        let directory = tempfile::tempdir().unwrap();
+        let gui = TunerPool::new(directory.path());
+        // A process that is certainly running, but is not this one.
+        let recorder = gui.with_pid(1);
+        let viewing = match gui.claim(&[dvbt(0)], &request(490, Priority::Viewing)).unwrap() {
+            Allocation::Claimed(claim) => claim,
+            allocation => panic!("Not claimed: {:?}", allocation),
+        };
+        assert_eq!(recorder.get_claims().unwrap(), vec![viewing.clone()]);
+        assert_eq!(recorder.claim(&[dvbt(0)], &request(490, Priority::Viewing)).unwrap(), Allocation::Unavailable);
+        assert_eq!(recorder.claim(&[dvbt(0)], &request(490, Priority::Recording)).unwrap(), Allocation::Busy(tuner(0, 0), vec![viewing.clone()]));
+        gui.release(&viewing);
+        assert!(recorder.get_claims().unwrap().is_empty());
+    }
+
+    #[test]
+    fn claims_of_ended_processes_are_removed() {
+        let directory = tempfile::tempdir().unwrap();
+        let pool = TunerPool::new(directory.path());
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let ended_pid = child.id();
+        child.wait().unwrap();
+        let stale = claim("stale", 0, 490, Priority::Recording, ended_pid);
+        fs::write(pool.claim_path("stale"), stale.to_file_contents()).unwrap();
+        assert!(pool.get_claims().unwrap().is_empty());
+        assert!(! pool.claim_path("stale").exists());
+    }
+
+    #[test]
+    fn a_tuner_given_up_is_claimed() {
+        let directory = tempfile::tempdir().unwrap();
+        let gui = TunerPool::new(directory.path());
+        let recorder = gui.with_pid(1);
+        let viewing = match gui.claim_tuner(tuner(0, 0), &request(490, Priority::Viewing)).unwrap() {
+            Allocation::Claimed(claim) => claim,
+            allocation => panic!("Not claimed: {:?}", allocation),
+        };
+        let handle = thread::spawn({
+            let gui = gui.clone();
+            move || {
+                loop {
+                    let requests = gui.get_preemption_requests();
+                    if let Some((claim, description)) = requests.first() {
+                        assert_eq!(description, "ScheduledRecording 506");
+                        gui.release(claim);
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+        });
+        let recording = recorder.claim_waiting(&[dvbt(0)], &request(506, Priority::ScheduledRecording), Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(recording.tuner, viewing.tuner);
+        assert_eq!(recording.pid, 1);
+        assert_eq!(gui.get_claims().unwrap(), vec![recording]);
+    }
+
+    #[test]
+    fn a_refusal_to_give_up_a_tuner_is_an_error() {
+        let directory = tempfile::tempdir().unwrap();
+        let gui = TunerPool::new(directory.path());
+        let recorder = gui.with_pid(1);
+        let viewing = match gui.claim_tuner(tuner(0, 0), &request(490, Priority::Viewing)).unwrap() {
+            Allocation::Claimed(claim) => claim,
+            allocation => panic!("Not claimed: {:?}", allocation),
+        };
+        recorder.request_preemption(&[viewing.clone()], "Recording").unwrap();
+        assert_eq!(gui.get_preemption_requests(), vec![(viewing.clone(), "Recording".to_string())]);
+        gui.refuse_preemption(&viewing);
+        assert!(gui.get_preemption_requests().is_empty());
+        assert!(recorder.wait_for_preemption(tuner(0, 0), &[viewing.clone()], &request(506, Priority::Recording), Duration::from_secs(10)).is_err());
+        assert_eq!(gui.get_claims().unwrap(), vec![viewing]);
+    }
+
+    #[test]
+    fn preemption_requests_are_passed_on_when_they_change() {
+        let directory = tempfile::tempdir().unwrap();
+        let gui = TunerPool::new(directory.path());
+        let recorder = gui.with_pid(1);
+        let viewing = match gui.claim_tuner(tuner(0, 0), &request(490, Priority::Viewing)).unwrap() {
+            Allocation::Claimed(claim) => claim,
+            allocation => panic!("Not claimed: {:?}", allocation),
+        };
+        recorder.request_preemption(&[viewing.clone()], "Recording").unwrap();
+        let mut sent = vec![];
+        gui.watch_preemption_requests(Duration::from_millis(1), |requests| {
+            // Refusing ends the request, which is the change that is passed on next.
+            if let Some((claim, _)) = requests.first() {
+                gui.refuse_preemption(claim);
+            }
+            sent.push(requests);
+            sent.len() < 2
+        });
+        assert_eq!(sent, vec![vec![(viewing, "Recording".to_string())], vec![]]);
+    }
+
+    #[test]
+    fn waiting_for_a_tuner_times_out() {
+        let directory = tempfile::tempdir().unwrap();
+        let gui = TunerPool::new(directory.path());
+        let recorder = gui.with_pid(1);
+        let viewing = match gui.claim_tuner(tuner(0, 0), &request(490, Priority::Viewing)).unwrap() {
+            Allocation::Claimed(claim) => claim,
+            allocation => panic!("Not claimed: {:?}", allocation),
+        };
+        assert!(recorder.claim_waiting(&[dvbt(0)], &request(506, Priority::Recording), Duration::from_millis(100)).is_err());
+        assert_eq!(gui.get_claims().unwrap(), vec![viewing]);
+        assert!(gui.get_preemption_requests().is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ *  Me TV — It's TV for me computer.
+ *
+ *  A GTK+/GStreamer client for watching and recording DVB.
+ *
+ *  Copyright © 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Recording a transport stream file with me-tv-record, from the command line to the file.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Needs GStreamer with its MPEG-TS, libav, and x264 plugins, so only run when asked for
+// with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn a_transport_stream_file_is_recorded() {
+    let ts_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("audio_service.ts");
+    let directory = tempfile::tempdir().unwrap();
+    let output_path = directory.path().join("recording.mp4");
+    // The recording ends at the end of the file, two seconds, well before the duration.
+    let output = Command::new(env!("CARGO_BIN_EXE_me-tv-record"))
+        .arg("--channel").arg("Me TV Radio")
+        .arg("--duration").arg("1")
+        .arg("--ts-file").arg(&ts_file)
+        .arg("--output").arg(&output_path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "me-tv-record failed: {}", stdout);
+    assert!(! stdout.contains("Error:"), "me-tv-record failed: {}", stdout);
+    let recording = fs::read(&output_path).unwrap();
+    assert!(recording.len() > 8);
+    assert_eq!(&recording[4..8], b"ftyp");
+}